@@ -0,0 +1,27 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use codecrafters_bittorrent::{
+    decode::{decode_bencoded_value, DecodeContext},
+    utils::{char_slice_to_isize, char_slice_to_usize},
+};
+
+fn parse_digits(c: &mut Criterion) {
+    c.bench_function("char_slice_to_usize", |b| {
+        b.iter(|| char_slice_to_usize(black_box(b"1048576")))
+    });
+    c.bench_function("char_slice_to_isize", |b| {
+        b.iter(|| char_slice_to_isize(black_box(b"-9223372036854775808")))
+    });
+}
+
+fn decode_torrent(c: &mut Criterion) {
+    let data = std::fs::read("sample.torrent").expect("sample.torrent not found");
+    c.bench_function("decode sample.torrent", |b| {
+        b.iter(|| decode_bencoded_value(&mut DecodeContext::new(black_box(data.clone()))))
+    });
+}
+
+criterion_group!(benches, parse_digits, decode_torrent);
+criterion_main!(benches);
@@ -0,0 +1,8 @@
+#![no_main]
+
+use codecrafters_bittorrent::decode::{decode_bencoded_value, DecodeContext};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_bencoded_value(&mut DecodeContext::new(data.to_vec()));
+});
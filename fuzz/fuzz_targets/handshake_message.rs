@@ -0,0 +1,8 @@
+#![no_main]
+
+use codecrafters_bittorrent::http::HandshakeMessage;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = HandshakeMessage::from_bytes(data);
+});
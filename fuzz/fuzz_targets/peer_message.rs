@@ -0,0 +1,8 @@
+#![no_main]
+
+use codecrafters_bittorrent::http::parse_peer_message;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_peer_message(data);
+});
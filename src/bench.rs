@@ -0,0 +1,172 @@
+//! Measure local throughput of the work done while downloading, to tell whether disk or CPU is
+//! slower than the network.
+
+use std::{
+    io::{self, Write},
+    os::unix::fs::FileExt,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    decode::{decode_bencoded_value, DecodeContext},
+    http::{read_file, write_file, DiskBackend},
+    utils::random_u64,
+};
+
+/// Size of data hashed at once, a typical piece length.
+const PIECE_SIZE: usize = 256 * 1024;
+
+/// Size of each random write, the same as a block requested from peers.
+const BLOCK_SIZE: usize = 16 * 1024;
+
+/// Bytes processed by one benchmark and time spent.
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    pub name: &'static str,
+    pub bytes: usize,
+    pub elapsed: Duration,
+}
+
+impl BenchResult {
+    /// Throughput in bytes per second.
+    pub fn rate(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// SHA-1 hash `size` bytes in pieces.
+pub fn hash(size: usize) -> BenchResult {
+    let data = vec![0x5a; PIECE_SIZE];
+    let start = Instant::now();
+    let mut done = 0;
+    while done < size {
+        let mut hasher = Sha1::new();
+        hasher.update(&data);
+        std::hint::black_box(hasher.finalize());
+        done += PIECE_SIZE;
+    }
+    BenchResult {
+        name: "sha1 hash",
+        bytes: done,
+        elapsed: start.elapsed(),
+    }
+}
+
+/// Decode a bencoded list about `size` bytes long, holding file entries like the ones in torrent
+/// files.
+pub fn decode(size: usize) -> BenchResult {
+    let entry = b"d6:lengthi1048576e4:pathl5:music13:track-01.flacee";
+    let mut data = Vec::with_capacity(size + entry.len() + 2);
+    data.push(b'l');
+    while data.len() < size {
+        data.extend_from_slice(entry);
+    }
+    data.push(b'e');
+    let bytes = data.len();
+
+    let start = Instant::now();
+    let value = decode_bencoded_value(&mut DecodeContext::new(data));
+    let elapsed = start.elapsed();
+    std::hint::black_box(value.expect("invalid bencode in benchmark"));
+    BenchResult {
+        name: "bencode decode",
+        bytes,
+        elapsed,
+    }
+}
+
+/// Write `size` bytes to a temporary file in `dir` from start to end.
+pub fn sequential_write(dir: &Path, size: usize) -> io::Result<BenchResult> {
+    let data = vec![0x5a; PIECE_SIZE];
+    let mut file = tempfile::tempfile_in(dir)?;
+    let start = Instant::now();
+    let mut done = 0;
+    while done < size {
+        file.write_all(&data)?;
+        done += data.len();
+    }
+    file.sync_all()?;
+    Ok(BenchResult {
+        name: "sequential write",
+        bytes: done,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Write `size` bytes to a temporary file in `dir` as blocks in random order, like pieces
+/// arriving from peers.
+pub fn random_write(dir: &Path, size: usize) -> io::Result<BenchResult> {
+    let data = vec![0x5a; BLOCK_SIZE];
+    let file = tempfile::tempfile_in(dir)?;
+    let mut offsets = (0..size.div_ceil(BLOCK_SIZE))
+        .map(|x| (x * BLOCK_SIZE) as u64)
+        .collect::<Vec<_>>();
+    shuffle(&mut offsets);
+
+    let start = Instant::now();
+    for offset in offsets.iter() {
+        file.write_all_at(&data, *offset)?;
+    }
+    file.sync_all()?;
+    Ok(BenchResult {
+        name: "random write",
+        bytes: offsets.len() * BLOCK_SIZE,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Write a `size` bytes file to `dir` with disk `backend` and read it back, the way downloaded
+/// files are written and checked.
+pub async fn disk_backend(
+    dir: &Path,
+    size: usize,
+    backend: DiskBackend,
+) -> io::Result<[BenchResult; 2]> {
+    let (write_name, read_name) = match backend {
+        DiskBackend::Tokio => ("tokio write", "tokio read"),
+        DiskBackend::IoUring => ("io-uring write", "io-uring read"),
+    };
+    let file = tempfile::NamedTempFile::new_in(dir)?;
+    let path = file.path().to_str().expect("non utf-8 temporary path");
+    let start = Instant::now();
+    write_file(backend, vec![0x5a; size], path).await?;
+    let write = BenchResult {
+        name: write_name,
+        bytes: size,
+        elapsed: start.elapsed(),
+    };
+    let start = Instant::now();
+    let data = read_file(backend, path).await?;
+    let read = BenchResult {
+        name: read_name,
+        bytes: data.len(),
+        elapsed: start.elapsed(),
+    };
+    Ok([write, read])
+}
+
+/// Run all benchmarks, disk ones write to `dir`.
+pub async fn run_all(dir: &Path, size: usize) -> io::Result<Vec<BenchResult>> {
+    let mut ret = vec![
+        hash(size),
+        decode(size),
+        sequential_write(dir, size)?,
+        random_write(dir, size)?,
+    ];
+    for backend in [DiskBackend::Tokio, DiskBackend::IoUring] {
+        if backend.is_available() {
+            ret.extend(disk_backend(dir, size, backend).await?);
+        }
+    }
+    Ok(ret)
+}
+
+/// Fisher-Yates shuffle.
+fn shuffle<T>(data: &mut [T]) {
+    for i in (1..data.len()).rev() {
+        data.swap(i, (random_u64() % (i as u64 + 1)) as usize);
+    }
+}
@@ -1,9 +1,11 @@
+use std::{collections::HashMap, ops::Range};
+
 use anyhow::{bail, Context};
 use serde_json::Number;
 
 use crate::utils::{
-    char_slice_to_isize, char_slice_to_usize, encode_bytes_to_string, u8_is_digit, BtError,
-    BtResult,
+    char_slice_to_isize, char_slice_to_usize, encode_bytes_to_string, raw_bytes_to_string,
+    string_to_raw_bytes, u8_is_digit, BtResult, DecodeError,
 };
 
 pub struct DecodeContext {
@@ -12,11 +14,46 @@ pub struct DecodeContext {
 
     /// Index of [data] currently decoding.
     pos: usize,
+
+    /// Count of lists and dictionaries containing current position.
+    depth: usize,
+
+    /// Byte ranges of values in the top level dictionary, by key.
+    spans: HashMap<String, Range<usize>>,
+
+    /// Whether values of "pieces" and "peers" are decoded as hex strings.
+    hex_keys: bool,
 }
 
+/// Max nesting of lists and dictionaries, deeper data is rejected instead of overflowing the
+/// stack.
+const MAX_DEPTH: usize = 256;
+
 impl DecodeContext {
     pub fn new(data: Vec<u8>) -> Self {
-        Self { data, pos: 0 }
+        Self {
+            data,
+            pos: 0,
+            depth: 0,
+            spans: HashMap::new(),
+            hex_keys: true,
+        }
+    }
+
+    /// Context decoding every string the same way, without hex strings for "pieces" and
+    /// "peers".
+    pub fn raw(data: Vec<u8>) -> Self {
+        Self {
+            hex_keys: false,
+            ..Self::new(data)
+        }
+    }
+
+    /// Byte range of the value of `key` in the top level dictionary decoded.
+    ///
+    /// Use it to get the original bytes of a value, e.g. the info dict to calculate info hash.
+    pub fn value_span(&self, key: &str) -> Option<Range<usize>> {
+        self.spans.get(key).cloned()
     }
 
     fn pos(&self) -> usize {
@@ -25,12 +62,12 @@ impl DecodeContext {
 
     fn position(&self, ch: u8) -> BtResult<usize> {
         if self.ended() {
-            bail!(BtError::Ended)
+            bail!(DecodeError::Ended)
         }
 
         match self.data.iter().skip(self.pos).position(|x| x == &ch) {
             Some(v) => Ok(v),
-            None => Err(BtError::CharNotFound { pos: self.pos, ch }).context("when find_pos"),
+            None => Err(DecodeError::CharNotFound { pos: self.pos, ch }).context("when find_pos"),
         }
     }
 
@@ -47,17 +84,32 @@ impl DecodeContext {
     }
 
     fn advance_many(&mut self, step: usize) -> Option<&[u8]> {
-        if self.ended() || self.pos + step > self.data.len() {
+        let end = self.pos.checked_add(step)?;
+        if end > self.data.len() {
             return None;
         }
 
-        let ret = &self.data[self.pos..(self.pos + step)];
-        self.pos += step;
+        let ret = &self.data[self.pos..end];
+        self.pos = end;
         Some(ret)
     }
 
     fn ended(&self) -> bool {
-        self.pos > self.data.len() - 1
+        self.pos >= self.data.len()
+    }
+
+    /// Enter a list or dictionary.
+    fn enter(&mut self) -> BtResult<()> {
+        if self.depth >= MAX_DEPTH {
+            bail!(DecodeError::TooDeep(self.pos))
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Leave a list or dictionary.
+    fn leave(&mut self) {
+        self.depth -= 1;
     }
 
     /// Used in test.
@@ -75,8 +127,8 @@ impl From<&str> for DecodeContext {
 
 /// String "5:hello" -> "hello"
 fn decode_string(ctx: &mut DecodeContext) -> BtResult<String> {
-    if ctx.peek().map(|x| u8_is_digit(x)) != Some(true) {
-        bail!(BtError::InvalidString(ctx.pos()))
+    if ctx.peek().map(u8_is_digit) != Some(true) {
+        bail!(DecodeError::InvalidString(ctx.pos()))
     }
 
     let col_idx = ctx
@@ -101,8 +153,8 @@ fn decode_string(ctx: &mut DecodeContext) -> BtResult<String> {
 ///
 /// Like string, but contents are not valid utf8.
 fn decode_bytes(ctx: &mut DecodeContext) -> BtResult<Vec<u8>> {
-    if ctx.peek().map(|x| u8_is_digit(x)) != Some(true) {
-        bail!(BtError::InvalidString(ctx.pos()))
+    if ctx.peek().map(u8_is_digit) != Some(true) {
+        bail!(DecodeError::InvalidString(ctx.pos()))
     }
 
     let col_idx = ctx
@@ -126,10 +178,12 @@ fn decode_bytes(ctx: &mut DecodeContext) -> BtResult<Vec<u8>> {
 /// Interger "i52e" -> 52; "i-52e" -> -52
 fn decode_integer(ctx: &mut DecodeContext) -> BtResult<isize> {
     if ctx.peek() != Some(&b'i') {
-        bail!(BtError::InvalidInterger(ctx.pos()))
+        bail!(DecodeError::InvalidInterger(ctx.pos()))
     }
 
-    let interger_end_pos = ctx.position(b'e').unwrap();
+    let interger_end_pos = ctx
+        .position(b'e')
+        .context("failed to find the end of integer")?;
     // When convert string to integer, do not include the trailing 'e'.
     ctx.advance();
     let number = ctx
@@ -150,10 +204,11 @@ fn decode_integer(ctx: &mut DecodeContext) -> BtResult<isize> {
 /// Returns a json array.
 fn decode_list(ctx: &mut DecodeContext) -> BtResult<serde_json::Value> {
     if ctx.peek() != Some(&b'l') {
-        bail!(BtError::InvalidList(ctx.pos()))
+        bail!(DecodeError::InvalidList(ctx.pos()))
     }
     // Pass the head of list "l".
     ctx.advance();
+    ctx.enter()?;
 
     let mut values = vec![];
 
@@ -164,11 +219,12 @@ fn decode_list(ctx: &mut DecodeContext) -> BtResult<serde_json::Value> {
             _ => { /* continue parsing list */ }
         }
 
-        let value = decode_bencoded_value(ctx)
+        let value = decode_value(ctx)
             .with_context(|| format!("failed to decode list element at pos {}", ctx.pos()))?;
         values.push(value);
     }
     ctx.advance();
+    ctx.leave();
 
     let ret = serde_json::Value::Array(values);
     Ok(ret)
@@ -182,10 +238,11 @@ fn decode_list(ctx: &mut DecodeContext) -> BtResult<serde_json::Value> {
 /// Key must be string and sorted.
 fn decode_dictionary(ctx: &mut DecodeContext) -> BtResult<serde_json::Value> {
     if ctx.peek() != Some(&b'd') {
-        bail!(BtError::InvalidMap(ctx.pos()))
+        bail!(DecodeError::InvalidMap(ctx.pos()))
     }
     // Pass the heading "d".
     ctx.advance();
+    ctx.enter()?;
 
     #[derive(PartialEq, Eq)]
     enum ParseState {
@@ -197,31 +254,39 @@ fn decode_dictionary(ctx: &mut DecodeContext) -> BtResult<serde_json::Value> {
     let mut values = serde_json::Map::new();
     loop {
         match ctx.peek() {
-            Some(&b'e') => break,
+            Some(&b'e') if state == ParseState::None => break,
+            Some(&b'e') => bail!(DecodeError::InvalidMap(ctx.pos())),
             None => bail!("invalid input: dictionary not ended"),
             _ => { /* Continue parsing map */ }
         }
 
         match state {
             ParseState::None => {
-                let value = decode_bencoded_value(ctx)
-                    .with_context(|| format!("failed to decode dictionary at {}", ctx.pos()))?;
-                match value.as_str() {
-                    Some(v) => {
-                        state = ParseState::Key(v.to_string());
-                    }
-                    None => return Err(BtError::InvalidMapKey(ctx.pos, value).into()),
+                // Keys are byte strings like other strings, not necessarily utf8, each byte is
+                // kept as a char.
+                if ctx.peek().map(u8_is_digit) != Some(true) {
+                    let pos = ctx.pos();
+                    let value = decode_value(ctx)
+                        .with_context(|| format!("failed to decode dictionary at {pos}"))?;
+                    return Err(DecodeError::InvalidMapKey(pos, value).into());
                 }
+                let key = decode_bytes(ctx)
+                    .with_context(|| format!("failed to decode dictionary key at {}", ctx.pos()))?;
+                state = ParseState::Key(raw_bytes_to_string(&key));
             }
             ParseState::Key(k) => {
-                if ["pieces", "peers"].contains(&k.as_str()) {
+                if ctx.hex_keys && ["pieces", "peers"].contains(&k.as_str()) {
                     let value = decode_bytes(ctx)
                         .with_context(|| format!("failed to decode dictionary at {}", ctx.pos()))?;
                     values.insert(k, serde_json::Value::String(encode_bytes_to_string(&value)));
                     state = ParseState::None;
                 } else {
-                    let value = decode_bencoded_value(ctx)
+                    let start = ctx.pos();
+                    let value = decode_value(ctx)
                         .with_context(|| format!("failed to decode dictionary at {}", ctx.pos()))?;
+                    if ctx.depth == 1 {
+                        ctx.spans.insert(k.clone(), start..ctx.pos());
+                    }
                     values.insert(k, value);
                     state = ParseState::None;
                 }
@@ -229,24 +294,243 @@ fn decode_dictionary(ctx: &mut DecodeContext) -> BtResult<serde_json::Value> {
         }
     }
     ctx.advance();
+    ctx.leave();
 
     let ret = serde_json::Value::Object(values);
     Ok(ret)
 }
 
-pub fn decode_bencoded_value(ctx: &mut DecodeContext) -> BtResult<serde_json::Value> {
-    let flag = ctx.peek().context("reached the end of data")?;
+/// Decode a bencoded value starting at current position of `ctx`.
+pub fn decode_bencoded_value(ctx: &mut DecodeContext) -> Result<serde_json::Value, DecodeError> {
+    decode_value(ctx).map_err(|e| match e.downcast::<DecodeError>() {
+        Ok(v) => v,
+        Err(e) => DecodeError::Malformed(e.into()),
+    })
+}
+
+/// Decode a whole bencoded value, the reverse of [`crate::encode::to_bytes`].
+///
+/// Strings become json strings of one char per byte, whatever their keys. Trailing data after the
+/// value is an error.
+pub fn from_bytes(data: &[u8]) -> Result<serde_json::Value, DecodeError> {
+    decode_whole(data, decode_bencoded_value)
+}
+
+/// A decoded value with the offset it starts at in data.
+///
+/// Unlike json values, strings are kept as bytes and dictionary entries in the order found,
+/// duplicates included, to check the structure of data beyond its values.
+#[derive(Debug)]
+pub(crate) enum Node {
+    Int(isize),
+    Bytes(Vec<u8>),
+    List(Vec<(usize, Node)>),
+    Dict(Vec<(Vec<u8>, usize, Node)>),
+}
+
+impl Node {
+    /// Offset and value of the first entry of `key`, if a dictionary.
+    pub(crate) fn get(&self, key: &str) -> Option<(usize, &Node)> {
+        match self {
+            Node::Dict(entries) => entries
+                .iter()
+                .find(|(k, _, _)| k == key.as_bytes())
+                .map(|(_, offset, v)| (*offset, v)),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_int(&self) -> Option<isize> {
+        match self {
+            Node::Int(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Node::Bytes(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+fn decode_node_value(ctx: &mut DecodeContext) -> BtResult<Node> {
+    match ctx.peek() {
+        Some(b'i') => Ok(Node::Int(decode_integer(ctx)?)),
+        Some(b'l') => {
+            ctx.advance();
+            ctx.enter()?;
+            let mut values = vec![];
+            loop {
+                match ctx.peek() {
+                    Some(b'e') => break,
+                    None => bail!("invalid input: list not ended"),
+                    _ => {}
+                }
+                let offset = ctx.pos();
+                let value = decode_node_value(ctx)
+                    .with_context(|| format!("failed to decode list element at pos {offset}"))?;
+                values.push((offset, value));
+            }
+            ctx.advance();
+            ctx.leave();
+            Ok(Node::List(values))
+        }
+        Some(b'd') => {
+            ctx.advance();
+            ctx.enter()?;
+            let mut entries = vec![];
+            loop {
+                match ctx.peek() {
+                    Some(b'e') => break,
+                    None => bail!("invalid input: dictionary not ended"),
+                    _ => {}
+                }
+                let key = decode_bytes(ctx)
+                    .with_context(|| format!("failed to decode dictionary key at {}", ctx.pos()))?;
+                let offset = ctx.pos();
+                let value = decode_node_value(ctx)
+                    .with_context(|| format!("failed to decode dictionary at {offset}"))?;
+                entries.push((key, offset, value));
+            }
+            ctx.advance();
+            ctx.leave();
+            Ok(Node::Dict(entries))
+        }
+        _ => Ok(Node::Bytes(decode_bytes(ctx)?)),
+    }
+}
+
+/// Decode a value at current position of `ctx` as a [`Node`].
+fn decode_node(ctx: &mut DecodeContext) -> Result<Node, DecodeError> {
+    decode_node_value(ctx).map_err(|e| match e.downcast::<DecodeError>() {
+        Ok(v) => v,
+        Err(e) => DecodeError::Malformed(e.into()),
+    })
+}
+
+/// Decode a whole bencoded value as a [`Node`], trailing data after the value is an error.
+pub(crate) fn node_from_bytes(data: &[u8]) -> Result<Node, DecodeError> {
+    decode_whole(data, decode_node)
+}
+
+/// Decode `data` with `decode`, failing if it does not consume all of the data.
+fn decode_whole<T>(
+    data: &[u8],
+    decode: fn(&mut DecodeContext) -> Result<T, DecodeError>,
+) -> Result<T, DecodeError> {
+    let mut ctx = DecodeContext::raw(data.to_vec());
+    let value = decode(&mut ctx)?;
+    if !ctx.ended() {
+        return Err(DecodeError::Malformed(
+            format!("trailing data at {}", ctx.pos()).into(),
+        ));
+    }
+    Ok(value)
+}
+
+/// Convert strings and keys in a decoded `value` for display.
+///
+/// Decoded strings hold one char per byte. Here bytes forming valid utf8 are shown as the text
+/// they encode, other bytes are escaped as `\xNN`.
+pub fn display_value(value: &serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::String(s) => serde_json::Value::String(display_string(s)),
+        serde_json::Value::Array(values) => {
+            serde_json::Value::Array(values.iter().map(display_value).collect())
+        }
+        serde_json::Value::Object(map) => serde_json::Value::Object(
+            map.iter()
+                .map(|(k, v)| (display_string(k), display_value(v)))
+                .collect(),
+        ),
+        v => v.clone(),
+    }
+}
+
+fn display_string(s: &str) -> String {
+    let bytes = string_to_raw_bytes(s);
+    let mut ret = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        ret.push_str(chunk.valid());
+        for b in chunk.invalid() {
+            ret.push_str(&format!("\\x{b:02x}"));
+        }
+    }
+    ret
+}
+
+fn decode_value(ctx: &mut DecodeContext) -> BtResult<serde_json::Value> {
+    let flag = ctx.peek().ok_or(DecodeError::Ended)?;
     if u8_is_digit(flag) {
         let s = decode_string(ctx).context("failed to decode string")?;
-        return Ok(serde_json::Value::String(s));
+        Ok(serde_json::Value::String(s))
     } else if flag == &b'i' {
         let n = decode_integer(ctx).context("failed to decode interger")?;
-        return Ok(serde_json::Value::Number(Number::from(n)));
+        Ok(serde_json::Value::Number(Number::from(n)))
     } else if flag == &b'l' {
-        return decode_list(ctx);
+        decode_list(ctx)
     } else if flag == &b'd' {
-        return decode_dictionary(ctx);
+        decode_dictionary(ctx)
     } else {
-        panic!("unsupported format");
+        bail!("unsupported format {flag} at {}", ctx.pos())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_malformed_without_panic() {
+        let deep = "l".repeat(100_000);
+        let inputs = [
+            "",
+            "i",
+            "ie",
+            "i-e",
+            "5:ab",
+            "99999999999999999999999:a",
+            "18446744073709551615:a",
+            "d3:foo",
+            "d3:fooe",
+            "l",
+            deep.as_str(),
+        ];
+        for input in inputs {
+            assert!(
+                decode_bencoded_value(&mut DecodeContext::from(input)).is_err(),
+                "input {input:?} should fail"
+            );
+        }
+        assert!(matches!(
+            decode_bencoded_value(&mut DecodeContext::from(deep.as_str())),
+            Err(DecodeError::TooDeep(_))
+        ));
+        assert_eq!(
+            decode_bencoded_value(&mut DecodeContext::from("0:")).unwrap(),
+            serde_json::json!("")
+        );
+        assert_eq!(
+            decode_bencoded_value(&mut DecodeContext::from("ll1:aee")).unwrap(),
+            serde_json::json!([["a"]])
+        );
+    }
+
+    #[test]
+    fn test_non_utf8_keys() {
+        let data = b"d1:\\i3e6:na\xc3\xafvei2e2:\xff\xfei1ee".to_vec();
+        let value = decode_bencoded_value(&mut DecodeContext::new(data.clone())).unwrap();
+        assert_eq!(value["\u{ff}\u{fe}"], 1);
+        assert_eq!(crate::encode::to_bytes(&value).unwrap(), data);
+        assert_eq!(
+            display_value(&value),
+            serde_json::json!({ "\\xff\\xfe": 1, "naïve": 2, "\\": 3 })
+        );
+        assert!(matches!(
+            decode_bencoded_value(&mut DecodeContext::from("di1ei2ee")),
+            Err(DecodeError::InvalidMapKey(1, _))
+        ));
     }
 }
@@ -0,0 +1,91 @@
+use std::net::IpAddr;
+
+use sha1::{Digest, Sha1};
+
+/// Size of the bloom filter in bytes.
+pub const BLOOM_FILTER_SIZE: usize = 256;
+
+/// Count of bits in the bloom filter, "m" in BEP 33.
+const BITS: usize = BLOOM_FILTER_SIZE * 8;
+
+/// Bloom filter of peer ips defined in BEP 33.
+///
+/// DHT nodes return `BFsd` (seeders) and `BFpe` (downloaders) filters in `get_peers` responses
+/// when requested with `scrape=1`, the count of peers in swarm is estimated from the bits set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter([u8; BLOOM_FILTER_SIZE]);
+
+impl BloomFilter {
+    pub fn new() -> Self {
+        Self([0u8; BLOOM_FILTER_SIZE])
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        Some(Self(data.try_into().ok()?))
+    }
+
+    /// Add an ip into filter.
+    ///
+    /// Two indexes are taken from the sha1 of ip, each sets one bit.
+    ///
+    /// Used in test.
+    #[allow(unused)]
+    pub fn insert(&mut self, ip: &IpAddr) {
+        let mut hasher = Sha1::new();
+        match ip {
+            IpAddr::V4(v) => hasher.update(v.octets()),
+            IpAddr::V6(v) => hasher.update(v.octets()),
+        }
+        let hash: [u8; 20] = hasher.finalize().into();
+        let index1 = (hash[0] as usize | (hash[1] as usize) << 8) % BITS;
+        let index2 = (hash[2] as usize | (hash[3] as usize) << 8) % BITS;
+        self.0[index1 / 8] |= 1 << (index1 % 8);
+        self.0[index2 / 8] |= 1 << (index2 % 8);
+    }
+
+    /// Merge filter returned by another node.
+    pub fn union(&mut self, other: &BloomFilter) {
+        for (x, y) in self.0.iter_mut().zip(other.0.iter()) {
+            *x |= y;
+        }
+    }
+
+    /// Estimate the count of ips inserted.
+    ///
+    /// size = ln(c / m) / (k * ln(1 - 1 / m)) where c is the count of zero bits and k is 2.
+    pub fn estimated_size(&self) -> f64 {
+        let zeros = self
+            .0
+            .iter()
+            .map(|x| x.count_zeros() as usize)
+            .sum::<usize>();
+        if zeros == BITS {
+            return 0.0;
+        }
+        // A full filter can not tell the size, keep it at the max value we can estimate.
+        let c = zeros.max(1) as f64;
+        let m = BITS as f64;
+        (c / m).ln() / (2.0 * (1.0 - 1.0 / m).ln())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    /// Test vector in BEP 33.
+    #[test]
+    fn test_estimated_size() {
+        let mut filter = BloomFilter::new();
+        assert_eq!(filter.estimated_size(), 0.0);
+        for i in 0..=255 {
+            filter.insert(&IpAddr::V4(Ipv4Addr::new(192, 0, 2, i)));
+        }
+        for i in 0..=0x3e7 {
+            filter.insert(&IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i)));
+        }
+        assert_eq!(filter.estimated_size().round(), 1225.0);
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use serde_json::json;
+use tokio::{net::UdpSocket, time::Instant};
+
+mod bloom;
+
+use crate::{
+    decode::{decode_bencoded_value, DecodeContext},
+    encode::{encode_dictionary, EncodeContext},
+    http::{dht_allowed, Peer, PeerSource, ScrapeInfo},
+    utils::{
+        random_bytes, raw_bytes_to_string, string_to_raw_bytes, BtError, BtResult, TrackerError,
+    },
+};
+
+use self::bloom::BloomFilter;
+
+/// Well known nodes to join the DHT network.
+const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Count of nodes queried at the same time.
+const ALPHA: usize = 8;
+
+/// Count of closest nodes a lookup converges to, the "K" in Kademlia.
+const K: usize = 8;
+
+/// Max count of nodes queried in a single lookup.
+const MAX_QUERIES: usize = 128;
+
+/// Time to wait for responses of a batch of queries.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Length of a node in compact node info: 20 bytes id, 4 bytes ip and 2 bytes port.
+const COMPACT_NODE_LENGTH: usize = 26;
+
+#[derive(Debug, Clone)]
+struct Node {
+    /// Node id, unknown for bootstrap nodes until they respond.
+    id: Option<[u8; 20]>,
+
+    addr: SocketAddr,
+}
+
+impl Node {
+    /// XOR distance to `target`, nodes with unknown id are the farthest.
+    fn distance(&self, target: &[u8; 20]) -> [u8; 20] {
+        match &self.id {
+            Some(id) => std::array::from_fn(|i| id[i] ^ target[i]),
+            None => [0xff; 20],
+        }
+    }
+}
+
+/// Result of a `get_peers` lookup.
+#[derive(Debug, Default)]
+pub struct GetPeersResult {
+    pub peers: Vec<Peer>,
+
+    /// Union of seeder filters, only returned when scraping.
+    pub seeds: Option<BloomFilter>,
+
+    /// Union of downloader filters, only returned when scraping.
+    pub downloaders: Option<BloomFilter>,
+}
+
+/// A `get_peers` response.
+struct Response {
+    /// Transaction id.
+    tid: u16,
+
+    /// Id of the responding node.
+    id: [u8; 20],
+
+    /// Nodes closer to the info hash.
+    nodes: Vec<Node>,
+
+    peers: Vec<Peer>,
+
+    seeds: Option<BloomFilter>,
+
+    downloaders: Option<BloomFilter>,
+}
+
+/// Generate a node id for current session.
+fn random_node_id() -> [u8; 20] {
+    let mut id = [0u8; 20];
+    random_bytes(&mut id);
+    id
+}
+
+/// Build a bencoded `get_peers` query.
+///
+/// Set `scrape` to ask for the BEP 33 bloom filters.
+fn get_peers_query(tid: u16, node_id: &[u8; 20], info_hash: &[u8; 20], scrape: bool) -> Vec<u8> {
+    let mut args = json!({
+        "id": raw_bytes_to_string(node_id),
+        "info_hash": raw_bytes_to_string(info_hash),
+    });
+    if scrape {
+        args["scrape"] = json!(1);
+    }
+    let query = json!({
+        "a": args,
+        "q": "get_peers",
+        "t": raw_bytes_to_string(&tid.to_be_bytes()),
+        "y": "q",
+    });
+    let mut ctx = EncodeContext::new();
+    encode_dictionary(&mut ctx, query.as_object().unwrap()).expect("query holds byte strings");
+    ctx.consume()
+}
+
+fn parse_response(data: &[u8]) -> BtResult<Response> {
+    if data.is_empty() {
+        bail!("empty message");
+    }
+    let value = decode_bencoded_value(&mut DecodeContext::new(data.to_vec()))
+        .context("bencode decode failed")?;
+    let raw_field = |v: &serde_json::Value, key: &str| {
+        v.get(key)
+            .and_then(|x| x.as_str())
+            .map(string_to_raw_bytes)
+            .with_context(|| format!("{key} not found"))
+    };
+
+    if value.get("y").and_then(|x| x.as_str()) != Some("r") {
+        bail!("not a response");
+    }
+    let tid = raw_field(&value, "t")?;
+    let tid = u16::from_be_bytes(tid.try_into().ok().context("invalid transaction id")?);
+    let r = value.get("r").context("response body not found")?;
+    let id = raw_field(r, "id")?
+        .try_into()
+        .ok()
+        .context("invalid node id")?;
+
+    let nodes = raw_field(r, "nodes")
+        .unwrap_or_default()
+        .chunks_exact(COMPACT_NODE_LENGTH)
+        .map(|x| {
+            let ip = Ipv4Addr::new(x[20], x[21], x[22], x[23]);
+            let port = u16::from_be_bytes([x[24], x[25]]);
+            Node {
+                id: Some(x[0..20].try_into().unwrap()),
+                addr: SocketAddr::V4(SocketAddrV4::new(ip, port)),
+            }
+        })
+        .collect();
+
+    let peers = r
+        .get("values")
+        .and_then(|x| x.as_array())
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(|x| x.as_str())
+                .filter_map(|x| string_to_raw_bytes(x).try_into().ok())
+                .map(|x: [u8; 6]| Peer::from_compact(&x, PeerSource::Dht))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let filter = |key: &str| {
+        raw_field(r, key)
+            .ok()
+            .and_then(|x| BloomFilter::from_bytes(&x))
+    };
+
+    Ok(Response {
+        tid,
+        id,
+        nodes,
+        peers,
+        seeds: filter("BFsd"),
+        downloaders: filter("BFpe"),
+    })
+}
+
+/// Find peers of the torrent by `info_hash` through DHT.
+///
+/// Iteratively query nodes closer to the info hash until the closest nodes all responded.
+pub async fn get_peers(info_hash: &[u8; 20], scrape: bool) -> Result<GetPeersResult, BtError> {
+    lookup(info_hash, scrape)
+        .await
+        .map_err(|e| BtError::classify(e, |e| TrackerError::Dht(e).into()))
+}
+
+async fn lookup(info_hash: &[u8; 20], scrape: bool) -> BtResult<GetPeersResult> {
+    if !dht_allowed() {
+        bail!("dht is disabled when peers are connected through a proxy");
+    }
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .context("failed to bind udp socket")?;
+    let node_id = random_node_id();
+
+    let mut candidates = vec![];
+    for host in BOOTSTRAP_NODES {
+        // Unavailable bootstrap nodes are fine as long as one of them works.
+        if let Ok(addrs) = tokio::net::lookup_host(host).await {
+            candidates.extend(
+                addrs
+                    .filter(|x| x.is_ipv4())
+                    .map(|addr| Node { id: None, addr }),
+            );
+        }
+    }
+    if candidates.is_empty() {
+        bail!("no dht bootstrap node available");
+    }
+
+    let mut result = GetPeersResult::default();
+    let mut queried = HashSet::new();
+    let mut tid: u16 = 0;
+    let mut buf = [0u8; 2048];
+
+    loop {
+        candidates.sort_by_key(|x| x.distance(info_hash));
+        if candidates.iter().take(K).all(|x| queried.contains(&x.addr)) {
+            break;
+        }
+
+        let batch = candidates
+            .iter()
+            .filter(|x| !queried.contains(&x.addr))
+            .take(ALPHA)
+            .cloned()
+            .collect::<Vec<_>>();
+        if batch.is_empty() || queried.len() >= MAX_QUERIES {
+            break;
+        }
+
+        // Transaction id -> node address.
+        let mut pending = HashMap::new();
+        for node in batch {
+            tid = tid.wrapping_add(1);
+            queried.insert(node.addr);
+            let query = get_peers_query(tid, &node_id, info_hash, scrape);
+            if socket.send_to(&query, node.addr).await.is_ok() {
+                pending.insert(tid, node.addr);
+            }
+        }
+
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        while !pending.is_empty() {
+            let (n, from) =
+                match tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+                    Ok(Ok(v)) => v,
+                    Ok(Err(_)) => continue,
+                    Err(_) => break,
+                };
+            let resp = match parse_response(&buf[0..n]) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            if pending.get(&resp.tid) != Some(&from) {
+                continue;
+            }
+            pending.remove(&resp.tid);
+
+            if let Some(node) = candidates.iter_mut().find(|x| x.addr == from) {
+                node.id = Some(resp.id);
+            }
+            for node in resp.nodes {
+                if !candidates.iter().any(|x| x.addr == node.addr) {
+                    candidates.push(node);
+                }
+            }
+            result.peers.extend(resp.peers);
+            for (filter, total) in [
+                (resp.seeds, &mut result.seeds),
+                (resp.downloaders, &mut result.downloaders),
+            ] {
+                if let Some(filter) = filter {
+                    total.get_or_insert_with(BloomFilter::new).union(&filter);
+                }
+            }
+        }
+
+        // Nodes not responding are no longer candidates.
+        candidates.retain(|x| !pending.values().any(|addr| addr == &x.addr));
+    }
+
+    let mut seen = HashSet::new();
+    result.peers.retain(|x| seen.insert((x.ip.clone(), x.port)));
+    Ok(result)
+}
+
+/// Estimate seeder and leecher counts of the torrent from DHT, using bloom filters in BEP 33.
+pub async fn scrape(info_hash: &[u8; 20]) -> Result<ScrapeInfo, BtError> {
+    let result = get_peers(info_hash, true).await?;
+    if result.seeds.is_none() && result.downloaders.is_none() {
+        return Err(TrackerError::Dht("no dht node responded with scrape info".into()).into());
+    }
+    let estimate = |x: Option<BloomFilter>| x.map(|x| x.estimated_size().round() as usize);
+    Ok(ScrapeInfo {
+        complete: estimate(result.seeds).unwrap_or_default(),
+        incomplete: estimate(result.downloaders).unwrap_or_default(),
+        downloaded: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        let data = [
+            &b"d1:rd2:id20:"[..],
+            &[1; 20],
+            b"5:nodes26:",
+            &[2; 20],
+            &[10, 0, 0, 1, 0x1a, 0xe1],
+            b"6:valuesl6:",
+            &[10, 0, 0, 2, 0x1a, 0xe2],
+            b"3:badee1:t2:\x00\x071:y1:re",
+        ]
+        .concat();
+        let response = parse_response(&data).unwrap();
+        assert_eq!(response.tid, 7);
+        assert_eq!(response.id, [1; 20]);
+        assert_eq!(response.nodes.len(), 1);
+        assert_eq!(response.nodes[0].id, Some([2; 20]));
+        assert_eq!(response.nodes[0].addr.to_string(), "10.0.0.1:6881");
+        // Values of wrong length are skipped.
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(response.peers[0].to_string(), "10.0.0.2:6882");
+        assert_eq!(response.peers[0].source, PeerSource::Dht);
+        assert!(response.seeds.is_none() && response.downloaders.is_none());
+
+        let query = get_peers_query(7, &[1; 20], &[3; 20], false);
+        assert!(matches!(
+            parse_response(&query),
+            Err(e) if e.to_string() == "not a response"
+        ));
+        assert!(parse_response(b"d1:rd2:id3:abce1:t2:\x00\x071:y1:re").is_err());
+        assert!(parse_response(b"").is_err());
+    }
+}
@@ -1,12 +1,34 @@
-use crate::utils::decode_bytes_from_string;
+use crate::utils::EncodeError;
 
 pub struct EncodeContext {
     data: Vec<u8>,
+
+    /// Whether values of "pieces" and "peers" are hex strings written as the bytes they encode,
+    /// the reverse of [`crate::decode::DecodeContext::new`].
+    hex_keys: bool,
+}
+
+impl Default for EncodeContext {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl EncodeContext {
     pub fn new() -> Self {
-        Self { data: vec![] }
+        Self {
+            data: vec![],
+            hex_keys: true,
+        }
+    }
+
+    /// Context encoding every string the same way, the reverse of
+    /// [`crate::decode::DecodeContext::raw`].
+    pub fn raw() -> Self {
+        Self {
+            data: vec![],
+            hex_keys: false,
+        }
     }
 
     fn push_char(&mut self, v: char) {
@@ -38,32 +60,35 @@ impl EncodeContext {
 }
 
 /// String "5:hello" -> "hello"
-fn encode_string(ctx: &mut EncodeContext, s: &str) {
-    ctx.push_usize(s.len());
+///
+/// Strings are encoded back to the raw bytes they were decoded from, so binary
+/// strings (e.g. node ids in DHT messages) survive a round trip. Text has to be converted with
+/// [`crate::utils::raw_bytes_to_string`] first, chars out of byte range are an error.
+fn encode_string(ctx: &mut EncodeContext, s: &str) -> Result<(), EncodeError> {
+    let bytes = s
+        .chars()
+        .map(|x| u8::try_from(x).map_err(|_| EncodeError::InvalidChar(x)))
+        .collect::<Result<Vec<_>, _>>()?;
+    ctx.push_usize(bytes.len());
     ctx.push_char(':');
-    ctx.append(s.as_bytes().to_vec());
+    ctx.append(bytes);
+    Ok(())
 }
 
 /// Interger "i52e" -> 52; "i-52e" -> -52
-fn encode_integer(ctx: &mut EncodeContext, i: isize) {
-    ctx.push_char('i');
-    if i < 0 {
-        ctx.push_char('-');
-        ctx.push_usize(i as usize);
-    } else {
-        ctx.push_usize(i.abs() as usize);
-    }
-    ctx.push_char('e');
+fn encode_integer(ctx: &mut EncodeContext, i: i128) {
+    ctx.append(format!("i{i}e").into_bytes());
 }
 
 /// List starts with "l" and ends with "e".
 /// "l5:helloi52ee" ["hello", 52]
-fn encode_list(ctx: &mut EncodeContext, v: &Vec<serde_json::Value>) {
+fn encode_list(ctx: &mut EncodeContext, v: &Vec<serde_json::Value>) -> Result<(), EncodeError> {
     ctx.push_char('l');
     for vv in v {
-        encode_json_value(ctx, vv);
+        encode_json_value(ctx, vv)?;
     }
     ctx.push_char('e');
+    Ok(())
 }
 
 /// Dictionary
@@ -72,28 +97,120 @@ fn encode_list(ctx: &mut EncodeContext, v: &Vec<serde_json::Value>) {
 /// "d3:foo3:bar5:helloi52ee" -> {"hello": 52, "foo":"bar"}
 ///
 /// Key must be string and sorted.
-pub fn encode_dictionary(ctx: &mut EncodeContext, v: &serde_json::Map<String, serde_json::Value>) {
+pub fn encode_dictionary(
+    ctx: &mut EncodeContext,
+    v: &serde_json::Map<String, serde_json::Value>,
+) -> Result<(), EncodeError> {
     ctx.push_char('d');
     for (k, v) in v.iter() {
-        encode_string(ctx, k);
-        if ["pieces", "peers"].contains(&k.as_str()) {
-            let bs = decode_bytes_from_string(v.as_str().unwrap());
+        encode_string(ctx, k)?;
+        if ctx.hex_keys && ["pieces", "peers"].contains(&k.as_str()) {
+            let bs = v
+                .as_str()
+                .and_then(|x| hex::decode(x).ok())
+                .ok_or_else(|| EncodeError::InvalidHex(k.clone()))?;
             ctx.push_usize(bs.len());
             ctx.push_char(':');
             ctx.append(bs);
         } else {
-            encode_json_value(ctx, v);
+            encode_json_value(ctx, v)?;
         }
     }
     ctx.push_char('e');
+    Ok(())
 }
 
-fn encode_json_value(ctx: &mut EncodeContext, v: &serde_json::Value) {
+fn encode_json_value(ctx: &mut EncodeContext, v: &serde_json::Value) -> Result<(), EncodeError> {
     match v {
-        serde_json::Value::Number(number) => encode_integer(ctx, number.as_i64().unwrap() as isize),
+        serde_json::Value::Number(number) => {
+            let i = match (number.as_i64(), number.as_u64()) {
+                (Some(v), _) => v as i128,
+                (None, Some(v)) => v as i128,
+                _ => return Err(EncodeError::Unsupported(v.clone())),
+            };
+            encode_integer(ctx, i);
+            Ok(())
+        }
         serde_json::Value::String(s) => encode_string(ctx, s),
         serde_json::Value::Array(values) => encode_list(ctx, values),
         serde_json::Value::Object(map) => encode_dictionary(ctx, map),
-        _ => panic!("unsupported data"),
+        serde_json::Value::Null | serde_json::Value::Bool(_) => {
+            Err(EncodeError::Unsupported(v.clone()))
+        }
+    }
+}
+
+/// Encode a value produced by [`crate::decode::from_bytes`] back to bencode.
+///
+/// `from_bytes(&to_bytes(&v)?)` gives `v` back, binary strings included. Null, bool and float,
+/// which bencode can not represent, and strings holding chars out of byte range are an error.
+pub fn to_bytes(value: &serde_json::Value) -> Result<Vec<u8>, EncodeError> {
+    let mut ctx = EncodeContext::raw();
+    encode_json_value(&mut ctx, value)?;
+    Ok(ctx.consume())
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+    use serde_json::Value;
+
+    use crate::{decode::from_bytes, utils::raw_bytes_to_string};
+
+    use super::*;
+
+    /// Bencode strings, holding any bytes.
+    fn bytes_string() -> impl Strategy<Value = String> {
+        prop::collection::vec(any::<u8>(), 0..32).prop_map(|x| raw_bytes_to_string(&x))
+    }
+
+    fn bencode_value() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            bytes_string().prop_map(Value::String),
+            any::<i64>().prop_map(|x| Value::Number(x.into())),
+        ];
+        leaf.prop_recursive(4, 64, 8, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..8).prop_map(Value::Array),
+                prop::collection::btree_map(bytes_string(), inner, 0..8)
+                    .prop_map(|x| Value::Object(x.into_iter().collect())),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(value in bencode_value()) {
+            let encoded = to_bytes(&value).unwrap();
+            let decoded = from_bytes(&encoded).unwrap();
+            prop_assert_eq!(&decoded, &value);
+            prop_assert_eq!(to_bytes(&decoded).unwrap(), encoded);
+        }
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        let value = serde_json::json!({ "peers": "xyz", "pieces": 1, "u": u64::MAX });
+        assert_eq!(
+            to_bytes(&value).unwrap(),
+            b"d5:peers3:xyz6:piecesi1e1:ui18446744073709551615ee"
+        );
+        for value in [
+            Value::Null,
+            serde_json::json!(true),
+            serde_json::json!(1.5),
+            serde_json::json!("\u{100}"),
+        ] {
+            assert!(to_bytes(&value).is_err());
+        }
+
+        // Hex strings are only special in the context used for torrents.
+        let value = serde_json::json!({ "pieces": "ab" });
+        let mut ctx = EncodeContext::new();
+        encode_dictionary(&mut ctx, value.as_object().unwrap()).unwrap();
+        assert_eq!(ctx.consume(), b"d6:pieces1:\xabe");
+        let mut ctx = EncodeContext::new();
+        let value = serde_json::json!({ "pieces": "xyz" });
+        assert!(encode_dictionary(&mut ctx, value.as_object().unwrap()).is_err());
     }
 }
@@ -0,0 +1,165 @@
+//! Download engine, the single code path from a torrent file or magnet link to downloaded data.
+//!
+//! Commands of the binary are thin wrappers around [`Engine`], so are other users of the library.
+
+use crate::{
+    dht,
+    http::{
+        discover_peer, download_piece, magnet_handshake, DownloadHandle, DownloadOptions, Peers,
+    },
+    magnet::Magnet,
+    torrent::Torrent,
+    utils::{BtError, ParseError, PeerError, ProtocolError},
+};
+
+/// Where to get the torrent from.
+#[derive(Debug, Clone)]
+pub enum Source {
+    Torrent(Torrent),
+
+    /// Info dict is fetched from peers before downloading.
+    Magnet(Magnet),
+}
+
+impl Source {
+    /// Parse `target` as a magnet link if it starts with `magnet:`, otherwise as a torrent file
+    /// path.
+    pub fn parse(target: &str) -> Result<Self, BtError> {
+        if target.starts_with("magnet:") {
+            Ok(Self::Magnet(Magnet::new(target)?))
+        } else {
+            Ok(Self::Torrent(Torrent::parse_from_file(target)?))
+        }
+    }
+
+    pub fn info_hash(&self) -> &[u8; 20] {
+        match self {
+            Source::Torrent(v) => v.info_hash(),
+            Source::Magnet(v) => &v.info_hash,
+        }
+    }
+
+    /// Tracker to announce to, magnet links may have none.
+    pub fn tracker_url(&self) -> Option<&str> {
+        match self {
+            Source::Torrent(v) => Some(v.tracker_url()),
+            Source::Magnet(v) => v.tracker_url(),
+        }
+    }
+}
+
+impl From<Torrent> for Source {
+    fn from(value: Torrent) -> Self {
+        Self::Torrent(value)
+    }
+}
+
+impl From<Magnet> for Source {
+    fn from(value: Magnet) -> Self {
+        Self::Magnet(value)
+    }
+}
+
+/// Entry of all downloads.
+pub struct Engine;
+
+impl Engine {
+    /// Get the torrent of `source`, fetching the info dict from peers for magnet links.
+    ///
+    /// Fetched info dicts are checked against the info hash in the magnet link.
+    pub async fn resolve(source: impl Into<Source>) -> Result<Torrent, BtError> {
+        let magnet = match source.into() {
+            Source::Torrent(v) => return Ok(v),
+            Source::Magnet(v) => v,
+        };
+        // Torrents without tracker find peers in DHT.
+        let tracker_url = magnet.tracker_url().unwrap_or_default().to_string();
+        let resp = magnet_handshake(&magnet, true).await?;
+        let raw_info = resp.raw_info.ok_or(ParseError::MissingField("info"))?;
+        let torrent = Torrent::from_metadata(tracker_url, &raw_info)?;
+        // Peers may send anything, only the info dict of the magnet's hash is the right one.
+        if torrent.info_hash() != &magnet.info_hash {
+            return Err(ProtocolError::MetadataHashMismatch {
+                expected: hex::encode(magnet.info_hash),
+                actually: hex::encode(torrent.info_hash()),
+            }
+            .into());
+        }
+        Ok(torrent)
+    }
+
+    /// Start downloading the whole file of `source` to `file_path`.
+    ///
+    /// Returns after the torrent is resolved, the download keeps running in background.
+    pub async fn download(
+        source: impl Into<Source>,
+        file_path: String,
+        options: DownloadOptions,
+    ) -> Result<DownloadHandle, BtError> {
+        let torrent = Self::resolve(source).await?;
+        Ok(DownloadHandle::spawn(torrent, file_path, options))
+    }
+
+    /// Download piece `index` of `source` to `file_path`.
+    ///
+    /// Peers are discovered from tracker if `peers` is `None`.
+    pub async fn download_piece(
+        source: impl Into<Source>,
+        index: usize,
+        file_path: String,
+        peers: Option<Peers>,
+    ) -> Result<(), BtError> {
+        let torrent = Self::resolve(source).await?;
+        let peers = match peers {
+            Some(v) => v,
+            None if !torrent.has_trackers() => dht::get_peers(torrent.info_hash(), false)
+                .await?
+                .peers
+                .into_iter()
+                .collect(),
+            None => {
+                let peer_info = discover_peer(
+                    torrent.tracker_url(),
+                    torrent.info_hash(),
+                    0,
+                    0,
+                    torrent.length(),
+                )
+                .await?;
+                peer_info.check_reachable()?;
+                peer_info.peers
+            }
+        };
+        if peers.is_empty() {
+            return Err(PeerError::NoPeers.into());
+        }
+        download_piece(&torrent, &peers, file_path, index).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_source() {
+        let source = Source::parse(
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&tr=http%3A%2F%2Fa.b%2Fannounce",
+        )
+        .unwrap();
+        assert!(matches!(source, Source::Magnet(_)));
+        assert_eq!(source.tracker_url(), Some("http://a.b/announce"));
+
+        let source = Source::parse(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.torrent")).unwrap();
+        assert!(matches!(source, Source::Torrent(_)));
+        assert_eq!(
+            hex::encode(source.info_hash()),
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+        );
+
+        assert!(matches!(
+            Source::parse("magnet:?xt=urn:sha1:abc"),
+            Err(BtError::Parse(ParseError::InvalidMagnetPrefix))
+        ));
+    }
+}
@@ -0,0 +1,182 @@
+//! Country and ASN of peers from MaxMind databases, to show where the swarm is.
+
+use std::{io, net::IpAddr, path::Path};
+
+use maxminddb::{geoip2, Reader};
+use serde::Serialize;
+
+use crate::utils::BtError;
+
+/// Where a peer is, fields are `None` if not found in the databases.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PeerLocation {
+    /// ISO 3166 country code.
+    pub country: Option<String>,
+
+    /// Autonomous system number.
+    pub asn: Option<u32>,
+
+    /// Organization of the autonomous system.
+    pub as_org: Option<String>,
+}
+
+/// Opened MaxMind databases.
+pub struct GeoIp {
+    /// Country or city databases.
+    country: Vec<Reader<Vec<u8>>>,
+
+    asn: Vec<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    /// Open databases in `paths`, database types are detected from their metadata.
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> Result<Self, BtError> {
+        let mut geoip = Self {
+            country: vec![],
+            asn: vec![],
+        };
+        for path in paths {
+            let reader = Reader::open_readfile(path)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if reader.metadata.database_type.contains("ASN") {
+                geoip.asn.push(reader);
+            } else {
+                geoip.country.push(reader);
+            }
+        }
+        Ok(geoip)
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> PeerLocation {
+        let mut location = PeerLocation::default();
+        for reader in self.country.iter() {
+            if let Ok(v) = reader.lookup::<geoip2::Country>(ip) {
+                location.country = v.country.and_then(|x| x.iso_code).map(|x| x.to_string());
+                break;
+            }
+        }
+        for reader in self.asn.iter() {
+            if let Ok(v) = reader.lookup::<geoip2::Asn>(ip) {
+                location.asn = v.autonomous_system_number;
+                location.as_org = v.autonomous_system_organization.map(|x| x.to_string());
+                break;
+            }
+        }
+        location
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Control byte and size of a MaxMind data field of `kind`, extended types are above 7.
+    fn field(kind: u8, size: usize) -> Vec<u8> {
+        let (size, extra) = match size {
+            0..=28 => (size as u8, vec![]),
+            _ => (29, vec![(size - 29) as u8]),
+        };
+        let mut buf = match kind {
+            0..=7 => vec![kind << 5 | size],
+            _ => vec![size, kind - 7],
+        };
+        buf.extend(extra);
+        buf
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut buf = field(2, s.len());
+        buf.extend(s.as_bytes());
+        buf
+    }
+
+    fn uint(kind: u8, v: u64) -> Vec<u8> {
+        let bytes = v.to_be_bytes();
+        let bytes = &bytes[v.leading_zeros() as usize / 8..];
+        let mut buf = field(kind, bytes.len());
+        buf.extend(bytes);
+        buf
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut buf = field(7, entries.len());
+        for (k, v) in entries {
+            buf.extend(string(k));
+            buf.extend(v);
+        }
+        buf
+    }
+
+    /// IPv4 database holding `data` for 1.0.0.0/8 only.
+    fn database(database_type: &str, data: Vec<u8>) -> Vec<u8> {
+        let node_count = 8u32;
+        let mut buf = vec![];
+        for i in 0..node_count {
+            let next = if i == 7 { node_count + 16 } else { i + 1 };
+            let (left, right) = if i == 7 {
+                (node_count, next)
+            } else {
+                (next, node_count)
+            };
+            buf.extend(&left.to_be_bytes()[1..]);
+            buf.extend(&right.to_be_bytes()[1..]);
+        }
+        buf.extend([0; 16]);
+        buf.extend(data);
+        buf.extend(b"\xab\xcd\xefMaxMind.com");
+        buf.extend(map(&[
+            ("binary_format_major_version", uint(5, 2)),
+            ("binary_format_minor_version", uint(5, 0)),
+            ("build_epoch", uint(9, 0)),
+            ("database_type", string(database_type)),
+            ("description", map(&[])),
+            ("ip_version", uint(5, 4)),
+            ("languages", field(11, 0)),
+            ("node_count", uint(6, node_count as u64)),
+            ("record_size", uint(5, 24)),
+        ]));
+        buf
+    }
+
+    #[test]
+    fn test_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let country = dir.path().join("country.mmdb");
+        let asn = dir.path().join("asn.mmdb");
+        std::fs::write(
+            &country,
+            database(
+                "GeoLite2-Country",
+                map(&[("country", map(&[("iso_code", string("AU"))]))]),
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            &asn,
+            database(
+                "GeoLite2-ASN",
+                map(&[
+                    ("autonomous_system_number", uint(6, 13335)),
+                    ("autonomous_system_organization", string("Example")),
+                ]),
+            ),
+        )
+        .unwrap();
+
+        let geoip = GeoIp::open(&[&country, &asn]).unwrap();
+        assert_eq!(
+            geoip.lookup("1.2.3.4".parse().unwrap()),
+            PeerLocation {
+                country: Some("AU".to_string()),
+                asn: Some(13335),
+                as_org: Some("Example".to_string()),
+            }
+        );
+        assert_eq!(
+            geoip.lookup("2.2.3.4".parse().unwrap()),
+            PeerLocation::default()
+        );
+
+        assert!(GeoIp::open(&[dir.path().join("missing.mmdb")]).is_err());
+    }
+}
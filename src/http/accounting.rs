@@ -0,0 +1,173 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::disk::write_atomic;
+
+/// Bytes transferred, summed over all runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferTotals {
+    pub downloaded: usize,
+
+    pub uploaded: usize,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct TransferRecords {
+    lifetime: TransferTotals,
+
+    /// Keyed by hex info hash.
+    torrents: HashMap<String, TransferTotals>,
+}
+
+impl TransferRecords {
+    /// Read records in `path`, empty if the file does not exist.
+    fn read(path: &Path) -> io::Result<Self> {
+        match std::fs::read(path) {
+            Ok(v) => Ok(serde_json::from_slice(&v)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn add(&mut self, info_hash: &str, downloaded: usize, uploaded: usize) {
+        for totals in [
+            &mut self.lifetime,
+            self.torrents.entry(info_hash.to_string()).or_default(),
+        ] {
+            totals.downloaded += downloaded;
+            totals.uploaded += uploaded;
+        }
+    }
+}
+
+/// Bytes transferred per torrent and in all torrents, saved in the session directory.
+///
+/// Downloads sharing the session directory each add their own bytes, saving merges them into the
+/// file under a lock instead of overwriting others.
+#[derive(Debug)]
+pub struct TransferLog {
+    path: PathBuf,
+
+    records: TransferRecords,
+
+    /// Added since loaded or last saved, keyed by hex info hash.
+    pending: Vec<(String, usize, usize)>,
+}
+
+impl TransferLog {
+    /// Load the log in `session_dir`.
+    ///
+    /// Missing or broken log files are shown as empty, but broken ones are never overwritten, see
+    /// [`TransferLog::save`].
+    pub fn load(session_dir: &Path) -> Self {
+        let path = session_dir.join("transfer.json");
+        let records = TransferRecords::read(&path).unwrap_or_default();
+        Self {
+            path,
+            records,
+            pending: vec![],
+        }
+    }
+
+    /// Add bytes transferred since loaded to the log file, which may have been updated by others
+    /// meanwhile.
+    ///
+    /// Fails without writing if the file can not be parsed, to keep totals recoverable by hand.
+    pub fn save(&mut self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let lock = File::create(self.path.with_extension("json.lock"))?;
+        lock.lock()?;
+        let mut records = TransferRecords::read(&self.path)?;
+        for (info_hash, downloaded, uploaded) in self.pending.iter() {
+            records.add(info_hash, *downloaded, *uploaded);
+        }
+        write_atomic(&self.path, &serde_json::to_vec(&records)?)?;
+        self.records = records;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Totals of torrent `info_hash`.
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> TransferTotals {
+        self.records
+            .torrents
+            .get(&hex::encode(info_hash))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Totals of all torrents.
+    pub fn lifetime(&self) -> TransferTotals {
+        self.records.lifetime
+    }
+
+    /// Add bytes transferred in a run of torrent `info_hash`.
+    pub(super) fn add(&mut self, info_hash: &[u8; 20], downloaded: usize, uploaded: usize) {
+        let info_hash = hex::encode(info_hash);
+        self.records.add(&info_hash, downloaded, uploaded);
+        self.pending.push((info_hash, downloaded, uploaded));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transfer_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = TransferLog::load(dir.path());
+        log.add(&[1u8; 20], 100, 0);
+        log.add(&[2u8; 20], 50, 10);
+        log.save().unwrap();
+
+        let mut log = TransferLog::load(dir.path());
+        log.add(&[1u8; 20], 20, 0);
+        assert_eq!(
+            log.torrent(&[1u8; 20]),
+            TransferTotals {
+                downloaded: 120,
+                uploaded: 0
+            }
+        );
+        assert_eq!(log.torrent(&[3u8; 20]), TransferTotals::default());
+        assert_eq!(
+            log.lifetime(),
+            TransferTotals {
+                downloaded: 170,
+                uploaded: 10
+            }
+        );
+
+        // Another download saved meanwhile, both are kept.
+        let mut other = TransferLog::load(dir.path());
+        other.add(&[2u8; 20], 5, 0);
+        other.save().unwrap();
+        log.save().unwrap();
+        log.save().unwrap();
+        let log = TransferLog::load(dir.path());
+        assert_eq!(log.lifetime().downloaded, 175);
+        assert_eq!(log.torrent(&[2u8; 20]).downloaded, 55);
+    }
+
+    #[test]
+    fn test_broken_log_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transfer.json");
+        std::fs::write(&path, b"{\"lifetime\":").unwrap();
+        let mut log = TransferLog::load(dir.path());
+        assert_eq!(log.lifetime(), TransferTotals::default());
+        log.add(&[1u8; 20], 100, 0);
+        assert!(log.save().is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"{\"lifetime\":");
+    }
+}
@@ -0,0 +1,154 @@
+/// Set of pieces as a bit vector, in the layout of `bitfield` messages: the highest bit of the
+/// first byte is piece 0.
+///
+/// Spare bits after the last piece are always clear.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+
+    /// Count of pieces.
+    len: usize,
+}
+
+impl Bitfield {
+    /// No piece in `len` pieces.
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// All of `len` pieces.
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self {
+            bytes: vec![0xff; len.div_ceil(8)],
+            len,
+        };
+        bitfield.clear_spare();
+        bitfield
+    }
+
+    /// Pieces in `bytes` of a `bitfield` message, extra bytes are dropped and missing ones are
+    /// treated as no piece.
+    pub fn from_bytes(mut bytes: Vec<u8>, len: usize) -> Self {
+        bytes.resize(len.div_ceil(8), 0);
+        let mut bitfield = Self { bytes, len };
+        bitfield.clear_spare();
+        bitfield
+    }
+
+    fn clear_spare(&mut self) {
+        if !self.len.is_multiple_of(8) {
+            if let Some(v) = self.bytes.last_mut() {
+                *v &= 0xff << (8 - self.len % 8);
+            }
+        }
+    }
+
+    /// Bytes as in a `bitfield` message.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Piece `index` is in set, `false` if out of range.
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    /// Add piece `index`, out of range ones are ignored.
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    /// Remove piece `index`.
+    pub fn unset(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.bytes.iter().map(|x| x.count_ones() as usize).sum()
+    }
+
+    /// No piece in set.
+    pub fn none(&self) -> bool {
+        self.bytes.iter().all(|x| *x == 0)
+    }
+
+    /// Pieces in both sets, the length is the shorter one.
+    pub fn intersection(&self, other: &Bitfield) -> Bitfield {
+        let len = self.len.min(other.len);
+        let bytes = self
+            .bytes
+            .iter()
+            .zip(other.bytes.iter())
+            .map(|(a, b)| a & b)
+            .collect();
+        Self::from_bytes(bytes, len)
+    }
+
+    /// Pieces in `self` but not in `other`.
+    pub fn difference(&self, other: &Bitfield) -> Bitfield {
+        let bytes = self
+            .bytes
+            .iter()
+            .enumerate()
+            .map(|(i, a)| a & !other.bytes.get(i).copied().unwrap_or(0))
+            .collect();
+        Self::from_bytes(bytes, self.len)
+    }
+
+    /// Indexes of pieces in set, in ascending order.
+    pub fn iter_ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|x| self.has(*x))
+    }
+
+    /// The lowest index in set.
+    pub fn first_one(&self) -> Option<usize> {
+        self.iter_ones().next()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bitfield() {
+        let mut ours = Bitfield::new(10);
+        ours.set(1);
+        ours.set(9);
+        ours.set(10);
+        assert_eq!(ours.as_bytes(), &[0b0100_0000, 0b0100_0000]);
+        assert!(ours.has(9) && !ours.has(8) && !ours.has(10));
+        assert_eq!(ours.count_ones(), 2);
+
+        assert_eq!(Bitfield::full(10).as_bytes(), &[0xff, 0b1100_0000]);
+        let theirs = Bitfield::from_bytes(vec![0b1100_0000, 0xff, 0xff], 10);
+        assert_eq!(theirs.as_bytes(), &[0b1100_0000, 0b1100_0000]);
+
+        let needed = Bitfield::full(10).difference(&ours);
+        assert_eq!(needed.count_ones(), 8);
+        assert_eq!(
+            theirs.intersection(&needed).iter_ones().collect::<Vec<_>>(),
+            [0, 8]
+        );
+        assert_eq!(needed.first_one(), Some(0));
+
+        ours.unset(1);
+        ours.unset(9);
+        assert!(ours.none());
+    }
+}
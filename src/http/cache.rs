@@ -0,0 +1,232 @@
+use std::{collections::BTreeMap, fmt::Display, io, str::FromStr, time::Duration};
+
+use serde::Deserialize;
+
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    time::Instant,
+};
+
+/// When written data is synced to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FsyncPolicy {
+    /// Leave it to the system.
+    Never,
+
+    /// Once after all pieces written.
+    #[default]
+    OnComplete,
+
+    /// After each flush of the cache, slow but loses little on crash.
+    EachFlush,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Self::Never),
+            "on-complete" => Ok(Self::OnComplete),
+            "each-flush" => Ok(Self::EachFlush),
+            v => Err(format!(
+                "unknown fsync policy {v}, expected \"never\", \"on-complete\" or \"each-flush\""
+            )),
+        }
+    }
+}
+
+impl Display for FsyncPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsyncPolicy::Never => write!(f, "never"),
+            FsyncPolicy::OnComplete => write!(f, "on-complete"),
+            FsyncPolicy::EachFlush => write!(f, "each-flush"),
+        }
+    }
+}
+
+/// Options of the write cache.
+#[derive(Debug, Clone)]
+pub struct WriteCacheOptions {
+    /// Bytes of verified pieces held before writing them out.
+    pub size: usize,
+
+    /// Max time verified pieces are held before writing them out.
+    pub flush_interval: Duration,
+
+    pub fsync: FsyncPolicy,
+}
+
+impl Default for WriteCacheOptions {
+    fn default() -> Self {
+        Self {
+            size: 16 * 1024 * 1024,
+            flush_interval: Duration::from_secs(10),
+            fsync: FsyncPolicy::default(),
+        }
+    }
+}
+
+/// Hold verified pieces in memory and write them to file in batches, sorted by offset.
+///
+/// Pieces arrive in any order, batching them turns many random small writes into fewer
+/// sequential ones.
+pub(super) struct WriteCache {
+    file: File,
+
+    options: WriteCacheOptions,
+
+    /// Offset in file -> data.
+    pending: BTreeMap<u64, Vec<u8>>,
+
+    pending_bytes: usize,
+
+    last_flush: Instant,
+}
+
+impl WriteCache {
+    /// Create file at `file_path` with `length` bytes, replacing existing file.
+    ///
+    /// The existing file is unlinked instead of truncated, it may be a hard link to a duplicate
+    /// download elsewhere.
+    pub(super) async fn create(
+        file_path: &str,
+        length: usize,
+        options: WriteCacheOptions,
+    ) -> io::Result<Self> {
+        match tokio::fs::remove_file(file_path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        Self::open_with(file_path, length, options, true).await
+    }
+
+    /// Open existing file at `file_path` keeping its data, resized to `length` bytes.
+    pub(super) async fn open(
+        file_path: &str,
+        length: usize,
+        options: WriteCacheOptions,
+    ) -> io::Result<Self> {
+        Self::open_with(file_path, length, options, false).await
+    }
+
+    async fn open_with(
+        file_path: &str,
+        length: usize,
+        options: WriteCacheOptions,
+        truncate: bool,
+    ) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(truncate)
+            .truncate(truncate)
+            .open(file_path)
+            .await?;
+        file.set_len(length as u64).await?;
+        Ok(Self {
+            file,
+            options,
+            pending: BTreeMap::new(),
+            pending_bytes: 0,
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// Add `data` at `offset`, write out all pending data if the cache is full or held too long.
+    pub(super) async fn write(&mut self, offset: usize, data: Vec<u8>) -> io::Result<()> {
+        self.pending_bytes += data.len();
+        self.pending.insert(offset as u64, data);
+        if self.pending_bytes >= self.options.size
+            || self.last_flush.elapsed() >= self.options.flush_interval
+        {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Read `length` bytes at `offset`, pending data is written out first.
+    pub(super) async fn read(&mut self, offset: usize, length: usize) -> io::Result<Vec<u8>> {
+        if !self.pending.is_empty() {
+            self.flush().await?;
+        }
+        let mut buf = vec![0u8; length];
+        self.file.seek(io::SeekFrom::Start(offset as u64)).await?;
+        self.file.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
+
+    /// Write out all pending data.
+    pub(super) async fn flush(&mut self) -> io::Result<()> {
+        // Continuous pieces are written without seeking.
+        let mut pos = None;
+        for (offset, data) in std::mem::take(&mut self.pending) {
+            if pos != Some(offset) {
+                self.file.seek(io::SeekFrom::Start(offset)).await?;
+            }
+            self.file.write_all(&data).await?;
+            pos = Some(offset + data.len() as u64);
+        }
+        self.file.flush().await?;
+        self.pending_bytes = 0;
+        self.last_flush = Instant::now();
+        if self.options.fsync == FsyncPolicy::EachFlush {
+            self.file.sync_data().await?;
+        }
+        Ok(())
+    }
+
+    /// Write out all pending data and sync as the policy says.
+    pub(super) async fn finish(mut self) -> io::Result<()> {
+        self.flush().await?;
+        if self.options.fsync == FsyncPolicy::OnComplete {
+            self.file.sync_all().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let path = path.to_str().unwrap();
+        let options = WriteCacheOptions {
+            size: 4,
+            ..Default::default()
+        };
+        let mut cache = WriteCache::create(path, 6, options).await.unwrap();
+        cache.write(4, b"ef".to_vec()).await.unwrap();
+        cache.write(0, b"ab".to_vec()).await.unwrap();
+        assert_eq!(cache.pending_bytes, 0);
+        cache.write(2, b"cd".to_vec()).await.unwrap();
+        assert_eq!(cache.pending_bytes, 2);
+        assert_eq!(cache.read(1, 3).await.unwrap(), b"bcd");
+        cache.finish().await.unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"abcdef");
+    }
+
+    #[tokio::test]
+    async fn test_create_over_hard_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("a");
+        let path = dir.path().join("b");
+        std::fs::write(&source, b"abcdef").unwrap();
+        std::fs::hard_link(&source, &path).unwrap();
+        let path = path.to_str().unwrap();
+        let mut cache = WriteCache::create(path, 3, WriteCacheOptions::default())
+            .await
+            .unwrap();
+        cache.write(0, b"xyz".to_vec()).await.unwrap();
+        cache.finish().await.unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"xyz");
+        assert_eq!(std::fs::read(&source).unwrap(), b"abcdef");
+    }
+}
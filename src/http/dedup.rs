@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::torrent::Torrent;
+
+use super::disk::write_atomic;
+
+/// Files downloaded before, keyed by their content, saved in the session directory.
+///
+/// Torrents with the same piece length and hashes have the same content even if their info hashes
+/// differ, like ones made for different trackers. The file of one can be hard linked for the
+/// other instead of downloading and storing the same data twice.
+#[derive(Debug)]
+pub struct ContentIndex {
+    path: PathBuf,
+
+    /// Keyed by hex [`content_key`].
+    files: HashMap<String, PathBuf>,
+
+    /// Files recorded since loaded, not saved yet.
+    pending: HashMap<String, PathBuf>,
+}
+
+impl ContentIndex {
+    /// Load the index in `session_dir`.
+    ///
+    /// Missing or broken index files are treated as empty.
+    pub fn load(session_dir: &Path) -> Self {
+        let path = session_dir.join("content.json");
+        let files = read_files(&path);
+        Self {
+            path,
+            files,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Add files recorded since loaded to the index file, which may have been updated by others
+    /// meanwhile.
+    pub fn save(&mut self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let lock = File::create(self.path.with_extension("json.lock"))?;
+        lock.lock()?;
+        let mut files = read_files(&self.path);
+        files.extend(self.pending.drain());
+        write_atomic(&self.path, &serde_json::to_vec(&files)?)?;
+        self.files = files;
+        Ok(())
+    }
+
+    /// File recorded with the same content as `torrent`, if it still exists.
+    ///
+    /// The file may have changed since recorded, check it before use.
+    pub fn find(&self, torrent: &Torrent) -> Option<&Path> {
+        self.files
+            .get(&content_key(torrent))
+            .map(PathBuf::as_path)
+            .filter(|x| x.is_file())
+    }
+
+    /// Record `path` as the complete content of `torrent`.
+    pub(super) fn insert(&mut self, torrent: &Torrent, path: PathBuf) {
+        let key = content_key(torrent);
+        self.files.insert(key.clone(), path.clone());
+        self.pending.insert(key, path);
+    }
+}
+
+/// Read index file at `path`, missing or broken files are empty.
+fn read_files(path: &Path) -> HashMap<String, PathBuf> {
+    std::fs::read(path)
+        .ok()
+        .and_then(|x| serde_json::from_slice(&x).ok())
+        .unwrap_or_default()
+}
+
+/// Key of the content of `torrent`, from its length, piece length and piece hashes.
+fn content_key(torrent: &Torrent) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update((torrent.length() as u64).to_be_bytes());
+    hasher.update((torrent.nominal_piece_length() as u64).to_be_bytes());
+    for hash in torrent.info.piece_hashes.iter() {
+        hasher.update(hash);
+    }
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_torrent(name: &str) -> Torrent {
+        content_torrent(name, 'a')
+    }
+
+    /// Torrent named `name` of a piece whose hash is `hash` repeated.
+    fn content_torrent(name: &str, hash: char) -> Torrent {
+        let data = format!(
+            "d8:announce3:foo4:infod6:lengthi2e4:name{}:{name}12:piece lengthi2e6:pieces20:{}ee",
+            name.len(),
+            hash.to_string().repeat(20)
+        );
+        Torrent::parse_from_bytes(data.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_content_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a");
+        std::fs::write(&file, b"ab").unwrap();
+        let mut index = ContentIndex::load(dir.path());
+        index.insert(&test_torrent("a"), file.clone());
+        index.save().unwrap();
+
+        let index = ContentIndex::load(dir.path());
+        // Same content under another name.
+        assert_eq!(index.find(&test_torrent("b")), Some(file.as_path()));
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(index.find(&test_torrent("b")), None);
+    }
+
+    #[test]
+    fn test_save_keeps_others() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        std::fs::write(&a, b"ab").unwrap();
+        std::fs::write(&b, b"cd").unwrap();
+        // Two runs loaded the index before either saved.
+        let mut first = ContentIndex::load(dir.path());
+        let mut second = ContentIndex::load(dir.path());
+        first.insert(&test_torrent("a"), a.clone());
+        first.save().unwrap();
+        second.insert(&content_torrent("b", 'b'), b.clone());
+        second.save().unwrap();
+
+        let index = ContentIndex::load(dir.path());
+        assert_eq!(index.find(&test_torrent("a")), Some(a.as_path()));
+        assert_eq!(index.find(&content_torrent("b", 'b')), Some(b.as_path()));
+    }
+}
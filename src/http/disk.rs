@@ -0,0 +1,385 @@
+use std::{
+    fmt::Display,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use crate::utils::BtError;
+
+/// How downloaded data is written to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiskBackend {
+    /// Tokio file api, writes run on the blocking thread pool.
+    #[default]
+    Tokio,
+
+    /// Submit writes to io_uring, only available on Linux with the `io-uring` feature.
+    IoUring,
+}
+
+impl DiskBackend {
+    /// Whether the backend is built in on this platform.
+    pub fn is_available(&self) -> bool {
+        match self {
+            DiskBackend::Tokio => true,
+            DiskBackend::IoUring => cfg!(all(target_os = "linux", feature = "io-uring")),
+        }
+    }
+}
+
+impl FromStr for DiskBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tokio" => Ok(Self::Tokio),
+            "io-uring" if Self::IoUring.is_available() => Ok(Self::IoUring),
+            "io-uring" => {
+                Err("io-uring disk backend requires Linux and the io-uring feature".to_string())
+            }
+            v => Err(format!(
+                "unknown disk backend {v}, expected \"tokio\" or \"io-uring\""
+            )),
+        }
+    }
+}
+
+impl Display for DiskBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiskBackend::Tokio => write!(f, "tokio"),
+            DiskBackend::IoUring => write!(f, "io-uring"),
+        }
+    }
+}
+
+/// Mode bits of created files and directories, the umask applies to the ones not set.
+///
+/// Only takes effect on unix.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileModes {
+    /// Mode of downloaded files.
+    pub file: Option<u32>,
+
+    /// Mode of created directories.
+    pub dir: Option<u32>,
+}
+
+static FILE_MODES: OnceLock<FileModes> = OnceLock::new();
+
+fn file_modes() -> &'static FileModes {
+    FILE_MODES.get_or_init(FileModes::default)
+}
+
+/// Set mode bits of created files and directories, must be called before downloading.
+pub fn set_file_modes(modes: FileModes) -> Result<(), BtError> {
+    FILE_MODES
+        .set(modes)
+        .map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "file modes already set"))?;
+    Ok(())
+}
+
+/// Create directory `path` and its parents with the mode set in [`set_file_modes`].
+pub fn create_dir_all(path: &Path) -> io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    if let Some(mode) = file_modes().dir {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(mode);
+    }
+    builder.create(path)
+}
+
+/// Apply the file mode set in [`set_file_modes`] to downloaded `file_path`.
+///
+/// An `executable` file gets execute bits for whoever can read it.
+pub(super) fn apply_file_mode(file_path: &str, executable: bool) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let configured = file_modes().file;
+        if configured.is_none() && !executable {
+            return Ok(());
+        }
+        let mode = match configured {
+            Some(v) => v,
+            None => std::fs::metadata(file_path)?.permissions().mode() & 0o7777,
+        };
+        let mode = if executable {
+            with_execute_bits(mode)
+        } else {
+            mode
+        };
+        std::fs::set_permissions(file_path, std::fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (file_path, executable);
+    Ok(())
+}
+
+/// Add execute bits to `mode` where it has read bits.
+fn with_execute_bits(mode: u32) -> u32 {
+    mode | ((mode & 0o444) >> 2)
+}
+
+/// Move file `from` to `to`, replacing existing file.
+///
+/// Falls back to [`copy_across`] if they are on different file systems.
+pub(super) async fn move_file(from: &str, to: &str) -> io::Result<()> {
+    match tokio::fs::rename(from, to).await {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => copy_across(from, to).await,
+        v => v,
+    }
+}
+
+/// Copy `from` to a temporary file beside `to`, sync it and rename it to `to`, then remove `from`.
+///
+/// `to` never holds a partial copy: if copying fails, like on a full disk, only the temporary
+/// file is written and it is removed.
+async fn copy_across(from: &str, to: &str) -> io::Result<()> {
+    let (from, to) = (PathBuf::from(from), PathBuf::from(to));
+    tokio::task::spawn_blocking(move || {
+        let mut file = tempfile::NamedTempFile::new_in(parent_dir(&to))?;
+        io::copy(&mut std::fs::File::open(&from)?, file.as_file_mut())?;
+        file.as_file()
+            .set_permissions(std::fs::metadata(&from)?.permissions())?;
+        file.as_file().sync_all()?;
+        file.persist(&to).map_err(|e| e.error)?;
+        std::fs::remove_file(&from)
+    })
+    .await?
+}
+
+/// Write `data` to a temporary file beside `path`, sync it and rename it to `path`.
+///
+/// `path` holds either its old content or all of `data`, even if interrupted.
+pub(super) fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = tempfile::NamedTempFile::new_in(parent_dir(path))?;
+    file.write_all(data)?;
+    file.as_file().sync_all()?;
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Directory `path` is in, the current one for bare file names.
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(v) if !v.as_os_str().is_empty() => v,
+        _ => Path::new("."),
+    }
+}
+
+/// Hard link `to` to existing file `from`, so the same data is available at both paths.
+///
+/// Falls back to copying if they are on different file systems.
+pub fn link_file(from: &Path, to: &Path) -> io::Result<()> {
+    match std::fs::hard_link(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => std::fs::copy(from, to).map(|_| ()),
+        v => v,
+    }
+}
+
+/// Write `data` to `file_path` with `backend`, replacing existing file.
+pub async fn write_file(backend: DiskBackend, data: Vec<u8>, file_path: &str) -> io::Result<()> {
+    if std::fs::exists(file_path)? {
+        std::fs::remove_file(file_path)?;
+    }
+    match backend {
+        DiskBackend::Tokio => tokio::fs::write(file_path, data).await,
+        DiskBackend::IoUring => uring::write_file(data, file_path.to_string()).await,
+    }
+}
+
+/// Read the whole of `file_path` with `backend`.
+pub async fn read_file(backend: DiskBackend, file_path: &str) -> io::Result<Vec<u8>> {
+    match backend {
+        DiskBackend::Tokio => tokio::fs::read(file_path).await,
+        DiskBackend::IoUring => uring::read_file(file_path.to_string()).await,
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring {
+    use std::{future::Future, io, sync::OnceLock};
+
+    use tokio::sync::{mpsc, oneshot};
+    use tokio_uring::buf::IoBuf;
+
+    /// Size read at once when the file grows while reading it.
+    const READ_CHUNK: usize = 256 * 1024;
+
+    /// Called on the worker, spawns the io work there.
+    type Job = Box<dyn FnOnce() + Send>;
+
+    /// Jobs run on the io_uring worker.
+    static WORKER: OnceLock<mpsc::UnboundedSender<Job>> = OnceLock::new();
+
+    /// Run `f` on the io_uring worker thread and wait for its result.
+    ///
+    /// The io_uring runtime is single threaded and can not live in tokio's one, so one runtime
+    /// runs on its own thread for the whole process and all jobs are spawned on it.
+    async fn run<T, F, Fut>(f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = io::Result<T>> + 'static,
+    {
+        let worker = WORKER.get_or_init(|| {
+            let (tx, mut rx) = mpsc::unbounded_channel::<Job>();
+            std::thread::spawn(move || {
+                tokio_uring::start(async move {
+                    while let Some(job) = rx.recv().await {
+                        job();
+                    }
+                })
+            });
+            tx
+        });
+        let (tx, rx) = oneshot::channel();
+        // Futures of tokio_uring are not Send, create them on the worker.
+        let job: Job = Box::new(move || {
+            tokio_uring::spawn(async move {
+                let _ = tx.send(f().await);
+            });
+        });
+        worker
+            .send(job)
+            .map_err(|_| io::Error::other("io_uring worker stopped"))?;
+        rx.await
+            .map_err(|_| io::Error::other("io_uring worker stopped"))?
+    }
+
+    pub(super) async fn write_file(data: Vec<u8>, file_path: String) -> io::Result<()> {
+        run(move || async move {
+            let file = tokio_uring::fs::File::create(&file_path).await?;
+            let mut buf = data;
+            let mut pos = 0;
+            while pos < buf.len() {
+                let (written, slice) = file.write_at(buf.slice(pos..), pos as u64).await;
+                buf = slice.into_inner();
+                match written? {
+                    0 => return Err(io::ErrorKind::WriteZero.into()),
+                    n => pos += n,
+                }
+            }
+            file.sync_all().await?;
+            file.close().await
+        })
+        .await
+    }
+
+    pub(super) async fn read_file(file_path: String) -> io::Result<Vec<u8>> {
+        run(move || async move {
+            let len = std::fs::metadata(&file_path)?.len() as usize;
+            let file = tokio_uring::fs::File::open(&file_path).await?;
+            let mut buf = Vec::with_capacity(len.max(1));
+            loop {
+                if buf.len() == buf.capacity() {
+                    buf.reserve(READ_CHUNK);
+                }
+                let pos = buf.len();
+                let (read, slice) = file.read_at(buf.slice(pos..), pos as u64).await;
+                buf = slice.into_inner();
+                if read? == 0 {
+                    break;
+                }
+            }
+            file.close().await?;
+            Ok(buf)
+        })
+        .await
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+mod uring {
+    use std::io;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "io-uring disk backend requires Linux and the io-uring feature",
+        )
+    }
+
+    pub(super) async fn write_file(_data: Vec<u8>, _file_path: String) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub(super) async fn read_file(_file_path: String) -> io::Result<Vec<u8>> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_execute_bits() {
+        assert_eq!(with_execute_bits(0o644), 0o755);
+        assert_eq!(with_execute_bits(0o600), 0o700);
+        assert_eq!(with_execute_bits(0o640), 0o750);
+    }
+
+    #[tokio::test]
+    async fn test_disk_backends() {
+        assert_eq!(
+            "io-uring".parse::<DiskBackend>().is_ok(),
+            DiskBackend::IoUring.is_available()
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let path = path.to_str().unwrap();
+        for backend in [DiskBackend::Tokio, DiskBackend::IoUring] {
+            if !backend.is_available() {
+                continue;
+            }
+            write_file(backend, b"old data".to_vec(), path)
+                .await
+                .unwrap();
+            write_file(backend, b"abc".to_vec(), path).await.unwrap();
+            assert_eq!(read_file(backend, path).await.unwrap(), b"abc");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_move_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        create_dir_all(&dir.join("incomplete")).unwrap();
+        let from = dir.join("incomplete").join("a");
+        let to = dir.join("a");
+        std::fs::write(&from, b"abc").unwrap();
+        std::fs::write(&to, b"old").unwrap();
+        move_file(from.to_str().unwrap(), to.to_str().unwrap())
+            .await
+            .unwrap();
+        assert!(!from.exists());
+        assert_eq!(std::fs::read(&to).unwrap(), b"abc");
+    }
+
+    #[tokio::test]
+    async fn test_copy_across() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("a");
+        let to = dir.path().join("b");
+        std::fs::write(&from, b"abc").unwrap();
+        std::fs::write(&to, b"old").unwrap();
+        copy_across(from.to_str().unwrap(), to.to_str().unwrap())
+            .await
+            .unwrap();
+        assert!(!from.exists());
+        assert_eq!(std::fs::read(&to).unwrap(), b"abc");
+
+        // Failed copy leaves the destination and no temporary file behind.
+        let err = copy_across(from.to_str().unwrap(), to.to_str().unwrap()).await;
+        assert!(err.is_err());
+        assert_eq!(std::fs::read(&to).unwrap(), b"abc");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,1051 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use std::sync::{Arc, Mutex as StdMutex};
+
+use tokio::{
+    sync::{mpsc, watch, Mutex},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    dht,
+    torrent::Torrent,
+    utils::{BtError, BtResult},
+};
+
+use super::{
+    accounting::TransferLog,
+    bitfield::Bitfield,
+    cache::WriteCacheOptions,
+    dedup::ContentIndex,
+    disk, fetch_file,
+    picker::{Deadlines, PiecePicker},
+    reputation::PeerDb,
+    send_keep_alive,
+    storage::{DownloadStorage, FileStorage, MemoryStorage, Storage, StreamStorage},
+    verify::{check_file, PieceState},
+    Discovery, DiskBackend, PeerConnection, PeerSource, Peers, TrackerTiers, RATE_SMOOTHING,
+};
+
+/// Time without a piece verified before reporting [`DownloadEvent::Stalled`].
+pub(super) const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Max bytes of pieces completed out of order held when streaming to stdout.
+const STREAM_BUFFER_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Interval of keep-alive messages sent to peers while paused.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub(super) type EventSender = mpsc::UnboundedSender<DownloadEvent>;
+
+/// Pause and cancel state shared between [`DownloadHandle`] and the download task.
+#[derive(Debug, Clone)]
+pub(super) struct DownloadControl {
+    paused: watch::Receiver<bool>,
+    cancel: CancellationToken,
+}
+
+impl DownloadControl {
+    /// Control for downloads can not be paused or cancelled.
+    pub(super) fn unstoppable() -> Self {
+        Self {
+            paused: watch::channel(false).1,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Wait until the download is cancelled.
+    pub(super) async fn cancelled(&self) {
+        self.cancel.cancelled().await
+    }
+
+    /// Run `future` to the end, or fail with [`BtError::Cancelled`] once cancelled.
+    ///
+    /// For steps not checking the control themselves, like announcing to trackers.
+    pub(super) async fn cancellable<T, E: From<BtError>>(
+        &self,
+        future: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        tokio::select! {
+            v = future => v,
+            _ = self.cancel.cancelled() => Err(BtError::Cancelled.into()),
+        }
+    }
+
+    /// Return immediately if running, otherwise keep `peer_connections` alive until resumed.
+    ///
+    /// Fail if cancelled.
+    pub(super) async fn wait_resumed(
+        &self,
+        peer_connections: &[Arc<Mutex<PeerConnection>>],
+    ) -> BtResult<()> {
+        let mut paused = self.paused.clone();
+        loop {
+            if self.cancel.is_cancelled() {
+                return Err(BtError::Cancelled.into());
+            }
+            if !*paused.borrow_and_update() {
+                return Ok(());
+            }
+            tokio::select! {
+                _ = paused.changed() => {}
+                _ = self.cancel.cancelled() => {}
+                _ = tokio::time::sleep(KEEP_ALIVE_INTERVAL) => send_keep_alive(peer_connections).await,
+            }
+        }
+    }
+
+    /// Keep `peer_connections` alive until `deadline`.
+    ///
+    /// Fail if cancelled.
+    pub(super) async fn wait_until(
+        &self,
+        deadline: Instant,
+        peer_connections: &[Arc<Mutex<PeerConnection>>],
+    ) -> BtResult<()> {
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            tokio::select! {
+                _ = tokio::time::sleep((deadline - now).min(KEEP_ALIVE_INTERVAL)) => {
+                    send_keep_alive(peer_connections).await
+                }
+                _ = self.cancel.cancelled() => return Err(BtError::Cancelled.into()),
+            }
+        }
+    }
+}
+
+/// Progress of a download.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadEvent {
+    /// Download started, `length` bytes in `piece_count` pieces to download.
+    Started { length: usize, piece_count: usize },
+
+    /// Connected and finished handshake with a peer learned from `source`.
+    PeerConnected { addr: String, source: PeerSource },
+
+    /// Failed to connect or handshake with `peer` learned from `source`.
+    PeerConnectFailed { peer: String, source: PeerSource },
+
+    /// Dropped a peer failed or not responding, or left when the download finished, after
+    /// `downloaded` bytes received from it.
+    PeerDisconnected {
+        addr: String,
+        source: PeerSource,
+        downloaded: usize,
+    },
+
+    /// Piece `index` of `length` bytes is downloaded and passed hash check.
+    PieceVerified { index: usize, length: usize },
+
+    /// Piece `index` of `length` bytes is downloaded but failed hash check, data is discarded and
+    /// the piece is downloaded again.
+    HashFailed { index: usize, length: usize },
+
+    /// Downloading piece `index` made no progress for a while.
+    Stalled { index: usize },
+
+    /// All pieces downloaded and saved.
+    Completed,
+
+    /// The same content was downloaded for another torrent, the file is hard linked from `from`
+    /// instead of downloading.
+    Linked { from: PathBuf },
+
+    /// Failed to get peers from tracker, the message includes all causes.
+    TrackerError(String),
+
+    /// Tracker reported our external ip.
+    ExternalIp { ip: IpAddr },
+
+    /// No connected peer has piece `index`, other pieces are downloaded first and more peers are
+    /// discovered if needed.
+    PieceUnavailable { index: usize },
+
+    /// Count of connected peers having each piece, sent when connected peers change.
+    Availability { peers: Vec<usize> },
+}
+
+/// Statistics of peers learned from one source.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceStats {
+    /// Count of peers tried to connect.
+    pub tried: usize,
+
+    /// Count of peers connected.
+    pub connected: usize,
+
+    /// Bytes received from disconnected peers, including the ones left when finished.
+    pub downloaded: usize,
+}
+
+/// Statistics of a download, collected from its events.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// Bytes of verified pieces.
+    pub downloaded: usize,
+
+    /// Bytes sent to peers, always 0 since we do not upload.
+    pub uploaded: usize,
+
+    /// Exponentially weighted moving average of download rate, in bytes per second.
+    pub download_rate: f64,
+
+    /// Average of upload rate, in bytes per second.
+    pub upload_rate: f64,
+
+    pub pieces_verified: usize,
+
+    pub piece_count: usize,
+
+    /// Count of peers connected now.
+    pub peers: usize,
+
+    /// Count of pieces failed hash check.
+    pub hash_failures: usize,
+
+    /// Bytes discarded because of failed hash check.
+    pub corrupt: usize,
+
+    /// Peers by where they were learned from, to tell whether a source is worth using.
+    pub sources: BTreeMap<PeerSource, SourceStats>,
+
+    /// Time of the last piece verified, the rate is updated from it.
+    last_verified: Option<Instant>,
+}
+
+impl Stats {
+    fn update(&mut self, event: &DownloadEvent, now: Instant) {
+        match event {
+            DownloadEvent::Started { piece_count, .. } => {
+                self.piece_count = *piece_count;
+                self.last_verified = Some(now);
+            }
+            DownloadEvent::PeerConnected { source, .. } => {
+                self.peers += 1;
+                let stats = self.sources.entry(source.clone()).or_default();
+                stats.tried += 1;
+                stats.connected += 1;
+            }
+            DownloadEvent::PeerConnectFailed { source, .. } => {
+                self.sources.entry(source.clone()).or_default().tried += 1;
+            }
+            DownloadEvent::PeerDisconnected {
+                source, downloaded, ..
+            } => {
+                self.peers = self.peers.saturating_sub(1);
+                self.sources.entry(source.clone()).or_default().downloaded += downloaded;
+            }
+            DownloadEvent::PieceVerified { length, .. } => {
+                self.downloaded += length;
+                self.pieces_verified += 1;
+                if let Some(last) = self.last_verified {
+                    let elapsed = now.duration_since(last).as_secs_f64();
+                    if elapsed > 0.0 {
+                        let sample = *length as f64 / elapsed;
+                        self.download_rate += RATE_SMOOTHING * (sample - self.download_rate);
+                    }
+                }
+                self.last_verified = Some(now);
+            }
+            DownloadEvent::HashFailed { length, .. } => {
+                self.hash_failures += 1;
+                self.corrupt += length;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Options of a download.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    /// Directory to remember peers in, known good peers are connected first and known bad ones
+    /// are skipped next time. Bytes transferred are also added up there, see [`TransferLog`].
+    ///
+    /// Completed files are recorded there too, a later torrent with the same content is hard
+    /// linked from the recorded file instead of downloaded, see [`ContentIndex`].
+    pub session_dir: Option<PathBuf>,
+
+    /// Download from these peers only, without asking trackers.
+    pub peers: Option<Peers>,
+
+    /// How to write the downloaded file.
+    pub disk_backend: DiskBackend,
+
+    /// Also check MD5 of the whole file if the torrent has one.
+    pub verify_md5: bool,
+
+    /// Download the first and last piece early, media players need them to start playback.
+    ///
+    /// `None` turns it on for media files by extension.
+    pub prioritize_head_tail: Option<bool>,
+
+    /// Write verified pieces to file in batches while downloading, instead of holding the whole
+    /// file in memory until completed. Only works with [`DiskBackend::Tokio`].
+    pub write_cache: Option<WriteCacheOptions>,
+
+    /// Directory the file is written to while downloading, it is moved to the output path once
+    /// completed and verified. Ignored when writing to stdout.
+    pub incomplete_dir: Option<PathBuf>,
+
+    /// Repair the existing file at the output path in place: only pieces missing or failing the
+    /// hash check are downloaded, good ones are kept. Uses the write cache options, or the
+    /// default ones if not set, and ignores `incomplete_dir`.
+    pub repair: bool,
+}
+
+/// A download running in background.
+///
+/// Events are buffered until received, dropping the handle does not stop the download.
+pub struct DownloadHandle {
+    events: mpsc::UnboundedReceiver<DownloadEvent>,
+    task: JoinHandle<Result<(), BtError>>,
+
+    /// Collects stats from events, and records bytes transferred in the session directory when
+    /// the download finished.
+    forwarder: JoinHandle<()>,
+
+    paused: watch::Sender<bool>,
+    cancel: CancellationToken,
+    deadlines: Deadlines,
+    stats: Arc<StdMutex<Stats>>,
+    name: String,
+    info_hash: [u8; 20],
+    length: usize,
+    file_path: String,
+}
+
+impl DownloadHandle {
+    /// Start downloading the whole file of `torrent` to `file_path`, `-` for stdout.
+    ///
+    /// Pieces are written to stdout in order as soon as they are complete.
+    pub fn spawn(torrent: Torrent, file_path: String, options: DownloadOptions) -> Self {
+        let (tx, mut task_rx) = mpsc::unbounded_channel();
+        let (user_tx, rx) = mpsc::unbounded_channel();
+        // Stats are collected from the same events the user receives.
+        let stats = Arc::new(StdMutex::new(Stats::default()));
+        let task_stats = stats.clone();
+        let session_dir = options.session_dir.clone();
+        let info_hash = *torrent.info_hash();
+        let forwarder = tokio::spawn(async move {
+            while let Some(event) = task_rx.recv().await {
+                task_stats.lock().unwrap().update(&event, Instant::now());
+                let _ = user_tx.send(event);
+            }
+            // All events are received once the download task finished.
+            if let Some(dir) = session_dir {
+                let stats = task_stats.lock().unwrap().clone();
+                let mut log = TransferLog::load(&dir);
+                log.add(&info_hash, stats.downloaded + stats.corrupt, stats.uploaded);
+                if let Err(e) = log.save() {
+                    eprintln!(">>> failed to save transfer log: {e}");
+                }
+            }
+        });
+        let (paused, paused_rx) = watch::channel(false);
+        let cancel = CancellationToken::new();
+        let control = DownloadControl {
+            paused: paused_rx,
+            cancel: cancel.clone(),
+        };
+        let deadlines = Deadlines::default();
+        let picker = deadlines.clone();
+        let name = torrent.name().to_string();
+        let length = torrent.length();
+        let task_file_path = file_path.clone();
+        let task = tokio::spawn(async move {
+            run(torrent, task_file_path, options, picker, tx, control).await
+        });
+        Self {
+            events: rx,
+            task,
+            forwarder,
+            paused,
+            cancel,
+            deadlines,
+            stats,
+            name,
+            info_hash,
+            length,
+            file_path,
+        }
+    }
+
+    /// Name of the downloading file in torrent.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn info_hash(&self) -> &[u8; 20] {
+        &self.info_hash
+    }
+
+    /// Length of the whole file in bytes.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Path the file is written to, `-` for stdout.
+    pub fn file_path(&self) -> &str {
+        &self.file_path
+    }
+
+    /// Stop requesting pieces, connections are kept alive.
+    ///
+    /// Blocks already requested are still received.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    /// Continue a paused download.
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    /// Stop downloading and close all connections, [`DownloadHandle::wait`] returns
+    /// [`BtError::Cancelled`].
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Download piece `index` before others, wanted within `deadline` from now.
+    ///
+    /// Pieces with deadlines are downloaded by the earliest deadline first. A deadline passed for
+    /// a while is dropped, so pieces nobody waits for any more do not stay in front.
+    pub fn set_piece_deadline(&self, index: usize, deadline: Duration) {
+        self.deadlines.set(index, Instant::now() + deadline);
+    }
+
+    /// Drop all piece deadlines.
+    pub fn clear_piece_deadlines(&self) {
+        self.deadlines.clear();
+    }
+
+    /// Statistics of the download so far.
+    pub fn stats(&self) -> Stats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Wait for the next event, `None` if the download finished and all events are received.
+    pub async fn next_event(&mut self) -> Option<DownloadEvent> {
+        self.events.recv().await
+    }
+
+    /// Wait until the download finished and bytes transferred are recorded.
+    pub async fn wait(self) -> Result<(), BtError> {
+        let result = match self.task.await {
+            Ok(v) => v,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        };
+        if let Err(e) = self.forwarder.await {
+            std::panic::resume_unwind(e.into_panic());
+        }
+        result
+    }
+}
+
+async fn run(
+    torrent: Torrent,
+    file_path: String,
+    options: DownloadOptions,
+    deadlines: Deadlines,
+    events: EventSender,
+    control: DownloadControl,
+) -> Result<(), BtError> {
+    let mut content_index = match &options.session_dir {
+        Some(dir) if file_path != "-" => Some(ContentIndex::load(dir)),
+        _ => None,
+    };
+    let duplicate = match &content_index {
+        Some(index) if !options.repair => index.find(&torrent).map(Path::to_path_buf),
+        _ => None,
+    };
+    if let Some(from) = duplicate {
+        if control
+            .cancellable(link_duplicate(&torrent, &from, &file_path))
+            .await?
+        {
+            let _ = events.send(DownloadEvent::Started {
+                length: 0,
+                piece_count: 0,
+            });
+            let _ = events.send(DownloadEvent::Linked { from });
+            let _ = events.send(DownloadEvent::Completed);
+            return Ok(());
+        }
+    }
+    let piece_count = torrent.info.piece_hashes.len();
+    let needed = if options.repair {
+        if file_path == "-" || options.disk_backend != DiskBackend::Tokio {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "repair requires an output file and the tokio disk backend",
+            )
+            .into());
+        }
+        let states = {
+            let torrent = torrent.clone();
+            let path = PathBuf::from(&file_path);
+            let check = tokio::task::spawn_blocking(move || check_file(&torrent, &path));
+            control
+                .cancellable(async { check.await.expect("check worker panicked") })
+                .await?
+        };
+        let mut needed = Bitfield::new(piece_count);
+        for (index, state) in states.iter().enumerate() {
+            if *state != PieceState::Good {
+                needed.set(index);
+            }
+        }
+        needed
+    } else {
+        Bitfield::full(piece_count)
+    };
+    let _ = events.send(DownloadEvent::Started {
+        length: needed
+            .iter_ones()
+            .filter_map(|x| torrent.piece_length(x))
+            .sum(),
+        piece_count: needed.count_ones(),
+    });
+    if needed.none() {
+        // Nothing to repair.
+        let _ = events.send(DownloadEvent::Completed);
+        return Ok(());
+    }
+    let mut peer_db = options
+        .session_dir
+        .map(|dir| PeerDb::load(&dir, torrent.info_hash()));
+    // Given peers are the only source, trackers are not asked for more peers.
+    let mut trackers = None;
+    let mut use_dht = false;
+    let peers = match options.peers {
+        Some(v) => v,
+        None => {
+            let peers = if torrent.has_trackers() {
+                let mut tiers = TrackerTiers::from_torrent(&torrent);
+                let peers = control
+                    .cancellable(discover(&torrent, &mut tiers, &needed, &events))
+                    .await?;
+                trackers = Some(tiers);
+                peers
+            } else {
+                // Trackerless torrents, like ones from magnet links without `tr`.
+                use_dht = true;
+                let result = control
+                    .cancellable(dht::get_peers(torrent.info_hash(), false))
+                    .await?;
+                result
+                    .peers
+                    .into_iter()
+                    .collect::<Peers>()
+                    .sanitize(None, &[])
+            };
+            match &peer_db {
+                Some(db) => db.rank(peers),
+                None => peers,
+            }
+        }
+    };
+    // Where the file lives until completed.
+    let work_path = match &options.incomplete_dir {
+        Some(dir) if file_path != "-" && !options.repair => {
+            disk::create_dir_all(dir)?;
+            let name = std::path::Path::new(&file_path)
+                .file_name()
+                .unwrap_or(torrent.name().as_ref());
+            dir.join(name).to_string_lossy().into_owned()
+        }
+        _ => file_path.clone(),
+    };
+    let mut storage = match options.write_cache {
+        cache_options if options.repair => DownloadStorage::File(
+            FileStorage::open(
+                &work_path,
+                torrent.length(),
+                torrent.nominal_piece_length(),
+                cache_options.unwrap_or_default(),
+            )
+            .await?,
+        ),
+        _ if file_path == "-" => {
+            if options.verify_md5 && torrent.md5sum().is_some() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "md5 check is not supported when writing to stdout",
+                )
+                .into());
+            }
+            DownloadStorage::Stream(StreamStorage::new(
+                tokio::io::stdout(),
+                torrent.length(),
+                torrent.nominal_piece_length(),
+                STREAM_BUFFER_LIMIT,
+            ))
+        }
+        Some(_) if options.disk_backend != DiskBackend::Tokio => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "write cache requires the tokio disk backend",
+            )
+            .into());
+        }
+        Some(cache_options) => DownloadStorage::File(
+            FileStorage::create(
+                &work_path,
+                torrent.length(),
+                torrent.nominal_piece_length(),
+                cache_options,
+            )
+            .await?,
+        ),
+        None => DownloadStorage::Memory(MemoryStorage::new(piece_count)),
+    };
+    let picker =
+        PiecePicker::for_torrent(&torrent, options.prioritize_head_tail).with_deadlines(deadlines);
+    let result = fetch_file(
+        &torrent,
+        &peers,
+        &picker,
+        &events,
+        &control,
+        peer_db.as_mut(),
+        match trackers.as_mut() {
+            Some(tiers) => Discovery::Trackers(tiers),
+            None if use_dht => Discovery::Dht(Some(Instant::now())),
+            None => Discovery::None,
+        },
+        &mut storage,
+        needed,
+    )
+    .await;
+    if let Some(db) = &peer_db {
+        db.save()?;
+    }
+    let result = result.map_err(BtError::peer);
+    if let (Err(BtError::Cancelled), DownloadStorage::Memory(held)) = (&result, &mut storage) {
+        // Verified pieces are written where they belong, so repairing the file resumes from them.
+        let held = std::mem::take(held);
+        if file_path != "-" {
+            save_held_pieces(held, &torrent, &work_path).await?;
+        }
+    }
+    result?;
+    // Data is already in file or stdout if written while downloading.
+    let (mut data, written) = match storage {
+        DownloadStorage::File(v) => {
+            v.finish().await?;
+            (vec![], true)
+        }
+        DownloadStorage::Memory(v) => (v.into_data(), false),
+        DownloadStorage::Stream(_) => (vec![], true),
+    };
+    if options.verify_md5 && torrent.md5sum().is_some() {
+        if written {
+            data = disk::read_file(options.disk_backend, &work_path).await?;
+        }
+        let torrent = torrent.clone();
+        data = tokio::task::spawn_blocking(move || torrent.verify_md5(&data).map(|_| data))
+            .await
+            .expect("md5 worker panicked")?;
+    }
+    if !written {
+        disk::write_file(options.disk_backend, data, &work_path).await?;
+    }
+    if work_path != file_path {
+        disk::move_file(&work_path, &file_path).await?;
+    }
+    if file_path != "-" {
+        disk::apply_file_mode(&file_path, torrent.is_executable())?;
+    }
+    if let Some(index) = &mut content_index {
+        index.insert(&torrent, std::path::absolute(&file_path)?);
+        index.save()?;
+    }
+    let _ = events.send(DownloadEvent::Completed);
+    Ok(())
+}
+
+/// Hard link `file_path` to `from` having the same content as `torrent`, replacing existing file.
+///
+/// Returns false without linking if `from` no longer passes the hash check.
+async fn link_duplicate(torrent: &Torrent, from: &Path, file_path: &str) -> Result<bool, BtError> {
+    let states = {
+        let torrent = torrent.clone();
+        let from = from.to_path_buf();
+        tokio::task::spawn_blocking(move || check_file(&torrent, &from))
+            .await
+            .expect("check worker panicked")?
+    };
+    if states.iter().any(|x| *x != PieceState::Good) {
+        return Ok(false);
+    }
+    if std::path::absolute(file_path)? != from {
+        match std::fs::remove_file(file_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        disk::link_file(from, Path::new(file_path))?;
+    }
+    Ok(true)
+}
+
+/// Ask trackers for peers, keep the ones worth connecting.
+///
+/// Pieces not `needed`, like ones already checked good when repairing, are announced as
+/// downloaded.
+async fn discover(
+    torrent: &Torrent,
+    trackers: &mut TrackerTiers,
+    needed: &Bitfield,
+    events: &EventSender,
+) -> Result<Peers, BtError> {
+    let left = needed
+        .iter_ones()
+        .filter_map(|x| torrent.piece_length(x))
+        .sum::<usize>();
+    let peer_info = match trackers
+        .announce(torrent.info_hash(), 0, torrent.length() - left, left)
+        .await
+        .and_then(|x| {
+            x.check_reachable()?;
+            Ok(x)
+        }) {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = events.send(DownloadEvent::TrackerError(error_chain(&e)));
+            return Err(e);
+        }
+    };
+
+    if let Some(ip) = peer_info.external_ip {
+        let _ = events.send(DownloadEvent::ExternalIp { ip });
+    }
+
+    Ok(peer_info.peers.sanitize(peer_info.external_ip, &[]))
+}
+
+/// Write pieces held in `memory` to file at `file_path` at their offsets, other bytes are zero.
+///
+/// Nothing is written if no piece is held.
+async fn save_held_pieces(
+    memory: MemoryStorage,
+    torrent: &Torrent,
+    file_path: &str,
+) -> Result<(), BtError> {
+    if memory.bytes() == 0 {
+        return Ok(());
+    }
+    let mut file = FileStorage::create(
+        file_path,
+        torrent.length(),
+        torrent.nominal_piece_length(),
+        WriteCacheOptions::default(),
+    )
+    .await?;
+    for (index, piece) in memory.into_pieces().into_iter().enumerate() {
+        if !piece.is_empty() {
+            file.write_block(index, 0, piece).await?;
+        }
+    }
+    file.finish().await?;
+    Ok(())
+}
+
+/// Join the messages of `err` and all its sources.
+fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(e) = source {
+        message.push_str(&format!(": {e}"));
+        source = e.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancellable() {
+        let (_paused, paused_rx) = watch::channel(false);
+        let control = DownloadControl {
+            paused: paused_rx,
+            cancel: CancellationToken::new(),
+        };
+        assert_eq!(
+            control
+                .cancellable(async { Ok::<_, BtError>(1) })
+                .await
+                .unwrap(),
+            1
+        );
+        control.cancel.cancel();
+        let pending = std::future::pending::<Result<(), BtError>>();
+        assert!(matches!(
+            control.cancellable(pending).await,
+            Err(BtError::Cancelled)
+        ));
+    }
+
+    /// Data of the torrent served by [`cancelling_peer`], in pieces of 4 bytes.
+    const CANCEL_DATA: &[u8] = b"abcdefg";
+
+    /// Torrent of [`CANCEL_DATA`].
+    fn cancel_torrent() -> Torrent {
+        use sha1::{Digest, Sha1};
+
+        let hashes = CANCEL_DATA
+            .chunks(4)
+            .flat_map(Sha1::digest)
+            .collect::<Vec<_>>();
+        let head = "d8:announce3:foo4:infod6:lengthi7e4:name1:a12:piece lengthi4e6:pieces40:";
+        Torrent::parse_from_bytes(&[head.as_bytes(), &hashes, b"ee"].concat()).unwrap()
+    }
+
+    /// A peer serving the first piece asked of [`CANCEL_DATA`], which cancels the returned token
+    /// when asked for another piece.
+    ///
+    /// The peer task returns the index of the piece served.
+    async fn cancelling_peer() -> (Torrent, Peers, CancellationToken, JoinHandle<usize>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::http::{read_message, HandshakeMessage, Peer, PieceMessage};
+
+        let torrent = cancel_torrent();
+        let info_hash = *torrent.info_hash();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let cancel = CancellationToken::new();
+        let peer_cancel = cancel.clone();
+        let peer = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut handshake = vec![0u8; HandshakeMessage::length()];
+            socket.read_exact(&mut handshake).await.unwrap();
+            let reply = HandshakeMessage::new(info_hash, [1; 20]).to_bytes();
+            socket.write_all(&reply).await.unwrap();
+            let bitfield = PieceMessage::Bitfield {
+                bitfield: vec![0b1100_0000],
+            };
+            bitfield.write_to(&mut socket).await.unwrap();
+            let mut served = None;
+            while let Ok(message) = read_message(&mut socket, None).await {
+                match message {
+                    PieceMessage::Interested => {
+                        PieceMessage::Unchoke.write_to(&mut socket).await.unwrap()
+                    }
+                    PieceMessage::Request { index, .. } if served.is_none() => {
+                        let block = CANCEL_DATA.chunks(4).nth(index as usize).unwrap().to_vec();
+                        let piece = PieceMessage::Piece {
+                            index,
+                            begin: 0,
+                            block,
+                        };
+                        piece.write_to(&mut socket).await.unwrap();
+                        served = Some(index as usize);
+                    }
+                    PieceMessage::Request { .. } => peer_cancel.cancel(),
+                    _ => {}
+                }
+            }
+            served.unwrap()
+        });
+        let peers = Peers::from_iter([Peer {
+            ip: "127.0.0.1".to_string(),
+            port,
+            source: PeerSource::Manual,
+        }]);
+        (torrent, peers, cancel, peer)
+    }
+
+    /// Check piece `served` of [`CANCEL_DATA`] is written in file at `path`.
+    fn assert_piece_saved(path: &Path, served: usize) {
+        let file = std::fs::read(path).unwrap();
+        let range = served * 4..(served * 4 + 4).min(CANCEL_DATA.len());
+        assert_eq!(file[range.clone()], CANCEL_DATA[range]);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_keeps_downloaded_pieces() {
+        let (torrent, peers, cancel, peer) = cancelling_peer().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        // The write cache holds pieces far longer than the test runs.
+        let mut storage =
+            FileStorage::create(path.to_str().unwrap(), 7, 4, WriteCacheOptions::default())
+                .await
+                .unwrap();
+        let (events, _rx) = mpsc::unbounded_channel();
+        let control = DownloadControl {
+            paused: watch::channel(false).1,
+            cancel,
+        };
+        let result = fetch_file(
+            &torrent,
+            &peers,
+            &PiecePicker::new(2),
+            &events,
+            &control,
+            None,
+            Discovery::None,
+            &mut storage,
+            Bitfield::full(2),
+        )
+        .await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<BtError>(),
+            Some(BtError::Cancelled)
+        ));
+        assert_piece_saved(&path, peer.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_saves_pieces_in_memory() {
+        let (torrent, peers, cancel, peer) = cancelling_peer().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        // Without write cache options, pieces are held in memory.
+        let options = DownloadOptions {
+            peers: Some(peers),
+            ..Default::default()
+        };
+        let handle = DownloadHandle::spawn(torrent, path.to_string_lossy().into_owned(), options);
+        cancel.cancelled().await;
+        handle.cancel();
+        assert!(matches!(handle.wait().await, Err(BtError::Cancelled)));
+        assert_piece_saved(&path, peer.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_unavailable_piece() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::http::{read_message, HandshakeMessage, Peer, PieceMessage};
+
+        let torrent = cancel_torrent();
+        let info_hash = *torrent.info_hash();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // A peer having no piece.
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut handshake = vec![0u8; HandshakeMessage::length()];
+            socket.read_exact(&mut handshake).await.unwrap();
+            let reply = HandshakeMessage::new(info_hash, [1; 20]).to_bytes();
+            socket.write_all(&reply).await.unwrap();
+            let bitfield = PieceMessage::Bitfield { bitfield: vec![0] };
+            bitfield.write_to(&mut socket).await.unwrap();
+            while read_message(&mut socket, None).await.is_ok() {}
+        });
+        let peers = Peers::from_iter([Peer {
+            ip: "127.0.0.1".to_string(),
+            port,
+            source: PeerSource::Manual,
+        }]);
+        let (events, mut rx) = mpsc::unbounded_channel();
+        let control = DownloadControl {
+            paused: watch::channel(false).1,
+            cancel: CancellationToken::new(),
+        };
+        let cancel = control.cancel.clone();
+        let mut storage = MemoryStorage::new(2);
+        let picker = PiecePicker::new(2);
+        let download = fetch_file(
+            &torrent,
+            &peers,
+            &picker,
+            &events,
+            &control,
+            None,
+            Discovery::None,
+            &mut storage,
+            Bitfield::full(2),
+        );
+        let watch = async {
+            while let Some(event) = rx.recv().await {
+                if matches!(event, DownloadEvent::PieceUnavailable { .. }) {
+                    break;
+                }
+            }
+            // Still waiting for the piece, not failed.
+            cancel.cancel();
+        };
+        let (result, _) = tokio::join!(download, watch);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<BtError>(),
+            Some(BtError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_stats() {
+        let start = Instant::now();
+        let mut stats = Stats::default();
+        let addr = "1.2.3.4:6881".to_string();
+        for event in [
+            DownloadEvent::Started {
+                length: 3072,
+                piece_count: 3,
+            },
+            DownloadEvent::PeerConnected {
+                addr: addr.clone(),
+                source: PeerSource::Dht,
+            },
+            DownloadEvent::PeerConnected {
+                addr: addr.clone(),
+                source: PeerSource::Manual,
+            },
+            DownloadEvent::PeerConnectFailed {
+                peer: "5.6.7.8:6881".to_string(),
+                source: PeerSource::Dht,
+            },
+            DownloadEvent::PeerDisconnected {
+                addr,
+                source: PeerSource::Dht,
+                downloaded: 512,
+            },
+            DownloadEvent::HashFailed {
+                index: 1,
+                length: 1024,
+            },
+        ] {
+            stats.update(&event, start);
+        }
+        stats.update(
+            &DownloadEvent::PieceVerified {
+                index: 0,
+                length: 1024,
+            },
+            start + Duration::from_secs(1),
+        );
+        assert_eq!(stats.downloaded, 1024);
+        assert_eq!(stats.pieces_verified, 1);
+        assert_eq!(stats.piece_count, 3);
+        assert_eq!(stats.peers, 1);
+        assert_eq!(stats.hash_failures, 1);
+        assert_eq!(stats.corrupt, 1024);
+        assert_eq!(
+            stats.sources[&PeerSource::Dht],
+            SourceStats {
+                tried: 2,
+                connected: 1,
+                downloaded: 512,
+            }
+        );
+        assert_eq!(stats.sources[&PeerSource::Manual].connected, 1);
+        assert!((stats.download_rate - 1024.0 * RATE_SMOOTHING).abs() < 1e-9);
+    }
+}
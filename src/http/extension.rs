@@ -0,0 +1,147 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
+use anyhow::Context;
+use serde_json::json;
+
+use crate::{
+    decode::{decode_bencoded_value, DecodeContext},
+    encode::{encode_dictionary, EncodeContext},
+    utils::{raw_bytes_to_string, string_to_raw_bytes, BtResult},
+};
+
+use super::external_ip::{compact_ip, parse_compact_ip};
+
+/// Client name and version sent in "v".
+const CLIENT_VERSION: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+/// Max count of outstanding requests we accept from a peer, sent in "reqq".
+const REQQ: usize = 250;
+
+/// Payload of the extension handshake message in BEP 10, a bencoded dictionary.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExtensionHandshake {
+    /// Supported extension messages, extension names mapped to the message ids.
+    pub m: BTreeMap<String, u8>,
+
+    /// The peer only uploads and does not want any piece from others.
+    ///
+    /// Such peers are seeds in the sense of piece availability and never expect reciprocation.
+    pub upload_only: bool,
+
+    /// Size of the info dictionary in bytes, set when the metadata is known.
+    pub metadata_size: Option<usize>,
+
+    /// Client name and version.
+    pub client: Option<String>,
+
+    /// Max count of outstanding requests the peer accepts.
+    pub reqq: Option<usize>,
+
+    /// The ip of receiver seen by the sender.
+    pub yourip: Option<IpAddr>,
+}
+
+impl ExtensionHandshake {
+    /// Build the handshake we send with `extensions` supported.
+    ///
+    /// * Set `metadata_size` if we have the info dictionary.
+    /// * Set `upload_only` when we have the complete file and switched to seeding.
+    pub fn new(
+        extensions: &[(&str, usize)],
+        metadata_size: Option<usize>,
+        upload_only: bool,
+    ) -> Self {
+        Self {
+            m: extensions
+                .iter()
+                .map(|(name, id)| (name.to_string(), *id as u8))
+                .collect(),
+            upload_only,
+            metadata_size,
+            client: Some(CLIENT_VERSION.to_string()),
+            reqq: Some(REQQ),
+            yourip: None,
+        }
+    }
+
+    /// The message id the peer expects for extension `name`.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut dict = json!({ "m": self.m });
+        if self.upload_only {
+            dict["upload_only"] = json!(1);
+        }
+        if let Some(v) = self.metadata_size {
+            dict["metadata_size"] = json!(v);
+        }
+        if let Some(v) = &self.client {
+            dict["v"] = json!(v);
+        }
+        if let Some(v) = self.reqq {
+            dict["reqq"] = json!(v);
+        }
+        if let Some(v) = &self.yourip {
+            dict["yourip"] = json!(raw_bytes_to_string(&compact_ip(v)));
+        }
+        let mut ctx = EncodeContext::new();
+        encode_dictionary(&mut ctx, dict.as_object().unwrap())
+            .expect("extension handshake holds byte strings");
+        ctx.consume()
+    }
+
+    /// Parse from the bencoded dictionary, not including the extension message id.
+    pub fn from_bytes(data: &[u8]) -> BtResult<Self> {
+        let value = decode_bencoded_value(&mut DecodeContext::new(data.to_vec()))
+            .context("failed to decode extension handshake from bencode")?;
+        let m = value
+            .get("m")
+            .and_then(|x| x.as_object())
+            .context("extension map not found")?
+            .iter()
+            // Id 0 means the extension is disabled.
+            .filter_map(|(name, id)| Some((name.to_owned(), id.as_u64().filter(|x| *x > 0)? as u8)))
+            .collect();
+        let upload_only = value
+            .get("upload_only")
+            .and_then(|x| x.as_i64())
+            .is_some_and(|x| x != 0);
+        let number = |key: &str| value.get(key).and_then(|x| x.as_u64()).map(|x| x as usize);
+        Ok(Self {
+            m,
+            upload_only,
+            metadata_size: number("metadata_size"),
+            client: value.get("v").and_then(|x| x.as_str()).map(String::from),
+            reqq: number("reqq"),
+            yourip: value
+                .get("yourip")
+                .and_then(|x| x.as_str())
+                .and_then(|x| parse_compact_ip(&string_to_raw_bytes(x))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_handshake_round_trip() {
+        let mut ours =
+            ExtensionHandshake::new(&[("ut_metadata", 1), ("ut_pex", 2)], Some(1024), true);
+        ours.yourip = Some(IpAddr::from([1, 2, 3, 4]));
+        let bytes = ours.to_bytes();
+        assert!(bytes.starts_with(b"d1:md11:ut_metadatai1e6:ut_pexi2ee13:metadata_sizei1024e"));
+
+        let theirs = ExtensionHandshake::from_bytes(&bytes).unwrap();
+        assert_eq!(theirs.extension_id("ut_metadata"), Some(1));
+        assert_eq!(theirs.extension_id("ut_pex"), Some(2));
+        assert!(theirs.upload_only);
+        assert_eq!(theirs.metadata_size, Some(1024));
+        assert_eq!(theirs.client.as_deref(), Some(CLIENT_VERSION));
+        assert_eq!(theirs.reqq, Some(REQQ));
+        assert_eq!(theirs.yourip, ours.yourip);
+    }
+}
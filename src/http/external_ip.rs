@@ -0,0 +1,119 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::utils::string_to_raw_bytes;
+
+/// Guesses of our external ip, reported by trackers ("external ip") and peers ("yourip").
+///
+/// Behind NAT the local address is useless to others, these reports are what they see.
+#[derive(Debug, Clone, Default)]
+pub struct ExternalIp {
+    /// Reported ips with the count of reports, in the order first reported.
+    votes: Vec<(IpAddr, usize)>,
+}
+
+/// Reports gathered in this process from trackers.
+///
+/// Peers are not counted: any single peer could set the ip we announce to all trackers.
+static REPORTED: Mutex<ExternalIp> = Mutex::new(ExternalIp { votes: vec![] });
+
+/// Record a report of our external ip by a tracker.
+pub(super) fn report(ip: IpAddr) {
+    REPORTED.lock().unwrap().add(ip);
+}
+
+/// Our external ip to send in announces, the best guess from tracker reports so far.
+///
+/// `None` if it is the address of our own interface, then we are not behind NAT and trackers
+/// already see it.
+pub(super) fn announced() -> Option<IpAddr> {
+    let ip = REPORTED.lock().unwrap().best()?;
+    (!is_local(ip)).then_some(ip)
+}
+
+/// `ip` is the source address the system picks to reach hosts of its family.
+///
+/// No packet is sent, connecting a udp socket only selects the source address.
+fn is_local(ip: IpAddr) -> bool {
+    let (local, remote) = match ip {
+        IpAddr::V4(_) => ("0.0.0.0:0", "8.8.8.8:80"),
+        IpAddr::V6(_) => ("[::]:0", "[2001:4860:4860::8888]:80"),
+    };
+    std::net::UdpSocket::bind(local)
+        .and_then(|x| x.connect(remote).and_then(|_| x.local_addr()))
+        .is_ok_and(|x| x.ip() == ip)
+}
+
+impl ExternalIp {
+    pub fn add(&mut self, ip: IpAddr) {
+        match self.votes.iter_mut().find(|(x, _)| *x == ip) {
+            Some((_, count)) => *count += 1,
+            None => self.votes.push((ip, 1)),
+        }
+    }
+
+    /// The ip reported most, ties are broken by the earliest reported.
+    pub fn best(&self) -> Option<IpAddr> {
+        self.votes
+            .iter()
+            .rev()
+            .max_by_key(|(_, count)| *count)
+            .map(|(ip, _)| *ip)
+    }
+}
+
+/// Parse ip from its raw bytes: 4 bytes for ipv4 or 16 bytes for ipv6.
+pub(super) fn parse_compact_ip(data: &[u8]) -> Option<IpAddr> {
+    if let Ok(v) = <[u8; 4]>::try_from(data) {
+        return Some(IpAddr::V4(Ipv4Addr::from(v)));
+    }
+    if let Ok(v) = <[u8; 16]>::try_from(data) {
+        return Some(IpAddr::V6(Ipv6Addr::from(v)));
+    }
+    None
+}
+
+/// The reverse of [`parse_compact_ip`].
+pub(super) fn compact_ip(ip: &IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(v) => v.octets().to_vec(),
+        IpAddr::V6(v) => v.octets().to_vec(),
+    }
+}
+
+/// Deserialize an optional compact ip from the decoded bencode string.
+pub(super) fn deserialize_compact_ip<'de, D>(deserializer: D) -> Result<Option<IpAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let v = Option::<String>::deserialize(deserializer)?;
+    Ok(v.and_then(|x| parse_compact_ip(&string_to_raw_bytes(&x))))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_best_external_ip() {
+        let a = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        let b = IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8));
+        let mut ip = ExternalIp::default();
+        assert_eq!(ip.best(), None);
+        ip.add(a);
+        ip.add(b);
+        assert_eq!(ip.best(), Some(a));
+        ip.add(b);
+        assert_eq!(ip.best(), Some(b));
+
+        // A documentation address is never ours.
+        assert!(!is_local(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))));
+
+        assert_eq!(parse_compact_ip(&compact_ip(&a)), Some(a));
+        assert_eq!(parse_compact_ip(&[1, 2, 3]), None);
+    }
+}
@@ -1,23 +1,32 @@
-use std::borrow::Cow;
+use std::net::IpAddr;
 
 use anyhow::{bail, Context};
-use reqwest::{StatusCode, Url};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 use crate::{
     decode::{decode_bencoded_value, DecodeContext},
+    dht,
     magnet::Magnet,
     torrent::TorrentInfo,
-    utils::{BtError, BtResult},
+    utils::{BtResult, ProtocolError},
 };
 
-use super::{HandshakeMessage, Peer, PeerInfo, PieceMessage, EXT_ID_MAP, PEER_ID, PORT};
+use super::{
+    dial_peer, discover_peer, extension::ExtensionHandshake, read_bitfield, read_exact_idle,
+    read_frame, wire_dump::WireDump, ExternalIp, HandshakeMessage, Peer, Peers, PieceMessage,
+    EXT_ID_MAP, EXT_METADATA_ID, HANDSHAKE_TIMEOUT, PEER_ID,
+};
 
 use self::metadata::MessageType;
 
+/// Size of metadata pieces in BEP 9, all but the last one.
+const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+/// Max length of extension messages read when fetching metadata: a metadata piece and the
+/// dictionary in front of it.
+const MAX_METADATA_MESSAGE_LENGTH: usize = METADATA_PIECE_SIZE + 1024;
+
 mod metadata {
     use anyhow::{bail, Context};
     use serde_json::json;
@@ -25,10 +34,11 @@ mod metadata {
     use crate::{
         decode::{decode_bencoded_value, DecodeContext},
         encode::{encode_dictionary, EncodeContext},
-        http::EXT_METADATA_ID,
-        torrent::TorrentInfo,
+        http::{EXT_METADATA_ID, MAX_METADATA_SIZE},
     };
 
+    use super::METADATA_PIECE_SIZE;
+
     /// The message id follows BitTorrent protocol.
     ///
     /// For message implemented by extension, the value is always 20.
@@ -75,11 +85,18 @@ mod metadata {
 
         /// Type of the message.
         msg_type: MessageType,
+
+        /// Index of the metadata piece.
+        piece: usize,
     }
 
     impl Message {
-        pub(super) fn new(ext_id: u8, msg_type: MessageType) -> Self {
-            Self { ext_id, msg_type }
+        pub(super) fn new(ext_id: u8, msg_type: MessageType, piece: usize) -> Self {
+            Self {
+                ext_id,
+                msg_type,
+                piece,
+            }
         }
 
         pub(super) fn to_bytes(&self) -> Vec<u8> {
@@ -87,11 +104,12 @@ mod metadata {
 
             let dict = json!({
                 "msg_type": self.msg_type.id(),
-                "piece": 0,
+                "piece": self.piece,
             });
 
             let mut ctx = EncodeContext::new();
-            encode_dictionary(&mut ctx, &dict.as_object().unwrap());
+            encode_dictionary(&mut ctx, dict.as_object().unwrap())
+                .expect("metadata request has integers only");
             let mut dict_bytes = ctx.consume();
             // Add length.
             // Length is 1(message id) + 1(extension message id) + dict_bytes.len()
@@ -109,22 +127,13 @@ mod metadata {
             buf
         }
 
-        /// Build a [`Message::Data`] from bytes with known length.
+        /// Parse a [`MessageType::Data`] message of metadata piece `piece`, `data` is the
+        /// message without the length prefix.
         ///
-        /// * `length` is the length of `data`.
-        /// * `data` contains all data we need to build a `Message` and the length should be `length`.
-        pub(super) fn parse_torrent_data(length: u32, data: &[u8]) -> anyhow::Result<TorrentInfo> {
-            let length = length as usize;
-            if length < 3 {
-                bail!("too short message response, len={length}");
-            }
-
-            if data.len() != length {
-                bail!(
-                    "invalid bytes length, declared as {}, but got {}",
-                    length,
-                    data.len()
-                );
+        /// Returns "total_size", the size of the whole info dict, and the piece content.
+        pub(super) fn parse_piece(data: &[u8], piece: usize) -> anyhow::Result<(usize, Vec<u8>)> {
+            if data.len() < 3 {
+                bail!("too short message response, len={}", data.len());
             }
 
             if data[0] != 20 {
@@ -134,44 +143,45 @@ mod metadata {
             if ext_id as usize != EXT_METADATA_ID {
                 bail!("invalid metadata extension id, we have {EXT_METADATA_ID} but peer responded {ext_id}")
             }
-            // In the response message, two dictionaries are joined together:
-            //
-            // * Info dictionary contains "msg_type", "piece", and "total_size" where "total_size"
-            //   is the length of second dictionary.
-            // * Metadata piece contents bencoded into dictionary.
-            //   * Tip in stage ns5: If metadata is larger than 16kb, you would need to request multiple
-            //     pieces, but for the purposes of this challenge there will only be one piece.
-            let mut tmp_buf = Vec::with_capacity(length - 2 + 2);
-            tmp_buf.push(b'l');
-            tmp_buf.append(&mut data[2..length].to_vec());
-            tmp_buf.push(b'e');
-            let mut ctx = DecodeContext::new(tmp_buf);
-            let data_value =
-                decode_bencoded_value(&mut ctx).context("failed to decode response")?;
-            let content_list = data_value.as_array().context("response is not an array")?;
-            // Info dictionary of metadata.
-            let info_dict = content_list[0]
-                .as_object()
-                .context("response is not a map")?;
-            let msg_type = MessageType::try_from(
-                info_dict
-                    .get("msg_type")
-                    .context("msg_type not found")?
-                    .as_number()
-                    .context("invalid msg_type")?
-                    .as_u64()
-                    .unwrap() as u8,
-            )?;
-            // Skip validating peice.
-            // Skip validating total_size: in the challenge metadata is small enough to send by only one piece.
+            // In the response message, a dictionary of "msg_type", "piece" and "total_size" is
+            // followed by the piece content, which is only a slice of the bencoded info dict.
+            let mut ctx = DecodeContext::new(data[2..].to_vec());
+            let dict = decode_bencoded_value(&mut ctx).context("failed to decode response")?;
+            let dict = dict.as_object().context("response is not a map")?;
+            let number = |key: &str| {
+                dict.get(key)
+                    .and_then(|x| x.as_u64())
+                    .map(|x| x as usize)
+                    .with_context(|| format!("{key} not found"))
+            };
+            let msg_type = MessageType::try_from(number("msg_type")? as u8)?;
+            if msg_type == MessageType::Reject {
+                bail!("peer rejected metadata piece {piece}")
+            }
             if msg_type != MessageType::Data {
                 bail!("invalid data message id, got {:?}", msg_type)
             }
-
-            let torrent_info: TorrentInfo = serde_json::from_value(content_list[1].to_owned())
-                .context("invalid torrent info")?;
-
-            Ok(torrent_info)
+            let got = number("piece")?;
+            if got != piece {
+                bail!("expected metadata piece {piece}, got {got}")
+            }
+            let total_size = number("total_size")?;
+            if total_size > MAX_METADATA_SIZE {
+                bail!("metadata of {total_size} bytes is too large")
+            }
+            // Pieces are full size but the last one, the content is the tail of the message.
+            let size = total_size
+                .checked_sub(piece * METADATA_PIECE_SIZE)
+                .filter(|x| *x > 0)
+                .with_context(|| format!("piece {piece} out of metadata of {total_size} bytes"))?
+                .min(METADATA_PIECE_SIZE);
+            if size > data.len() - 2 {
+                bail!(
+                    "invalid total_size {total_size}, message length {}",
+                    data.len()
+                )
+            }
+            Ok((total_size, data[data.len() - size..].to_vec()))
         }
     }
 }
@@ -179,7 +189,13 @@ mod metadata {
 pub struct MagnetHandshakeResult {
     pub message: HandshakeMessage,
     pub ut_metadata_id: u32,
+
+    /// Best guess of our external ip, from tracker and peer reports.
+    pub external_ip: Option<IpAddr>,
     pub torrent_info: Option<TorrentInfo>,
+
+    /// Bencoded info dict as the peer sent it.
+    pub raw_info: Option<Vec<u8>>,
 }
 
 /// Connect a single peer.
@@ -190,168 +206,298 @@ async fn connect_peer(
 ) -> BtResult<MagnetHandshakeResult> {
     /* Handshake */
 
-    let message = HandshakeMessage::with_ext(
-        info_hash,
-        PEER_ID.as_bytes().try_into().unwrap(),
-        [0, 0, 0, 0, 0, 0x10, 0, 0],
-    );
+    let message = HandshakeMessage::builder(info_hash, PEER_ID.as_bytes().try_into().unwrap())
+        .extension(true)
+        .build();
 
-    println!(">>> handshake: ip={}, port={}", peer.ip, peer.port);
+    eprintln!(">>> handshake: ip={}, port={}", peer.ip, peer.port);
     let handshake_message_bytes = message.to_bytes();
-    // println!(">>> handshake request: {:?}", handshake_message_bytes);
+    // eprintln!(">>> handshake request: {:?}", handshake_message_bytes);
 
-    let mut socket = TcpStream::connect(format!("{}:{}", peer.ip, peer.port).as_str())
-        .await
-        .context("failed to dial")?;
-    let (mut rd, mut wr) = socket.split();
-    if let Err(e) = wr.write_all(&handshake_message_bytes).await {
+    let addr = peer.to_string();
+    let mut socket = dial_peer(&addr, &info_hash).await?;
+    let mut dump = WireDump::open(&addr);
+    if let Err(e) = socket.write_all(&handshake_message_bytes).await {
         bail!("failed to send handshake message: {e}")
     }
 
-    // Tempoary buffer.
-    let mut buf = [0u8; 2048];
-
     let mut handshake_buf = vec![0u8; HandshakeMessage::length()];
-    rd.read_exact(&mut handshake_buf).await?;
-    // Here we ignore the handshake returned.
+    read_exact_idle(&mut socket, &mut handshake_buf, HANDSHAKE_TIMEOUT)
+        .await
+        .context("failed to read handshake")?;
     let handshake_resp =
         HandshakeMessage::from_bytes(&handshake_buf).context("invalid resp message format")?;
+    handshake_resp.verify_info_hash(&info_hash)?;
+    handshake_resp.verify_peer_id(&message.peer_id)?;
 
-    // println!(">>> wait for bitfield");
+    // eprintln!(">>> wait for bitfield");
 
     /* Wait for Bitfield */
 
-    let mut bitfield_buf = [0u8; 5];
-    let n = rd.read_exact(&mut bitfield_buf).await?;
-    if n == 0 {
-        bail!("empty bitfield message");
-    }
-    // Read the payload of bitfield so the reader is clean.
-    let l = u32::from_be_bytes([
-        bitfield_buf[0],
-        bitfield_buf[1],
-        bitfield_buf[2],
-        bitfield_buf[3],
-    ]) - 1;
-    let mut tmp_buf = vec![0u8; l as usize];
-    rd.read_exact(&mut tmp_buf).await?;
-
-    match PieceMessage::from_bytes(&bitfield_buf)? {
-        PieceMessage::Bitfield => { /* Expected bitfield message */ }
-        v => bail!("invalid bitfield message: id={}", v.id()),
-    }
+    // Piece count is unknown before we have the metadata.
+    let (_, first) = read_bitfield(&mut socket, None, dump.as_mut()).await?;
 
     // Only do the extension handshake if peer support.
     if !handshake_resp.has_ext() {
         bail!("peer does not support extension");
     }
 
-    let bytes = PieceMessage::new_extension(&EXT_ID_MAP).to_bytes();
-    println!(">>> [ext] start handshake: {:?}", &bytes);
-    wr.write(&bytes)
+    // The extension handshake exchange does not depend on order: if the peer already sent its
+    // handshake, it is waiting in the reader and ours works as the reply.
+    //
+    // We are downloading, not upload only, and the metadata is not known yet.
+    let mut ours = ExtensionHandshake::new(&EXT_ID_MAP, None, false);
+    ours.yourip = peer.ip.parse().ok();
+    let bytes = PieceMessage::new_extension(&ours).to_bytes();
+    socket
+        .write_all(&bytes)
         .await
         .context("failed to send extension message")?;
-    println!(">>> [ext] waiting response");
-    // Read the extension handshake response.
-    let n = rd.read(&mut buf).await?;
-    println!(">>> [ext] finish handshake, got: {:?}", &buf[0..n]);
-    match PieceMessage::from_bytes(&buf[0..n])? {
-        PieceMessage::Extension { extensions } => {
-            let mut ctx = DecodeContext::new(extensions[1..].to_vec());
-            let v = decode_bencoded_value(&mut ctx)
-                .context("failed to decode handshake response from bencode")?;
-            let outer_dict = v.as_object().unwrap();
-            let inner_dict = outer_dict.get("m").unwrap().as_object().unwrap();
-            let ut_metadata_id = inner_dict
-                .get("ut_metadata")
-                .and_then(|x| x.as_i64())
-                .context("invalid ut_metadata id")? as u8;
-            let torrent_info;
-            if request_metadata {
-                println!(">>> [ext] send metadata request message");
-                let req = metadata::Message::new(ut_metadata_id, MessageType::Request);
-                let req_bytes = req.to_bytes();
-                println!(">>> [ext] request: {:?}", req_bytes);
-                wr.write(&req_bytes)
-                    .await
-                    .context("failed to send metadata request")?;
-                let resp_len = rd
-                    .read_u32()
-                    .await
-                    .context("failed to read response length")?;
-                let mut resp_buf = vec![0u8; resp_len as usize];
-                rd.read_exact(&mut resp_buf)
-                    .await
-                    .context("failed to read response")?;
-                torrent_info = Some(metadata::Message::parse_torrent_data(
-                    resp_len,
-                    resp_buf.as_slice(),
-                )?);
-            } else {
-                torrent_info = None;
+    let peer_ext = match first {
+        // Peers without any piece may send it in place of the bitfield.
+        Some(PieceMessage::Extension { extensions }) if extensions.first() == Some(&0) => {
+            ExtensionHandshake::from_bytes(&extensions[1..])?
+        }
+        _ => read_extension_handshake(&mut socket, dump.as_mut()).await?,
+    };
+    let ut_metadata_id = peer_ext
+        .extension_id("ut_metadata")
+        .context("invalid ut_metadata id")?;
+    let (torrent_info, raw_info);
+    if request_metadata {
+        let raw = fetch_metadata(
+            &mut socket,
+            ut_metadata_id,
+            peer_ext.metadata_size,
+            dump.as_mut(),
+        )
+        .await?;
+        let value = decode_bencoded_value(&mut DecodeContext::new(raw.clone()))
+            .context("failed to decode info dict")?;
+        let info = serde_json::from_value(value).context("invalid torrent info")?;
+        (torrent_info, raw_info) = (Some(info), Some(raw));
+    } else {
+        (torrent_info, raw_info) = (None, None);
+    }
+    Ok(MagnetHandshakeResult {
+        message: handshake_resp,
+        ut_metadata_id: ut_metadata_id as u32,
+        external_ip: peer_ext.yourip,
+        torrent_info,
+        raw_info,
+    })
+}
+
+/// Fetch the info dict from a peer sending metadata messages as `ext_id`, piece by piece.
+///
+/// `metadata_size` from the peer's extension handshake, or "total_size" of the first piece if the
+/// peer sent none, must match "total_size" of all pieces.
+async fn fetch_metadata<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    ext_id: u8,
+    metadata_size: Option<usize>,
+    mut dump: Option<&mut WireDump>,
+) -> BtResult<Vec<u8>> {
+    let mut raw = vec![];
+    let mut expected = metadata_size;
+    loop {
+        let piece = raw.len() / METADATA_PIECE_SIZE;
+        let req = metadata::Message::new(ext_id, MessageType::Request, piece);
+        socket
+            .write_all(&req.to_bytes())
+            .await
+            .context("failed to send metadata request")?;
+        let frame = read_metadata_message(socket, dump.as_deref_mut())
+            .await
+            .context("failed to read response")?;
+        let (total_size, mut data) = metadata::Message::parse_piece(&frame[4..], piece)?;
+        match expected {
+            Some(v) if v != total_size => {
+                bail!("metadata piece {piece} has total_size {total_size}, expected {v}")
+            }
+            Some(_) => {}
+            None => expected = Some(total_size),
+        }
+        raw.append(&mut data);
+        if raw.len() >= total_size {
+            return Ok(raw);
+        }
+    }
+}
+
+/// Read messages until a metadata message, skipping others like `have`.
+async fn read_metadata_message<R: AsyncRead + Unpin>(
+    rd: &mut R,
+    mut dump: Option<&mut WireDump>,
+) -> BtResult<Vec<u8>> {
+    loop {
+        let frame = read_frame(rd, MAX_METADATA_MESSAGE_LENGTH, dump.as_deref_mut()).await?;
+        // Extension message id 20, then the metadata extension id we told the peer.
+        if frame[4..].starts_with(&[20, EXT_METADATA_ID as u8]) {
+            return Ok(frame);
+        }
+    }
+}
+
+/// Read messages until the extension handshake, whose extension id is 0, skipping others like
+/// `have`.
+async fn read_extension_handshake<R: AsyncRead + Unpin>(
+    rd: &mut R,
+    mut dump: Option<&mut WireDump>,
+) -> BtResult<ExtensionHandshake> {
+    loop {
+        let frame = read_frame(rd, MAX_METADATA_MESSAGE_LENGTH, dump.as_deref_mut()).await?;
+        match PieceMessage::from_bytes(&frame) {
+            Ok(PieceMessage::Extension { extensions }) if extensions.first() == Some(&0) => {
+                return ExtensionHandshake::from_bytes(&extensions[1..]);
             }
-            Ok(MagnetHandshakeResult {
-                message: handshake_resp,
-                ut_metadata_id: ut_metadata_id as u32,
-                torrent_info,
-            })
+            _ => continue,
         }
-        v => bail!(">>> [ext] unexpected handshake message id={}", v.id()),
     }
 }
 
-/// Magnet handshake queries peer info from tracker and handshake with peer to get peer id.
+/// Check the SHA-1 of bencoded info dict `raw` is `info_hash`.
+fn check_metadata_hash(raw: &[u8], info_hash: &[u8; 20]) -> BtResult<()> {
+    let actually: [u8; 20] = Sha1::digest(raw).into();
+    if &actually != info_hash {
+        bail!(ProtocolError::MetadataHashMismatch {
+            expected: hex::encode(info_hash),
+            actually: hex::encode(actually),
+        });
+    }
+    Ok(())
+}
+
+/// Magnet handshake queries peers from tracker, or DHT if the magnet link has none, and handshakes
+/// with the first peer answering to get peer id.
 pub(super) async fn handshake(
     magnet: &Magnet,
     request_metadata: bool,
 ) -> BtResult<MagnetHandshakeResult> {
-    let mut tracker_url = match &magnet.tracker_url {
-        Some(v) => Url::parse(v).context("invalid url")?,
-        None => bail!("tracker url not provided"),
+    let (peers, tracker_ip) = match magnet.tracker_url() {
+        Some(tracker_url) => {
+            eprintln!(">>> magnet handshake: tracker={}", tracker_url);
+            let peer_info = discover_peer(tracker_url, &magnet.info_hash, 0, 0, 1)
+                .await
+                .context("failed to discover peer")?;
+            peer_info.check_reachable()?;
+            (peer_info.peers, peer_info.external_ip)
+        }
+        None => {
+            eprintln!(">>> magnet handshake: no tracker, looking up dht");
+            let result = dht::get_peers(&magnet.info_hash, false)
+                .await
+                .context("failed to discover peer in dht")?;
+            (result.peers.into_iter().collect::<Peers>(), None)
+        }
     };
+    // Peers from DHT are often gone, try them in order.
+    let mut last_error = None;
+    for peer in peers.iter() {
+        let resp = connect_peer(peer, magnet.info_hash, request_metadata)
+            .await
+            .and_then(|resp| {
+                // A peer sending metadata of another torrent is as bad as one not answering.
+                if let Some(raw) = &resp.raw_info {
+                    check_metadata_hash(raw, &magnet.info_hash)?;
+                }
+                Ok(resp)
+            });
+        let mut resp = match resp {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!(">>> magnet handshake with {peer} failed: {e:#}");
+                last_error = Some(e);
+                continue;
+            }
+        };
+        let mut external_ip = ExternalIp::default();
+        for ip in [tracker_ip, resp.external_ip].into_iter().flatten() {
+            external_ip.add(ip);
+        }
+        resp.external_ip = external_ip.best();
+        return Ok(resp);
+    }
+    match last_error {
+        Some(e) => Err(e.context("peer handshake failed")),
+        None => bail!("no peers found"),
+    }
+}
 
-    println!(">>> magnet handshake: tracker={}", tracker_url);
-    tracker_url
-        .query_pairs_mut()
-        .encoding_override(Some(&|input| {
-            // Ref: https://app.codecrafters.io/courses/bittorrent/stages/fi9
-            if input == "{{info_hash}}" {
-                Cow::Owned(magnet.info_hash.to_vec())
-            } else {
-                Cow::Borrowed(input.as_bytes())
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Metadata message of piece `piece` of `metadata`, or a reject if `piece` is beyond it.
+    fn metadata_response(metadata: &[u8], piece: usize) -> Vec<u8> {
+        let content = metadata
+            .chunks(METADATA_PIECE_SIZE)
+            .nth(piece)
+            .unwrap_or_default();
+        let dict = match content.is_empty() {
+            true => format!("d8:msg_typei2e5:piecei{piece}ee"),
+            false => format!(
+                "d8:msg_typei1e5:piecei{piece}e10:total_sizei{}ee",
+                metadata.len()
+            ),
+        };
+        let payload = [&[20, EXT_METADATA_ID as u8], dict.as_bytes(), content].concat();
+        [&(payload.len() as u32).to_be_bytes()[..], &payload].concat()
+    }
+
+    #[tokio::test]
+    async fn test_fetch_metadata_pieces() {
+        let metadata = (0..METADATA_PIECE_SIZE * 2 + 100)
+            .map(|x| x as u8)
+            .collect::<Vec<_>>();
+        let (mut ours, mut theirs) = tokio::io::duplex(1 << 16);
+        let served = metadata.clone();
+        let peer = tokio::spawn(async move {
+            let mut requested = vec![];
+            while let Ok(frame) = read_frame(&mut theirs, 1024, None).await {
+                // "d8:msg_typei0e5:piecei" is followed by the piece index.
+                let piece = frame[28] - b'0';
+                requested.push(piece);
+                // A have message in between is skipped.
+                PieceMessage::new_have(0)
+                    .write_to(&mut theirs)
+                    .await
+                    .unwrap();
+                let response = metadata_response(&served, piece as usize);
+                theirs.write_all(&response).await.unwrap();
             }
-        }))
-        .append_pair("info_hash", "{{info_hash}}")
-        .append_pair("uploaded", "0")
-        .append_pair("downloaded", "0")
-        .append_pair("left", "1")
-        .append_pair("compact", "1")
-        .append_pair("peer_id", PEER_ID)
-        .append_pair("port", PORT)
-        .finish();
-
-    let resp = reqwest::get(tracker_url)
-        .await
-        .context("http request failed")?;
-    if resp.status() != StatusCode::OK {
-        bail!(BtError::NetworkError(resp.status().as_u16()))
+            requested
+        });
+
+        let raw = fetch_metadata(&mut ours, 3, Some(metadata.len()), None)
+            .await
+            .unwrap();
+        assert_eq!(raw, metadata);
+        // Metadata size must match total_size of the peer.
+        let err = fetch_metadata(&mut ours, 3, Some(10), None).await;
+        assert!(err.is_err());
+        drop(ours);
+        assert_eq!(peer.await.unwrap(), [0, 1, 2, 0]);
+
+        let request = metadata::Message::new(3, MessageType::Request, 2).to_bytes();
+        assert_eq!(&request[4..6], &[20, 3]);
+        assert!(request.ends_with(b"d8:msg_typei0e5:piecei2ee"));
+
+        let reject = metadata_response(&metadata, 5);
+        assert!(metadata::Message::parse_piece(&reject[4..], 5).is_err());
+        let first = metadata_response(&metadata, 0);
+        assert!(metadata::Message::parse_piece(&first[4..], 1).is_err());
     }
 
-    let peer_info = resp
-        .bytes()
-        .await
-        .context("invalid resp data")
-        .and_then(|data| {
-            decode_bencoded_value(&mut DecodeContext::new(data.as_ref().to_vec()))
-                .context("bencode decode failed")
-        })
-        .and_then(|value| {
-            serde_json::from_value::<PeerInfo>(value).context("failed to deserialize peer info")
-        })?;
-
-    let peer = &peer_info.peers[0];
-    let resp = connect_peer(peer, magnet.info_hash, request_metadata)
-        .await
-        .context("peer handshake failed")?;
-    Ok(resp)
+    #[test]
+    fn test_check_metadata_hash() {
+        let raw = b"d6:lengthi7e4:name1:ae";
+        let info_hash: [u8; 20] = Sha1::digest(raw).into();
+        assert!(check_metadata_hash(raw, &info_hash).is_ok());
+        let err = check_metadata_hash(b"d4:name1:be", &info_hash).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ProtocolError::MetadataHashMismatch { .. })
+        ));
+    }
 }
@@ -1,44 +1,250 @@
 use std::{
     borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     ops::{Deref, DerefMut},
+    path::Path,
     str::FromStr,
-    sync::{Arc, Mutex},
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
 };
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, Context};
+use futures::StreamExt;
 use reqwest::{StatusCode, Url};
-use serde::{de::Visitor, Deserialize};
+use serde::Deserialize;
 use sha1::{Digest, Sha1};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    sync::{Mutex, Semaphore},
+    task::JoinHandle,
 };
-
+use tokio_native_tls::native_tls;
+
+mod accounting;
+mod bitfield;
+mod cache;
+mod dedup;
+mod disk;
+mod event;
+mod extension;
+mod external_ip;
 mod magnet;
+mod picker;
+mod reputation;
+mod socket;
+mod socks;
+mod ssl;
+mod storage;
+mod swarm;
+mod tier;
 mod torrent;
+mod transport;
+mod udp;
+mod verify;
+mod webtorrent;
+mod wire_dump;
 
 use crate::{
     decode::{decode_bencoded_value, DecodeContext},
-    http::{magnet::MagnetHandshakeResult, piece_message::PieceMessage},
+    dht,
+    http::{
+        event::{DownloadControl, EventSender, STALL_TIMEOUT},
+        extension::ExtensionHandshake,
+        magnet::MagnetHandshakeResult,
+        picker::PiecePicker,
+        piece_message::PieceMessage,
+        reputation::PeerDb,
+        socket::socket_options,
+        transport::PeerStream,
+        wire_dump::WireDump,
+    },
     magnet::Magnet,
     torrent::Torrent,
-    utils::{decode_bytes_from_string, parallel_future, BtError, BtResult},
+    utils::{
+        decode_bytes_from_string, is_host_name, raw_bytes_to_string, string_to_raw_bytes, BtError,
+        BtResult, PeerError, ProtocolError, TrackerError,
+    },
+};
+
+pub use self::{
+    accounting::{TransferLog, TransferTotals},
+    bitfield::Bitfield,
+    cache::{FsyncPolicy, WriteCacheOptions},
+    dedup::ContentIndex,
+    disk::{
+        create_dir_all, link_file, read_file, set_file_modes, write_file, DiskBackend, FileModes,
+    },
+    event::{DownloadEvent, DownloadHandle, DownloadOptions, SourceStats, Stats},
+    external_ip::ExternalIp,
+    socket::{dht_allowed, set_socket_options, SocketOptions},
+    ssl::set_ssl_identity,
+    storage::{FileStorage, MemoryStorage, Storage, StreamStorage},
+    swarm::{sample_swarm, swarm_health, SwarmHealth, SwarmSample},
+    tier::{AggregatedPeerInfo, TrackedPeer, TrackerTiers},
+    transport::PeerTransport,
+    verify::{check_file, find_content, PieceState},
+    wire_dump::{replay, set_wire_dump_dir, ReplayEntry},
 };
 
 /// Random peer id generated by running `openssl rand -base64 20 | head -c 20`.
-pub const PEER_ID: &'static str = "l154rKqOHkfMLEGAecey";
+pub const PEER_ID: &str = "l154rKqOHkfMLEGAecey";
 
 /// Port.
-const PORT: &'static str = "6881";
+const PORT: &str = "6881";
+
+/// Weight of the latest block when averaging peer throughput.
+const RATE_SMOOTHING: f64 = 0.3;
+
+/// Count of downloaded pieces waiting for hash check before downloading more.
+const HASH_QUEUE_SIZE: usize = 4;
+
+/// Times a piece is downloaded again after failed hash check before giving up.
+const HASH_RETRIES: usize = 3;
+
+/// Percents of pieces verified at which trackers are announced again for fresh peers.
+const REANNOUNCE_MILESTONES: [usize; 3] = [25, 50, 75];
+
+/// Count of connected peers below which trackers are announced again for more.
+const MIN_PEERS: usize = 5;
+
+/// Min interval of DHT lookups for more peers of trackerless torrents.
+const DHT_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Interval of checking connected peers again while no needed piece is available.
+const UNAVAILABLE_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time without any needed piece available before giving up the download.
+const UNAVAILABLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Time to wait for a peer to unchoke us, a peer not answering in time is dropped.
+const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time a peer may send nothing in the middle of reading from it before it is taken as stalled.
+///
+/// Idle peers send keep-alives every two minutes, leave some margin over that.
+const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(150);
+
+/// Time to wait for the handshake of a peer just connected, which has no reason to stay idle.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
 
 /// Size of each block in piece.
 /// 16 kb.
 const BLOCK_SIZE: usize = 16 * 1024;
 
+/// Max length of messages read while downloading, a piece message carrying a whole block is the
+/// longest one. Longer length prefixes are rejected before allocating for them.
+const MAX_MESSAGE_LENGTH: usize = BLOCK_SIZE + 13;
+
+/// Max size of info dict fetched from peers, bounds the piece count of torrents from magnet
+/// links: each piece takes 20 bytes of hash.
+const MAX_METADATA_SIZE: usize = 8 * 1024 * 1024;
+
 const EXT_METADATA_ID: usize = 1;
-const EXT_ID_MAP: [(&'static str, usize); 1] = [("ut_metadata", EXT_METADATA_ID)];
+const EXT_ID_MAP: [(&str, usize); 1] = [("ut_metadata", EXT_METADATA_ID)];
 
-#[derive(Debug, Clone)]
+/// Default count of outbound connection attempts at the same time.
+const DEFAULT_MAX_DIALS: usize = 32;
+
+/// Permits of outbound connection attempts, dials beyond the limit wait in queue.
+static DIAL_LIMIT: OnceLock<Semaphore> = OnceLock::new();
+
+fn dial_limit() -> &'static Semaphore {
+    DIAL_LIMIT.get_or_init(|| Semaphore::new(DEFAULT_MAX_DIALS))
+}
+
+/// Our ipv6 address sent in http announces.
+static ANNOUNCE_IPV6: OnceLock<Ipv6Addr> = OnceLock::new();
+
+/// Send `ip` as our ipv6 address in http announces, so that trackers can give it to peers on
+/// ipv6 networks while we announce over ipv4.
+pub fn set_announce_ipv6(ip: Ipv6Addr) -> Result<(), BtError> {
+    ANNOUNCE_IPV6
+        .set(ip)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::AlreadyExists, "ipv6 already set"))?;
+    Ok(())
+}
+
+/// Limit count of outbound connection attempts at the same time to `max`, must be called before
+/// connecting peers.
+///
+/// Only attempts are limited, established connections do not take the budget.
+pub fn set_max_dials(max: usize) -> Result<(), BtError> {
+    if max == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "max dials should be greater than zero",
+        )
+        .into());
+    }
+    DIAL_LIMIT.set(Semaphore::new(max)).map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::AlreadyExists, "dial limit already set")
+    })?;
+    Ok(())
+}
+
+/// Http client shared by all tracker requests.
+static TRACKER_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+fn tracker_client() -> &'static reqwest::Client {
+    TRACKER_CLIENT.get_or_init(|| {
+        tracker_client_builder()
+            .build()
+            .expect("failed to build tracker client")
+    })
+}
+
+/// Builder of tracker clients, sending requests through the peer proxy if set.
+///
+/// Host names are resolved by the proxy too, so neither requests nor DNS queries reveal us.
+fn tracker_client_builder() -> reqwest::ClientBuilder {
+    let builder = reqwest::Client::builder();
+    match socket_options().proxy {
+        Some(proxy) => builder
+            .proxy(reqwest::Proxy::all(format!("socks5h://{proxy}")).expect("invalid proxy url")),
+        None => builder,
+    }
+}
+
+/// TLS connector of `wss://` trackers, set along with [`TRACKER_CLIENT`], the default one is used
+/// if not set.
+static TRACKER_TLS: OnceLock<native_tls::TlsConnector> = OnceLock::new();
+
+fn tracker_tls() -> Option<&'static native_tls::TlsConnector> {
+    TRACKER_TLS.get()
+}
+
+/// Configure TLS of https and wss tracker requests, must be called before any tracker request and
+/// after [`set_socket_options`].
+///
+/// * `ca` is a pem file of extra root certificate, for trackers using self-signed certificates.
+/// * `insecure` disables certificate validation entirely.
+pub fn configure_tracker_tls(ca: Option<&Path>, insecure: bool) -> Result<(), BtError> {
+    let mut builder = tracker_client_builder().danger_accept_invalid_certs(insecure);
+    let mut tls = native_tls::TlsConnector::builder();
+    tls.danger_accept_invalid_certs(insecure)
+        .danger_accept_invalid_hostnames(insecure);
+    if let Some(path) = ca {
+        let pem = std::fs::read(path)?;
+        let cert =
+            reqwest::Certificate::from_pem(&pem).map_err(|e| TrackerError::Request(e.into()))?;
+        builder = builder.add_root_certificate(cert);
+        let cert =
+            native_tls::Certificate::from_pem(&pem).map_err(|e| TrackerError::Request(e.into()))?;
+        tls.add_root_certificate(cert);
+    }
+    let client = builder
+        .build()
+        .map_err(|e| TrackerError::Request(e.into()))?;
+    let tls = tls.build().map_err(|e| TrackerError::Request(e.into()))?;
+    TRACKER_CLIENT
+        .set(client)
+        .map_err(|_| TrackerError::Request("tracker client already in use".into()))?;
+    let _ = TRACKER_TLS.set(tls);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Peers(Vec<Peer>);
 
 impl IntoIterator for Peers {
@@ -50,6 +256,12 @@ impl IntoIterator for Peers {
     }
 }
 
+impl FromIterator<Peer> for Peers {
+    fn from_iter<T: IntoIterator<Item = Peer>>(iter: T) -> Self {
+        Peers(iter.into_iter().collect())
+    }
+}
+
 impl Deref for Peers {
     type Target = [Peer];
 
@@ -69,92 +281,172 @@ pub struct PeerInfo {
     #[allow(dead_code)]
     pub interval: usize,
 
+    /// Seconds to wait at least before announcing again, if the tracker sets it.
+    #[serde(rename = "min interval", default)]
+    pub min_interval: Option<usize>,
+
+    /// Peers in compact formats, parsed apart to be tagged with the tracker.
+    #[serde(skip)]
     pub peers: Peers,
-}
 
-#[derive(Debug, Clone)]
-pub struct Peer {
-    pub ip: String,
-    pub port: u16,
+    /// Hex peer ids of peers only reachable by WebRTC, returned by WebTorrent trackers.
+    #[serde(skip)]
+    pub webrtc_peers: Vec<String>,
+
+    /// Our ip seen by the tracker.
+    #[serde(
+        rename = "external ip",
+        default,
+        deserialize_with = "external_ip::deserialize_compact_ip"
+    )]
+    pub external_ip: Option<IpAddr>,
+
+    /// Count of seeders reported by tracker.
+    #[serde(default)]
+    pub complete: Option<usize>,
+
+    /// Count of leechers reported by tracker.
+    #[serde(default)]
+    pub incomplete: Option<usize>,
 }
 
-struct PeersVisitor;
-
-impl<'de> Deserialize<'de> for Peers {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        deserializer.deserialize_bytes(PeersVisitor)
+impl PeerInfo {
+    /// Fail if all peers found are WebRTC peers we can not connect to.
+    pub fn check_reachable(&self) -> Result<(), TrackerError> {
+        if self.peers.is_empty() && !self.webrtc_peers.is_empty() {
+            return Err(TrackerError::WebRtcOnlyPeers(self.webrtc_peers.len()));
+        }
+        Ok(())
     }
 }
 
-impl<'de> Visitor<'de> for PeersVisitor {
-    type Value = Peers;
+/// Where a peer was learned from.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PeerSource {
+    /// Returned by the tracker of the url.
+    Tracker(String),
 
-    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        formatter.write_str("bytes array with length multiple of 6 bytes")
-    }
+    /// Found in DHT.
+    Dht,
 
-    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
-    where
-        E: serde::de::Error,
-    {
-        if v.len() % 6 != 0 {
-            return Err(E::custom(
-                "peer info bytes length is not multiple of 6 bytes",
-            ));
-        }
+    /// Given by user, like `--peer`.
+    Manual,
+}
 
-        let mut peers = vec![];
-        for vv in v.chunks_exact(6) {
-            let mut ip = String::new();
-            ip.push_str(vv[0].to_string().as_str());
-            ip.push('.');
-            ip.push_str(vv[1].to_string().as_str());
-            ip.push('.');
-            ip.push_str(vv[2].to_string().as_str());
-            ip.push('.');
-            ip.push_str(vv[3].to_string().as_str());
-            let port = u16::from_be_bytes([vv[4], vv[5]]);
-            let peer = Peer { ip, port };
-            peers.push(peer);
+impl std::fmt::Display for PeerSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerSource::Tracker(url) => write!(f, "tracker {url}"),
+            PeerSource::Dht => write!(f, "dht"),
+            PeerSource::Manual => write!(f, "manual"),
         }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub ip: String,
+    pub port: u16,
+    pub source: PeerSource,
+}
+
+impl Peer {
+    /// Parse peer from the 6 bytes compact format: 4 bytes ip and 2 bytes port in big endian.
+    pub fn from_compact(data: &[u8; 6], source: PeerSource) -> Self {
+        let ip = std::net::Ipv4Addr::new(data[0], data[1], data[2], data[3]).to_string();
+        let port = u16::from_be_bytes([data[4], data[5]]);
+        Self { ip, port, source }
+    }
 
-        Ok(Peers(peers))
+    /// Parse peer from the 18 bytes compact ipv6 format in BEP 7: 16 bytes ip and 2 bytes port.
+    pub fn from_compact6(data: &[u8; 18], source: PeerSource) -> Self {
+        let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&data[..16]).unwrap()).to_string();
+        let port = u16::from_be_bytes([data[16], data[17]]);
+        Self { ip, port, source }
     }
 
-    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
-    where
-        E: serde::de::Error,
-    {
-        self.visit_bytes(v.as_slice())
+    /// Socket address of the peer, `None` if the ip is invalid.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        Some(SocketAddr::new(self.ip.parse().ok()?, self.port))
     }
+}
 
-    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
-    where
-        E: serde::de::Error,
-    {
-        let v = decode_bytes_from_string(v);
-        self.visit_bytes(v.as_slice())
+impl std::fmt::Display for Peer {
+    /// Format as `ip:port`, ipv6 ips are bracketed so that the result can be dialed.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.ip.contains(':') {
+            write!(f, "[{}]:{}", self.ip, self.port)
+        } else {
+            write!(f, "{}:{}", self.ip, self.port)
+        }
     }
+}
 
-    fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
-    where
-        E: serde::de::Error,
-    {
-        let v = decode_bytes_from_string(v.as_str());
-        self.visit_bytes(v.as_slice())
+impl Peers {
+    /// Drop peers not worth connecting to: duplicated, invalid, loopback, ourselves and
+    /// already `connected` ones, which are "host:port" strings peers are formatted to.
+    ///
+    /// Set `own_ip` to our external ip to recognize ourselves. Peers with host names like onion
+    /// ones are kept, they are resolved when dialed.
+    pub fn sanitize(self, own_ip: Option<IpAddr>, connected: &[String]) -> Peers {
+        let own_port = PORT.parse::<u16>().unwrap();
+        let mut seen = HashSet::new();
+        let peers = self
+            .0
+            .into_iter()
+            .filter(|peer| {
+                let usable = peer.port != 0
+                    && match peer.ip.parse::<IpAddr>() {
+                        Ok(ip) => {
+                            let is_self = Some(ip) == own_ip && peer.port == own_port;
+                            !ip.is_unspecified()
+                                && !ip.is_loopback()
+                                && !ip.is_multicast()
+                                && ip != IpAddr::from([255, 255, 255, 255])
+                                && !is_self
+                        }
+                        Err(_) => is_host_name(&peer.ip),
+                    };
+                let addr = peer.to_string();
+                usable && !connected.contains(&addr) && seen.insert(addr)
+            })
+            .collect();
+        Peers(peers)
     }
 }
 
+/// Announce to tracker and get peers of the torrent.
 pub async fn discover_peer(
     tracker_url: &str,
     info_hash: &[u8; 20],
     uploaded: usize,
     downloaded: usize,
     left: usize,
-) -> BtResult<PeerInfo> {
+) -> Result<PeerInfo, BtError> {
+    announce(tracker_url, info_hash, uploaded, downloaded, left, 0)
+        .await
+        .map_err(BtError::tracker)
+}
+
+/// Build the http announce url.
+///
+/// Query parameters already in `tracker_url`, like passkeys of private trackers, are kept as is
+/// and ours are appended after them.
+///
+/// `corrupt` is bytes discarded for failed hash check, private trackers count it apart from
+/// `downloaded` to keep ratios accurate. It is only sent when not zero.
+///
+/// `ip` is our external ip reported by trackers, sent so that peers behind NAT are announced with
+/// the address others can reach.
+fn announce_url(
+    tracker_url: &str,
+    info_hash: &[u8; 20],
+    uploaded: usize,
+    downloaded: usize,
+    left: usize,
+    corrupt: usize,
+    ip: Option<IpAddr>,
+) -> BtResult<Url> {
     let mut url = Url::from_str(tracker_url).context("invalid url")?;
     url.query_pairs_mut()
         .encoding_override(Some(&|input| {
@@ -170,13 +462,80 @@ pub async fn discover_peer(
         .append_pair("downloaded", downloaded.to_string().as_str())
         .append_pair("left", left.to_string().as_str())
         .append_pair("compact", "1")
+        // For trackers ignoring compact, peer ids are not used anyway.
+        .append_pair("no_peer_id", "1")
         .append_pair("peer_id", PEER_ID)
-        .append_pair("port", PORT)
-        .finish();
+        .append_pair("port", PORT);
+    if let Some(ip) = ANNOUNCE_IPV6.get() {
+        url.query_pairs_mut()
+            .append_pair("ipv6", ip.to_string().as_str());
+    }
+    if let Some(ip) = ip {
+        url.query_pairs_mut()
+            .append_pair("ip", ip.to_string().as_str());
+    }
+    if corrupt > 0 {
+        url.query_pairs_mut()
+            .append_pair("corrupt", corrupt.to_string().as_str());
+    }
+    Ok(url)
+}
+
+/// Announce to tracker, returned peers are tagged with it by each protocol.
+///
+/// `corrupt` is only reported to http trackers, other protocols have no field for it.
+///
+/// With a proxy set, only http trackers are announced to, through the proxy. UDP and websocket
+/// ones would be reached directly.
+async fn announce(
+    tracker_url: &str,
+    info_hash: &[u8; 20],
+    uploaded: usize,
+    downloaded: usize,
+    left: usize,
+    corrupt: usize,
+) -> BtResult<PeerInfo> {
+    let is_http = tracker_url.starts_with("http://") || tracker_url.starts_with("https://");
+    if socket_options().proxy.is_some() && !is_http {
+        bail!(TrackerError::Unproxied(tracker_url.to_string()));
+    }
+    let info = if tracker_url.starts_with("ws://") || tracker_url.starts_with("wss://") {
+        webtorrent::announce(tracker_url, info_hash, uploaded, downloaded, left).await?
+    } else if tracker_url.starts_with("udp://") {
+        udp::announce(tracker_url, info_hash, uploaded, downloaded, left).await?
+    } else {
+        http_announce(tracker_url, info_hash, uploaded, downloaded, left, corrupt).await?
+    };
+    if let Some(ip) = info.external_ip {
+        external_ip::report(ip);
+    }
+    Ok(info)
+}
 
-    let resp = reqwest::get(url).await.context("http request failed")?;
+async fn http_announce(
+    tracker_url: &str,
+    info_hash: &[u8; 20],
+    uploaded: usize,
+    downloaded: usize,
+    left: usize,
+    corrupt: usize,
+) -> BtResult<PeerInfo> {
+    let url = announce_url(
+        tracker_url,
+        info_hash,
+        uploaded,
+        downloaded,
+        left,
+        corrupt,
+        external_ip::announced(),
+    )?;
+    let resp = tracker_client()
+        .get(url)
+        .send()
+        .await
+        .context("http request failed")?;
     if resp.status() != StatusCode::OK {
-        bail!(BtError::NetworkError(resp.status().as_u16()))
+        bail!(TrackerError::Status(resp.status().as_u16()))
     }
 
     resp.bytes()
@@ -186,11 +545,120 @@ pub async fn discover_peer(
             decode_bencoded_value(&mut DecodeContext::new(data.as_ref().to_vec()))
                 .context("bencode decode failed")
         })
-        .and_then(|value| {
-            serde_json::from_value::<PeerInfo>(value).context("failed to deserialize peer info")
-        })
+        .and_then(|value| parse_peer_info(value, tracker_url))
+}
+
+/// Deserialize http announce response from `tracker_url`, ipv6 peers in `peers6` of BEP 7 are
+/// appended to peers.
+fn parse_peer_info(value: serde_json::Value, tracker_url: &str) -> BtResult<PeerInfo> {
+    let source = PeerSource::Tracker(tracker_url.to_string());
+    // Decoded as hex, see `DecodeContext::new`.
+    let peers = value
+        .get("peers")
+        .and_then(|x| x.as_str())
+        .map(decode_bytes_from_string)
+        .context("peers not found")?;
+    if !peers.len().is_multiple_of(6) {
+        bail!("peer info bytes length is not multiple of 6 bytes");
+    }
+    let peers6 = value
+        .get("peers6")
+        .and_then(|x| x.as_str())
+        .map(string_to_raw_bytes)
+        .unwrap_or_default();
+    let mut info =
+        serde_json::from_value::<PeerInfo>(value).context("failed to deserialize peer info")?;
+    info.peers = peers
+        .chunks_exact(6)
+        .map(|x| Peer::from_compact(x.try_into().unwrap(), source.clone()))
+        .chain(
+            peers6
+                .chunks_exact(18)
+                .map(|x| Peer::from_compact6(x.try_into().unwrap(), source.clone())),
+        )
+        .collect();
+    Ok(info)
+}
+
+/// Swarm statistics of a torrent.
+#[derive(Debug, Clone)]
+pub struct ScrapeInfo {
+    /// Count of peers having the entire file.
+    pub complete: usize,
+
+    /// Count of peers still downloading.
+    pub incomplete: usize,
+
+    /// Times the torrent has been downloaded, only reported by trackers.
+    pub downloaded: Option<usize>,
+}
+
+/// Query the statistics of torrent from tracker.
+///
+/// The scrape url is the announce url with the last "announce" in path replaced by "scrape",
+/// trackers not following this convention do not support scrape.
+pub async fn scrape_tracker(
+    tracker_url: &str,
+    info_hash: &[u8; 20],
+) -> Result<ScrapeInfo, BtError> {
+    scrape(tracker_url, info_hash)
+        .await
+        .map_err(BtError::tracker)
+}
+
+/// Scrape url of the tracker at announce url `tracker_url`, querying `info_hash`.
+fn scrape_url(tracker_url: &str, info_hash: &[u8; 20]) -> BtResult<Url> {
+    let mut url = Url::from_str(tracker_url).context("invalid url")?;
+    let path = url.path().to_string();
+    match path.rsplit_once('/') {
+        Some((dir, name)) if name.starts_with("announce") => {
+            url.set_path(&format!("{dir}/{}", name.replacen("announce", "scrape", 1)))
+        }
+        _ => bail!("tracker does not support scrape"),
+    }
+    url.query_pairs_mut()
+        .encoding_override(Some(&|input| {
+            if input == "{{info_hash}}" {
+                Cow::Owned(info_hash.to_vec())
+            } else {
+                Cow::Borrowed(input.as_bytes())
+            }
+        }))
+        .append_pair("info_hash", "{{info_hash}}")
+        .finish();
+    Ok(url)
+}
+
+async fn scrape(tracker_url: &str, info_hash: &[u8; 20]) -> BtResult<ScrapeInfo> {
+    let url = scrape_url(tracker_url, info_hash)?;
+    let resp = tracker_client()
+        .get(url)
+        .send()
+        .await
+        .context("http request failed")?;
+    if resp.status() != StatusCode::OK {
+        bail!(TrackerError::Status(resp.status().as_u16()))
+    }
+
+    let data = resp.bytes().await.context("invalid resp data")?;
+    let value = decode_bencoded_value(&mut DecodeContext::new(data.to_vec()))
+        .context("bencode decode failed")?;
+    // Keys of "files" are raw info hashes.
+    let stats = value
+        .get("files")
+        .and_then(|x| x.get(raw_bytes_to_string(info_hash)))
+        .context("torrent not found in scrape response")?;
+    let count = |key: &str| stats.get(key).and_then(|x| x.as_u64()).map(|x| x as usize);
+    Ok(ScrapeInfo {
+        complete: count("complete").context("invalid complete count")?,
+        incomplete: count("incomplete").context("invalid incomplete count")?,
+        downloaded: count("downloaded"),
+    })
 }
 
+/// Protocol string at the start of handshake messages.
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
 #[derive(Debug)]
 pub struct HandshakeMessage {
     /// Sha1 info hash.
@@ -199,24 +667,63 @@ pub struct HandshakeMessage {
     /// Peer id in byte array.
     pub peer_id: [u8; 20],
 
-    /// The bytes in extension
-    ext: Option<[u8; 8]>,
+    /// Reserved bytes, each bit marks an extension supported.
+    reserved: [u8; 8],
+}
+
+/// Build a [`HandshakeMessage`] with extensions we support.
+#[derive(Debug, Clone)]
+pub struct HandshakeBuilder {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    reserved: [u8; 8],
+}
+
+impl HandshakeBuilder {
+    fn set_bit(mut self, byte: usize, mask: u8, on: bool) -> Self {
+        if on {
+            self.reserved[byte] |= mask;
+        } else {
+            self.reserved[byte] &= !mask;
+        }
+        self
+    }
+
+    /// Support DHT (BEP 5).
+    pub fn dht(self, on: bool) -> Self {
+        self.set_bit(7, 0x01, on)
+    }
+
+    /// Support fast extension (BEP 6).
+    pub fn fast(self, on: bool) -> Self {
+        self.set_bit(7, 0x04, on)
+    }
+
+    /// Support extension protocol (BEP 10).
+    pub fn extension(self, on: bool) -> Self {
+        self.set_bit(5, 0x10, on)
+    }
+
+    pub fn build(self) -> HandshakeMessage {
+        HandshakeMessage {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+            reserved: self.reserved,
+        }
+    }
 }
 
 impl HandshakeMessage {
+    /// Handshake without any extension.
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
-        Self {
-            info_hash,
-            peer_id,
-            ext: None,
-        }
+        Self::builder(info_hash, peer_id).build()
     }
 
-    pub fn with_ext(info_hash: [u8; 20], peer_id: [u8; 20], ext: [u8; 8]) -> Self {
-        Self {
+    pub fn builder(info_hash: [u8; 20], peer_id: [u8; 20]) -> HandshakeBuilder {
+        HandshakeBuilder {
             info_hash,
             peer_id,
-            ext: Some(ext),
+            reserved: [0; 8],
         }
     }
 
@@ -230,88 +737,294 @@ impl HandshakeMessage {
         1 + 19 + 8 + 20 + 20 + 6
     }
 
+    /// Supports extension protocol (BEP 10).
     pub fn has_ext(&self) -> bool {
-        self.ext.is_some()
+        self.reserved[5] & 0x10 != 0
+    }
+
+    /// Fail if the handshake is not for `expected` info hash.
+    pub fn verify_info_hash(&self, expected: &[u8; 20]) -> Result<(), ProtocolError> {
+        if &self.info_hash != expected {
+            return Err(ProtocolError::InfoHashMismatch {
+                expected: hex::encode(expected),
+                actually: hex::encode(self.info_hash),
+            });
+        }
+        Ok(())
+    }
+
+    /// Fail if the handshake carries `ours` peer id, we dialed ourselves through a tracker or a
+    /// NAT.
+    ///
+    /// Ids of other peers are not checked against trackers, compact responses do not carry them.
+    pub fn verify_peer_id(&self, ours: &[u8; 20]) -> Result<(), ProtocolError> {
+        if &self.peer_id == ours {
+            return Err(ProtocolError::OwnPeerId);
+        }
+        Ok(())
     }
 
-    pub fn from_bytes(buffer: &[u8]) -> Result<Self> {
+    pub fn from_bytes(buffer: &[u8]) -> Result<Self, ProtocolError> {
+        if buffer.len() < Self::length() {
+            return Err(ProtocolError::InvalidHandshake(buffer.len()));
+        }
         if buffer.len() != Self::length() && buffer.len() != Self::ext_length() {
-            println!(
+            eprintln!(
                 "warning: invalid handshake message length: {}, data={:?}",
                 buffer.len(),
                 buffer,
             )
         }
+        if buffer[0] as usize != PROTOCOL.len() || &buffer[1..20] != PROTOCOL {
+            return Err(ProtocolError::InvalidProtocol(raw_bytes_to_string(
+                &buffer[1..1 + (buffer[0] as usize).min(buffer.len() - 1)],
+            )));
+        }
         const HEADER_LEN: usize = 1 + 19 + 8;
-        // TODO: Check header.
-        let info_hash = buffer[HEADER_LEN..HEADER_LEN + 20]
-            .iter()
-            .map(|x| x.to_owned().to_owned())
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
-        let peer_id = buffer[HEADER_LEN + 20..HEADER_LEN + 20 + 20]
-            .iter()
-            .map(|x| x.to_owned().to_owned())
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
-        let ext = match &buffer[1 + 19..1 + 19 + 8] {
-            &[0, 0, 0, 0, 0, 0, 0, 0] => None,
-            v => Some(v.to_owned().try_into().unwrap()),
-        };
+        let mut reserved = [0u8; 8];
+        reserved.copy_from_slice(&buffer[1 + 19..HEADER_LEN]);
+        let mut info_hash = [0u8; 20];
+        info_hash.copy_from_slice(&buffer[HEADER_LEN..HEADER_LEN + 20]);
+        let mut peer_id = [0u8; 20];
+        peer_id.copy_from_slice(&buffer[HEADER_LEN + 20..HEADER_LEN + 20 + 20]);
         Ok(Self {
             info_hash,
             peer_id,
-            ext,
+            reserved,
         })
     }
 
     fn to_bytes(&self) -> Vec<u8> {
         let mut buffer = Vec::with_capacity(128);
-        buffer.push(19);
-        buffer.extend_from_slice(b"BitTorrent protocol");
-        buffer.extend_from_slice(&self.ext.unwrap_or([0u8; 8]));
+        buffer.push(PROTOCOL.len() as u8);
+        buffer.extend_from_slice(PROTOCOL);
+        buffer.extend_from_slice(&self.reserved);
         buffer.extend_from_slice(self.info_hash.as_slice());
         buffer.extend_from_slice(self.peer_id.as_slice());
         buffer
     }
 }
 
+/// Handshake with peer at `ip`:`port`, return the handshake peer responded.
 pub async fn handshake(
     ip: &str,
     port: u16,
     message: HandshakeMessage,
-) -> BtResult<HandshakeMessage> {
-    let mut socket = TcpStream::connect(format!("{ip}:{port}").as_str())
+) -> Result<HandshakeMessage, BtError> {
+    handshake_peer(ip, port, message)
         .await
-        .context("failed to dial")?;
-    let (mut rd, mut wr) = socket.split();
-    if let Err(e) = wr.write_all(&message.to_bytes()).await {
+        .map_err(BtError::peer)
+}
+
+async fn handshake_peer(
+    ip: &str,
+    port: u16,
+    message: HandshakeMessage,
+) -> BtResult<HandshakeMessage> {
+    let mut socket = dial_peer(&format!("{ip}:{port}"), &message.info_hash).await?;
+    if let Err(e) = socket.write_all(&message.to_bytes()).await {
         bail!("failed to send handshake message: {e}")
     }
 
-    let mut buf = vec![0; 256];
+    let mut buf = vec![0; HandshakeMessage::length()];
+    read_exact_idle(&mut socket, &mut buf, HANDSHAKE_TIMEOUT)
+        .await
+        .context("failed to read handshake")?;
+    HandshakeMessage::from_bytes(&buf).context("invalid resp message format")
+}
 
+/// Connect to peer at `addr`, retried as configured in [`set_socket_options`].
+///
+/// Waits in queue if too many connection attempts are in progress, see [`set_max_dials`]. The
+/// wait between retries does not hold the budget.
+///
+/// Goes through the proxy if set, with streams of torrent `info_hash` isolated from others.
+///
+/// Nagle's algorithm is disabled, small messages like requests are sent without delay.
+async fn dial_peer(addr: &str, info_hash: &[u8; 20]) -> BtResult<PeerStream> {
+    let options = socket_options();
+    let mut attempt = 0;
     loop {
-        let n = rd.read(&mut buf).await?;
+        let result = {
+            let _permit = dial_limit().acquire().await.context("dial limit closed")?;
+            let connect = async {
+                match options.proxy {
+                    // Each torrent gets its own circuits.
+                    Some(proxy) => socks::connect(proxy, addr, &hex::encode(info_hash))
+                        .await
+                        .map(|x| Box::new(x) as PeerStream),
+                    None => socket::connect(addr, options).await.and_then(|x| {
+                        x.set_nodelay(true).context("failed to set TCP_NODELAY")?;
+                        Ok(Box::new(x) as PeerStream)
+                    }),
+                }
+            };
+            tokio::time::timeout(options.connect_timeout, connect)
+                .await
+                .context("connect timed out")
+                .and_then(|x| x)
+        };
+        match result {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt >= options.connect_retries => return Err(e),
+            Err(e) => {
+                eprintln!(">>> dial {addr} failed, retry: {e:#}");
+                tokio::time::sleep(options.backoff(attempt)).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Parse a peer message including its length prefix, for fuzzing the parser.
+#[cfg(feature = "fuzzing")]
+pub fn parse_peer_message(data: &[u8]) -> Result<(), BtError> {
+    PieceMessage::from_bytes(data)
+        .map(|_| ())
+        .map_err(BtError::peer)
+}
+
+/// Fill `buf` from `rd`, fail if no byte arrives for `idle`.
+///
+/// The timeout is reset on any bytes received, so a slow peer still sending a large message is
+/// not dropped, only one stalled after sending part of it.
+async fn read_exact_idle<R: AsyncRead + Unpin>(
+    rd: &mut R,
+    buf: &mut [u8],
+    idle: Duration,
+) -> std::io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = tokio::time::timeout(idle, rd.read(&mut buf[filled..]))
+            .await
+            .map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::TimedOut, "peer stalled while sending")
+            })??;
         if n == 0 {
-            break;
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        filled += n;
+    }
+    Ok(())
+}
+
+/// Read a whole peer message: 4 bytes length prefix and the payload it declares.
+///
+/// Keep-alive messages (zero length) are skipped. Returned bytes include the length prefix.
+/// Messages declaring a length over `max_length` are rejected before reading the payload.
+///
+/// Read messages are also recorded to `dump` if any.
+async fn read_frame<R: AsyncRead + Unpin>(
+    rd: &mut R,
+    max_length: usize,
+    dump: Option<&mut WireDump>,
+) -> BtResult<Vec<u8>> {
+    loop {
+        let mut prefix = [0u8; 4];
+        read_exact_idle(rd, &mut prefix, PEER_IDLE_TIMEOUT)
+            .await
+            .context("failed to read message length")?;
+        let length = u32::from_be_bytes(prefix) as usize;
+        if length == 0 {
+            continue;
+        }
+        if length > max_length {
+            bail!(ProtocolError::MessageTooLong {
+                length,
+                max: max_length,
+            });
+        }
+        let mut frame = vec![0u8; 4 + length];
+        frame[0..4].copy_from_slice(&prefix);
+        read_exact_idle(rd, &mut frame[4..], PEER_IDLE_TIMEOUT)
+            .await
+            .context("failed to read message payload")?;
+        if let Some(dump) = dump {
+            dump.record(&frame);
+        }
+        return Ok(frame);
+    }
+}
+
+/// Read the next peer message we know, messages with unknown ids are skipped.
+///
+/// Peers may send messages we do not implement, like suggest piece in the fast extension, the
+/// whole frame is already consumed so the stream keeps in sync.
+async fn read_message<R: AsyncRead + Unpin>(
+    rd: &mut R,
+    dump: Option<&mut WireDump>,
+) -> BtResult<PieceMessage> {
+    read_message_within(rd, MAX_MESSAGE_LENGTH, dump).await
+}
+
+/// [`read_message`] allowing messages up to `max_length`.
+async fn read_message_within<R: AsyncRead + Unpin>(
+    rd: &mut R,
+    max_length: usize,
+    mut dump: Option<&mut WireDump>,
+) -> BtResult<PieceMessage> {
+    loop {
+        let frame = read_frame(rd, max_length, dump.as_deref_mut()).await?;
+        match PieceMessage::from_bytes(&frame) {
+            Ok(v) => return Ok(v),
+            Err(e) => match e.downcast_ref::<ProtocolError>() {
+                Some(ProtocolError::UnknownMessage(id)) => {
+                    eprintln!(
+                        ">>> skip unknown message: id={id}, length={}",
+                        frame.len() - 4
+                    );
+                }
+                _ => return Err(e),
+            },
         }
-        let resp =
-            HandshakeMessage::from_bytes(&buf[0..n]).context("invalid resp message format")?;
-        return Ok(resp);
     }
+}
 
-    bail!("empty responce");
+/// Read the bitfield message peer sends right after handshake.
+///
+/// If `piece_count` is known, the length of bitfield is validated against it, otherwise all bits
+/// are taken as pieces. Spare bits at the end are ignored.
+///
+/// Peers having no piece may skip the bitfield and send other messages first, like `unchoke` or
+/// the extension handshake. Then the bitfield is empty and the message read is returned for the
+/// caller to handle.
+async fn read_bitfield<R: AsyncRead + Unpin>(
+    rd: &mut R,
+    piece_count: Option<usize>,
+    dump: Option<&mut WireDump>,
+) -> BtResult<(Bitfield, Option<PieceMessage>)> {
+    // Without the piece count, the metadata size bounds it.
+    let max_pieces = piece_count.unwrap_or(MAX_METADATA_SIZE / 20);
+    let max_length = (1 + max_pieces.div_ceil(8)).max(MAX_MESSAGE_LENGTH);
+    let message = read_message_within(rd, max_length, dump)
+        .await
+        .context("failed to read bitfield")?;
+    let bitfield = match message {
+        PieceMessage::Bitfield { bitfield } => bitfield,
+        v => return Ok((Bitfield::new(piece_count.unwrap_or(0)), Some(v))),
+    };
+    if let Some(count) = piece_count {
+        let expected = count.div_ceil(8);
+        if bitfield.len() != expected {
+            bail!(ProtocolError::InvalidBitfieldLength {
+                length: bitfield.len(),
+                expected,
+            });
+        }
+    }
+    let len = piece_count.unwrap_or(bitfield.len() * 8);
+    Ok((Bitfield::from_bytes(bitfield, len), None))
 }
 
 mod piece_message {
+    use std::io::{self, IoSlice};
+
     use anyhow::bail;
+    use tokio::io::{AsyncWrite, AsyncWriteExt};
 
     use crate::{
-        encode::{encode_dictionary, EncodeContext},
-        utils::BtResult,
+        http::extension::ExtensionHandshake,
+        utils::{BtResult, ProtocolError},
     };
 
     /// Different types of message when communicating piece download process.
@@ -323,20 +1036,42 @@ mod piece_message {
     /// * message payload which occupies all remaining size.
     ///   * Not all messages have payload, payload may have different
     ///     sections that described by theirself's field.
+    #[derive(Debug, Clone, PartialEq, Eq)]
     pub(crate) enum PieceMessage {
         /// Server returned message after handshake.
-        Bitfield,
+        ///
+        /// Have payload.
+        Bitfield {
+            /// One bit for each piece, the high bit in the first byte is piece 0.
+            ///
+            /// Bit set means the peer has that piece.
+            bitfield: Vec<u8>,
+        },
 
         /// Message sent to server.
         Interested,
 
-        /// Server returns this message before we can make `Request`s.
-        Unchoke,
+        /// We no longer want any piece from the peer.
+        NotInterested,
 
-        /// Request for a 16kb sized block data of the piece.
+        /// The sender has downloaded and verified a piece.
         ///
         /// Have payload.
-        Request {
+        Have {
+            /// Piece index, start from 0.
+            index: u32,
+        },
+
+        /// Server returns this message before we can make `Request`s.
+        Unchoke,
+
+        /// Server stops accepting our `Request`s.
+        Choke,
+
+        /// Request for a 16kb sized block data of the piece.
+        ///
+        /// Have payload.
+        Request {
             /// Piece index, start from 0.
             index: u32,
 
@@ -367,9 +1102,29 @@ mod piece_message {
             block: Vec<u8>,
         },
 
-        /// Extension handshake.
+        /// Cancel a block requested before, e.g. received from another peer.
+        ///
+        /// Have payload.
+        Cancel {
+            /// Piece index, start from 0.
+            index: u32,
+
+            /// Byte offset in current piece, start from 0.
+            begin: u32,
+
+            /// Length of the block.
+            length: u32,
+        },
+
+        /// Port of the DHT node of peer, sent by peers supporting DHT.
+        ///
+        /// Have payload.
+        Port { port: u16 },
+
+        /// Extension protocol message (BEP 10).
         Extension {
-            /// Bencoded dictionary.
+            /// Extended message id followed by the payload, id 0 is the handshake whose payload
+            /// is a bencoded dictionary.
             extensions: Vec<u8>,
         },
     }
@@ -379,6 +1134,14 @@ mod piece_message {
             Self::Interested
         }
 
+        pub fn new_not_interested() -> Self {
+            Self::NotInterested
+        }
+
+        pub fn new_have(piece_index: u32) -> Self {
+            Self::Have { index: piece_index }
+        }
+
         pub fn new_request(piece_index: u32, block_offset: u32, length: u32) -> Self {
             Self::Request {
                 index: piece_index,
@@ -387,41 +1150,25 @@ mod piece_message {
             }
         }
 
-        pub fn new_extension(extensions: &[(&'static str, usize)]) -> Self {
-            // The inner dictionary, pairs of extension name and extension id.
-            let inner_dict = {
-                let mut m: serde_json::Map<String, serde_json::Value> = serde_json::Map::new();
-                for ext in extensions {
-                    m.insert(
-                        ext.0.to_string(),
-                        serde_json::Value::Number(
-                            serde_json::value::Number::from_i128(ext.1 as i128).unwrap(),
-                        ),
-                    );
-                }
-                m
-            };
-
-            // The outer dictionary, pairs of key "m" and inner dictionary
-            let outer_dict = {
-                let mut m: serde_json::Map<String, serde_json::Value> = serde_json::Map::new();
-                m.insert(String::from("m"), serde_json::Value::Object(inner_dict));
-                let mut ctx = EncodeContext::new();
-                encode_dictionary(&mut ctx, &m);
-                ctx.consume()
-            };
-            Self::Extension {
-                extensions: outer_dict,
-            }
+        pub fn new_extension(handshake: &ExtensionHandshake) -> Self {
+            // Extension message id of handshake.
+            let mut extensions = vec![0];
+            extensions.extend_from_slice(&handshake.to_bytes());
+            Self::Extension { extensions }
         }
 
         pub const fn id(&self) -> u8 {
             match self {
-                PieceMessage::Bitfield => 5,
+                PieceMessage::Bitfield { .. } => 5,
                 PieceMessage::Interested => 2,
+                PieceMessage::NotInterested => 3,
+                PieceMessage::Have { .. } => 4,
                 PieceMessage::Unchoke => 1,
+                PieceMessage::Choke => 0,
                 PieceMessage::Request { .. } => 6,
                 PieceMessage::Piece { .. } => 7,
+                PieceMessage::Cancel { .. } => 8,
+                PieceMessage::Port { .. } => 9,
                 PieceMessage::Extension { .. } => 20,
             }
         }
@@ -429,55 +1176,101 @@ mod piece_message {
         /// The length of the message.
         fn length(&self) -> u32 {
             match self {
-                PieceMessage::Bitfield | PieceMessage::Interested | PieceMessage::Unchoke => 1,
-                PieceMessage::Request { .. } => 13,
+                PieceMessage::Interested
+                | PieceMessage::NotInterested
+                | PieceMessage::Unchoke
+                | PieceMessage::Choke => 1,
+                PieceMessage::Have { .. } => 5,
+                PieceMessage::Bitfield { bitfield } => 1 + bitfield.len() as u32,
+                PieceMessage::Request { .. } | PieceMessage::Cancel { .. } => 13,
                 PieceMessage::Piece { block, .. } => 9 + block.len() as u32,
-                PieceMessage::Extension { extensions } => 1 + 1 + extensions.len() as u32,
+                PieceMessage::Port { .. } => 3,
+                PieceMessage::Extension { extensions } => 1 + extensions.len() as u32,
             }
         }
 
-        pub(crate) fn to_bytes(&self) -> Vec<u8> {
-            let mut buffer = Vec::with_capacity(8);
+        /// Length prefix, message id and fixed size fields, everything before [`Self::payload`].
+        fn header(&self) -> Vec<u8> {
+            let mut buffer = Vec::with_capacity(17);
             buffer.extend_from_slice(&self.length().to_be_bytes());
             buffer.push(self.id());
             match self {
+                PieceMessage::Have { index } => buffer.extend_from_slice(&index.to_be_bytes()),
                 PieceMessage::Request {
                     index,
                     begin,
                     length,
-                } => {
-                    buffer.extend_from_slice(&index.to_be_bytes());
-                    buffer.extend_from_slice(&begin.to_be_bytes());
-                    buffer.extend_from_slice(&length.to_be_bytes());
                 }
-                PieceMessage::Piece {
+                | PieceMessage::Cancel {
                     index,
                     begin,
-                    block,
+                    length,
                 } => {
                     buffer.extend_from_slice(&index.to_be_bytes());
                     buffer.extend_from_slice(&begin.to_be_bytes());
-                    buffer.extend_from_slice(block.as_slice());
+                    buffer.extend_from_slice(&length.to_be_bytes());
                 }
-                PieceMessage::Extension { extensions } => {
-                    // Extension message id.
-                    buffer.push(b'\x00');
-                    // Extension message payload
-                    buffer.extend_from_slice(extensions.as_slice());
+                PieceMessage::Port { port } => buffer.extend_from_slice(&port.to_be_bytes()),
+                PieceMessage::Piece { index, begin, .. } => {
+                    buffer.extend_from_slice(&index.to_be_bytes());
+                    buffer.extend_from_slice(&begin.to_be_bytes());
                 }
                 _ => { /* Do nothing */ }
             }
             buffer
         }
 
+        /// Variable sized data at the end of message.
+        fn payload(&self) -> &[u8] {
+            match self {
+                PieceMessage::Bitfield { bitfield } => bitfield,
+                PieceMessage::Piece { block, .. } => block,
+                PieceMessage::Extension { extensions } => extensions,
+                _ => &[],
+            }
+        }
+
+        pub(crate) fn to_bytes(&self) -> Vec<u8> {
+            let mut buffer = self.header();
+            buffer.extend_from_slice(self.payload());
+            buffer
+        }
+
+        /// Write the message to `wr`, header and payload are sent together without copying them
+        /// into one buffer.
+        pub(crate) async fn write_to<W: AsyncWrite + Unpin>(&self, wr: &mut W) -> io::Result<()> {
+            let header = self.header();
+            let mut bufs = [IoSlice::new(&header), IoSlice::new(self.payload())];
+            let mut bufs = &mut bufs[..];
+            while !bufs.is_empty() {
+                let n = wr.write_vectored(bufs).await?;
+                if n == 0 {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                IoSlice::advance_slices(&mut bufs, n);
+            }
+            Ok(())
+        }
+
         pub(crate) fn from_bytes(data: &[u8]) -> BtResult<Self> {
             // 4 bytes length.
             if data.len() < 4 {
                 bail!("data too short: length={}", data.len())
             }
 
-            let length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
-            // println!(">>> recv msg_id={}, length={}", &data[4], length);
+            let length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            if length == 0 {
+                bail!("unexpected keep-alive message")
+            }
+            if data.len() < 4 + length {
+                bail!(
+                    "message truncated: length={}, expected={}",
+                    data.len(),
+                    4 + length
+                )
+            }
+            let payload = &data[5..4 + length];
+            // eprintln!(">>> recv msg_id={}, length={}", &data[4], length);
             // |--------|--|------------|
             //   |       |           |
             // length    id          payload
@@ -498,13 +1291,38 @@ mod piece_message {
             //
             // So the `length` is: 1(id) + 4(index) + 4(begin) + BLOCK_SIZE, usually 16394.
             match data[4] {
-                5 => Ok(Self::Bitfield),
+                5 => Ok(Self::Bitfield {
+                    bitfield: payload.to_vec(),
+                }),
                 2 => Ok(Self::Interested),
+                3 => Ok(Self::NotInterested),
+                4 => Ok(Self::Have {
+                    index: read_u32(fixed_payload(payload, 4, "have")?, 0),
+                }),
                 1 => Ok(Self::Unchoke),
-                6 => bail!("unexpected request message"),
-                7 => Self::piece_from_bytes(&data[5..(5 + (length - 1) as usize)]),
-                20 => Self::extension_from_bytes(&data[5..(5 + (length - 1) as usize)]),
-                v => bail!("unknown message id {v}"),
+                0 => Ok(Self::Choke),
+                6 => {
+                    let payload = fixed_payload(payload, 12, "request")?;
+                    Ok(Self::Request {
+                        index: read_u32(payload, 0),
+                        begin: read_u32(payload, 4),
+                        length: read_u32(payload, 8),
+                    })
+                }
+                7 => Self::piece_from_bytes(payload),
+                8 => {
+                    let payload = fixed_payload(payload, 12, "cancel")?;
+                    Ok(Self::Cancel {
+                        index: read_u32(payload, 0),
+                        begin: read_u32(payload, 4),
+                        length: read_u32(payload, 8),
+                    })
+                }
+                9 => Ok(Self::Port {
+                    port: u16::from_be_bytes(fixed_payload(payload, 2, "port")?.try_into()?),
+                }),
+                20 => Self::extension_from_bytes(payload),
+                v => bail!(ProtocolError::UnknownMessage(v)),
             }
         }
 
@@ -516,8 +1334,8 @@ mod piece_message {
                 bail!("data too short for piece message: length={}", payload.len())
             }
 
-            let index = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
-            let begin = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
+            let index = read_u32(payload, 0);
+            let begin = read_u32(payload, 4);
             let block = payload[8..].to_vec();
             Ok(Self::Piece {
                 index,
@@ -527,8 +1345,10 @@ mod piece_message {
         }
 
         /// Parse `PieceMessage::Extension` from bytes.
+        ///
+        /// The data is the payload part of the message.
         fn extension_from_bytes(payload: &[u8]) -> BtResult<Self> {
-            if payload.len() < 1 {
+            if payload.is_empty() {
                 bail!("data too short for piece message: length={}", payload.len())
             }
 
@@ -537,11 +1357,181 @@ mod piece_message {
             })
         }
     }
+
+    /// Check the payload of message `name` is `length` bytes long.
+    fn fixed_payload<'a>(payload: &'a [u8], length: usize, name: &str) -> BtResult<&'a [u8]> {
+        if payload.len() != length {
+            bail!(
+                "invalid {name} message payload length {}, expected {length}",
+                payload.len()
+            )
+        }
+        Ok(payload)
+    }
+
+    /// Read big endian u32 at `pos`, caller ensures the length.
+    fn read_u32(data: &[u8], pos: usize) -> u32 {
+        u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+    }
+}
+
+/// An established connection with peer, ready to request blocks.
+#[derive(Debug)]
+struct PeerConnection {
+    socket: PeerStream,
+
+    /// "host:port" the peer was dialed at.
+    addr: String,
+
+    source: PeerSource,
+
+    /// Pieces the peer has, updated by the `have` messages it sends.
+    bitfield: Bitfield,
+
+    /// We told the peer we are interested in its pieces.
+    interested: bool,
+
+    /// The peer is not accepting our requests.
+    choked: bool,
+
+    /// Bytes of blocks received.
+    downloaded: usize,
+
+    /// Time spent waiting for blocks.
+    busy: Duration,
+
+    /// Moving average of block throughput in bytes per second, `None` before the first block.
+    rate: Option<f64>,
+
+    /// Where received messages are dumped, if enabled.
+    dump: Option<WireDump>,
+}
+
+impl PeerConnection {
+    /// Event reporting the connection is dropped.
+    fn disconnected(&self) -> DownloadEvent {
+        DownloadEvent::PeerDisconnected {
+            addr: self.addr.clone(),
+            source: self.source.clone(),
+            downloaded: self.downloaded,
+        }
+    }
+
+    /// Fold the throughput of a block of `size` bytes received in `elapsed` into the average.
+    fn update_rate(rate: &mut Option<f64>, size: usize, elapsed: Duration) {
+        let sample = size as f64 / elapsed.as_secs_f64().max(0.001);
+        *rate = Some(match *rate {
+            Some(v) => v + RATE_SMOOTHING * (sample - v),
+            None => sample,
+        });
+    }
+
+    /// Update the peer state tracked from `message`.
+    ///
+    /// Peers may send any message at any time. The ones not about the state are ignored: we do not
+    /// upload, so requests and interest are of no use, and a block here is a late one we
+    /// requested before being choked.
+    ///
+    /// Peers announcing `upload_only` in the extension handshake are taken as seeds having all
+    /// pieces.
+    fn track(&mut self, message: &PieceMessage) {
+        match message {
+            PieceMessage::Choke => self.choked = true,
+            PieceMessage::Unchoke => self.choked = false,
+            PieceMessage::Have { index } => self.bitfield.set(*index as usize),
+            PieceMessage::Extension { extensions } if extensions.first() == Some(&0) => {
+                match ExtensionHandshake::from_bytes(&extensions[1..]) {
+                    Ok(v) if v.upload_only => self.bitfield = Bitfield::full(self.bitfield.len()),
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!(">>> invalid extension handshake from {}: {e:#}", self.addr)
+                    }
+                }
+            }
+            v => eprintln!(">>> ignore message from {}: id={}", self.addr, v.id()),
+        }
+    }
+
+    /// Send `interested` if the peer has any of the `needed` pieces, or `not interested` if it has
+    /// none of them, only when our interest changes.
+    ///
+    /// While interested, wait until the peer unchokes us, also when it choked us again since.
+    async fn update_interest(&mut self, needed: &Bitfield) -> BtResult<()> {
+        let interested = !self.bitfield.intersection(needed).none();
+        if interested != self.interested {
+            if interested {
+                PieceMessage::new_interested()
+                    .write_to(&mut self.socket)
+                    .await
+                    .context("failed to write interested message")?;
+            } else {
+                PieceMessage::new_not_interested()
+                    .write_to(&mut self.socket)
+                    .await
+                    .context("failed to write not interested message")?;
+            }
+            self.interested = interested;
+        }
+
+        while self.interested && self.choked {
+            let message = read_message(&mut self.socket, self.dump.as_mut())
+                .await
+                .context("failed to read unchoke message")?;
+            self.track(&message);
+        }
+        Ok(())
+    }
+}
+
+/// Update our interest in each connected peer with the `needed` pieces, return the connections
+/// ready to request piece `index`.
+///
+/// Peers failed or not answering are dropped from `peer_connections`, so that a dead socket does
+/// not fail the whole download. More peers are connected later if no one is left for the piece.
+async fn connections_for_piece(
+    peer_connections: &mut Vec<Arc<Mutex<PeerConnection>>>,
+    index: usize,
+    needed: &Bitfield,
+    events: &EventSender,
+) -> BtResult<Vec<Arc<Mutex<PeerConnection>>>> {
+    // Peers are waited on at the same time, so one slow to unchoke delays the piece only once.
+    let states = futures::future::join_all(peer_connections.iter().map(|conn| async move {
+        let mut guard = conn.lock().await;
+        match tokio::time::timeout(UNCHOKE_TIMEOUT, guard.update_interest(needed)).await {
+            Ok(Ok(())) => Ok(!guard.choked && guard.bitfield.has(index)),
+            Ok(Err(e)) => {
+                eprintln!(">>> drop peer {}: {e:#}", guard.addr);
+                Err(guard.disconnected())
+            }
+            Err(_) => {
+                eprintln!(">>> drop peer {}: not responding", guard.addr);
+                Err(guard.disconnected())
+            }
+        }
+    }))
+    .await;
+    let mut ready = vec![];
+    let mut dead = vec![];
+    for (pos, state) in states.into_iter().enumerate() {
+        match state {
+            Ok(true) => ready.push(peer_connections[pos].clone()),
+            Ok(false) => {}
+            Err(event) => dead.push((pos, event)),
+        }
+    }
+    for (pos, event) in dead.into_iter().rev() {
+        peer_connections.remove(pos);
+        let _ = events.send(event);
+    }
+    if ready.is_empty() {
+        bail!(PeerError::PieceUnavailable(index));
+    }
+    Ok(ready)
 }
 
 #[derive(Debug)]
 struct BlockTask {
-    pub socket: Arc<Mutex<TcpStream>>,
+    pub conn: Arc<Mutex<PeerConnection>>,
     pub piece_index: usize,
     pub block_index: usize,
     pub block_size: usize,
@@ -551,6 +1541,9 @@ struct BlockTask {
 struct BlockTaskResult {
     pub block_index: usize,
     pub data: Vec<u8>,
+
+    /// Connection the block was received from.
+    pub conn: Arc<Mutex<PeerConnection>>,
 }
 
 /// Download a single piece.
@@ -559,7 +1552,7 @@ struct BlockTaskResult {
 ///
 /// 1. Handshake.
 /// 2. Wait for a `bitfield` message.
-/// 3. Send an `interested` message.
+/// 3. Send an `interested` message to peers having the piece.
 /// 4. Wait for an `unchoke` message.
 /// 5. Break the piece into blocks, each block is 16kb sized. For each block:
 ///   1. Send a `request` message for each block.
@@ -569,20 +1562,41 @@ pub async fn download_piece(
     peers: &Peers,
     file_path: String,
     piece_index: usize,
-) -> BtResult<()> {
-    let conns = self::torrent::setup_connection(peers, torrent.info_hash())
+) -> Result<(), BtError> {
+    let piece_data = fetch_piece(torrent, peers, piece_index)
+        .await
+        .map_err(BtError::peer)?;
+    save_data_to_file(piece_data, &file_path).await?;
+    Ok(())
+}
+
+async fn fetch_piece(torrent: &Torrent, peers: &Peers, piece_index: usize) -> BtResult<Vec<u8>> {
+    let (mut conns, _) = self::torrent::setup_connection(peers, torrent)
         .await
         .context("failed to setup info hash")?;
-    let piece_data = download_piece_internal(torrent, &conns, piece_index).await?;
-    check_hash(&piece_data, &torrent.info.piece_hashes[piece_index]).context("")?;
-    save_data_to_file(piece_data, &file_path).await
+    // Nobody listens to the events.
+    let (events, _) = tokio::sync::mpsc::unbounded_channel();
+    let mut needed = Bitfield::new(torrent.info.piece_hashes.len());
+    needed.set(piece_index);
+    let ready = connections_for_piece(&mut conns, piece_index, &needed, &events).await?;
+    let (piece_data, _) = download_piece_internal(torrent, &ready, piece_index).await?;
+    let hash = torrent.info.piece_hashes[piece_index].clone();
+    let (piece_data, verified) = spawn_check_hash(piece_data, hash)
+        .await
+        .context("hash worker failed")?;
+    verified?;
+    broadcast_have(&conns, piece_index).await;
+    Ok(piece_data)
 }
 
+/// Download piece `piece_index` with its blocks spread over `peer_connections`.
+///
+/// Returns the piece data and the connections its blocks were received from.
 async fn download_piece_internal(
     torrent: &Torrent,
-    peer_connections: &Vec<Arc<Mutex<TcpStream>>>,
+    peer_connections: &[Arc<Mutex<PeerConnection>>],
     piece_index: usize,
-) -> BtResult<Vec<u8>> {
+) -> BtResult<(Vec<u8>, Vec<Arc<Mutex<PeerConnection>>>)> {
     let piece_length = torrent
         .piece_length(piece_index)
         .expect("piece index out of range");
@@ -591,34 +1605,102 @@ async fn download_piece_internal(
     // Last block size should be geater than zero.
     // If piece is exactly divided into multiple BLOCK_SIZE, the size of last one is also BLOCK_SIZE.
     let last_block_size = if m == 0 { BLOCK_SIZE } else { m };
-    println!(
+    eprintln!(
         ">>> piece {}: piece_length={}, block_count={}, last_block_size={}",
         piece_index, piece_length, block_count, last_block_size
     );
 
-    let mut tasks = vec![];
-    for i in 0..block_count {
-        tasks.push(BlockTask {
-            socket: peer_connections[i % peer_connections.len()].clone(),
-            piece_index: piece_index,
-            block_index: i,
-            block_size: if i < block_count - 1 {
-                BLOCK_SIZE
-            } else {
-                last_block_size
-            },
-            block_offset: i * BLOCK_SIZE,
-        });
+    // Blocks failed on a peer, like when it chokes us, are downloaded again from the others.
+    let mut peers = peer_connections.to_vec();
+    let mut pending = (0..block_count).collect::<Vec<_>>();
+    let mut data = vec![];
+    let mut last_error = None;
+    while !pending.is_empty() {
+        if peers.is_empty() {
+            return Err(
+                last_error.unwrap_or_else(|| PeerError::PieceUnavailable(piece_index).into())
+            );
+        }
+        let mut rates = vec![];
+        for conn in peers.iter() {
+            rates.push(conn.lock().await.rate);
+        }
+        let assignment = assign_blocks(&rates, pending.len());
+
+        let tasks = pending
+            .drain(..)
+            .zip(assignment)
+            .map(|(i, peer)| BlockTask {
+                conn: peers[peer].clone(),
+                piece_index,
+                block_index: i,
+                block_size: if i < block_count - 1 {
+                    BLOCK_SIZE
+                } else {
+                    last_block_size
+                },
+                block_offset: i * BLOCK_SIZE,
+            });
+        let results = futures::stream::iter(tasks.map(|task| async move {
+            let (conn, index) = (task.conn.clone(), task.block_index);
+            (conn, index, download_block(task).await)
+        }))
+        .buffered(peers.len())
+        .collect::<Vec<_>>()
+        .await;
+        for (conn, index, result) in results {
+            match result {
+                Ok(v) => data.push(v),
+                Err(e) => {
+                    eprintln!(">>> block {index} of piece {piece_index} failed: {e:#}");
+                    peers.retain(|x| !Arc::ptr_eq(x, &conn));
+                    pending.push(index);
+                    last_error = Some(e);
+                }
+            }
+        }
     }
+    data.sort_by_key(|x| x.block_index);
 
-    // println!(">>> parallel max size: {}", peer_connections.len());
-    let mut data = parallel_future(tasks.into_iter(), peer_connections.len(), |task| {
-        download_block(task)
-    })
-    .await?;
-    data.sort_by(|x, y| x.block_index.cmp(&y.block_index));
+    let mut sources: Vec<Arc<Mutex<PeerConnection>>> = vec![];
+    for block in data.iter() {
+        if !sources.iter().any(|x| Arc::ptr_eq(x, &block.conn)) {
+            sources.push(block.conn.clone());
+        }
+    }
+    let data = data.into_iter().flat_map(|x| x.data).collect::<Vec<_>>();
+    Ok((data, sources))
+}
+
+/// Choose the peer to download each of `block_count` blocks from, by index in `rates`.
+///
+/// Each block goes to the peer expected to finish it first, so faster peers get more and earlier
+/// blocks while slower ones still get the rest. Peers without a rate are treated as average ones.
+fn assign_blocks(rates: &[Option<f64>], block_count: usize) -> Vec<usize> {
+    let known = rates.iter().flatten().collect::<Vec<_>>();
+    let average = if known.is_empty() {
+        1.0
+    } else {
+        known.iter().copied().sum::<f64>() / known.len() as f64
+    };
+    let rates = rates
+        .iter()
+        .map(|x| x.unwrap_or(average).max(f64::MIN_POSITIVE))
+        .collect::<Vec<_>>();
 
-    Ok(data.into_iter().flat_map(|x| x.data).collect::<Vec<_>>())
+    let mut assigned = vec![0usize; rates.len()];
+    let mut ret = Vec::with_capacity(block_count);
+    for _ in 0..block_count {
+        let (peer, _) = rates
+            .iter()
+            .enumerate()
+            .map(|(idx, rate)| (idx, (assigned[idx] + 1) as f64 / rate))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .expect("no peer to assign blocks");
+        assigned[peer] += 1;
+        ret.push(peer);
+    }
+    ret
 }
 
 /// Download the data of a block in piece.
@@ -627,83 +1709,511 @@ async fn download_piece_internal(
 ///
 /// The block info is specified in `task` parameter.
 async fn download_block(task: BlockTask) -> BtResult<BlockTaskResult> {
-    let mut socket = task.socket.lock().unwrap();
-    let (mut rd, mut wr) = socket.split();
+    let mut guard = task.conn.lock().await;
+    let conn = &mut *guard;
 
     // Each piece is transfers as several blocks. The index of block defines the data position within piece.
     // let mut block_index = 0;
 
+    // Requests are discarded while choking.
+    if conn.choked {
+        bail!(
+            "peer choked us before requesting block {}",
+            task.block_index
+        );
+    }
+
+    let start = Instant::now();
     let curr_block_offset = task.block_offset;
     let curr_block_size = task.block_size;
     // println!(
     //     ">>> {} request: piece_index={}, block_index={}, block_offset={}, block_size={}",
     //     task.block_index, task.piece_index, task.block_index, curr_block_offset, curr_block_size
     // );
-    wr.write(
-        &PieceMessage::new_request(
-            task.piece_index as u32,
-            curr_block_offset as u32,
-            curr_block_size as u32,
-        )
-        .to_bytes(),
+    PieceMessage::new_request(
+        task.piece_index as u32,
+        curr_block_offset as u32,
+        curr_block_size as u32,
     )
+    .write_to(&mut conn.socket)
     .await?;
 
-    // Get all data for current block, it may be separated into different `read`s.
-    // That's why we need a loop.
-
-    // Record all bytes we read for current block.
-    // Read until the block >= total_size.
-    //
-    // The total size is geater than `curr_block_size` because we have extra sections on data.
-    let total_size = curr_block_size + 4 + 1 + 4 + 4;
-    let mut blk_buf = vec![0u8; total_size];
-    rd.read_exact(&mut blk_buf).await?;
-    // println!(">>> total_size={}, len={}", total_size, blk_buf.len());
-    match PieceMessage::from_bytes(&blk_buf)? {
-        PieceMessage::Piece { block, .. } => {
-            // println!(
-            //     ">>> receive: piece_index={}, block_index={}, begin={}, block_len={}",
-            //     index,
-            //     task.block_index,
-            //     begin,
-            //     block.len()
-            // );
-            Ok(BlockTaskResult {
-                block_index: task.block_index,
-                data: block,
-            })
+    // Peer may send other messages before the piece we requested.
+    loop {
+        match read_message(&mut conn.socket, conn.dump.as_mut()).await? {
+            PieceMessage::Piece {
+                index,
+                begin,
+                block,
+            } if index as usize == task.piece_index && begin as usize == curr_block_offset => {
+                conn.downloaded += block.len();
+                conn.busy += start.elapsed();
+                PeerConnection::update_rate(&mut conn.rate, block.len(), start.elapsed());
+                return Ok(BlockTaskResult {
+                    block_index: task.block_index,
+                    data: block,
+                    conn: task.conn.clone(),
+                });
+            }
+            message => {
+                conn.track(&message);
+                // Requests are discarded when choking.
+                if conn.choked {
+                    bail!("peer choked us before sending block {}", task.block_index);
+                }
+            }
         }
-        v => bail!("{}: invalid message: id={}", task.block_index, v.id()),
+    }
+}
+
+/// Count of connected peers having each of `piece_count` pieces.
+async fn availability(
+    peer_connections: &[Arc<Mutex<PeerConnection>>],
+    piece_count: usize,
+) -> Vec<usize> {
+    let mut counts = vec![0; piece_count];
+    for conn in peer_connections {
+        let conn = conn.lock().await;
+        for (idx, count) in counts.iter_mut().enumerate() {
+            if conn.bitfield.has(idx) {
+                *count += 1;
+            }
+        }
+    }
+    counts
+}
+
+/// Tell all connected peers that we have piece `index`.
+async fn broadcast_have(peer_connections: &[Arc<Mutex<PeerConnection>>], index: usize) {
+    let message = PieceMessage::new_have(index as u32);
+    for conn in peer_connections {
+        // Failing to notify a peer does not affect downloading.
+        let _ = message.write_to(&mut conn.lock().await.socket).await;
+    }
+}
+
+/// Send keep-alive messages to all connected peers.
+async fn send_keep_alive(peer_connections: &[Arc<Mutex<PeerConnection>>]) {
+    for conn in peer_connections {
+        let _ = conn.lock().await.socket.write_all(&[0u8; 4]).await;
     }
 }
 
 /// Download a whole file from torrent and save to `file_path`.
-pub async fn download_file(torrent: &Torrent, peers: &Peers, file_path: String) -> BtResult<()> {
-    let conns = self::torrent::setup_connection(peers, torrent.info_hash())
+pub async fn download_file(
+    torrent: &Torrent,
+    peers: &Peers,
+    file_path: String,
+) -> Result<(), BtError> {
+    // Nobody listens to the events.
+    let (events, _) = tokio::sync::mpsc::unbounded_channel();
+    let piece_count = torrent.info.piece_hashes.len();
+    let mut storage = MemoryStorage::new(piece_count);
+    fetch_file(
+        torrent,
+        peers,
+        &PiecePicker::new(piece_count),
+        &events,
+        &DownloadControl::unstoppable(),
+        None,
+        Discovery::None,
+        &mut storage,
+        Bitfield::full(piece_count),
+    )
+    .await
+    .map_err(BtError::peer)?;
+    save_data_to_file(storage.into_data(), &file_path).await?;
+    Ok(())
+}
+
+/// Download all pieces of `torrent`.
+///
+/// Only `needed` pieces are downloaded, in the order `picker` decides, skipping the ones no
+/// connected peer has.
+/// When none of the remaining pieces is available, more peers are asked from `discovery` as
+/// often as it allows, and connected peers are checked again every
+/// [`UNAVAILABLE_RETRY_INTERVAL`]. Fail if still none is available after
+/// [`UNAVAILABLE_TIMEOUT`].
+///
+/// Verified pieces are written to `storage`. Pieces failed hash check are downloaded again, at
+/// most [`HASH_RETRIES`] times, and their bytes are reported to trackers as corrupt.
+///
+/// Trackers are also announced again at [`REANNOUNCE_MILESTONES`] or when less than
+/// [`MIN_PEERS`] peers are connected, no more often than they allow.
+///
+/// `storage` is flushed even if failed. Once cancelled, pieces already downloaded are still
+/// checked and written before failing with [`BtError::Cancelled`].
+#[allow(clippy::too_many_arguments)]
+async fn fetch_file<S: Storage>(
+    torrent: &Torrent,
+    peers: &Peers,
+    picker: &PiecePicker,
+    events: &EventSender,
+    control: &DownloadControl,
+    mut peer_db: Option<&mut PeerDb>,
+    mut discovery: Discovery<'_>,
+    storage: &mut S,
+    mut needed: Bitfield,
+) -> BtResult<()> {
+    let piece_count = torrent.info.piece_hashes.len();
+    // Milestones count in pieces to download.
+    let total = needed.count_ones();
+    let (mut conns, failed) = control
+        .cancellable(self::torrent::setup_connection(peers, torrent))
         .await
         .context("failed to setup info hash")?;
+    report_connections(&conns, failed, events).await;
+    let _ = events.send(DownloadEvent::Availability {
+        peers: availability(&conns, piece_count).await,
+    });
+
+    // Pieces reported unavailable, report again only after they were available.
+    let mut unavailable = HashSet::new();
+    // Since when no needed piece is available.
+    let mut unavailable_since = None;
+    let mut hashing = VecDeque::<HashJob>::new();
+    // Piece index -> times failed hash check.
+    let mut hash_failures = HashMap::new();
+    let mut verified = 0;
+    let mut milestones = REANNOUNCE_MILESTONES.iter().peekable();
+    // Announce when allowed by the tracker, set by milestones and kept until done.
+    let mut reannounce = false;
+    let downloaded: BtResult<()> = async {
+        while !needed.none() || !hashing.is_empty() {
+            if needed.none() || hashing.len() >= HASH_QUEUE_SIZE {
+                let job = hashing.pop_front().unwrap();
+                let idx = job.index;
+                match finish_piece(job, &conns, events, peer_db.as_deref_mut()).await? {
+                    Some(data) => {
+                        store_piece(storage, idx, data).await?;
+                        verified += 1;
+                        while milestones
+                            .next_if(|x| verified * 100 >= **x * total)
+                            .is_some()
+                        {
+                            reannounce = true;
+                        }
+                    }
+                    None => {
+                        let failures = hash_failures.entry(idx).or_insert(0);
+                        *failures += 1;
+                        if *failures > HASH_RETRIES {
+                            bail!("piece {idx} hash mismatch");
+                        }
+                        if let Discovery::Trackers(trackers) = &mut discovery {
+                            trackers.add_corrupt(torrent.piece_length(idx).unwrap_or_default());
+                        }
+                        needed.set(idx);
+                    }
+                }
+                continue;
+            }
+            control.wait_resumed(&conns).await?;
+
+            if let Discovery::Trackers(trackers) = &discovery {
+                if (reannounce || conns.len() < MIN_PEERS) && trackers.can_announce(Instant::now())
+                {
+                    reannounce = false;
+                    // Not finding new peers is fine, we still have some.
+                    let found = control
+                        .cancellable(discover_more(torrent, &conns, &mut discovery, &needed))
+                        .await;
+                    match found {
+                        Ok((new_conns, failed)) => {
+                            report_connections(&new_conns, failed, events).await;
+                            conns.extend(new_conns);
+                            let _ = events.send(DownloadEvent::Availability {
+                                peers: availability(&conns, piece_count).await,
+                            });
+                        }
+                        Err(e) => eprintln!(">>> re-announce found no new peer: {e:#}"),
+                    }
+                }
+            }
 
-    let mut file_data = vec![];
-    for (idx, piece_hash) in torrent.info.piece_hashes.iter().enumerate() {
-        println!(">>> downloading piece {idx}");
-        let mut piece_data = download_piece_internal(torrent, &conns, idx)
-            .await
-            .with_context(|| format!("failed to download piece {idx} in file"))?;
-        check_hash(&piece_data, &piece_hash)
-            .with_context(|| format!("piece {idx} hash mismatch"))?;
-        file_data.append(&mut piece_data);
-        println!(">>> downloaded piece {idx}, file_size={}", file_data.len());
+            let mut next = None;
+            let connected = conns.len();
+            for idx in picker.order(&needed) {
+                match connections_for_piece(&mut conns, idx, &needed, events).await {
+                    Ok(v) => {
+                        unavailable.remove(&idx);
+                        next = Some((idx, v));
+                        break;
+                    }
+                    Err(e) if is_piece_unavailable(&e) => {
+                        if unavailable.insert(idx) {
+                            let _ = events.send(DownloadEvent::PieceUnavailable { index: idx });
+                        }
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            if conns.len() != connected {
+                let _ = events.send(DownloadEvent::Availability {
+                    peers: availability(&conns, piece_count).await,
+                });
+            }
+            let Some((idx, ready)) = next else {
+                let found = control
+                    .cancellable(discover_more(torrent, &conns, &mut discovery, &needed))
+                    .await;
+                match found {
+                    Ok((new_conns, failed)) => {
+                        report_connections(&new_conns, failed, events).await;
+                        conns.extend(new_conns);
+                        let _ = events.send(DownloadEvent::Availability {
+                            peers: availability(&conns, piece_count).await,
+                        });
+                    }
+                    Err(e) if matches!(e.downcast_ref(), Some(BtError::Cancelled)) => {
+                        return Err(e)
+                    }
+                    Err(e) => {
+                        let now = Instant::now();
+                        let deadline = *unavailable_since.get_or_insert(now) + UNAVAILABLE_TIMEOUT;
+                        if now >= deadline {
+                            return Err(e).with_context(|| {
+                                format!(
+                                    "piece {} unavailable in current swarm",
+                                    needed.first_one().unwrap_or_default()
+                                )
+                            });
+                        }
+                        // Connected peers may get pieces meanwhile, check them again even if
+                        // discovery is not allowed yet.
+                        let retry = (now + UNAVAILABLE_RETRY_INTERVAL).min(deadline);
+                        let wake = discovery.next_at(now).map_or(retry, |x| x.min(retry));
+                        control.wait_until(wake.max(now), &conns).await?;
+                    }
+                }
+                continue;
+            };
+            unavailable_since = None;
+
+            let (piece_data, sources) = {
+                let download = download_piece_internal(torrent, &ready, idx);
+                tokio::pin!(download);
+                loop {
+                    let result = tokio::select! {
+                        v = tokio::time::timeout(STALL_TIMEOUT, &mut download) => v,
+                        _ = control.cancelled() => bail!(BtError::Cancelled),
+                    };
+                    match result {
+                        Ok(v) => {
+                            break v.with_context(|| {
+                                format!("failed to download piece {idx} in file")
+                            })?
+                        }
+                        Err(_) => {
+                            let _ = events.send(DownloadEvent::Stalled { index: idx });
+                        }
+                    }
+                }
+            };
+            needed.unset(idx);
+            hashing.push_back(HashJob {
+                index: idx,
+                sources,
+                handle: spawn_check_hash(piece_data, torrent.info.piece_hashes[idx].clone()),
+            });
+        }
+        Ok(())
+    }
+    .await;
+    if matches!(
+        downloaded.as_ref().map_err(|e| e.downcast_ref::<BtError>()),
+        Err(Some(BtError::Cancelled))
+    ) {
+        // Pieces downloaded before cancelled are kept, so resuming does not download them again.
+        for job in hashing.drain(..) {
+            let idx = job.index;
+            if let Some(data) = finish_piece(job, &conns, events, peer_db.as_deref_mut()).await? {
+                store_piece(storage, idx, data).await?;
+            }
+        }
     }
+    let flushed = storage.flush().await.context("failed to flush storage");
+    for conn in conns.iter() {
+        let _ = events.send(conn.lock().await.disconnected());
+    }
+
+    downloaded.and(flushed)
+}
 
-    save_data_to_file(file_data, &file_path).await
+/// Send events of peers `connected` and `failed` to connect.
+async fn report_connections(
+    connected: &[Arc<Mutex<PeerConnection>>],
+    failed: Vec<Peer>,
+    events: &EventSender,
+) {
+    for conn in connected {
+        let conn = conn.lock().await;
+        let _ = events.send(DownloadEvent::PeerConnected {
+            addr: conn.addr.clone(),
+            source: conn.source.clone(),
+        });
+    }
+    for peer in failed {
+        let _ = events.send(DownloadEvent::PeerConnectFailed {
+            peer: peer.to_string(),
+            source: peer.source,
+        });
+    }
+}
+
+/// A downloaded piece waiting for hash check.
+struct HashJob {
+    index: usize,
+
+    /// Connections blocks of the piece were received from.
+    sources: Vec<Arc<Mutex<PeerConnection>>>,
+
+    handle: JoinHandle<(Vec<u8>, BtResult<()>)>,
+}
+
+/// Check hash of piece `data` on a blocking thread, so that hashing does not hold up tasks
+/// reading sockets.
+fn spawn_check_hash(
+    data: Vec<u8>,
+    expected_chksum: Vec<u8>,
+) -> JoinHandle<(Vec<u8>, BtResult<()>)> {
+    tokio::task::spawn_blocking(move || {
+        let verified = check_hash(&data, &expected_chksum);
+        (data, verified)
+    })
+}
+
+/// Wait for the hash check of `job`, then record the result.
+///
+/// Return data of the verified piece, or `None` if it failed hash check.
+async fn finish_piece(
+    job: HashJob,
+    peer_connections: &[Arc<Mutex<PeerConnection>>],
+    events: &EventSender,
+    peer_db: Option<&mut PeerDb>,
+) -> BtResult<Option<Vec<u8>>> {
+    let idx = job.index;
+    let (piece_data, verified) = job.handle.await.context("hash worker failed")?;
+    if let Some(db) = peer_db {
+        // Any of the peers sending blocks of a bad piece may have sent the bad data.
+        for conn in job.sources.iter() {
+            let conn = conn.lock().await;
+            match verified {
+                Ok(_) => db.record_speed(conn.addr.clone(), conn.downloaded, conn.busy),
+                Err(_) => db.strike(conn.addr.clone()),
+            }
+        }
+    }
+    if let Err(e) = verified {
+        eprintln!(">>> piece {idx} discarded: {e}");
+        let _ = events.send(DownloadEvent::HashFailed {
+            index: idx,
+            length: piece_data.len(),
+        });
+        return Ok(None);
+    }
+    let _ = events.send(DownloadEvent::PieceVerified {
+        index: idx,
+        length: piece_data.len(),
+    });
+    broadcast_have(peer_connections, idx).await;
+    Ok(Some(piece_data))
+}
+
+/// Write verified piece `idx` to `storage`.
+async fn store_piece<S: Storage>(storage: &mut S, idx: usize, data: Vec<u8>) -> BtResult<()> {
+    storage
+        .write_block(idx, 0, data)
+        .await
+        .with_context(|| format!("failed to write piece {idx}"))
+}
+
+fn is_piece_unavailable(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<PeerError>(),
+        Some(PeerError::PieceUnavailable(_))
+    )
+}
+
+/// Where more peers are asked for while downloading.
+pub(super) enum Discovery<'a> {
+    /// Only peers given at start are used.
+    None,
+
+    /// Announce to trackers, no more often than they allow.
+    Trackers(&'a mut TrackerTiers),
+
+    /// Look up DHT for trackerless torrents, no more often than [`DHT_INTERVAL`] since the last
+    /// lookup.
+    Dht(Option<Instant>),
+}
+
+impl Discovery<'_> {
+    /// Earliest time at or after `now` more peers can be asked for, `None` if never.
+    fn next_at(&self, now: Instant) -> Option<Instant> {
+        match self {
+            Self::None => None,
+            Self::Trackers(trackers) => Some(trackers.next_announce().map_or(now, |x| x.max(now))),
+            Self::Dht(last) => Some(last.map_or(now, |x| (x + DHT_INTERVAL).max(now))),
+        }
+    }
+}
+
+/// Ask `discovery` for peers not connected yet and connect them, peers failed to connect are
+/// returned apart.
+///
+/// Fail if no new peer is connected, or `discovery` does not allow asking yet.
+async fn discover_more(
+    torrent: &Torrent,
+    peer_connections: &[Arc<Mutex<PeerConnection>>],
+    discovery: &mut Discovery<'_>,
+    needed: &Bitfield,
+) -> BtResult<(Vec<Arc<Mutex<PeerConnection>>>, Vec<Peer>)> {
+    let first = needed.first_one().unwrap_or_default();
+    // Min interval and failure backoff also hold when peers lack pieces.
+    let now = Instant::now();
+    if discovery.next_at(now) != Some(now) {
+        bail!(PeerError::PieceUnavailable(first));
+    }
+    let mut connected = vec![];
+    for conn in peer_connections {
+        connected.push(conn.lock().await.addr.clone());
+    }
+    let peers = match discovery {
+        Discovery::None => bail!(PeerError::PieceUnavailable(first)),
+        Discovery::Trackers(trackers) => {
+            let left = needed
+                .iter_ones()
+                .filter_map(|x| torrent.piece_length(x))
+                .sum::<usize>();
+            let peer_info = trackers
+                .announce(torrent.info_hash(), 0, torrent.length() - left, left)
+                .await?;
+            peer_info.peers.sanitize(peer_info.external_ip, &connected)
+        }
+        Discovery::Dht(last) => {
+            *last = Some(now);
+            let result = dht::get_peers(torrent.info_hash(), false).await?;
+            result
+                .peers
+                .into_iter()
+                .collect::<Peers>()
+                .sanitize(None, &connected)
+        }
+    };
+    if peers.is_empty() {
+        bail!(PeerError::PieceUnavailable(first));
+    }
+    self::torrent::setup_connection(&peers, torrent)
+        .await
+        .context("failed to connect new peers")
 }
 
 fn check_hash(data: &[u8], expected_chksum: &[u8]) -> BtResult<()> {
     // Validate chksum.
     let mut hasher = Sha1::new();
     hasher.update(data);
-    let raw_chksum: [u8; 20] = hasher.finalize().try_into().unwrap();
+    let raw_chksum: [u8; 20] = hasher.finalize().into();
     let actual = hex::encode(raw_chksum);
 
     let expect = expected_chksum
@@ -712,7 +2222,7 @@ fn check_hash(data: &[u8], expected_chksum: &[u8]) -> BtResult<()> {
         .collect::<String>();
 
     if actual != expect {
-        Err(BtError::CheksumMismatchError {
+        Err(PeerError::CheksumMismatch {
             expected: expect,
             actually: actual,
         }
@@ -722,20 +2232,531 @@ fn check_hash(data: &[u8], expected_chksum: &[u8]) -> BtResult<()> {
     }
 }
 
-async fn save_data_to_file(data: Vec<u8>, file_path: &str) -> BtResult<()> {
-    // Download the first piece.
-    // Request for the first piece.
-    if std::fs::exists(&file_path).unwrap() {
-        std::fs::remove_file(&file_path).unwrap();
-    }
-    tokio::fs::write(file_path, data).await?;
-    Ok(())
+async fn save_data_to_file(data: Vec<u8>, file_path: &str) -> std::io::Result<()> {
+    disk::write_file(DiskBackend::default(), data, file_path).await
 }
 
 /// Magnet handshake queries peer info from tracker and handshake with peer to get peer id.
 pub async fn magnet_handshake(
     magnet: &Magnet,
     request_metadata: bool,
-) -> BtResult<MagnetHandshakeResult> {
-    self::magnet::handshake(magnet, request_metadata).await
+) -> Result<MagnetHandshakeResult, BtError> {
+    self::magnet::handshake(magnet, request_metadata)
+        .await
+        .map_err(BtError::peer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn test_announce_url_keeps_passkey() {
+        let query = format!(
+            "info_hash=%01%02%03%04%05%06%07%08%09%0A%0B%0C%0D%0E%0F%10%11%12%13%14&uploaded=0&downloaded=0&left=7&compact=1&no_peer_id=1&peer_id={PEER_ID}&port={PORT}"
+        );
+        let info_hash = std::array::from_fn(|i| i as u8 + 1);
+
+        let url = announce_url(
+            "https://t.example.com/a1b2c3/announce",
+            &info_hash,
+            0,
+            0,
+            7,
+            0,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            url.as_str(),
+            format!("https://t.example.com/a1b2c3/announce?{query}")
+        );
+
+        let url = announce_url(
+            "https://t.example.com/announce.php?passkey=a%2Fb~c&uid=1",
+            &info_hash,
+            0,
+            0,
+            7,
+            0,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            url.as_str(),
+            format!("https://t.example.com/announce.php?passkey=a%2Fb~c&uid=1&{query}")
+        );
+
+        let ip = Some(IpAddr::from([1, 2, 3, 4]));
+        let url = announce_url(
+            "https://t.example.com/announce",
+            &info_hash,
+            0,
+            0,
+            7,
+            512,
+            ip,
+        )
+        .unwrap();
+        assert_eq!(
+            url.as_str(),
+            format!("https://t.example.com/announce?{query}&ip=1.2.3.4&corrupt=512")
+        );
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        let info_hash = [0xab; 20];
+        let query = format!("info_hash={}", "%AB".repeat(20));
+        let url = scrape_url("http://t.example.com/x/announce.php?passkey=1", &info_hash).unwrap();
+        assert_eq!(
+            url.as_str(),
+            format!("http://t.example.com/x/scrape.php?passkey=1&{query}")
+        );
+        let url = scrape_url("http://t.example.com/announce", &info_hash).unwrap();
+        assert_eq!(url.as_str(), format!("http://t.example.com/scrape?{query}"));
+        assert!(scrape_url("http://t.example.com/a", &info_hash).is_err());
+        assert!(scrape_url("http://t.example.com/announce/x", &info_hash).is_err());
+    }
+
+    #[test]
+    fn test_parse_announce_response() {
+        let data = b"d8:completei5e11:external ip4:\x01\x02\x03\x0410:incompletei3e8:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe16:peers618:\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe2e";
+        let value = decode_bencoded_value(&mut DecodeContext::new(data.to_vec())).unwrap();
+        let info = parse_peer_info(value, "http://a/announce").unwrap();
+        assert_eq!(info.complete, Some(5));
+        assert_eq!(info.incomplete, Some(3));
+        assert_eq!(info.external_ip, Some(IpAddr::from([1, 2, 3, 4])));
+        assert_eq!(info.peers.len(), 2);
+        assert_eq!(info.peers[0].ip, "127.0.0.1");
+        assert_eq!(info.peers[0].port, 6881);
+        assert_eq!(info.peers[1].to_string(), "[2001:db8::1]:6882");
+        assert_eq!(
+            info.peers[1].source,
+            PeerSource::Tracker("http://a/announce".to_string())
+        );
+    }
+    #[test]
+    fn test_assign_blocks() {
+        // The fast peer gets three blocks for each one of the slow peer, starting with the first.
+        assert_eq!(
+            assign_blocks(&[Some(100.0), Some(300.0)], 8),
+            vec![1, 1, 0, 1, 1, 1, 0, 1]
+        );
+        // Peers not measured yet are treated as average ones.
+        assert_eq!(assign_blocks(&[Some(100.0), None], 4), vec![0, 1, 0, 1]);
+        assert_eq!(assign_blocks(&[None, None, None], 3), vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_write_message_vectored() {
+        let message = PieceMessage::Piece {
+            index: 1,
+            begin: 16384,
+            block: vec![7; 100],
+        };
+        let mut written = vec![];
+        message.write_to(&mut written).await.unwrap();
+        assert_eq!(written, message.to_bytes());
+        assert_eq!(&written[..5], &[0, 0, 0, 109, 7]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_exact_idle() {
+        let idle = Duration::from_millis(100);
+        let (mut rd, mut wr) = tokio::io::duplex(64);
+        let writer = tokio::spawn(async move {
+            // Slow in total but never idle for long.
+            for b in b"abcd" {
+                tokio::time::sleep(Duration::from_millis(40)).await;
+                wr.write_all(&[*b]).await.unwrap();
+            }
+            wr
+        });
+        let mut buf = [0u8; 4];
+        read_exact_idle(&mut rd, &mut buf, idle).await.unwrap();
+        assert_eq!(&buf, b"abcd");
+
+        // Stalled after part of the message.
+        let mut wr = writer.await.unwrap();
+        wr.write_all(b"e").await.unwrap();
+        let err = read_exact_idle(&mut rd, &mut buf, idle).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+        drop(wr);
+        let err = read_exact_idle(&mut rd, &mut buf, idle).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_drop_dead_connection() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut conns = vec![];
+        let mut servers = vec![];
+        for _ in 0..2 {
+            let socket = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            servers.push(listener.accept().await.unwrap().0);
+            conns.push(Arc::new(Mutex::new(PeerConnection {
+                addr: socket.local_addr().unwrap().to_string(),
+                source: PeerSource::Manual,
+                socket: Box::new(socket),
+                bitfield: Bitfield::full(1),
+                interested: false,
+                choked: true,
+                downloaded: 0,
+                busy: Duration::ZERO,
+                rate: None,
+                dump: None,
+            })));
+        }
+        // First peer is gone, the second unchokes us.
+        drop(servers.remove(0));
+        PieceMessage::Unchoke
+            .write_to(&mut servers[0])
+            .await
+            .unwrap();
+        let alive = conns[1].clone();
+
+        let (events, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let ready = connections_for_piece(&mut conns, 0, &Bitfield::full(1), &events)
+            .await
+            .unwrap();
+        assert_eq!(conns.len(), 1);
+        assert!(Arc::ptr_eq(&ready[0], &alive));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(DownloadEvent::PeerDisconnected { .. })
+        ));
+    }
+
+    impl PeerTransport for tokio::io::DuplexStream {}
+
+    /// Connection with a choking peer having `bitfield`, and the peer side of the stream.
+    fn test_connection(
+        bitfield: Bitfield,
+    ) -> (Arc<Mutex<PeerConnection>>, tokio::io::DuplexStream) {
+        let (ours, theirs) = tokio::io::duplex(1 << 16);
+        let conn = PeerConnection {
+            addr: "127.0.0.1:6881".to_string(),
+            source: PeerSource::Manual,
+            socket: Box::new(ours),
+            bitfield,
+            interested: false,
+            choked: true,
+            downloaded: 0,
+            busy: Duration::ZERO,
+            rate: None,
+            dump: None,
+        };
+        (Arc::new(Mutex::new(conn)), theirs)
+    }
+
+    /// Torrent of one piece, `data`, shorter than the piece length.
+    fn test_torrent(data: &[u8]) -> Torrent {
+        let head = format!(
+            "d8:announce3:foo4:infod6:lengthi{}e4:name1:a12:piece lengthi1048576e6:pieces20:",
+            data.len()
+        );
+        let data = [head.as_bytes(), &Sha1::digest(data), b"ee"].concat();
+        Torrent::parse_from_bytes(&data).unwrap()
+    }
+
+    /// Answer requests on `peer` with blocks of `piece`, until the stream is closed.
+    async fn serve_piece(mut peer: tokio::io::DuplexStream, piece: Vec<u8>) {
+        while let Ok(message) = read_message(&mut peer, None).await {
+            if let PieceMessage::Request {
+                index,
+                begin,
+                length,
+            } = message
+            {
+                let range = begin as usize..(begin + length) as usize;
+                let block = piece[range].to_vec();
+                let reply = PieceMessage::Piece {
+                    index,
+                    begin,
+                    block,
+                };
+                if reply.write_to(&mut peer).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_piece_sources() {
+        let piece = (0..BLOCK_SIZE + 100).map(|x| x as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&piece);
+        let mut conns = vec![];
+        for rate in [Some(100.0), Some(1.0)] {
+            let (conn, peer) = test_connection(Bitfield::full(1));
+            let mut guard = conn.lock().await;
+            (guard.rate, guard.choked) = (rate, false);
+            drop(guard);
+            conns.push(conn);
+            tokio::spawn(serve_piece(peer, piece.clone()));
+        }
+
+        let (data, sources) = download_piece_internal(&torrent, &conns, 0).await.unwrap();
+        assert_eq!(data, piece);
+        // Both blocks go to the fast peer, the slow one sent nothing.
+        assert_eq!(sources.len(), 1);
+        assert!(Arc::ptr_eq(&sources[0], &conns[0]));
+    }
+
+    #[tokio::test]
+    async fn test_requeue_choked_block() {
+        let piece = (0..BLOCK_SIZE * 2).map(|x| x as u8).collect::<Vec<_>>();
+        let torrent = test_torrent(&piece);
+        let (choking, mut peer) = test_connection(Bitfield::full(1));
+        tokio::spawn(async move {
+            read_message(&mut peer, None).await.unwrap();
+            PieceMessage::Choke.write_to(&mut peer).await.unwrap();
+            // Keep the connection open.
+            while read_message(&mut peer, None).await.is_ok() {}
+        });
+        let (serving, mut peer) = test_connection(Bitfield::full(1));
+        tokio::spawn(async move {
+            // Messages about uploading to the peer are ignored.
+            for message in [
+                PieceMessage::Interested,
+                PieceMessage::new_request(0, 0, 1),
+                PieceMessage::Port { port: 6881 },
+            ] {
+                message.write_to(&mut peer).await.unwrap();
+            }
+            serve_piece(peer, piece).await
+        });
+        let conns = vec![choking, serving];
+        for conn in conns.iter() {
+            conn.lock().await.choked = false;
+        }
+
+        let (data, sources) = download_piece_internal(&torrent, &conns, 0).await.unwrap();
+        assert_eq!(data.len(), BLOCK_SIZE * 2);
+        assert_eq!(sources.len(), 1);
+        assert!(Arc::ptr_eq(&sources[0], &conns[1]));
+        assert!(conns[0].lock().await.choked);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait_unchoke_concurrently() {
+        let mut conns = vec![];
+        for _ in 0..2 {
+            let (conn, mut peer) = test_connection(Bitfield::full(1));
+            // Interested and unchoked before, then choked again.
+            conn.lock().await.interested = true;
+            conns.push(conn);
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(20)).await;
+                PieceMessage::Unchoke.write_to(&mut peer).await.unwrap();
+            });
+        }
+
+        let start = tokio::time::Instant::now();
+        let (events, _) = tokio::sync::mpsc::unbounded_channel();
+        let ready = connections_for_piece(&mut conns, 0, &Bitfield::full(1), &events)
+            .await
+            .unwrap();
+        assert_eq!(ready.len(), 2);
+        // Waiting one after another would take 40s.
+        assert!(start.elapsed() < UNCHOKE_TIMEOUT);
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let messages = [
+            PieceMessage::Choke,
+            PieceMessage::Unchoke,
+            PieceMessage::Interested,
+            PieceMessage::NotInterested,
+            PieceMessage::new_have(7),
+            PieceMessage::Bitfield {
+                bitfield: vec![0b1010_0000, 0xff],
+            },
+            PieceMessage::new_request(1, 16384, 16384),
+            PieceMessage::Piece {
+                index: 1,
+                begin: 16384,
+                block: vec![1, 2, 3],
+            },
+            PieceMessage::Cancel {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            PieceMessage::Port { port: 6881 },
+            PieceMessage::Extension {
+                extensions: b"\x00d1:md11:ut_metadatai1eee".to_vec(),
+            },
+        ];
+        for message in messages {
+            let bytes = message.to_bytes();
+            assert_eq!(
+                u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize,
+                bytes.len() - 4
+            );
+            assert_eq!(bytes[4], message.id());
+            assert_eq!(PieceMessage::from_bytes(&bytes).unwrap(), message);
+        }
+
+        assert_eq!(
+            PieceMessage::Port { port: 6881 }.to_bytes(),
+            vec![0, 0, 0, 3, 9, 0x1a, 0xe1]
+        );
+        // Payload length must match the message type.
+        assert!(PieceMessage::from_bytes(&[0, 0, 0, 4, 9, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_parse_truncated_message() {
+        for data in [
+            &[0, 0, 0][..],
+            &[0, 0, 0, 0],
+            &[0, 0, 0, 1],
+            &[0, 0, 0, 5, 4, 0],
+            &[0, 0, 0, 9, 7, 0, 0, 0, 0, 0, 0, 0, 0],
+        ] {
+            assert!(PieceMessage::from_bytes(data).is_err(), "{data:?}");
+        }
+        assert!(matches!(
+            PieceMessage::from_bytes(&[0, 0, 0, 5, 4, 0, 0, 0, 3]).unwrap(),
+            PieceMessage::Have { index: 3 }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_upload_only_peer_is_seed() {
+        let (seed, _seed_side) = test_connection(Bitfield::new(3));
+        let (leech, _leech_side) = test_connection(Bitfield::new(3));
+        let handshake = |upload_only| {
+            PieceMessage::new_extension(&ExtensionHandshake::new(&[], None, upload_only))
+        };
+        seed.lock().await.track(&handshake(true));
+        leech.lock().await.track(&handshake(false));
+        leech.lock().await.track(&PieceMessage::new_have(1));
+
+        assert_eq!(availability(&[seed, leech], 3).await, vec![1, 2, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_read_message_skip_unknown() {
+        // Suggest piece (id 13) in the fast extension is not implemented.
+        let data = [
+            &[0, 0, 0, 5, 13, 0, 0, 0, 1][..],
+            &[0, 0, 0, 0],
+            &[0, 0, 0, 1, 42],
+            &[0, 0, 0, 5, 4, 0, 0, 0, 3],
+        ]
+        .concat();
+        let mut rd = &data[..];
+        assert_eq!(
+            read_message(&mut rd, None).await.unwrap(),
+            PieceMessage::Have { index: 3 }
+        );
+        assert!(rd.is_empty());
+
+        let mut rd = &[0, 0, 0, 1, 42, 0, 0, 0, 2, 4, 0][..];
+        assert!(read_message(&mut rd, None).await.is_err());
+
+        // The length is checked before reading the payload.
+        let mut rd = &[0xff, 0xff, 0xff, 0xff, 7][..];
+        let err = read_message(&mut rd, None).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::MessageTooLong {
+                length: 0xffff_ffff,
+                max: MAX_MESSAGE_LENGTH,
+            })
+        ));
+        let mut rd = &[0, 0, 0, 3, 5, 0xff, 0xff][..];
+        assert!(matches!(
+            read_bitfield(&mut rd, Some(8), None)
+                .await
+                .unwrap_err()
+                .downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::InvalidBitfieldLength {
+                length: 2,
+                expected: 1
+            })
+        ));
+        let mut rd = &[0, 0, 0x80, 0, 5][..];
+        assert!(matches!(
+            read_bitfield(&mut rd, Some(8), None)
+                .await
+                .unwrap_err()
+                .downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::MessageTooLong {
+                length: 0x8000,
+                max: MAX_MESSAGE_LENGTH,
+            })
+        ));
+        // Peers without pieces may skip the bitfield.
+        let mut rd = &[0, 0, 0, 1, 1][..];
+        let (bitfield, first) = read_bitfield(&mut rd, Some(8), None).await.unwrap();
+        assert!(bitfield.none());
+        assert_eq!(first, Some(PieceMessage::Unchoke));
+    }
+
+    #[test]
+    fn test_handshake_message() {
+        let info_hash = [1u8; 20];
+        let message = HandshakeMessage::builder(info_hash, [2u8; 20])
+            .extension(true)
+            .dht(true)
+            .fast(true)
+            .fast(false)
+            .build();
+        let bytes = message.to_bytes();
+        assert_eq!(&bytes[20..28], &[0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+
+        let parsed = HandshakeMessage::from_bytes(&bytes).unwrap();
+        assert!(parsed.has_ext());
+        assert_eq!(parsed.reserved, [0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+        assert!(parsed.verify_info_hash(&info_hash).is_ok());
+        assert!(parsed.verify_peer_id(&[3u8; 20]).is_ok());
+        assert!(matches!(
+            parsed.verify_peer_id(&parsed.peer_id),
+            Err(ProtocolError::OwnPeerId)
+        ));
+        assert!(matches!(
+            parsed.verify_info_hash(&[3u8; 20]),
+            Err(ProtocolError::InfoHashMismatch { .. })
+        ));
+
+        let mut bad = bytes.clone();
+        bad[1] = b'b';
+        assert!(matches!(
+            HandshakeMessage::from_bytes(&bad),
+            Err(ProtocolError::InvalidProtocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_sanitize_peers() {
+        let peer = |ip: &str, port| Peer {
+            ip: ip.to_string(),
+            port,
+            source: PeerSource::Manual,
+        };
+        let peers = Peers(vec![
+            peer("1.1.1.1", 1),
+            peer("1.1.1.1", 1),
+            peer("127.0.0.1", 1),
+            peer("0.0.0.0", 1),
+            peer("1.1.1.2", 0),
+            peer("in valid", 1),
+            peer("abc.onion", 1),
+            peer("1.1.1.3", 6881),
+            peer("1.1.1.3", 2),
+            peer("1.1.1.4", 1),
+        ]);
+        let peers = peers.sanitize(Some(IpAddr::from([1, 1, 1, 3])), &["1.1.1.4:1".to_string()]);
+        let addrs = peers.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        assert_eq!(addrs, vec!["1.1.1.1:1", "abc.onion:1", "1.1.1.3:2"]);
+    }
 }
@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::torrent::Torrent;
+
+use super::Bitfield;
+
+/// Extensions of media files players need the head and tail to start playback, where the
+/// container headers and indices are.
+const MEDIA_EXTENSIONS: [&str; 10] = [
+    "avi", "flv", "m4v", "mkv", "mov", "mp4", "mpg", "ts", "webm", "wmv",
+];
+
+/// Time after a deadline passed before it is dropped, so that pieces nobody waits for any more
+/// do not stay in front.
+const DEADLINE_DECAY: Duration = Duration::from_secs(30);
+
+/// Deadlines of pieces, shared between [`super::DownloadHandle`] and the download task.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Deadlines(Arc<Mutex<HashMap<usize, Instant>>>);
+
+impl Deadlines {
+    pub(super) fn set(&self, index: usize, deadline: Instant) {
+        self.0.lock().unwrap().insert(index, deadline);
+    }
+
+    pub(super) fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+
+    /// Pieces with deadlines by the earliest first, deadlines decayed at `now` are dropped.
+    fn active(&self, now: Instant) -> Vec<usize> {
+        let mut deadlines = self.0.lock().unwrap();
+        deadlines.retain(|_, deadline| *deadline + DEADLINE_DECAY > now);
+        let mut pieces = deadlines.iter().map(|(k, v)| (*v, *k)).collect::<Vec<_>>();
+        pieces.sort();
+        pieces.into_iter().map(|(_, index)| index).collect()
+    }
+}
+
+/// Decide the order pieces are downloaded in.
+///
+/// Pieces with deadlines come first by the earliest deadline, then prioritized pieces, then the
+/// others, each in index order.
+#[derive(Debug, Clone, Default)]
+pub(super) struct PiecePicker {
+    priority: Bitfield,
+
+    deadlines: Deadlines,
+}
+
+impl PiecePicker {
+    /// Pick pieces in index order.
+    pub(super) fn new(piece_count: usize) -> Self {
+        Self {
+            priority: Bitfield::new(piece_count),
+            deadlines: Deadlines::default(),
+        }
+    }
+
+    pub(super) fn with_deadlines(self, deadlines: Deadlines) -> Self {
+        Self { deadlines, ..self }
+    }
+
+    /// Prioritize the first and last piece of file in `torrent`.
+    ///
+    /// `head_tail` forces it on or off, `None` turns it on for media files by extension.
+    pub(super) fn for_torrent(torrent: &Torrent, head_tail: Option<bool>) -> Self {
+        let piece_count = torrent.info.piece_hashes.len();
+        let mut picker = Self::new(piece_count);
+        if head_tail.unwrap_or_else(|| is_media_file(torrent.name())) && piece_count > 0 {
+            picker.priority.set(0);
+            picker.priority.set(piece_count - 1);
+        }
+        picker
+    }
+
+    /// Pieces in `needed` in the order to download.
+    pub(super) fn order(&self, needed: &Bitfield) -> Vec<usize> {
+        self.order_at(needed, Instant::now())
+    }
+
+    fn order_at(&self, needed: &Bitfield, now: Instant) -> Vec<usize> {
+        let mut order = self
+            .deadlines
+            .active(now)
+            .into_iter()
+            .filter(|x| needed.has(*x))
+            .collect::<Vec<_>>();
+        let mut rest = needed.clone();
+        for index in order.iter() {
+            rest.unset(*index);
+        }
+        let first = self.priority.intersection(&rest);
+        order.extend(first.iter_ones());
+        order.extend(rest.difference(&first).iter_ones());
+        order
+    }
+}
+
+fn is_media_file(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| MEDIA_EXTENSIONS.contains(&x.to_ascii_lowercase().as_str()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_piece_order() {
+        let mut needed = Bitfield::full(5);
+        let mut picker = PiecePicker::new(5);
+        assert_eq!(picker.order(&needed), [0, 1, 2, 3, 4]);
+
+        picker.priority.set(0);
+        picker.priority.set(4);
+        needed.unset(0);
+        assert_eq!(picker.order(&needed), [4, 1, 2, 3]);
+
+        let now = Instant::now();
+        picker.deadlines.set(3, now + Duration::from_secs(2));
+        picker.deadlines.set(2, now + Duration::from_secs(1));
+        picker.deadlines.set(0, now);
+        assert_eq!(picker.order_at(&needed, now), [2, 3, 4, 1]);
+        let later = now + DEADLINE_DECAY + Duration::from_millis(1500);
+        assert_eq!(picker.order_at(&needed, later), [3, 4, 1, 2]);
+        picker.deadlines.clear();
+        assert_eq!(picker.order_at(&needed, now), [4, 1, 2, 3]);
+
+        assert!(is_media_file("a.b.MKV"));
+        assert!(!is_media_file("mkv"));
+        assert!(!is_media_file("a.iso"));
+    }
+}
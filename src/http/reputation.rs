@@ -0,0 +1,131 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Peer, Peers};
+
+/// Peers failed hash check this many times are skipped.
+const MAX_STRIKES: u32 = 3;
+
+/// What we observed from a peer in previous downloads.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct PeerRecord {
+    /// Download speed in bytes per second, measured in the latest download.
+    speed: f64,
+
+    /// Times pieces the peer contributed to failed hash check.
+    strikes: u32,
+
+    /// Unix timestamp in seconds.
+    last_seen: u64,
+}
+
+/// Peers of a torrent we met before, saved in the session directory.
+#[derive(Debug)]
+pub(super) struct PeerDb {
+    path: PathBuf,
+
+    /// Keyed by "ip:port".
+    peers: HashMap<String, PeerRecord>,
+}
+
+impl PeerDb {
+    /// Load the database of torrent `info_hash` from `session_dir`.
+    ///
+    /// Missing or broken database files are treated as empty.
+    pub(super) fn load(session_dir: &Path, info_hash: &[u8; 20]) -> Self {
+        let path = session_dir.join(format!("peers-{}.json", hex::encode(info_hash)));
+        let peers = std::fs::read(&path)
+            .ok()
+            .and_then(|x| serde_json::from_slice(&x).ok())
+            .unwrap_or_default();
+        Self { path, peers }
+    }
+
+    pub(super) fn save(&self) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.path, serde_json::to_vec(&self.peers)?)
+    }
+
+    /// Drop known bad peers, and put faster ones first.
+    ///
+    /// Peers never seen are put after known good ones.
+    pub(super) fn rank(&self, peers: Peers) -> Peers {
+        let speed = |peer: &Peer| {
+            self.peers
+                .get(&peer.to_string())
+                .map(|x| x.speed)
+                .unwrap_or_default()
+        };
+        let mut peers = peers
+            .0
+            .into_iter()
+            .filter(|peer| {
+                self.peers
+                    .get(&peer.to_string())
+                    .is_none_or(|x| x.strikes < MAX_STRIKES)
+            })
+            .collect::<Vec<_>>();
+        peers.sort_by(|a, b| speed(b).total_cmp(&speed(a)));
+        Peers(peers)
+    }
+
+    /// Record `downloaded` bytes from peer `addr` took `elapsed` time.
+    pub(super) fn record_speed(&mut self, addr: String, downloaded: usize, elapsed: Duration) {
+        let record = self.touch(addr);
+        if !elapsed.is_zero() {
+            record.speed = downloaded as f64 / elapsed.as_secs_f64();
+        }
+    }
+
+    /// Record peer `addr` sent data failed hash check.
+    pub(super) fn strike(&mut self, addr: String) {
+        self.touch(addr).strikes += 1;
+    }
+
+    fn touch(&mut self, addr: String) -> &mut PeerRecord {
+        let record = self.peers.entry(addr).or_default();
+        record.last_seen = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        record
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::PeerSource;
+
+    #[test]
+    fn test_rank_peers() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = PeerDb::load(dir.path(), &[0u8; 20]);
+        db.record_speed("10.0.0.1:1".to_string(), 100, Duration::from_secs(1));
+        db.record_speed("10.0.0.2:2".to_string(), 1000, Duration::from_secs(1));
+        for _ in 0..MAX_STRIKES {
+            db.strike("10.0.0.3:3".to_string());
+        }
+        db.save().unwrap();
+
+        let db = PeerDb::load(dir.path(), &[0u8; 20]);
+        let peers = Peers(
+            (1..=4)
+                .map(|x| Peer {
+                    ip: format!("10.0.0.{x}"),
+                    port: x,
+                    source: PeerSource::Manual,
+                })
+                .collect(),
+        );
+        let ports = db.rank(peers).iter().map(|x| x.port).collect::<Vec<_>>();
+        assert_eq!(ports, vec![2, 1, 4]);
+    }
+}
@@ -0,0 +1,136 @@
+use std::{net::SocketAddr, sync::OnceLock, time::Duration};
+
+use anyhow::{bail, Context};
+use tokio::net::{TcpSocket, TcpStream};
+
+use crate::utils::{BtError, BtResult};
+
+/// Options of sockets connecting peers.
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+    /// Size of `SO_SNDBUF`, system default if `None`.
+    pub send_buffer_size: Option<u32>,
+
+    /// Size of `SO_RCVBUF`, system default if `None`.
+    ///
+    /// Links with high bandwidth-delay product need a larger one to keep the pipe full.
+    pub recv_buffer_size: Option<u32>,
+
+    /// Times to retry connecting a peer after the first attempt failed.
+    pub connect_retries: usize,
+
+    /// Time to wait before the first retry, doubled for each later one.
+    pub connect_backoff: Duration,
+
+    /// Time to wait for one connect attempt, including the proxy handshake if any.
+    ///
+    /// Peers dropping SYN would otherwise hold a dial slot for the system connect timeout.
+    pub connect_timeout: Duration,
+
+    /// SOCKS5 proxy all peers and http trackers are connected through, like Tor.
+    ///
+    /// Host names including onion ones are resolved by the proxy. DHT, UDP and websocket trackers
+    /// are not used, they can not go through it.
+    pub proxy: Option<SocketAddr>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            connect_retries: 0,
+            connect_backoff: Duration::from_secs(1),
+            connect_timeout: Duration::from_secs(10),
+            proxy: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Time to wait before retry `attempt`, counting from 0, stops growing after 16 retries.
+    pub(super) fn backoff(&self, attempt: usize) -> Duration {
+        self.connect_backoff.saturating_mul(1 << attempt.min(16))
+    }
+}
+
+static SOCKET_OPTIONS: OnceLock<SocketOptions> = OnceLock::new();
+
+pub(super) fn socket_options() -> &'static SocketOptions {
+    SOCKET_OPTIONS.get_or_init(SocketOptions::default)
+}
+
+/// Set options of sockets connecting peers, must be called before connecting peers.
+pub fn set_socket_options(options: SocketOptions) -> Result<(), BtError> {
+    SOCKET_OPTIONS.set(options).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "socket options already set",
+        )
+    })?;
+    Ok(())
+}
+
+/// Connect to `addr` once, with buffer sizes in `options` applied before connecting.
+pub(super) async fn connect(addr: &str, options: &SocketOptions) -> BtResult<TcpStream> {
+    if is_onion(addr) {
+        bail!("onion peer {addr} can only be connected through a proxy");
+    }
+    let addr = tokio::net::lookup_host(addr)
+        .await
+        .context("failed to resolve peer address")?
+        .next()
+        .context("peer address not found")?;
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()
+    } else {
+        TcpSocket::new_v6()
+    }
+    .context("failed to create socket")?;
+    if let Some(size) = options.send_buffer_size {
+        socket
+            .set_send_buffer_size(size)
+            .context("failed to set SO_SNDBUF")?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        socket
+            .set_recv_buffer_size(size)
+            .context("failed to set SO_RCVBUF")?;
+    }
+    let stream = socket.connect(addr).await.context("failed to dial")?;
+    Ok(stream)
+}
+
+/// Peer at `addr` "host:port" is a Tor onion service.
+fn is_onion(addr: &str) -> bool {
+    addr.rsplit_once(':')
+        .is_some_and(|(host, _)| host.to_ascii_lowercase().ends_with(".onion"))
+}
+
+/// DHT is off when peers are connected through a proxy, its UDP traffic would bypass the proxy.
+pub fn dht_allowed() -> bool {
+    socket_options().proxy.is_none()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let options = SocketOptions {
+            connect_backoff: Duration::from_millis(500),
+            ..Default::default()
+        };
+        assert_eq!(options.backoff(0), Duration::from_millis(500));
+        assert_eq!(options.backoff(2), Duration::from_secs(2));
+        assert_eq!(options.backoff(100), options.backoff(16));
+    }
+
+    #[test]
+    fn test_is_onion() {
+        assert!(is_onion("abc.ONION:6881"));
+        assert!(!is_onion("10.0.0.1:6881"));
+        assert!(!is_onion("[::1]:6881"));
+    }
+}
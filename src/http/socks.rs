@@ -0,0 +1,186 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
+};
+
+use anyhow::{bail, Context};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::utils::BtResult;
+
+use super::transport::PeerTransport;
+
+const VERSION: u8 = 5;
+
+/// Username and password authentication, Tor uses the credentials to isolate streams.
+const METHOD_PASSWORD: u8 = 2;
+
+const CMD_CONNECT: u8 = 1;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// A stream to a peer through a SOCKS5 proxy.
+#[derive(Debug)]
+pub(super) struct ProxiedStream {
+    stream: TcpStream,
+}
+
+impl PeerTransport for ProxiedStream {}
+
+impl AsyncRead for ProxiedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxiedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
+
+/// Connect to `target` "host:port" through SOCKS5 `proxy`.
+///
+/// The host is resolved by the proxy, never locally. Streams with different `isolation` keys are
+/// sent as different users, so Tor does not share circuits between them.
+pub(super) async fn connect(
+    proxy: SocketAddr,
+    target: &str,
+    isolation: &str,
+) -> BtResult<ProxiedStream> {
+    let (host, port) = target
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host.trim_matches(['[', ']']), port.parse::<u16>().ok()?)))
+        .with_context(|| format!("invalid peer address {target}"))?;
+    let mut stream = TcpStream::connect(proxy)
+        .await
+        .context("failed to connect proxy")?;
+    stream
+        .set_nodelay(true)
+        .context("failed to set TCP_NODELAY")?;
+
+    stream.write_all(&[VERSION, 1, METHOD_PASSWORD]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [VERSION, METHOD_PASSWORD] {
+        bail!("proxy refused password authentication: {reply:?}")
+    }
+    // A fixed password, only the pair of credentials matters for isolation.
+    let mut auth = vec![1, isolation.len() as u8];
+    auth.extend_from_slice(isolation.as_bytes());
+    auth.extend_from_slice(&[1, b'x']);
+    stream.write_all(&auth).await?;
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        bail!("proxy authentication failed: {}", reply[1])
+    }
+
+    let mut request = vec![VERSION, CMD_CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(v)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&v.octets());
+        }
+        Ok(IpAddr::V6(v)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&v.octets());
+        }
+        Err(_) if host.len() <= u8::MAX as usize => {
+            request.extend_from_slice(&[ATYP_DOMAIN, host.len() as u8]);
+            request.extend_from_slice(host.as_bytes());
+        }
+        Err(_) => bail!("peer host name too long: {host}"),
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[1] != 0 {
+        bail!("proxy failed to connect {target}: reply {}", head[1])
+    }
+    // Skip the bound address.
+    let addr_len = match head[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        v => bail!("invalid proxy address type {v}"),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(ProxiedStream { stream })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_socks_connect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 3];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [5, 1, 2]);
+            socket.write_all(&[5, 2]).await.unwrap();
+            let mut buf = [0u8; 7];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [1, 3, b'a', b'b', b'c', 1, b'x']);
+            socket.write_all(&[1, 0]).await.unwrap();
+            let mut buf = vec![0u8; 5 + 11 + 2];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf[..5], [5, 1, 0, 3, 11]);
+            assert_eq!(&buf[5..16], b"peer2.onion");
+            assert_eq!(&buf[16..], 6881u16.to_be_bytes());
+            socket
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            socket.write_all(b"hello").await.unwrap();
+        });
+
+        let mut stream = connect(proxy, "peer2.onion:6881", "abc").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        server.await.unwrap();
+    }
+}
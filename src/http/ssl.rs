@@ -0,0 +1,58 @@
+use std::{io, sync::OnceLock};
+
+use anyhow::Context;
+use tokio_native_tls::{
+    native_tls::{Certificate, Identity, TlsConnector},
+    TlsStream,
+};
+
+use crate::utils::{BtError, BtResult};
+
+use super::transport::{PeerStream, PeerTransport};
+
+static SSL_IDENTITY: OnceLock<Identity> = OnceLock::new();
+
+/// Set our certificate and private key presented to peers of SSL torrents, must be called before
+/// connecting peers.
+///
+/// Both are PEM encoded, the key in PKCS #8. Peers only accept certificates signed by the
+/// authority in the torrent, so this is needed to join most SSL swarms.
+pub fn set_ssl_identity(cert: &[u8], key: &[u8]) -> Result<(), BtError> {
+    let identity = Identity::from_pkcs8(cert, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    SSL_IDENTITY
+        .set(identity)
+        .map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "ssl identity already set"))?;
+    Ok(())
+}
+
+impl<S: PeerTransport> PeerTransport for TlsStream<S> {}
+
+/// Wrap `stream` to a peer of SSL torrent `info_hash` in TLS.
+///
+/// The peer must present a certificate signed by `ca_pem` in the torrent. The server name is the
+/// hex info hash, peers serving several torrents pick the certificate by it.
+pub(super) async fn connect(
+    stream: PeerStream,
+    ca_pem: &str,
+    info_hash: &[u8; 20],
+) -> BtResult<PeerStream> {
+    let ca = Certificate::from_pem(ca_pem.as_bytes()).context("invalid ssl-cert in torrent")?;
+    let mut builder = TlsConnector::builder();
+    builder
+        .add_root_certificate(ca)
+        .disable_built_in_roots(true)
+        // Peers have no host names, the authority is all that is checked.
+        .danger_accept_invalid_hostnames(true);
+    if let Some(identity) = SSL_IDENTITY.get() {
+        builder.identity(identity.clone());
+    }
+    let connector = tokio_native_tls::TlsConnector::from(
+        builder.build().context("failed to build tls connector")?,
+    );
+    let stream = connector
+        .connect(&hex::encode(info_hash), stream)
+        .await
+        .context("tls handshake failed")?;
+    Ok(Box::new(stream))
+}
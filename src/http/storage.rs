@@ -0,0 +1,397 @@
+use std::{future::Future, io};
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::{
+    cache::{WriteCache, WriteCacheOptions},
+    check_hash,
+};
+
+/// Where downloaded pieces are kept.
+///
+/// Blocks are addressed by piece index and offset in piece. The download engine only talks to
+/// this trait, so backends can be swapped without touching it.
+pub trait Storage: Send {
+    /// Write `data` at `offset` in piece `index`.
+    fn write_block(
+        &mut self,
+        index: usize,
+        offset: usize,
+        data: Vec<u8>,
+    ) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Read `length` bytes at `offset` in piece `index`.
+    fn read_block(
+        &mut self,
+        index: usize,
+        offset: usize,
+        length: usize,
+    ) -> impl Future<Output = io::Result<Vec<u8>>> + Send;
+
+    /// Make written data durable as the backend defines.
+    fn flush(&mut self) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Check piece `index` of `length` bytes against `expected` hex SHA1 in torrent.
+    ///
+    /// Fail only if the piece can not be read, a mismatch returns `false`.
+    fn verify_piece(
+        &mut self,
+        index: usize,
+        length: usize,
+        expected: &[u8],
+    ) -> impl Future<Output = io::Result<bool>> + Send {
+        async move {
+            let data = self.read_block(index, 0, length).await?;
+            Ok(check_hash(&data, expected).is_ok())
+        }
+    }
+}
+
+/// Pieces written to a file through a [`WriteCache`].
+pub struct FileStorage {
+    cache: WriteCache,
+
+    /// Nominal piece length, all pieces but the last one have this length.
+    piece_length: usize,
+}
+
+impl FileStorage {
+    /// Create file at `file_path` with `length` bytes, replacing existing file.
+    pub async fn create(
+        file_path: &str,
+        length: usize,
+        piece_length: usize,
+        options: WriteCacheOptions,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            cache: WriteCache::create(file_path, length, options).await?,
+            piece_length,
+        })
+    }
+
+    /// Open existing file at `file_path` keeping its data, resized to `length` bytes.
+    pub async fn open(
+        file_path: &str,
+        length: usize,
+        piece_length: usize,
+        options: WriteCacheOptions,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            cache: WriteCache::open(file_path, length, options).await?,
+            piece_length,
+        })
+    }
+
+    /// Write out all pending data and sync as the fsync policy says.
+    pub async fn finish(self) -> io::Result<()> {
+        self.cache.finish().await
+    }
+}
+
+impl Storage for FileStorage {
+    async fn write_block(&mut self, index: usize, offset: usize, data: Vec<u8>) -> io::Result<()> {
+        self.cache
+            .write(index * self.piece_length + offset, data)
+            .await
+    }
+
+    async fn read_block(
+        &mut self,
+        index: usize,
+        offset: usize,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        self.cache
+            .read(index * self.piece_length + offset, length)
+            .await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.cache.flush().await
+    }
+}
+
+/// Pieces held in memory, without temporary files.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    pieces: Vec<Vec<u8>>,
+
+    /// Bytes held.
+    bytes: usize,
+
+    /// Max bytes held, writes beyond it fail.
+    limit: Option<usize>,
+}
+
+impl MemoryStorage {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            pieces: vec![vec![]; piece_count],
+            bytes: 0,
+            limit: None,
+        }
+    }
+
+    /// Hold at most `limit` bytes.
+    pub fn with_limit(piece_count: usize, limit: usize) -> Self {
+        Self {
+            limit: Some(limit),
+            ..Self::new(piece_count)
+        }
+    }
+
+    /// Bytes held.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Pieces joined in order.
+    pub fn into_data(self) -> Vec<u8> {
+        self.pieces.concat()
+    }
+
+    /// Pieces by index, empty for pieces not written.
+    pub fn into_pieces(self) -> Vec<Vec<u8>> {
+        self.pieces
+    }
+
+    /// Take out piece `index`, freeing its bytes.
+    fn take_piece(&mut self, index: usize) -> Vec<u8> {
+        let piece = std::mem::take(&mut self.pieces[index]);
+        self.bytes -= piece.len();
+        piece
+    }
+
+    fn piece_len(&self, index: usize) -> usize {
+        self.pieces.get(index).map_or(0, |x| x.len())
+    }
+}
+
+impl Storage for MemoryStorage {
+    async fn write_block(&mut self, index: usize, offset: usize, data: Vec<u8>) -> io::Result<()> {
+        let piece = self
+            .pieces
+            .get_mut(index)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "piece out of range"))?;
+        let end = offset + data.len();
+        let grow = end.saturating_sub(piece.len());
+        if self.limit.is_some_and(|x| self.bytes + grow > x) {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                "memory storage limit exceeded",
+            ));
+        }
+        self.bytes += grow;
+        if offset == 0 && piece.is_empty() {
+            *piece = data;
+            return Ok(());
+        }
+        if piece.len() < end {
+            piece.resize(end, 0);
+        }
+        piece[offset..end].copy_from_slice(&data);
+        Ok(())
+    }
+
+    async fn read_block(
+        &mut self,
+        index: usize,
+        offset: usize,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        self.pieces
+            .get(index)
+            .and_then(|x| x.get(offset..offset + length))
+            .map(|x| x.to_vec())
+            .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Pieces written to `W` in order as soon as they are complete, for piping the file to another
+/// program.
+///
+/// Pieces completed out of order are held in a bounded [`MemoryStorage`] until the ones before
+/// them are written, the next piece in order does not count against the bound. Written pieces can not be read back.
+pub struct StreamStorage<W> {
+    writer: W,
+
+    held: MemoryStorage,
+
+    /// Length of each piece.
+    lengths: Vec<usize>,
+
+    /// Index of the next piece to write.
+    next: usize,
+}
+
+impl<W: AsyncWrite + Unpin + Send> StreamStorage<W> {
+    /// Stream file of `length` bytes in pieces of `piece_length` to `writer`, holding at most
+    /// `limit` bytes of pieces out of order.
+    pub fn new(writer: W, length: usize, piece_length: usize, limit: usize) -> Self {
+        let lengths = (0..length.div_ceil(piece_length))
+            .map(|x| piece_length.min(length - x * piece_length))
+            .collect::<Vec<_>>();
+        Self {
+            writer,
+            held: MemoryStorage::with_limit(lengths.len(), limit),
+            lengths,
+            next: 0,
+        }
+    }
+
+    /// Give back the writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send> Storage for StreamStorage<W> {
+    async fn write_block(&mut self, index: usize, offset: usize, data: Vec<u8>) -> io::Result<()> {
+        if index < self.next {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "piece already streamed",
+            ));
+        }
+        // The next piece given whole is written without holding it.
+        if index == self.next
+            && offset == 0
+            && self.held.piece_len(index) == 0
+            && self.lengths.get(index) == Some(&data.len())
+        {
+            self.writer.write_all(&data).await?;
+            self.next += 1;
+        } else {
+            self.held.write_block(index, offset, data).await?;
+        }
+        while self.next < self.lengths.len()
+            && self.held.piece_len(self.next) == self.lengths[self.next]
+        {
+            let piece = self.held.take_piece(self.next);
+            self.writer.write_all(&piece).await?;
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    async fn read_block(
+        &mut self,
+        index: usize,
+        offset: usize,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        if index < self.next {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "piece already streamed",
+            ));
+        }
+        self.held.read_block(index, offset, length).await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush().await
+    }
+}
+
+/// Storage of a download, picked by options.
+pub(super) enum DownloadStorage {
+    File(FileStorage),
+    Memory(MemoryStorage),
+    Stream(StreamStorage<tokio::io::Stdout>),
+}
+
+impl Storage for DownloadStorage {
+    async fn write_block(&mut self, index: usize, offset: usize, data: Vec<u8>) -> io::Result<()> {
+        match self {
+            Self::File(v) => v.write_block(index, offset, data).await,
+            Self::Memory(v) => v.write_block(index, offset, data).await,
+            Self::Stream(v) => v.write_block(index, offset, data).await,
+        }
+    }
+
+    async fn read_block(
+        &mut self,
+        index: usize,
+        offset: usize,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        match self {
+            Self::File(v) => v.read_block(index, offset, length).await,
+            Self::Memory(v) => v.read_block(index, offset, length).await,
+            Self::Stream(v) => v.read_block(index, offset, length).await,
+        }
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::File(v) => v.flush().await,
+            Self::Memory(v) => v.flush().await,
+            Self::Stream(v) => v.flush().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let path = path.to_str().unwrap();
+        let mut file = FileStorage::create(path, 5, 2, WriteCacheOptions::default())
+            .await
+            .unwrap();
+        let mut memory = MemoryStorage::new(3);
+        for (index, data) in [(2, "e"), (0, "ab"), (1, "cd")] {
+            file.write_block(index, 0, data.into()).await.unwrap();
+            memory.write_block(index, 0, data.into()).await.unwrap();
+        }
+        memory.write_block(1, 1, b"x".to_vec()).await.unwrap();
+        assert_eq!(file.read_block(1, 1, 1).await.unwrap(), b"d");
+        assert_eq!(memory.read_block(1, 0, 2).await.unwrap(), b"cx");
+        // SHA1 of "ab".
+        let hash = b"da23614e02469a0d7c7bd1bdab5c9c474b1904dc";
+        assert!(file.verify_piece(0, 2, hash).await.unwrap());
+        assert!(!memory.verify_piece(1, 2, hash).await.unwrap());
+        file.finish().await.unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"abcde");
+        assert_eq!(memory.into_data(), b"abcxe");
+
+        // Reopened file keeps the data not written.
+        std::fs::write(path, b"abX").unwrap();
+        let mut file = FileStorage::open(path, 5, 2, WriteCacheOptions::default())
+            .await
+            .unwrap();
+        file.write_block(1, 0, b"cd".to_vec()).await.unwrap();
+        file.write_block(2, 0, b"e".to_vec()).await.unwrap();
+        file.finish().await.unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"abcde");
+
+        let mut memory = MemoryStorage::with_limit(2, 3);
+        memory.write_block(0, 0, b"ab".to_vec()).await.unwrap();
+        memory.write_block(0, 1, b"bc".to_vec()).await.unwrap();
+        assert_eq!(memory.bytes(), 3);
+        assert!(memory.write_block(1, 0, b"d".to_vec()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_storage() {
+        let mut stream = StreamStorage::new(vec![], 5, 2, 2);
+        stream.write_block(1, 0, b"cd".to_vec()).await.unwrap();
+        assert!(stream.writer.is_empty());
+        // Held pieces are over the limit.
+        assert!(stream.write_block(2, 0, b"e".to_vec()).await.is_err());
+        stream.write_block(0, 0, b"ab".to_vec()).await.unwrap();
+        assert!(stream.read_block(1, 0, 2).await.is_err());
+        stream.write_block(2, 0, b"e".to_vec()).await.unwrap();
+        stream.flush().await.unwrap();
+        assert_eq!(stream.into_inner(), b"abcde");
+    }
+}
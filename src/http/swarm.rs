@@ -0,0 +1,172 @@
+use std::{sync::Arc, time::Instant};
+
+use futures::StreamExt;
+use tokio::sync::Mutex;
+
+use crate::{
+    torrent::Torrent,
+    utils::{BtError, PeerError},
+};
+
+use super::{
+    connections_for_piece, download_piece_internal, torrent::connect_peer, Bitfield,
+    PeerConnection, Peers,
+};
+
+/// Count of peers connected at the same time when sampling.
+const CONCURRENCY: usize = 8;
+
+/// Piece availability in a sample of the swarm.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwarmHealth {
+    /// Count of peers tried.
+    pub sampled: usize,
+
+    /// Count of peers connected and sent their bitfields.
+    pub connected: usize,
+
+    /// Count of connected peers having each piece.
+    pub availability: Vec<usize>,
+}
+
+impl SwarmHealth {
+    fn new(piece_count: usize, sampled: usize, bitfields: &[Bitfield]) -> Self {
+        let mut availability = vec![0; piece_count];
+        for bitfield in bitfields {
+            for index in bitfield.iter_ones() {
+                availability[index] += 1;
+            }
+        }
+        Self {
+            sampled,
+            connected: bitfields.len(),
+            availability,
+        }
+    }
+
+    /// Count of pieces by the count of peers having them, the n-th element is for n copies.
+    pub fn histogram(&self) -> Vec<usize> {
+        let mut histogram = vec![0; self.connected + 1];
+        for v in self.availability.iter() {
+            histogram[*v] += 1;
+        }
+        histogram
+    }
+
+    /// Distributed copies: the least copies of any piece, plus the fraction of pieces having more
+    /// than that.
+    pub fn distributed_copies(&self) -> f64 {
+        let Some(min) = self.availability.iter().min().copied() else {
+            return 0.0;
+        };
+        let more = self.availability.iter().filter(|x| **x > min).count();
+        min as f64 + more as f64 / self.availability.len() as f64
+    }
+
+    /// All pieces are available in the sampled peers.
+    pub fn is_completable(&self) -> bool {
+        self.availability.iter().all(|x| *x > 0)
+    }
+
+    /// Index of the piece most peers have, `None` if no peer has any piece.
+    pub fn most_available(&self) -> Option<usize> {
+        self.availability
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| **x > 0)
+            .max_by_key(|(idx, x)| (**x, std::cmp::Reverse(*idx)))
+            .map(|(idx, _)| idx)
+    }
+}
+
+/// Sampled peers still connected, with the health gathered from them.
+pub struct SwarmSample {
+    pub health: SwarmHealth,
+
+    conns: Vec<Arc<Mutex<PeerConnection>>>,
+}
+
+impl SwarmSample {
+    /// Download piece `index` from the sampled peers into memory and return the bytes per second
+    /// its blocks were received at, as a sample of the download speed. Nothing is written.
+    ///
+    /// Only the block transfer is timed, not waiting for peers to unchoke us.
+    pub async fn time_piece(&mut self, torrent: &Torrent, index: usize) -> Result<f64, BtError> {
+        // Nobody listens to the events.
+        let (events, _) = tokio::sync::mpsc::unbounded_channel();
+        let mut needed = Bitfield::new(torrent.info.piece_hashes.len());
+        needed.set(index);
+        let ready = connections_for_piece(&mut self.conns, index, &needed, &events)
+            .await
+            .map_err(BtError::peer)?;
+        let start = Instant::now();
+        let (data, _) = download_piece_internal(torrent, &ready, index)
+            .await
+            .map_err(BtError::peer)?;
+        Ok(data.len() as f64 / start.elapsed().as_secs_f64().max(0.001))
+    }
+}
+
+/// Connect to at most `sample` peers and gather their bitfields, the connections are kept.
+///
+/// Peers failed to connect are ignored, only fails if none of them connected.
+pub async fn sample_swarm(
+    torrent: &Torrent,
+    peers: &Peers,
+    sample: usize,
+) -> Result<SwarmSample, BtError> {
+    let piece_count = torrent.info.piece_hashes.len();
+    let candidates = peers.iter().take(sample).cloned().collect::<Vec<_>>();
+    let conns = futures::stream::iter(candidates.iter())
+        .map(|peer| connect_peer(peer, torrent))
+        .buffer_unordered(CONCURRENCY)
+        .filter_map(|x| async move { x.ok() })
+        .collect::<Vec<_>>()
+        .await;
+    if conns.is_empty() {
+        return Err(PeerError::NoPeers.into());
+    }
+    let bitfields = conns.iter().map(|x| x.bitfield.clone()).collect::<Vec<_>>();
+    Ok(SwarmSample {
+        health: SwarmHealth::new(piece_count, candidates.len(), &bitfields),
+        conns: conns.into_iter().map(|x| Arc::new(Mutex::new(x))).collect(),
+    })
+}
+
+/// Connect to at most `sample` peers and gather their bitfields.
+///
+/// Peers failed to connect are ignored, only fails if none of them connected.
+pub async fn swarm_health(
+    torrent: &Torrent,
+    peers: &Peers,
+    sample: usize,
+) -> Result<SwarmHealth, BtError> {
+    sample_swarm(torrent, peers, sample).await.map(|x| x.health)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_swarm_health() {
+        let bitfields = [
+            Bitfield::from_bytes(vec![0b1100_0000], 3),
+            Bitfield::from_bytes(vec![0b1010_0000], 3),
+        ];
+        let health = SwarmHealth::new(3, 4, &bitfields);
+        assert_eq!(health.availability, [2, 1, 1]);
+        assert_eq!(health.histogram(), [0, 2, 1]);
+        assert!((health.distributed_copies() - (1.0 + 1.0 / 3.0)).abs() < 1e-9);
+        assert!(health.is_completable());
+        assert_eq!(health.most_available(), Some(0));
+
+        let health = SwarmHealth::new(3, 1, &bitfields[..1]);
+        assert_eq!(health.histogram(), [1, 2]);
+        assert!((health.distributed_copies() - 2.0 / 3.0).abs() < 1e-9);
+        assert!(!health.is_completable());
+
+        let health = SwarmHealth::new(3, 1, &[]);
+        assert_eq!(health.most_available(), None);
+    }
+}
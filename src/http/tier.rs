@@ -0,0 +1,344 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+
+use crate::{
+    torrent::Torrent,
+    utils::{BtError, TrackerError},
+};
+
+use super::{announce, Peer, PeerInfo};
+
+/// A tracker and how it behaved.
+#[derive(Debug, Clone)]
+struct TrackerState {
+    url: String,
+
+    /// Times failed to announce, reset when succeeded.
+    failures: usize,
+}
+
+/// A peer and the trackers reported it.
+#[derive(Debug, Clone)]
+pub struct TrackedPeer {
+    pub peer: Peer,
+
+    /// Urls of trackers reported the peer, in announce order.
+    pub trackers: Vec<String>,
+}
+
+/// Merged responses of all trackers.
+#[derive(Debug, Default)]
+pub struct AggregatedPeerInfo {
+    /// Distinct peers, in the order first reported.
+    pub peers: Vec<TrackedPeer>,
+
+    /// Distinct hex peer ids of WebRTC peers.
+    pub webrtc_peers: Vec<String>,
+
+    /// Max seeder count reported, trackers share peers so counts can not be summed.
+    pub complete: Option<usize>,
+
+    /// Max leecher count reported.
+    pub incomplete: Option<usize>,
+
+    /// Urls of trackers failed and the errors.
+    pub errors: Vec<(String, BtError)>,
+}
+
+impl AggregatedPeerInfo {
+    fn add(&mut self, url: &str, info: PeerInfo) {
+        let mut index = self
+            .peers
+            .iter()
+            .enumerate()
+            .map(|(idx, x)| ((x.peer.ip.clone(), x.peer.port), idx))
+            .collect::<HashMap<_, _>>();
+        for peer in info.peers.0 {
+            match index.get(&(peer.ip.clone(), peer.port)) {
+                Some(idx) => {
+                    let trackers = &mut self.peers[*idx].trackers;
+                    if !trackers.iter().any(|x| x == url) {
+                        trackers.push(url.to_string());
+                    }
+                }
+                None => {
+                    index.insert((peer.ip.clone(), peer.port), self.peers.len());
+                    self.peers.push(TrackedPeer {
+                        peer,
+                        trackers: vec![url.to_string()],
+                    });
+                }
+            }
+        }
+        for peer_id in info.webrtc_peers {
+            if !self.webrtc_peers.contains(&peer_id) {
+                self.webrtc_peers.push(peer_id);
+            }
+        }
+        self.complete = self.complete.max(info.complete);
+        self.incomplete = self.incomplete.max(info.incomplete);
+    }
+}
+
+/// Least time between announces if the tracker does not set `min interval`.
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Time to wait after all trackers failed, doubled for each later round failed in a row.
+const FAILURE_BACKOFF: Duration = Duration::from_secs(15);
+
+/// Time to wait after `failed_rounds` rounds of all trackers failed in a row, at most
+/// [`DEFAULT_MIN_INTERVAL`].
+fn failure_backoff(failed_rounds: usize) -> Duration {
+    FAILURE_BACKOFF
+        .saturating_mul(1 << failed_rounds.saturating_sub(1).min(16))
+        .min(DEFAULT_MIN_INTERVAL)
+}
+
+/// Least time between announces the tracker asked for in `info`.
+fn min_interval(info: &PeerInfo) -> Duration {
+    info.min_interval
+        .map_or(DEFAULT_MIN_INTERVAL, |x| Duration::from_secs(x as u64))
+}
+
+/// Trackers grouped in tiers, in BEP 12.
+///
+/// Trackers are tried tier by tier, in order within the tier. A tracker responded is moved to the
+/// front of its tier so later announces try it first.
+#[derive(Debug, Clone)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<TrackerState>>,
+
+    /// Bytes discarded for failed hash check, reported in announces.
+    corrupt: usize,
+
+    /// Time of the last announce, succeeded or not.
+    last_announce: Option<Instant>,
+
+    /// Least time between announces the last responded tracker asked for, or the backoff after
+    /// all trackers failed.
+    min_interval: Duration,
+
+    /// Rounds of all trackers failed in a row.
+    failed_rounds: usize,
+}
+
+impl TrackerTiers {
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        Self {
+            tiers: tiers
+                .into_iter()
+                .map(|tier| {
+                    tier.into_iter()
+                        .map(|url| TrackerState { url, failures: 0 })
+                        .collect::<Vec<_>>()
+                })
+                .filter(|tier| !tier.is_empty())
+                .collect(),
+            corrupt: 0,
+            last_announce: None,
+            min_interval: DEFAULT_MIN_INTERVAL,
+            failed_rounds: 0,
+        }
+    }
+
+    pub fn from_torrent(torrent: &Torrent) -> Self {
+        Self::new(torrent.tracker_tiers())
+    }
+
+    /// Count `bytes` discarded for failed hash check.
+    pub fn add_corrupt(&mut self, bytes: usize) {
+        self.corrupt += bytes;
+    }
+
+    /// Announcing again at `now` respects the min interval of the last responded tracker, or the
+    /// backoff if all trackers failed last time.
+    pub fn can_announce(&self, now: Instant) -> bool {
+        self.last_announce
+            .is_none_or(|x| now.duration_since(x) >= self.min_interval)
+    }
+
+    /// Earliest time announcing again is allowed, `None` if allowed already.
+    pub fn next_announce(&self) -> Option<Instant> {
+        self.last_announce.map(|x| x + self.min_interval)
+    }
+
+    /// Announce to the first tracker responded, return its response.
+    ///
+    /// Fail with the error of the last tracker tried if all trackers failed, then
+    /// [`TrackerTiers::can_announce`] backs off before the next round.
+    pub async fn announce(
+        &mut self,
+        info_hash: &[u8; 20],
+        uploaded: usize,
+        downloaded: usize,
+        left: usize,
+    ) -> Result<PeerInfo, BtError> {
+        let mut last_error = None;
+        for tier in self.tiers.iter_mut() {
+            for idx in 0..tier.len() {
+                let url = &tier[idx].url;
+                match announce(url, info_hash, uploaded, downloaded, left, self.corrupt)
+                    .await
+                    .map_err(BtError::tracker)
+                {
+                    Ok(v) => {
+                        self.last_announce = Some(Instant::now());
+                        self.min_interval = min_interval(&v);
+                        self.failed_rounds = 0;
+                        tier[idx].failures = 0;
+                        let tracker = tier.remove(idx);
+                        tier.insert(0, tracker);
+                        return Ok(v);
+                    }
+                    Err(e) => {
+                        eprintln!(">>> tracker {} failed: {e}", tier[idx].url);
+                        tier[idx].failures += 1;
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+        self.failed_rounds += 1;
+        self.last_announce = Some(Instant::now());
+        self.min_interval = failure_backoff(self.failed_rounds);
+        Err(last_error.unwrap_or_else(|| {
+            TrackerError::Request(anyhow!("no tracker available").into()).into()
+        }))
+    }
+
+    /// Announce to all trackers in all tiers concurrently and merge their responses.
+    ///
+    /// Fail with the error of the last tracker if all trackers failed.
+    pub async fn announce_all(
+        &self,
+        info_hash: &[u8; 20],
+        uploaded: usize,
+        downloaded: usize,
+        left: usize,
+    ) -> Result<AggregatedPeerInfo, BtError> {
+        let urls = self
+            .tiers
+            .iter()
+            .flatten()
+            .map(|x| x.url.as_str())
+            .collect::<Vec<_>>();
+        let results = futures::future::join_all(urls.iter().map(|url| async move {
+            announce(url, info_hash, uploaded, downloaded, left, self.corrupt)
+                .await
+                .map_err(BtError::tracker)
+        }))
+        .await;
+
+        let mut info = AggregatedPeerInfo::default();
+        let mut succeeded = false;
+        for (url, result) in urls.into_iter().zip(results) {
+            match result {
+                Ok(v) => {
+                    succeeded = true;
+                    info.add(url, v);
+                }
+                Err(e) => info.errors.push((url.to_string(), e)),
+            }
+        }
+        if !succeeded {
+            return Err(info.errors.pop().map(|(_, e)| e).unwrap_or_else(|| {
+                TrackerError::Request(anyhow!("no tracker available").into()).into()
+            }));
+        }
+        Ok(info)
+    }
+
+    /// Urls of trackers and their failure counts, tier by tier.
+    pub fn failures(&self) -> Vec<Vec<(&str, usize)>> {
+        self.tiers
+            .iter()
+            .map(|tier| tier.iter().map(|x| (x.url.as_str(), x.failures)).collect())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::{PeerSource, Peers};
+
+    fn peer_info(peers: &[(&str, u16)], complete: usize) -> PeerInfo {
+        PeerInfo {
+            interval: 0,
+            min_interval: None,
+            peers: Peers(
+                peers
+                    .iter()
+                    .map(|(ip, port)| Peer {
+                        ip: ip.to_string(),
+                        port: *port,
+                        source: PeerSource::Manual,
+                    })
+                    .collect(),
+            ),
+            webrtc_peers: vec![],
+            external_ip: None,
+            complete: Some(complete),
+            incomplete: None,
+        }
+    }
+
+    #[test]
+    fn test_aggregate_peers() {
+        let mut info = AggregatedPeerInfo::default();
+        info.add("a", peer_info(&[("1.1.1.1", 1), ("2.2.2.2", 2)], 3));
+        info.add("b", peer_info(&[("2.2.2.2", 2), ("3.3.3.3", 3)], 5));
+        let peers = info
+            .peers
+            .iter()
+            .map(|x| (x.peer.ip.as_str(), x.trackers.join(",")))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            peers,
+            [
+                ("1.1.1.1", "a".to_string()),
+                ("2.2.2.2", "a,b".to_string()),
+                ("3.3.3.3", "b".to_string()),
+            ]
+        );
+        assert_eq!(info.complete, Some(5));
+        assert_eq!(info.incomplete, None);
+    }
+
+    #[test]
+    fn test_can_announce() {
+        let mut tiers = TrackerTiers::new(vec![vec!["a".to_string()]]);
+        let now = Instant::now();
+        assert!(tiers.can_announce(now));
+        assert_eq!(tiers.next_announce(), None);
+
+        let mut info = peer_info(&[], 0);
+        info.min_interval = Some(60);
+        tiers.last_announce = Some(now);
+        tiers.min_interval = min_interval(&info);
+        assert!(!tiers.can_announce(now + Duration::from_secs(59)));
+        assert!(tiers.can_announce(now + Duration::from_secs(60)));
+        assert_eq!(tiers.next_announce(), Some(now + Duration::from_secs(60)));
+
+        info.min_interval = None;
+        assert_eq!(min_interval(&info), DEFAULT_MIN_INTERVAL);
+    }
+
+    #[tokio::test]
+    async fn test_backoff_on_failure() {
+        // Invalid urls fail without touching the network.
+        let mut tiers = TrackerTiers::new(vec![vec!["a".to_string()], vec!["b".to_string()]]);
+        assert!(tiers.announce(&[0; 20], 0, 0, 0).await.is_err());
+        let now = Instant::now();
+        assert!(!tiers.can_announce(now));
+        assert!(tiers.can_announce(now + FAILURE_BACKOFF));
+
+        assert!(tiers.announce(&[0; 20], 0, 0, 0).await.is_err());
+        assert_eq!(tiers.min_interval, FAILURE_BACKOFF * 2);
+        assert_eq!(failure_backoff(100), DEFAULT_MIN_INTERVAL);
+    }
+}
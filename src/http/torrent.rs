@@ -1,93 +1,123 @@
-use std::sync::{Arc, Mutex};
+use std::{sync::Arc, time::Duration};
 
 use anyhow::{bail, Context};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use futures::StreamExt;
+use tokio::{io::AsyncWriteExt, sync::Mutex};
 
-use crate::utils::{parallel_future, BtResult};
+use crate::{torrent::Torrent, utils::BtResult};
 
-use super::{HandshakeMessage, Peer, Peers, PieceMessage, PEER_ID};
+use super::{
+    dial_peer, extension::ExtensionHandshake, read_bitfield, read_exact_idle, ssl,
+    wire_dump::WireDump, HandshakeMessage, Peer, PeerConnection, Peers, PieceMessage,
+    HANDSHAKE_TIMEOUT, PEER_ID,
+};
 
 /// Setup connections with all available peers.
+///
+/// Peers failed to connect are returned apart, only fails if none of them connected.
 pub(super) async fn setup_connection(
     peers: &Peers,
-    info_hash: &[u8; 20],
-) -> BtResult<Vec<Arc<Mutex<TcpStream>>>> {
-    let conns = parallel_future(peers.iter(), 3, |peer| {
-        connect_peer(&peer, info_hash.clone())
-    })
-    .await
-    .context("failed to setup peer connections")?
-    .into_iter()
-    .map(|conn| Arc::new(Mutex::new(conn)))
-    .collect::<Vec<_>>();
-
-    Ok(conns)
+    torrent: &Torrent,
+) -> BtResult<(Vec<Arc<Mutex<PeerConnection>>>, Vec<Peer>)> {
+    // Peers are moved into the futures, borrowed ones can not be sent to spawned tasks.
+    let results = futures::stream::iter(peers.iter().cloned())
+        .map(|peer| async move {
+            let result = connect_peer(&peer, torrent).await;
+            (peer, result)
+        })
+        .buffered(3)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut conns = vec![];
+    let mut failed = vec![];
+    let mut last_error = None;
+    for (peer, result) in results {
+        match result {
+            Ok(conn) => conns.push(Arc::new(Mutex::new(conn))),
+            Err(e) => {
+                eprintln!(">>> failed to connect peer {peer}: {e:#}");
+                failed.push(peer);
+                last_error = Some(e);
+            }
+        }
+    }
+    if let (true, Some(e)) = (conns.is_empty(), last_error) {
+        return Err(e.context("failed to setup peer connections"));
+    }
+
+    Ok((conns, failed))
 }
 
 /// Connect a single peer.
-async fn connect_peer(peer: &Peer, info_hash: [u8; 20]) -> BtResult<TcpStream> {
+pub(super) async fn connect_peer(peer: &Peer, torrent: &Torrent) -> BtResult<PeerConnection> {
+    let info_hash = *torrent.info_hash();
+    let piece_count = torrent.info.piece_hashes.len();
+
     /* Handshake */
 
-    let message = HandshakeMessage::new(info_hash, PEER_ID.as_bytes().try_into().unwrap());
+    // The extension protocol tells upload only peers, which are seeds.
+    let message = HandshakeMessage::builder(info_hash, PEER_ID.as_bytes().try_into().unwrap())
+        .extension(true)
+        .build();
 
-    println!(">>> handshake: ip={}, port={}", peer.ip, peer.port);
+    eprintln!(">>> handshake: ip={}, port={}", peer.ip, peer.port);
     let handshake_message_bytes = message.to_bytes();
-    // println!(">>> handshake request: {:?}", handshake_message_bytes);
+    // eprintln!(">>> handshake request: {:?}", handshake_message_bytes);
 
-    let mut socket = TcpStream::connect(format!("{}:{}", peer.ip, peer.port).as_str())
-        .await
-        .context("failed to dial")?;
-    let (mut rd, mut wr) = socket.split();
-    if let Err(e) = wr.write_all(&handshake_message_bytes).await {
+    let addr = peer.to_string();
+    let mut socket = dial_peer(&addr, &info_hash).await?;
+    if let Some(ca) = torrent.ssl_cert() {
+        socket = ssl::connect(socket, ca, &info_hash).await?;
+    }
+    if let Err(e) = socket.write_all(&handshake_message_bytes).await {
         bail!("failed to send handshake message: {e}")
     }
 
-    // Tempoary buffer.
-    let mut buf = [0u8; 2048];
-
     let mut handshake_buf = vec![0u8; HandshakeMessage::length()];
-    rd.read_exact(&mut handshake_buf).await?;
-    // Here we ignore the handshake returned.
-    let _ = HandshakeMessage::from_bytes(&handshake_buf).context("invalid resp message format")?;
-
-    // println!(">>> wait for bitfield");
-
-    /* Wait for Bitfield */
-
-    let n = rd.read(&mut buf).await?;
-    if n == 0 {
-        bail!("empty bitfield message");
-    }
-
-    match PieceMessage::from_bytes(&buf[0..n])? {
-        PieceMessage::Bitfield => { /* Expected bitfield message */ }
-        v => bail!("invalid bitfield message: id={}", v.id()),
-    }
-
-    // println!(">>> send interested");
-
-    /* Send Interested */
-
-    wr.write(&PieceMessage::new_interested().to_bytes())
+    read_exact_idle(&mut socket, &mut handshake_buf, HANDSHAKE_TIMEOUT)
         .await
-        .context("failed to write interested message")?;
+        .context("failed to read handshake")?;
+    let handshake_resp =
+        HandshakeMessage::from_bytes(&handshake_buf).context("invalid resp message format")?;
+    // A peer of another swarm would send pieces failing every hash check.
+    handshake_resp.verify_info_hash(&info_hash)?;
+    handshake_resp.verify_peer_id(&message.peer_id)?;
 
-    // println!(">>> waiting unchoke");
+    // eprintln!(">>> wait for bitfield");
 
-    /* Wait for Unchoke */
+    /* Wait for Bitfield */
 
-    let n = rd.read(&mut buf).await?;
-    if n == 0 {
-        bail!(" empty unchoke message");
+    let mut dump = WireDump::open(&addr);
+    let (bitfield, first) = read_bitfield(&mut socket, Some(piece_count), dump.as_mut()).await?;
+
+    if handshake_resp.has_ext() {
+        // No extension messages are supported, the handshake only asks the peer for its own.
+        //
+        // We are downloading, not upload only.
+        let ours = ExtensionHandshake::new(&[], None, false);
+        PieceMessage::new_extension(&ours)
+            .write_to(&mut socket)
+            .await
+            .context("failed to send extension handshake")?;
     }
 
-    match PieceMessage::from_bytes(&buf[0..n])? {
-        PieceMessage::Unchoke => { /* Expected unchoke message */ }
-        v => bail!("invalid unchoke message: id={}", v.id()),
+    // Interested messages are sent later, only to peers having pieces we need.
+
+    let mut conn = PeerConnection {
+        addr,
+        source: peer.source.clone(),
+        socket,
+        bitfield,
+        interested: false,
+        choked: true,
+        downloaded: 0,
+        busy: Duration::ZERO,
+        rate: None,
+        dump,
+    };
+    if let Some(message) = first {
+        conn.track(&message);
     }
-
-    Ok(socket)
+    Ok(conn)
 }
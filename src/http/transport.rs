@@ -0,0 +1,22 @@
+use std::fmt::Debug;
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+/// A byte stream carrying the wire protocol with a peer.
+///
+/// Handshakes and messages are only read from and written to it, so the protocol works the same
+/// on any transport: plain TCP, proxied streams, or others like uTP once implemented.
+///
+/// Peers are known by the address they were dialed at, not the remote end of the stream, which is
+/// the proxy for proxied ones.
+pub trait PeerTransport: AsyncRead + AsyncWrite + Unpin + Send + Debug {}
+
+impl PeerTransport for TcpStream {}
+
+impl PeerTransport for Box<dyn PeerTransport> {}
+
+/// Connected stream of any transport.
+pub(super) type PeerStream = Box<dyn PeerTransport>;
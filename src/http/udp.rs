@@ -0,0 +1,221 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{bail, Context};
+use reqwest::Url;
+use tokio::net::UdpSocket;
+
+use crate::utils::{random_u64, BtResult, TrackerError};
+
+use super::{Peer, PeerInfo, PeerSource, Peers, PEER_ID, PORT};
+
+/// Magic constant identifying the protocol in connect requests.
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+
+/// Announce event "started".
+const EVENT_STARTED: u32 = 2;
+
+/// Option type of URLData in BEP 41, carrying path and query of the announce url.
+const OPTION_URL_DATA: u8 = 2;
+
+/// Option type marking the end of options.
+const OPTION_END: u8 = 0;
+
+/// Time to wait for each response.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Times to send a request before giving up, lost datagrams are common.
+const ATTEMPTS: usize = 3;
+
+/// Announce to a UDP tracker (`udp://`) in BEP 15.
+pub(super) async fn announce(
+    tracker_url: &str,
+    info_hash: &[u8; 20],
+    uploaded: usize,
+    downloaded: usize,
+    left: usize,
+) -> BtResult<PeerInfo> {
+    let url = Url::parse(tracker_url).context("invalid url")?;
+    let host = url.host_str().context("tracker host not found")?;
+    let port = url.port().context("tracker port not found")?;
+    // Prefer ipv4, fall back to ipv6 on v6-only networks.
+    let mut addrs = tokio::net::lookup_host((host, port))
+        .await
+        .context("failed to resolve tracker")?
+        .collect::<Vec<_>>();
+    addrs.sort_by_key(SocketAddr::is_ipv6);
+    let addr = *addrs.first().context("tracker address not found")?;
+
+    let bind = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind)
+        .await
+        .context("failed to bind udp socket")?;
+    socket
+        .connect(addr)
+        .await
+        .context("failed to connect tracker")?;
+
+    let tid = transaction_id();
+    let mut request = Vec::with_capacity(16);
+    request.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+    request.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+    request.extend_from_slice(&tid.to_be_bytes());
+    let resp = request_response(&socket, &request, tid, ACTION_CONNECT).await?;
+    let connection_id = u64::from_be_bytes(
+        resp.get(8..16)
+            .context("connect response too short")?
+            .try_into()
+            .unwrap(),
+    );
+
+    let tid = transaction_id();
+    let mut request = Vec::with_capacity(128);
+    request.extend_from_slice(&connection_id.to_be_bytes());
+    request.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+    request.extend_from_slice(&tid.to_be_bytes());
+    request.extend_from_slice(info_hash);
+    request.extend_from_slice(PEER_ID.as_bytes());
+    request.extend_from_slice(&(downloaded as u64).to_be_bytes());
+    request.extend_from_slice(&(left as u64).to_be_bytes());
+    request.extend_from_slice(&(uploaded as u64).to_be_bytes());
+    request.extend_from_slice(&EVENT_STARTED.to_be_bytes());
+    // Let tracker use the source address.
+    request.extend_from_slice(&0u32.to_be_bytes());
+    // Key.
+    request.extend_from_slice(&tid.to_be_bytes());
+    // Default count of peers wanted.
+    request.extend_from_slice(&(-1i32).to_be_bytes());
+    request.extend_from_slice(&PORT.parse::<u16>().unwrap().to_be_bytes());
+    request.extend(url_data_options(&url));
+    let resp = request_response(&socket, &request, tid, ACTION_ANNOUNCE).await?;
+
+    // |action|tid|interval|leechers|seeders|peers...|
+    if resp.len() < 20 {
+        bail!("announce response too short: length={}", resp.len())
+    }
+    let interval = u32::from_be_bytes(resp[8..12].try_into().unwrap());
+    let leechers = u32::from_be_bytes(resp[12..16].try_into().unwrap());
+    let seeders = u32::from_be_bytes(resp[16..20].try_into().unwrap());
+    // Trackers reply peers of the address family the announce is sent over.
+    let source = PeerSource::Tracker(tracker_url.to_string());
+    let peers = if addr.is_ipv4() {
+        resp[20..]
+            .chunks_exact(6)
+            .map(|x| Peer::from_compact(x.try_into().unwrap(), source.clone()))
+            .collect()
+    } else {
+        resp[20..]
+            .chunks_exact(18)
+            .map(|x| Peer::from_compact6(x.try_into().unwrap(), source.clone()))
+            .collect()
+    };
+    Ok(PeerInfo {
+        interval: interval as usize,
+        min_interval: None,
+        peers: Peers(peers),
+        webrtc_peers: vec![],
+        external_ip: None,
+        complete: Some(seeders as usize),
+        incomplete: Some(leechers as usize),
+    })
+}
+
+/// Send `request` and wait for the response of transaction `tid`, retry on timeout.
+///
+/// Returned response is validated to be `action`, starting with the action and transaction id.
+async fn request_response(
+    socket: &UdpSocket,
+    request: &[u8],
+    tid: u32,
+    action: u32,
+) -> BtResult<Vec<u8>> {
+    let mut buf = vec![0u8; 2048];
+    for _ in 0..ATTEMPTS {
+        socket
+            .send(request)
+            .await
+            .context("failed to send request")?;
+        let n = match tokio::time::timeout(TIMEOUT, socket.recv(&mut buf)).await {
+            Ok(v) => v.context("failed to receive response")?,
+            Err(_) => continue,
+        };
+        let resp = &buf[0..n];
+        if resp.len() < 8 || resp[4..8] != tid.to_be_bytes() {
+            continue;
+        }
+        let resp_action = u32::from_be_bytes(resp[0..4].try_into().unwrap());
+        if resp_action == ACTION_ERROR {
+            let message = String::from_utf8_lossy(&resp[8..]).to_string();
+            bail!(TrackerError::Failure(message));
+        }
+        if resp_action != action {
+            bail!("unexpected action {resp_action} in response, expected {action}");
+        }
+        return Ok(resp.to_vec());
+    }
+    bail!("tracker not responding")
+}
+
+/// BEP 41 options carrying the path and query of announce url, needed by trackers identifying
+/// users by passkeys in url.
+///
+/// The data is split into options at most 255 bytes each, followed by an end of options.
+fn url_data_options(url: &Url) -> Vec<u8> {
+    let mut data = url.path().to_string();
+    if let Some(query) = url.query() {
+        data.push('?');
+        data.push_str(query);
+    }
+    let mut options = vec![];
+    if !data.is_empty() && data != "/" {
+        for chunk in data.as_bytes().chunks(255) {
+            options.push(OPTION_URL_DATA);
+            options.push(chunk.len() as u8);
+            options.extend_from_slice(chunk);
+        }
+    }
+    options.push(OPTION_END);
+    options
+}
+
+/// A transaction id differs between requests.
+fn transaction_id() -> u32 {
+    random_u64() as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_url_data_options() {
+        let url = Url::parse("udp://tracker.example.com:80/announce?passkey=abc").unwrap();
+        assert_eq!(
+            url_data_options(&url),
+            b"\x02\x15/announce?passkey=abc\x00".to_vec()
+        );
+
+        let url =
+            Url::parse("udp://tracker.example.com:80/a1b2/announce?passkey=a%2Fb&uid=1").unwrap();
+        assert_eq!(
+            url_data_options(&url),
+            b"\x02\x22/a1b2/announce?passkey=a%2Fb&uid=1\x00".to_vec()
+        );
+
+        let url = Url::parse("udp://tracker.example.com:80").unwrap();
+        assert_eq!(url_data_options(&url), vec![OPTION_END]);
+
+        let url = Url::parse(&format!("udp://tracker.example.com:80/{}", "a".repeat(300))).unwrap();
+        let options = url_data_options(&url);
+        assert_eq!(&options[0..2], &[OPTION_URL_DATA, 255]);
+        assert_eq!(&options[257..259], &[OPTION_URL_DATA, 46]);
+        assert_eq!(options.last(), Some(&OPTION_END));
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use crate::{torrent::Torrent, utils::BtError};
+
+use super::check_hash;
+
+/// A piece of local file compared with the torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceState {
+    Good,
+
+    /// Hash does not match.
+    Corrupt,
+
+    /// File ends before the piece does.
+    Missing,
+}
+
+/// Compare file at `path` with piece hashes of `torrent`, return the state of each piece.
+///
+/// A file longer than the torrent is only compared up to the torrent length.
+pub fn check_file(torrent: &Torrent, path: &Path) -> Result<Vec<PieceState>, BtError> {
+    let mut file = File::open(path)?;
+    let mut states = vec![];
+    let mut buf = vec![];
+    for (index, expected) in torrent.info.piece_hashes.iter().enumerate() {
+        let length = torrent.piece_length(index).unwrap_or_default();
+        buf.resize(length, 0);
+        let state = if read_full(&mut file, &mut buf)? < length {
+            PieceState::Missing
+        } else if check_hash(&buf, expected).is_ok() {
+            PieceState::Good
+        } else {
+            PieceState::Corrupt
+        };
+        states.push(state);
+    }
+    Ok(states)
+}
+
+/// Find the content of `torrent` among files under `dir`, whatever the files are named.
+///
+/// Files of the torrent length are sampled by the first, middle and last piece, and the first one
+/// passing is checked in full. `None` if no file matches.
+pub fn find_content(torrent: &Torrent, dir: &Path) -> Result<Option<PathBuf>, BtError> {
+    let mut candidates = vec![];
+    collect_files(dir, torrent.length() as u64, &mut candidates)?;
+    candidates.sort();
+    let piece_count = torrent.info.piece_hashes.len();
+    let mut samples = vec![0, piece_count / 2, piece_count.saturating_sub(1)];
+    samples.dedup();
+    for path in candidates {
+        let mut file = File::open(&path)?;
+        let mut buf = vec![];
+        let mut sampled = true;
+        for index in samples.iter().copied().filter(|x| *x < piece_count) {
+            let length = torrent.piece_length(index).unwrap_or_default();
+            buf.resize(length, 0);
+            file.seek(SeekFrom::Start(
+                (index * torrent.nominal_piece_length()) as u64,
+            ))?;
+            if read_full(&mut file, &mut buf)? < length
+                || check_hash(&buf, &torrent.info.piece_hashes[index]).is_err()
+            {
+                sampled = false;
+                break;
+            }
+        }
+        if sampled
+            && check_file(torrent, &path)?
+                .iter()
+                .all(|x| *x == PieceState::Good)
+        {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Collect regular files of `length` bytes under `dir` into `files`, symlinks are not followed.
+fn collect_files(dir: &Path, length: u64, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), length, files)?;
+        } else if file_type.is_file() && entry.metadata()?.len() == length {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Fill `buf` from `rd` until it ends, return count of bytes read.
+fn read_full(rd: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match rd.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod test {
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+
+    fn test_torrent() -> Torrent {
+        let pieces = [&b"ab"[..], b"cd", b"e"]
+            .iter()
+            .flat_map(|x| <[u8; 20]>::from(Sha1::digest(x)))
+            .collect::<Vec<_>>();
+        let mut data =
+            b"d8:announce3:foo4:infod6:lengthi5e4:name1:a12:piece lengthi2e6:pieces60:".to_vec();
+        data.extend_from_slice(&pieces);
+        data.extend_from_slice(b"ee");
+        Torrent::parse_from_bytes(&data).unwrap()
+    }
+
+    #[test]
+    fn test_check_file() {
+        let torrent = test_torrent();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        std::fs::write(&path, b"abXd").unwrap();
+        assert_eq!(
+            check_file(&torrent, &path).unwrap(),
+            [PieceState::Good, PieceState::Corrupt, PieceState::Missing]
+        );
+        std::fs::write(&path, b"abcdef").unwrap();
+        assert_eq!(check_file(&torrent, &path).unwrap(), [PieceState::Good; 3]);
+    }
+
+    #[test]
+    fn test_find_content() {
+        let torrent = test_torrent();
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), b"abcdX").unwrap();
+        std::fs::write(dir.path().join("b"), b"abcdef").unwrap();
+        assert_eq!(find_content(&torrent, dir.path()).unwrap(), None);
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub/c"), b"abcde").unwrap();
+        assert_eq!(
+            find_content(&torrent, dir.path()).unwrap(),
+            Some(dir.path().join("sub/c"))
+        );
+    }
+}
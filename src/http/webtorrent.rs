@@ -0,0 +1,166 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use futures::{SinkExt, StreamExt};
+use serde_json::json;
+use tokio::time::Instant;
+use tokio_tungstenite::{connect_async_tls_with_config, tungstenite::Message, Connector};
+
+use crate::utils::{raw_bytes_to_string, string_to_raw_bytes, BtResult, TrackerError};
+
+use super::{tracker_tls, PeerInfo, Peers, PEER_ID};
+
+/// Time to collect offers the tracker forwards from other peers.
+const OFFER_WAIT_TIME: Duration = Duration::from_secs(3);
+
+/// Count of peers we want from tracker.
+const NUM_WANT: usize = 50;
+
+/// Announce to a WebTorrent tracker (`ws://` or `wss://`).
+///
+/// WebTorrent peers are only reachable by WebRTC, tracker forwards their offers to us instead of
+/// returning addresses. We never answer the offers, only record the peer ids so these peers can be
+/// reported.
+pub(super) async fn announce(
+    tracker_url: &str,
+    info_hash: &[u8; 20],
+    uploaded: usize,
+    downloaded: usize,
+    left: usize,
+) -> BtResult<PeerInfo> {
+    // Same certificate settings as https trackers.
+    let connector = tracker_tls().cloned().map(Connector::NativeTls);
+    let (mut ws, _) = connect_async_tls_with_config(tracker_url, None, false, connector)
+        .await
+        .context("failed to connect websocket tracker")?;
+
+    // Binary strings are sent as strings with one char per byte.
+    let info_hash = raw_bytes_to_string(info_hash);
+    let request = json!({
+        "action": "announce",
+        "info_hash": info_hash,
+        "peer_id": PEER_ID,
+        "numwant": NUM_WANT,
+        "uploaded": uploaded,
+        "downloaded": downloaded,
+        "left": left,
+        "event": "started",
+        "offers": [],
+    });
+    ws.send(Message::Text(request.to_string()))
+        .await
+        .context("failed to send announce message")?;
+
+    let mut interval = None;
+    let mut complete = None;
+    let mut incomplete = None;
+    let mut webrtc_peers = vec![];
+    let deadline = Instant::now() + OFFER_WAIT_TIME;
+    loop {
+        let message = match tokio::time::timeout_at(deadline, ws.next()).await {
+            Ok(Some(v)) => v.context("failed to read tracker message")?,
+            Ok(None) | Err(_) => break,
+        };
+        let text = match message {
+            Message::Text(v) => v,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let value: serde_json::Value =
+            serde_json::from_str(&text).context("invalid tracker message")?;
+        if let Some(reason) = value.get("failure reason").and_then(|x| x.as_str()) {
+            bail!(TrackerError::Failure(reason.to_string()));
+        }
+        if value.get("info_hash").and_then(|x| x.as_str()) != Some(info_hash.as_str()) {
+            continue;
+        }
+        if let Some(v) = value.get("interval").and_then(|x| x.as_u64()) {
+            interval = Some(v as usize);
+        }
+        if let Some(v) = value.get("complete").and_then(|x| x.as_u64()) {
+            complete = Some(v as usize);
+        }
+        if let Some(v) = value.get("incomplete").and_then(|x| x.as_u64()) {
+            incomplete = Some(v as usize);
+        }
+        if value.get("offer").is_some() {
+            if let Some(peer_id) = value.get("peer_id").and_then(|x| x.as_str()) {
+                let peer_id = hex::encode(string_to_raw_bytes(peer_id));
+                if !webrtc_peers.contains(&peer_id) {
+                    webrtc_peers.push(peer_id);
+                }
+            }
+        }
+    }
+    let _ = ws.close(None).await;
+
+    Ok(PeerInfo {
+        interval: interval.context("no announce response from tracker")?,
+        min_interval: None,
+        peers: Peers(vec![]),
+        webrtc_peers,
+        external_ip: None,
+        complete,
+        incomplete,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_announce() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/announce", listener.local_addr().unwrap());
+        let info_hash = [0xab; 20];
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+            let request = match ws.next().await.unwrap().unwrap() {
+                Message::Text(v) => serde_json::from_str::<serde_json::Value>(&v).unwrap(),
+                v => panic!("unexpected message {v:?}"),
+            };
+            assert_eq!(request["action"], "announce");
+            let info_hash = request["info_hash"].clone();
+            let replies = [
+                json!({ "info_hash": info_hash, "interval": 120, "complete": 2, "incomplete": 1 }),
+                // Offers of other torrents are ignored.
+                json!({ "info_hash": "x", "offer": {}, "peer_id": "b".repeat(20) }),
+                json!({ "info_hash": info_hash, "offer": {}, "peer_id": "a".repeat(20) }),
+                json!({ "info_hash": info_hash, "offer": {}, "peer_id": "a".repeat(20) }),
+            ];
+            for reply in replies {
+                ws.send(Message::Text(reply.to_string())).await.unwrap();
+            }
+            ws.close(None).await.unwrap();
+        });
+
+        let info = announce(&url, &info_hash, 0, 0, 1).await.unwrap();
+        server.await.unwrap();
+        assert_eq!(info.interval, 120);
+        assert_eq!((info.complete, info.incomplete), (Some(2), Some(1)));
+        assert!(info.peers.is_empty());
+        assert_eq!(info.webrtc_peers, vec!["61".repeat(20)]);
+    }
+
+    #[tokio::test]
+    async fn test_announce_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/announce", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+            ws.next().await;
+            let reply = json!({ "failure reason": "unregistered torrent" });
+            ws.send(Message::Text(reply.to_string())).await.unwrap();
+        });
+        let err = announce(&url, &[0; 20], 0, 0, 1).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TrackerError>(),
+            Some(TrackerError::Failure(v)) if v == "unregistered torrent"
+        ));
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::OnceLock,
+};
+
+use crate::utils::BtError;
+
+use super::piece_message::PieceMessage;
+
+/// Directory to dump peer wire traffic in, dumping is disabled if not set.
+static DUMP_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Dump messages received from each peer to `dir`, must be called before connecting peers.
+///
+/// Each peer has a file named by its address, holding messages as they are on the wire: 4 bytes
+/// length prefix followed by the payload. Handshakes and keep-alive messages are not included.
+pub fn set_wire_dump_dir(dir: PathBuf) -> Result<(), BtError> {
+    std::fs::create_dir_all(&dir)?;
+    DUMP_DIR.set(dir).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "wire dump dir already set",
+        )
+    })?;
+    Ok(())
+}
+
+/// Dump file of a peer connection.
+#[derive(Debug)]
+pub(super) struct WireDump(File);
+
+impl WireDump {
+    /// Start dumping messages from peer `addr` "host:port", `None` if dumping is disabled or
+    /// failed.
+    ///
+    /// Messages of reconnected peers are appended to the same file.
+    pub(super) fn open(addr: &str) -> Option<Self> {
+        let dir = DUMP_DIR.get()?;
+        let (host, port) = addr.rsplit_once(':')?;
+        let path = dir.join(format!("{}_{port}.wire", host.trim_matches(['[', ']'])));
+        match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(v) => Some(Self(v)),
+            Err(e) => {
+                eprintln!(">>> failed to open wire dump {}: {e}", path.display());
+                None
+            }
+        }
+    }
+
+    /// Append a whole `frame` including its length prefix.
+    pub(super) fn record(&mut self, frame: &[u8]) {
+        let _ = self.0.write_all(frame);
+    }
+}
+
+/// A message in wire dump.
+#[derive(Debug)]
+pub struct ReplayEntry {
+    /// Offset of the length prefix in dump.
+    pub offset: usize,
+
+    /// Length of payload declared in the length prefix.
+    pub length: usize,
+
+    /// The parsed message in debug format, or why it failed to parse.
+    pub message: Result<String, BtError>,
+}
+
+/// Parse all messages in a wire dump again, to reproduce parsing bugs.
+///
+/// A message truncated at the end of dump fails to parse.
+pub fn replay(data: &[u8]) -> Vec<ReplayEntry> {
+    let mut entries = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let length = data
+            .get(offset..offset + 4)
+            .map(|x| u32::from_be_bytes(x.try_into().unwrap()) as usize)
+            .unwrap_or(0);
+        let end = (offset + 4)
+            .saturating_add(length)
+            .min(data.len())
+            .max(offset + 1);
+        let message = PieceMessage::from_bytes(&data[offset..end])
+            .map(|x| format!("{x:?}"))
+            .map_err(BtError::peer);
+        entries.push(ReplayEntry {
+            offset,
+            length,
+            message,
+        });
+        offset = end;
+    }
+    entries
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_replay() {
+        let data = [
+            &[0, 0, 0, 5, 4, 0, 0, 0, 3][..],
+            &[0, 0, 0, 1, 42],
+            &[0, 0, 0, 9, 6, 0, 0],
+        ]
+        .concat();
+        let entries = replay(&data);
+        assert_eq!(
+            entries.iter().map(|x| x.offset).collect::<Vec<_>>(),
+            [0, 9, 14]
+        );
+        assert_eq!(entries[0].message.as_deref().unwrap(), "Have { index: 3 }");
+        assert!(entries[1].message.is_err());
+        assert!(entries[2].message.is_err());
+        assert_eq!(entries[2].length, 9);
+    }
+}
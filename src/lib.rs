@@ -0,0 +1,18 @@
+//! BitTorrent client library, the `codecrafters-bittorrent` binary is built on it.
+//!
+//! * Parse torrent files with [`torrent::Torrent::parse_from_bytes`].
+//! * Parse magnet links with [`magnet::Magnet`]'s `FromStr` implementation.
+//! * Download from either of them with [`engine::Engine`].
+
+pub mod bench;
+pub mod decode;
+pub mod dht;
+pub mod encode;
+pub mod engine;
+#[cfg(feature = "geoip")]
+pub mod geoip;
+pub mod http;
+pub mod lint;
+pub mod magnet;
+pub mod torrent;
+pub mod utils;
@@ -0,0 +1,265 @@
+//! Check structure of torrent files beyond what parsing requires, for `lint` command.
+//!
+//! Each problem found is reported as a [`Warning`] with the offset of the related value in file.
+
+use std::fmt::Display;
+
+use crate::decode::{node_from_bytes, Node};
+
+/// Piece lengths out of this range are unusual.
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+
+/// A problem found in torrent file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    /// Offset in file of the value having the problem.
+    pub offset: usize,
+
+    pub message: String,
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:>8}: {}", self.offset, self.message)
+    }
+}
+
+/// Warn on dict keys not sorted or duplicated, in all nested dicts.
+fn check_sorted(node: &Node, offset: usize, warnings: &mut Vec<Warning>) {
+    match node {
+        Node::List(values) => {
+            for (offset, v) in values {
+                check_sorted(v, *offset, warnings);
+            }
+        }
+        Node::Dict(entries) => {
+            for pair in entries.windows(2) {
+                let (prev, next) = (&pair[0].0, &pair[1].0);
+                if prev >= next {
+                    warnings.push(Warning {
+                        offset,
+                        message: format!(
+                            "key {:?} {} {:?}, keys should be sorted",
+                            String::from_utf8_lossy(next),
+                            if prev == next { "duplicates" } else { "after" },
+                            String::from_utf8_lossy(prev),
+                        ),
+                    });
+                }
+            }
+            for (_, offset, v) in entries {
+                check_sorted(v, *offset, warnings);
+            }
+        }
+        Node::Int(_) | Node::Bytes(_) => {}
+    }
+}
+
+/// Warn on path components escaping the download directory.
+fn check_path(component: &[u8], offset: usize, warnings: &mut Vec<Warning>) {
+    let s = String::from_utf8_lossy(component);
+    if s.is_empty() || s == "." || s == ".." || s.contains(['/', '\\']) || s.contains('\0') {
+        warnings.push(Warning {
+            offset,
+            message: format!("unsafe path component {s:?}"),
+        });
+    }
+}
+
+fn missing(offset: usize, name: &str) -> Warning {
+    Warning {
+        offset,
+        message: format!("required field {name} not found"),
+    }
+}
+
+/// Check bencoded torrent file `data`, return all problems found.
+pub fn lint(data: &[u8]) -> Vec<Warning> {
+    let root = match node_from_bytes(data) {
+        Ok(v) => v,
+        Err(e) => {
+            return vec![Warning {
+                offset: 0,
+                message: format!("malformed bencode: {e}"),
+            }]
+        }
+    };
+
+    let mut warnings = vec![];
+    check_sorted(&root, 0, &mut warnings);
+    if root.get("announce").is_none() && root.get("announce-list").is_none() {
+        warnings.push(missing(0, "announce"));
+    }
+    let Some((info_offset, info)) = root.get("info") else {
+        warnings.push(missing(0, "info"));
+        return warnings;
+    };
+    if !matches!(info, Node::Dict(_)) {
+        warnings.push(Warning {
+            offset: info_offset,
+            message: "info is not a dict".to_string(),
+        });
+        return warnings;
+    }
+
+    for name in ["name", "piece length"] {
+        if info.get(name).is_none() {
+            warnings.push(missing(info_offset, &format!("info.{name}")));
+        }
+    }
+    let v2 = info.get("meta version").and_then(|(_, v)| v.as_int()) == Some(2);
+    let pieces = info.get("pieces");
+    if pieces.is_none() && !v2 {
+        warnings.push(missing(info_offset, "info.pieces"));
+    }
+
+    // Total length of all files.
+    let mut length = None;
+    match (info.get("length"), info.get("files")) {
+        (Some(_), Some((offset, _))) => warnings.push(Warning {
+            offset,
+            message: "both info.length and info.files found".to_string(),
+        }),
+        (Some((_, v)), None) => length = v.as_int(),
+        (None, Some((_, Node::List(files)))) => {
+            let mut total = Some(0isize);
+            for (offset, file) in files {
+                let file_length = file
+                    .get("length")
+                    .and_then(|(_, v)| v.as_int())
+                    .unwrap_or(0);
+                let sum = total.and_then(|x| x.checked_add(file_length));
+                if total.is_some() && sum.is_none() {
+                    warnings.push(Warning {
+                        offset: *offset,
+                        message: "total length of files overflows".to_string(),
+                    });
+                }
+                total = sum;
+                match file.get("path") {
+                    Some((_, Node::List(components))) if !components.is_empty() => {
+                        for (offset, c) in components {
+                            check_path(c.as_bytes().unwrap_or_default(), *offset, &mut warnings);
+                        }
+                    }
+                    _ => warnings.push(Warning {
+                        offset: *offset,
+                        message: "file without path".to_string(),
+                    }),
+                }
+            }
+            length = total;
+        }
+        (None, _) if v2 => {}
+        (None, _) => warnings.push(missing(info_offset, "info.length or info.files")),
+    }
+    if let Some((offset, Node::Bytes(name))) = info.get("name") {
+        check_path(name, offset, &mut warnings);
+    }
+
+    let piece_length = info.get("piece length").and_then(|(offset, v)| {
+        let v = v.as_int().filter(|x| *x > 0);
+        if v.is_none() {
+            warnings.push(Warning {
+                offset,
+                message: "piece length is not a positive integer".to_string(),
+            });
+        }
+        v.map(|x| (offset, x as usize))
+    });
+    if let Some((offset, piece_length)) = piece_length {
+        if !piece_length.is_power_of_two() {
+            warnings.push(Warning {
+                offset,
+                message: format!("piece length {piece_length} is not a power of 2"),
+            });
+        }
+        if !(MIN_PIECE_LENGTH..=MAX_PIECE_LENGTH).contains(&piece_length) {
+            warnings.push(Warning {
+                offset,
+                message: format!(
+                    "piece length {piece_length} out of usual range {MIN_PIECE_LENGTH}..={MAX_PIECE_LENGTH}"
+                ),
+            });
+        }
+    }
+
+    if let Some((offset, pieces)) = pieces {
+        let pieces = pieces.as_bytes().unwrap_or_default();
+        if pieces.len() % 20 != 0 {
+            warnings.push(Warning {
+                offset,
+                message: format!("pieces length {} is not a multiple of 20", pieces.len()),
+            });
+        } else if let (Some(length), Some((_, piece_length))) = (length, piece_length) {
+            let expected = (length.max(0) as usize).div_ceil(piece_length);
+            if pieces.len() / 20 != expected {
+                warnings.push(Warning {
+                    offset,
+                    message: format!(
+                        "{} piece hashes, expected {expected} for length {length}",
+                        pieces.len() / 20
+                    ),
+                });
+            }
+        }
+    }
+
+    // Hybrid torrents have both v1 and v2 fields, which should be complete on each side.
+    if v2 {
+        if info.get("file tree").is_none() {
+            warnings.push(missing(info_offset, "info.file tree"));
+        }
+        if root.get("piece layers").is_none() {
+            warnings.push(missing(0, "piece layers"));
+        }
+    } else if let Some((offset, _)) = info.get("file tree") {
+        warnings.push(Warning {
+            offset,
+            message: "info.file tree found without meta version 2".to_string(),
+        });
+    }
+
+    warnings
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lint() {
+        assert!(lint(include_bytes!("../sample.torrent")).is_empty());
+
+        let data = b"d8:announce3:foo4:infod4:name2:..6:lengthi3e12:piece lengthi1000e6:pieces40:0000000000000000000000000000000000000000ee";
+        let warnings = lint(data);
+        let messages = warnings
+            .iter()
+            .map(|x| x.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                "key \"length\" after \"name\", keys should be sorted",
+                "unsafe path component \"..\"",
+                "piece length 1000 is not a power of 2",
+                "piece length 1000 out of usual range 16384..=16777216",
+                "2 piece hashes, expected 1 for length 3",
+            ]
+        );
+        assert_eq!(warnings[0].offset, 22);
+        assert_eq!(warnings[1].offset, 29);
+
+        assert_eq!(lint(b"d4:info").len(), 1);
+
+        let data = format!(
+            "d4:infod5:filesld6:lengthi{0}eed6:lengthi{0}eee4:name1:a12:piece lengthi16384e6:pieces0:ee",
+            isize::MAX
+        );
+        let warnings = lint(data.as_bytes());
+        assert!(warnings
+            .iter()
+            .any(|x| x.message == "total length of files overflows"));
+    }
+}
@@ -1,6 +1,14 @@
-use anyhow::{bail, Context};
+use std::{fmt::Display, str::FromStr};
 
-#[derive(Debug)]
+use crate::utils::ParseError;
+
+/// Prefix of magnet links we support, followed by the hex encoded info hash.
+const MAGNET_PREFIX: &str = "magnet:?xt=urn:btih:";
+
+/// Parameters in BEP 9 and common extensions we keep but do not use.
+const KNOWN_PARAMS: &[&str] = &["x.pe", "ws", "xs", "as", "kt", "xl", "so"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Magnet {
     /// Hash of the info dictionary.
     pub info_hash: [u8; 20],
@@ -8,54 +16,159 @@ pub struct Magnet {
     /// Optional downloaded file name.
     pub download_name: Option<String>,
 
-    /// Optional tracker url.
-    pub tracker_url: Option<String>,
+    /// All distinct trackers in order.
+    pub trackers: Vec<String>,
+
+    /// Other parameters in order, like peer addresses and web seeds.
+    pub params: Vec<(String, String)>,
+
+    /// Problems found when parsing, see [`Magnet::validate`].
+    warnings: Vec<String>,
 }
 
 impl Magnet {
-    pub fn new(magnet_str: &str) -> anyhow::Result<Self> {
-        if !magnet_str.starts_with("magnet:?xt=urn:btih:") || magnet_str.len() < 20 + 40 {
-            bail!("invalid prefix or too short")
+    pub fn new(magnet_str: &str) -> Result<Self, ParseError> {
+        magnet_str.parse()
+    }
+
+    /// Problems in the parsed link not preventing its use: info hash not in lowercase, duplicate
+    /// names or trackers, which are dropped, and unknown parameters, which are kept.
+    pub fn validate(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// The canonical magnet link, same as formatting with [`Display`].
+    pub fn normalize(&self) -> String {
+        self.to_string()
+    }
+
+    /// The first tracker, if any.
+    pub fn tracker_url(&self) -> Option<&str> {
+        self.trackers.first().map(String::as_str)
+    }
+
+    pub fn print_info(&self) {
+        if let Some(url) = self.tracker_url() {
+            println!("Tracker URL: {}", url);
         }
+        println!("Info Hash: {}", hex::encode(self.info_hash));
+    }
+}
+
+impl FromStr for Magnet {
+    type Err = ParseError;
+
+    fn from_str(magnet_str: &str) -> Result<Self, Self::Err> {
+        let magnet_str = magnet_str
+            .strip_prefix(MAGNET_PREFIX)
+            .ok_or(ParseError::InvalidMagnetPrefix)?;
 
         let mut download_name = None;
-        let mut tracker_url = None;
+        let mut trackers = vec![];
+        let mut params = vec![];
+        let mut warnings = vec![];
 
-        let (_, magnet_str) = magnet_str.split_at(20);
-        let (info_hash, magnet_str) = magnet_str.split_at(40);
-        let info_hash = hex::decode(info_hash)
-            .context("invalid info hash hex code")?
-            .try_into()
-            .unwrap();
-        if magnet_str.is_empty() {
-            return Ok(Self {
-                info_hash,
-                download_name,
-                tracker_url,
-            });
+        let (info_hash, magnet_str) =
+            magnet_str.split_at(magnet_str.find('&').unwrap_or(magnet_str.len()));
+        if info_hash.chars().any(|x| x.is_ascii_uppercase()) {
+            warnings.push("info hash is not in lowercase".to_string());
         }
+        let info_hash = hex::decode(info_hash)
+            .ok()
+            .and_then(|x| x.try_into().ok())
+            .ok_or_else(|| ParseError::InvalidInfoHash(info_hash.to_string()))?;
 
         let segments = serde_urlencoded::from_str::<Vec<(String, String)>>(magnet_str)
-            .context("invalid magnet str segments")?;
+            .map_err(ParseError::InvalidMagnetParams)?;
         for (name, value) in segments {
             match name.as_str() {
-                "dn" => download_name = Some(value),
-                "tr" => tracker_url = Some(value),
-                _ => continue,
+                "dn" if download_name.is_none() => download_name = Some(value),
+                "dn" => warnings.push(format!("duplicate dn {value:?} dropped")),
+                "tr" if trackers.contains(&value) => {
+                    warnings.push(format!("duplicate tr {value:?} dropped"))
+                }
+                "tr" => trackers.push(value),
+                _ => {
+                    if !KNOWN_PARAMS.contains(&name.as_str()) {
+                        warnings.push(format!("unknown parameter {name}"));
+                    }
+                    params.push((name, value));
+                }
             }
         }
 
         Ok(Self {
             info_hash,
             download_name,
-            tracker_url,
+            trackers,
+            params,
+            warnings,
         })
     }
+}
 
-    pub fn print_info(&self) {
-        if let Some(url) = &self.tracker_url {
-            println!("Tracker URL: {}", url);
+impl Display for Magnet {
+    /// Format as the canonical magnet link: info hash in lowercase hex, then name, trackers and other
+    /// parameters in order.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{MAGNET_PREFIX}{}", hex::encode(self.info_hash))?;
+        let params = self
+            .download_name
+            .iter()
+            .map(|x| ("dn", x.as_str()))
+            .chain(self.trackers.iter().map(|x| ("tr", x.as_str())))
+            .chain(self.params.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+            .collect::<Vec<_>>();
+        if !params.is_empty() {
+            let params = serde_urlencoded::to_string(params).map_err(|_| std::fmt::Error)?;
+            write!(f, "&{params}")?;
         }
-        println!("Info Hash: {}", hex::encode(self.info_hash));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_magnet_round_trip() {
+        let s = "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&dn=magnet1.gif&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce";
+        let magnet = s.parse::<Magnet>().unwrap();
+        assert_eq!(magnet.download_name.as_deref(), Some("magnet1.gif"));
+        assert_eq!(
+            magnet.tracker_url(),
+            Some("http://bittorrent-test-tracker.codecrafters.io/announce")
+        );
+        assert_eq!(magnet.to_string(), s);
+        assert_eq!(magnet.to_string().parse::<Magnet>().unwrap(), magnet);
+
+        assert!(magnet.validate().is_empty());
+
+        let magnet = "magnet:?xt=urn:btih:AD42CE8109F54C99613CE38F9B4D87E70F24A165&tr=http%3a%2f%2fa%2fannounce&dn=a+b&x.pe=1.2.3.4%3A5&tr=http://a/announce&foo=1&tr=udp://b:80&ws=http%3A%2F%2Fc%2Fa"
+            .parse::<Magnet>()
+            .unwrap();
+        assert_eq!(
+            magnet.normalize(),
+            "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&dn=a+b&tr=http%3A%2F%2Fa%2Fannounce&tr=udp%3A%2F%2Fb%3A80&x.pe=1.2.3.4%3A5&foo=1&ws=http%3A%2F%2Fc%2Fa"
+        );
+        assert_eq!(magnet.tracker_url(), Some("http://a/announce"));
+        assert_eq!(
+            magnet.validate(),
+            [
+                "info hash is not in lowercase",
+                "duplicate tr \"http://a/announce\" dropped",
+                "unknown parameter foo",
+            ]
+        );
+
+        assert!(matches!(
+            "magnet:?xt=urn:btih:zz".parse::<Magnet>(),
+            Err(ParseError::InvalidInfoHash(_))
+        ));
+        assert!(matches!(
+            "http://example.com".parse::<Magnet>(),
+            Err(ParseError::InvalidMagnetPrefix)
+        ));
     }
 }
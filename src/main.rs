@@ -1,29 +1,408 @@
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{IsTerminal, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Range,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
 use anyhow::Context;
-use clap::{Args, Parser, Subcommand};
-use regex::Regex;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::signal::unix::{signal, SignalKind};
 
-use crate::{
-    decode::{decode_bencoded_value, DecodeContext},
+use codecrafters_bittorrent::{
+    bench,
+    decode::{decode_bencoded_value, display_value, DecodeContext},
+    dht,
+    engine::{Engine, Source},
     http::{
-        discover_peer, download_file, download_piece, handshake, magnet_handshake,
-        HandshakeMessage, PEER_ID,
+        check_file, configure_tracker_tls, create_dir_all, find_content, handshake, link_file,
+        magnet_handshake, replay, sample_swarm, scrape_tracker, set_announce_ipv6, set_file_modes,
+        set_max_dials, set_socket_options, set_ssl_identity, set_wire_dump_dir, swarm_health,
+        DiskBackend, DownloadEvent, DownloadHandle, DownloadOptions, FileModes, FsyncPolicy,
+        HandshakeMessage, Peer, PeerSource, Peers, PieceState, SocketOptions, Stats, TrackedPeer,
+        TrackerTiers, TransferLog, WriteCacheOptions, PEER_ID,
     },
+    lint,
     magnet::Magnet,
     torrent::Torrent,
-    utils::BtResult,
+    utils::{is_host_name, set_random_seed},
 };
 
-mod decode;
-mod encode;
-mod http;
-mod magnet;
-mod torrent;
-mod utils;
-
 #[derive(Debug, Clone, Parser)]
 struct Cli {
     #[command(subcommand)]
     pub command: Command,
+
+    #[arg(
+        long = "tracker-ca",
+        global = true,
+        help = "pem file of extra root certificate trusted by https and wss trackers"
+    )]
+    pub tracker_ca: Option<PathBuf>,
+
+    #[arg(
+        long = "tracker-insecure",
+        global = true,
+        help = "do not verify certificates of https and wss trackers"
+    )]
+    pub tracker_insecure: bool,
+
+    #[arg(
+        long = "seed",
+        global = true,
+        help = "seed of randomized behaviors like DHT node id, to reproduce a run"
+    )]
+    pub seed: Option<u64>,
+
+    #[arg(
+        long = "output-dir",
+        global = true,
+        help = "directory relative output paths are resolved against"
+    )]
+    pub output_dir: Option<PathBuf>,
+
+    #[arg(
+        long = "incomplete-dir",
+        global = true,
+        help = "directory downloads are written to until completed, then moved to the output path"
+    )]
+    pub incomplete_dir: Option<PathBuf>,
+
+    #[arg(
+        long = "config",
+        global = true,
+        help = "toml file of default global options, options given in command line take precedence"
+    )]
+    pub config: Option<PathBuf>,
+
+    #[arg(
+        long = "log-file",
+        global = true,
+        help = "append download events and errors to this file"
+    )]
+    pub log_file: Option<PathBuf>,
+
+    #[arg(
+        long = "wire-dump",
+        global = true,
+        help = "dump messages received from each peer to files in this directory, see replay"
+    )]
+    pub wire_dump: Option<PathBuf>,
+
+    #[arg(
+        long = "max-dials",
+        global = true,
+        help = "max count of peer connection attempts at the same time [default: 32]"
+    )]
+    pub max_dials: Option<usize>,
+
+    #[arg(
+        long = "send-buffer",
+        global = true,
+        help = "send buffer size of peer sockets in bytes [default: system]"
+    )]
+    pub send_buffer: Option<u32>,
+
+    #[arg(
+        long = "recv-buffer",
+        global = true,
+        help = "receive buffer size of peer sockets in bytes, raise on high latency links [default: system]"
+    )]
+    pub recv_buffer: Option<u32>,
+
+    #[arg(
+        long = "connect-retries",
+        global = true,
+        help = "times to retry connecting a peer [default: 0]"
+    )]
+    pub connect_retries: Option<usize>,
+
+    #[arg(
+        long = "connect-backoff",
+        global = true,
+        help = "seconds to wait before the first connect retry, doubled for later ones [default: 1]"
+    )]
+    pub connect_backoff: Option<f64>,
+
+    #[arg(
+        long = "connect-timeout",
+        global = true,
+        help = "seconds to wait for a peer connection to be established [default: 10]"
+    )]
+    pub connect_timeout: Option<f64>,
+
+    #[arg(
+        long = "proxy",
+        global = true,
+        help = "SOCKS5 proxy to connect peers and http trackers through, like Tor at 127.0.0.1:9050, also disables DHT, udp and websocket trackers"
+    )]
+    pub proxy: Option<SocketAddr>,
+
+    #[arg(
+        long = "ssl-cert",
+        global = true,
+        requires = "ssl_key",
+        help = "pem certificate presented to peers of ssl torrents, signed by the authority in torrent"
+    )]
+    pub ssl_cert: Option<PathBuf>,
+
+    #[arg(
+        long = "ssl-key",
+        global = true,
+        requires = "ssl_cert",
+        help = "pem pkcs8 private key of --ssl-cert"
+    )]
+    pub ssl_key: Option<PathBuf>,
+
+    #[arg(
+        long = "cache-size",
+        global = true,
+        help = "MiB of verified pieces held before writing to file, enables writing while downloading [default: 16]"
+    )]
+    pub cache_size: Option<usize>,
+
+    #[arg(
+        long = "flush-interval",
+        global = true,
+        help = "max seconds verified pieces are held before writing to file, enables writing while downloading [default: 10]"
+    )]
+    pub flush_interval: Option<u64>,
+
+    #[arg(
+        long = "fsync",
+        global = true,
+        help = "when to sync written data: \"never\", \"on-complete\" or \"each-flush\", enables writing while downloading [default: on-complete]"
+    )]
+    pub fsync: Option<FsyncPolicy>,
+
+    #[arg(
+        long = "announce-ipv6",
+        global = true,
+        help = "our ipv6 address sent to http trackers, for peers on ipv6 networks"
+    )]
+    pub announce_ipv6: Option<Ipv6Addr>,
+
+    #[arg(
+        long = "file-mode",
+        global = true,
+        value_parser = parse_mode,
+        help = "octal mode bits of downloaded files, execute bits are added for executable files in torrent [default: umask]"
+    )]
+    pub file_mode: Option<u32>,
+
+    #[arg(
+        long = "dir-mode",
+        global = true,
+        value_parser = parse_mode,
+        help = "octal mode bits of created directories [default: umask]"
+    )]
+    pub dir_mode: Option<u32>,
+}
+
+/// Global options read from the `--config` file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+struct Config {
+    output_dir: Option<PathBuf>,
+    incomplete_dir: Option<PathBuf>,
+    log_file: Option<PathBuf>,
+    tracker_ca: Option<PathBuf>,
+    tracker_insecure: bool,
+    seed: Option<u64>,
+    max_dials: Option<usize>,
+    send_buffer: Option<u32>,
+    recv_buffer: Option<u32>,
+    connect_retries: Option<usize>,
+    connect_backoff: Option<f64>,
+    connect_timeout: Option<f64>,
+    proxy: Option<SocketAddr>,
+    ssl_cert: Option<PathBuf>,
+    ssl_key: Option<PathBuf>,
+    cache_size: Option<usize>,
+    flush_interval: Option<u64>,
+    fsync: Option<FsyncPolicy>,
+    announce_ipv6: Option<Ipv6Addr>,
+    file_mode: Option<String>,
+    dir_mode: Option<String>,
+    on_complete: Option<String>,
+}
+
+impl Config {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("invalid config file {}", path.display()))
+    }
+}
+
+/// State shared by all commands, built once from global options and the config file.
+struct AppContext {
+    output_dir: Option<PathBuf>,
+
+    /// Directory downloads live in until completed.
+    incomplete_dir: Option<PathBuf>,
+
+    log: Option<Mutex<File>>,
+
+    /// Write cache of downloads, `None` to write the whole file when completed.
+    write_cache: Option<WriteCacheOptions>,
+
+    /// Command to run after each download completed, overridden by `--on-complete`.
+    on_complete: Option<String>,
+}
+
+impl AppContext {
+    fn new(cli: &Cli) -> anyhow::Result<Self> {
+        let config = match &cli.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        if let Some(seed) = cli.seed.or(config.seed) {
+            set_random_seed(seed);
+        }
+        let proxy = cli.proxy.or(config.proxy);
+        // Only announced when asked, nothing listens for peers to connect to it yet.
+        if let Some(ip) = cli.announce_ipv6.or(config.announce_ipv6) {
+            set_announce_ipv6(ip).context("failed to set announce ipv6")?;
+        }
+        if let Some(max) = cli.max_dials.or(config.max_dials) {
+            set_max_dials(max).context("failed to set max dials")?;
+        }
+        let mut socket_options = SocketOptions {
+            send_buffer_size: cli.send_buffer.or(config.send_buffer),
+            recv_buffer_size: cli.recv_buffer.or(config.recv_buffer),
+            proxy,
+            ..Default::default()
+        };
+        if let Some(v) = cli.connect_retries.or(config.connect_retries) {
+            socket_options.connect_retries = v;
+        }
+        if let Some(v) = cli.connect_backoff.or(config.connect_backoff) {
+            socket_options.connect_backoff =
+                Duration::try_from_secs_f64(v).context("invalid connect backoff")?;
+        }
+        if let Some(v) = cli.connect_timeout.or(config.connect_timeout) {
+            socket_options.connect_timeout =
+                Duration::try_from_secs_f64(v).context("invalid connect timeout")?;
+        }
+        set_socket_options(socket_options).context("failed to set socket options")?;
+        // Tracker clients go through the proxy in socket options.
+        let tracker_ca = cli.tracker_ca.as_deref().or(config.tracker_ca.as_deref());
+        let tracker_insecure = cli.tracker_insecure || config.tracker_insecure;
+        if tracker_ca.is_some() || tracker_insecure {
+            configure_tracker_tls(tracker_ca, tracker_insecure)
+                .context("failed to configure tracker tls")?;
+        }
+        match (
+            cli.ssl_cert.as_ref().or(config.ssl_cert.as_ref()),
+            cli.ssl_key.as_ref().or(config.ssl_key.as_ref()),
+        ) {
+            (Some(cert), Some(key)) => {
+                let cert = std::fs::read(cert)
+                    .with_context(|| format!("failed to read {}", cert.display()))?;
+                let key = std::fs::read(key)
+                    .with_context(|| format!("failed to read {}", key.display()))?;
+                set_ssl_identity(&cert, &key).context("failed to set ssl identity")?;
+            }
+            (None, None) => {}
+            _ => anyhow::bail!("ssl-cert and ssl-key must be set together"),
+        }
+        if let Some(dir) = &cli.wire_dump {
+            set_wire_dump_dir(dir.clone()).context("failed to enable wire dump")?;
+        }
+
+        let cache_size = cli.cache_size.or(config.cache_size);
+        let flush_interval = cli.flush_interval.or(config.flush_interval);
+        let fsync = cli.fsync.or(config.fsync);
+        let write_cache = (cache_size.is_some() || flush_interval.is_some() || fsync.is_some())
+            .then(|| {
+                let mut options = WriteCacheOptions::default();
+                if let Some(v) = cache_size {
+                    options.size = v * 1024 * 1024;
+                }
+                if let Some(v) = flush_interval {
+                    options.flush_interval = Duration::from_secs(v);
+                }
+                if let Some(v) = fsync {
+                    options.fsync = v;
+                }
+                options
+            });
+
+        let config_mode = |v: &Option<String>| {
+            v.as_deref()
+                .map(parse_mode)
+                .transpose()
+                .map_err(|e| anyhow::anyhow!(e))
+        };
+        let file_modes = FileModes {
+            file: cli.file_mode.or(config_mode(&config.file_mode)?),
+            dir: cli.dir_mode.or(config_mode(&config.dir_mode)?),
+        };
+        if file_modes != FileModes::default() {
+            set_file_modes(file_modes).context("failed to set file modes")?;
+        }
+
+        let output_dir = cli.output_dir.clone().or(config.output_dir);
+        if let Some(dir) = &output_dir {
+            create_dir_all(dir)
+                .with_context(|| format!("failed to create output dir {}", dir.display()))?;
+        }
+        let log = match cli.log_file.as_ref().or(config.log_file.as_ref()) {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("failed to open log file {}", path.display()))?;
+                Some(Mutex::new(file))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            output_dir,
+            incomplete_dir: cli.incomplete_dir.clone().or(config.incomplete_dir),
+            log,
+            write_cache,
+            on_complete: config.on_complete,
+        })
+    }
+
+    /// On-complete command of a download, `arg` given on command line or the configured one.
+    fn on_complete<'a>(&'a self, arg: Option<&'a str>) -> Option<&'a str> {
+        arg.or(self.on_complete.as_deref())
+    }
+
+    /// Resolve relative `path` against the output dir, `-` for stdout is kept as is.
+    fn output_path(&self, path: String) -> String {
+        match &self.output_dir {
+            Some(dir) if path != "-" && Path::new(&path).is_relative() => {
+                dir.join(path).to_string_lossy().into_owned()
+            }
+            _ => path,
+        }
+    }
+
+    /// Append a line to the log file, if any.
+    fn log(&self, message: &str) {
+        if let Some(file) = &self.log {
+            let secs = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let _ = writeln!(file.lock().unwrap(), "[{secs}] {message}");
+        }
+    }
 }
 
 #[derive(Debug, Clone, Subcommand)]
@@ -58,6 +437,12 @@ enum Command {
     #[command(name = "magnet_info", about = "fetch info from magnet link")]
     MagnetInfo(MagnetInfoArgs),
 
+    #[command(
+        name = "fetch_metadata",
+        about = "fetch info from magnet link and save it as torrent file, without downloading"
+    )]
+    FetchMetadata(FetchMetadataArgs),
+
     #[command(
         name = "magnet_download_piece",
         about = "download a speicified piece of file from magnet link"
@@ -69,6 +454,40 @@ enum Command {
         about = "download the whole file from magnet link"
     )]
     MagnetDownload(MagnetDownloadArgs),
+
+    #[command(
+        about = "query seeder and leecher counts from tracker, or estimate from DHT if no tracker"
+    )]
+    Scrape(ScrapeArgs),
+
+    #[command(about = "measure hashing, bencode decoding and disk write throughput")]
+    Bench(BenchArgs),
+
+    #[command(about = "check torrent file for structural problems")]
+    Lint(LintArgs),
+
+    #[command(about = "compare local file with piece hashes in torrent and list differing pieces")]
+    Diff(DiffArgs),
+
+    #[command(about = "re-download only missing or corrupted pieces of an existing file")]
+    Repair(RepairArgs),
+
+    #[command(
+        about = "find the content of torrent among local files whatever their names, and link it to the output path"
+    )]
+    CrossSeed(CrossSeedArgs),
+
+    #[command(about = "sample peers and print piece availability of the swarm")]
+    Swarm(SwarmArgs),
+
+    #[command(about = "parse messages in a wire dump again, for debugging the message parser")]
+    Replay(ReplayArgs),
+
+    #[command(about = "print shell completion script")]
+    Completions(CompletionsArgs),
+
+    #[command(hide = true, about = "print man page in roff format")]
+    Mangen,
 }
 
 #[derive(Debug, Clone, Args)]
@@ -81,12 +500,28 @@ struct DecodeArgs {
 struct PeersArgs {
     #[arg(help = "torrent file path")]
     file_path: String,
+
+    #[arg(long = "json", help = "print peers and swarm counts as json")]
+    json: bool,
+
+    #[cfg(feature = "geoip")]
+    #[arg(
+        long = "geoip-db",
+        help = "MaxMind country or ASN database to annotate peers with, can be repeated"
+    )]
+    geoip_db: Vec<PathBuf>,
 }
 
 #[derive(Debug, Clone, Args)]
 struct InfoArgs {
     #[arg(help = "torrent file path")]
     file_path: String,
+
+    #[arg(
+        long = "raw-info",
+        help = "also save the bencoded info dict, exactly the bytes info hash is calculated from"
+    )]
+    raw_info: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Args)]
@@ -109,6 +544,13 @@ struct DownloadPieceArgs {
 
     #[arg(help = "piece index")]
     index: usize,
+
+    #[arg(
+        long = "peer",
+        help = "download from this peer instead of asking trackers, in format <ip>:<port>, can be repeated",
+        value_parser = validate_ip_port
+    )]
+    peers: Vec<(String, u16)>,
 }
 
 #[derive(Debug, Clone, Args)]
@@ -116,18 +558,85 @@ struct DownloadArgs {
     #[arg(
         short = 'o',
         long = "output",
-        help = "path to save the whole downloaded file"
+        required_unless_present = "dry_run",
+        help = "path to save the whole downloaded file, \"-\" for stdout"
     )]
-    output: String,
+    output: Option<String>,
 
     #[arg(help = "torrent file path")]
     file_path: String,
+
+    #[arg(
+        long = "session-dir",
+        help = "directory to remember peers and bytes transferred in, known good peers are preferred in later downloads"
+    )]
+    session_dir: Option<PathBuf>,
+
+    #[arg(
+        long = "disk-backend",
+        default_value_t = DiskBackend::Tokio,
+        help = "how to write the downloaded file: \"tokio\" or \"io-uring\", io-uring requires the io-uring feature"
+    )]
+    disk_backend: DiskBackend,
+
+    #[arg(
+        long = "debug-availability",
+        help = "print count of peers having each piece when connected peers change"
+    )]
+    debug_availability: bool,
+
+    #[arg(
+        long = "verify-md5",
+        help = "check md5sum of the downloaded file if the torrent has one"
+    )]
+    verify_md5: bool,
+
+    #[arg(
+        long = "prioritize-head-tail",
+        help = "download the first and last piece early for playback, on by default for video files"
+    )]
+    prioritize_head_tail: bool,
+
+    #[command(flatten)]
+    progress: ProgressArgs,
+
+    #[arg(
+        long = "peer",
+        help = "download from this peer instead of asking trackers, in format <ip>:<port>, can be repeated",
+        value_parser = validate_ip_port
+    )]
+    peers: Vec<(String, u16)>,
+
+    #[arg(
+        long = "dry-run",
+        help = "connect peers and estimate the download without writing anything"
+    )]
+    dry_run: bool,
+
+    #[arg(
+        long = "sample",
+        default_value_t = 20,
+        help = "max count of peers to connect in dry run"
+    )]
+    sample: usize,
+
+    #[arg(
+        long = "on-complete",
+        help = "shell command to run after the download is verified, described in BT_NAME, BT_PATH, BT_INFO_HASH, BT_SIZE and BT_RATIO"
+    )]
+    on_complete: Option<String>,
 }
 
 #[derive(Debug, Clone, Args)]
 struct MagnetParseArgs {
     #[arg(help = "magnet string to parse")]
     magnet_str: String,
+
+    #[arg(
+        long = "normalize",
+        help = "print the canonical magnet link instead, problems found are printed to stderr"
+    )]
+    normalize: bool,
 }
 
 #[derive(Debug, Clone, Args)]
@@ -142,6 +651,19 @@ struct MagnetInfoArgs {
     magnet_str: String,
 }
 
+#[derive(Debug, Clone, Args)]
+struct FetchMetadataArgs {
+    #[arg(
+        short = 'o',
+        long = "output",
+        help = "path to save the torrent file, \"-\" for stdout"
+    )]
+    output: String,
+
+    #[arg(help = "magnet string to fetch info")]
+    magnet_str: String,
+}
+
 #[derive(Debug, Clone, Args)]
 struct MagnetDownloadPieceArgs {
     #[arg(short = 'o', long = "output", help = "path to save the piece of file")]
@@ -159,68 +681,683 @@ struct MagnetDownloadArgs {
     #[arg(
         short = 'o',
         long = "output",
-        help = "path to save the whole downloaded file"
+        help = "path to save the whole downloaded file, \"-\" for stdout"
     )]
     output: String,
 
     #[arg(help = "magnet string to parse")]
     magnet_str: String,
+
+    #[arg(
+        long = "prioritize-head-tail",
+        help = "download the first and last piece early for playback, on by default for video files"
+    )]
+    prioritize_head_tail: bool,
+
+    #[command(flatten)]
+    progress: ProgressArgs,
+
+    #[arg(
+        long = "on-complete",
+        help = "shell command to run after the download is verified, described in BT_NAME, BT_PATH, BT_INFO_HASH, BT_SIZE and BT_RATIO"
+    )]
+    on_complete: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ProgressMode {
+    /// Bar if stderr is a terminal, otherwise plain.
+    Auto,
+
+    /// Redraw a progress bar every second.
+    Bar,
+
+    /// Print a status line periodically, for logs.
+    Plain,
+
+    None,
+}
+
+#[derive(Debug, Clone, Args)]
+struct ProgressArgs {
+    #[arg(
+        long = "progress",
+        value_enum,
+        default_value_t = ProgressMode::Auto,
+        help = "how to show download progress"
+    )]
+    mode: ProgressMode,
+
+    #[arg(
+        long = "progress-interval",
+        default_value_t = 5,
+        help = "seconds between status lines of plain progress"
+    )]
+    interval: u64,
+
+    #[arg(
+        long = "progress-json",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "-",
+        value_name = "PATH",
+        help = "print newline delimited json events in place of other progress output: piece_done, speed_sample every second and completed; to stdout, or to PATH like /dev/fd/3 when downloading to stdout"
+    )]
+    json: Option<String>,
+
+    #[arg(
+        long = "debug-events",
+        help = "print every download event to stderr, they are written to --log-file anyway"
+    )]
+    debug_events: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+struct BenchArgs {
+    #[arg(
+        short = 'd',
+        long = "dir",
+        default_value = ".",
+        help = "directory to write test files in, usually the download directory"
+    )]
+    dir: PathBuf,
+
+    #[arg(
+        short = 's',
+        long = "size",
+        default_value_t = 256,
+        help = "MiB of data to process in each benchmark"
+    )]
+    size: usize,
+}
+
+#[derive(Debug, Clone, Args)]
+struct LintArgs {
+    #[arg(help = "torrent file path")]
+    file_path: PathBuf,
+}
+
+#[derive(Debug, Clone, Args)]
+struct DiffArgs {
+    #[arg(help = "torrent file path")]
+    file_path: String,
+
+    #[arg(help = "local file to compare")]
+    path: PathBuf,
+}
+
+#[derive(Debug, Clone, Args)]
+struct CrossSeedArgs {
+    #[arg(
+        short = 'o',
+        long = "output",
+        help = "path to link the matched file to, defaults to the name in torrent"
+    )]
+    output: Option<String>,
+
+    #[arg(help = "torrent file path")]
+    file_path: String,
+
+    #[arg(help = "directory of downloaded content to search")]
+    dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Args)]
+struct RepairArgs {
+    #[arg(
+        short = 'o',
+        long = "output",
+        help = "existing file to repair in place"
+    )]
+    output: String,
+
+    #[arg(help = "torrent file path")]
+    file_path: String,
+
+    #[command(flatten)]
+    progress: ProgressArgs,
+
+    #[arg(
+        long = "peer",
+        help = "download from this peer instead of asking trackers, in format <ip>:<port>, can be repeated",
+        value_parser = validate_ip_port
+    )]
+    peers: Vec<(String, u16)>,
+}
+
+#[derive(Debug, Clone, Args)]
+struct SwarmArgs {
+    #[arg(help = "torrent file path")]
+    file_path: String,
+
+    #[arg(
+        long = "sample",
+        default_value_t = 20,
+        help = "max count of peers to connect"
+    )]
+    sample: usize,
+}
+
+#[derive(Debug, Clone, Args)]
+struct ReplayArgs {
+    #[arg(help = "dump file written by --wire-dump")]
+    file_path: PathBuf,
+}
+
+#[derive(Debug, Clone, Args)]
+struct CompletionsArgs {
+    #[arg(help = "shell to generate for")]
+    shell: Shell,
+}
+
+#[derive(Debug, Clone, Args)]
+struct ScrapeArgs {
+    #[arg(help = "torrent file path or magnet string")]
+    target: String,
+}
+
+/// Runs of consecutive pieces in the same state, good pieces are skipped.
+fn bad_piece_runs(states: &[PieceState]) -> Vec<(PieceState, Range<usize>)> {
+    let mut runs: Vec<(PieceState, Range<usize>)> = vec![];
+    for (index, state) in states.iter().enumerate() {
+        match runs.last_mut() {
+            _ if *state == PieceState::Good => {}
+            Some((last, range)) if last == state && range.end == index => range.end += 1,
+            _ => runs.push((*state, index..index + 1)),
+        }
+    }
+    runs
+}
+
+/// Dump count of peers having each piece, 16 pieces per line.
+fn print_availability(peers: &[usize]) {
+    eprintln!("availability:");
+    for (line, counts) in peers.chunks(16).enumerate() {
+        let counts = counts
+            .iter()
+            .map(|x| format!("{x:>3}"))
+            .collect::<Vec<_>>()
+            .join("");
+        eprintln!("{:>6}:{counts}", line * 16);
+    }
+}
+
+/// Download progress collected from events.
+#[derive(Debug, Default)]
+struct Progress {
+    length: usize,
+
+    downloaded: usize,
+
+    peers: HashSet<String>,
+
+    /// Bytes per second between the last two samples.
+    speed: f64,
+
+    /// Time and downloaded bytes of the last sample.
+    sampled: Option<(Instant, usize)>,
+}
+
+impl Progress {
+    /// Width of progress bar, in chars.
+    const BAR_WIDTH: usize = 30;
+
+    fn update(&mut self, event: &DownloadEvent) {
+        match event {
+            DownloadEvent::Started { length, .. } => self.length = *length,
+            DownloadEvent::PeerConnected { addr, .. } => {
+                self.peers.insert(addr.clone());
+            }
+            DownloadEvent::PeerDisconnected { addr, .. } => {
+                self.peers.remove(addr);
+            }
+            DownloadEvent::PieceVerified { length, .. } => self.downloaded += length,
+            _ => {}
+        }
+    }
+
+    /// Update speed with the bytes downloaded since the last sample.
+    fn sample(&mut self, now: Instant) {
+        if let Some((time, downloaded)) = self.sampled {
+            let secs = now.duration_since(time).as_secs_f64();
+            if secs > 0.0 {
+                self.speed = (self.downloaded - downloaded) as f64 / secs;
+            }
+        }
+        self.sampled = Some((now, self.downloaded));
+    }
+
+    fn percent(&self) -> f64 {
+        if self.length == 0 {
+            return 0.0;
+        }
+        self.downloaded as f64 * 100.0 / self.length as f64
+    }
+
+    fn status(&self) -> String {
+        format!(
+            "{:5.1}% {:8.1} KiB/s {} peers",
+            self.percent(),
+            self.speed / 1024.0,
+            self.peers.len()
+        )
+    }
+
+    fn bar(&self) -> String {
+        let filled = ((self.percent() / 100.0 * Self::BAR_WIDTH as f64).round() as usize)
+            .min(Self::BAR_WIDTH);
+        format!(
+            "[{}{}] {}",
+            "#".repeat(filled),
+            " ".repeat(Self::BAR_WIDTH - filled),
+            self.status()
+        )
+    }
+
+    /// Json event of `event`, `None` if not reported.
+    fn json_event(event: &DownloadEvent) -> Option<serde_json::Value> {
+        match event {
+            DownloadEvent::PieceVerified { index, length } => Some(json!({
+                "event": "piece_done",
+                "index": index,
+                "length": length,
+            })),
+            DownloadEvent::Completed => Some(json!({ "event": "completed" })),
+            _ => None,
+        }
+    }
+
+    fn json_sample(&self) -> serde_json::Value {
+        json!({
+            "event": "speed_sample",
+            "bytes_per_second": self.speed.round() as u64,
+            "downloaded": self.downloaded,
+            "length": self.length,
+            "peers": self.peers.len(),
+        })
+    }
+
+    /// Write `value` as a line to `out`, flushed at once for readers following it.
+    fn write_json(out: &mut dyn Write, value: &serde_json::Value) -> std::io::Result<()> {
+        writeln!(out, "{value}")?;
+        out.flush()
+    }
+
+    fn show(&self, mode: ProgressMode) {
+        match mode {
+            ProgressMode::Bar => {
+                eprint!("\r{}", self.bar());
+                let _ = std::io::stderr().flush();
+            }
+            ProgressMode::Plain => eprintln!("{}", self.status()),
+            ProgressMode::Auto | ProgressMode::None => {}
+        }
+    }
+}
+
+/// Print events of `handle` until the download finished, events are also logged.
+///
+/// Ctrl-Z pauses the download before the process stops, and it resumes when the process continues.
+/// Ctrl-C cancels.
+async fn follow_download(
+    ctx: &AppContext,
+    mut handle: DownloadHandle,
+    debug_availability: bool,
+    progress_args: &ProgressArgs,
+    on_complete: Option<&str>,
+    session_dir: Option<&Path>,
+) -> anyhow::Result<()> {
+    // Json events go where the library never prints, so every line of the stream is json.
+    let mut json: Option<Box<dyn Write + Send>> = match progress_args.json.as_deref() {
+        None => None,
+        Some("-") if handle.file_path() == "-" => {
+            anyhow::bail!("--progress-json needs a path when downloading to stdout")
+        }
+        Some("-") => Some(Box::new(std::io::stdout())),
+        Some(path) => Some(Box::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open {path} for json progress"))?,
+        )),
+    };
+    let mode = match progress_args.mode {
+        _ if json.is_some() => ProgressMode::None,
+        ProgressMode::Auto if std::io::stderr().is_terminal() => ProgressMode::Bar,
+        ProgressMode::Auto => ProgressMode::Plain,
+        v => v,
+    };
+    let interval = Duration::from_secs(progress_args.interval.max(1));
+    let period = if mode == ProgressMode::Bar || json.is_some() {
+        Duration::from_secs(1)
+    } else {
+        interval
+    };
+    let mut ticker = tokio::time::interval(period);
+    // Plain status lines are printed every `interval` even if speed is sampled every second.
+    let mut shown = None::<Instant>;
+    let mut progress = Progress::default();
+    let mut sigtstp = signal(SignalKind::from_raw(libc::SIGTSTP))?;
+    let mut sigcont = signal(SignalKind::from_raw(libc::SIGCONT))?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    loop {
+        tokio::select! {
+            event = handle.next_event() => {
+                let Some(event) = event else { break };
+                ctx.log(&format!("{event:?}"));
+                progress.update(&event);
+                if let Some(out) = &mut json {
+                    if let Some(v) = Progress::json_event(&event) {
+                        Progress::write_json(out, &v)?;
+                    }
+                }
+                match event {
+                    DownloadEvent::Availability { peers } if debug_availability => {
+                        print_availability(&peers);
+                    }
+                    DownloadEvent::Availability { .. } => {}
+                    // Json events replace other progress output.
+                    _ if json.is_some() => {}
+                    DownloadEvent::PieceUnavailable { index } => {
+                        eprintln!("piece {index} unavailable in current swarm");
+                    }
+                    // Raw events would break the bar redrawn in place.
+                    event if progress_args.debug_events && mode != ProgressMode::Bar => {
+                        eprintln!(">>> {event:?}")
+                    }
+                    _ => {}
+                }
+            }
+            now = ticker.tick(), if mode != ProgressMode::None || json.is_some() => {
+                let now = now.into_std();
+                progress.sample(now);
+                if let Some(out) = &mut json {
+                    Progress::write_json(out, &progress.json_sample())?;
+                }
+                if mode == ProgressMode::Bar || shown.is_none_or(|x| now - x >= interval) {
+                    shown = Some(now);
+                    progress.show(mode);
+                }
+            }
+            _ = sigtstp.recv() => {
+                handle.pause();
+                eprintln!("paused");
+                // Handling SIGTSTP replaced the default action, stop ourselves.
+                // SAFETY: raise only sends a signal to the calling thread, it takes no pointers
+                // and SIGSTOP can not be caught, so no handler runs on this thread.
+                unsafe { libc::raise(libc::SIGSTOP) };
+            }
+            _ = sigcont.recv() => {
+                handle.resume();
+                eprintln!("resumed");
+            }
+            _ = sigint.recv() => handle.cancel(),
+        }
+    }
+    progress.sample(Instant::now());
+    progress.show(mode);
+    if mode == ProgressMode::Bar {
+        eprintln!();
+    }
+    let stats = handle.stats();
+    let summary = format!(
+        "Summary: {}/{} pieces, {} bytes downloaded, {} hash failures ({} bytes discarded)",
+        stats.pieces_verified,
+        stats.piece_count,
+        stats.downloaded,
+        stats.hash_failures,
+        stats.corrupt
+    );
+    eprintln!("{summary}");
+    ctx.log(&summary);
+    for (source, v) in stats.sources.iter() {
+        let line = format!(
+            "  {source}: {}/{} peers connected, {} bytes received",
+            v.connected, v.tried, v.downloaded
+        );
+        eprintln!("{line}");
+        ctx.log(&line);
+    }
+    let env = hook_env(
+        handle.name(),
+        handle.file_path(),
+        handle.info_hash(),
+        handle.length(),
+        &stats,
+    );
+    // Output of the command must not mix into the downloaded data or json events written to
+    // stdout.
+    let to_stdout = handle.file_path() == "-" || progress_args.json.as_deref() == Some("-");
+    let info_hash = *handle.info_hash();
+    handle.wait().await?;
+    if let Some(dir) = session_dir {
+        let log = TransferLog::load(dir);
+        let (torrent, lifetime) = (log.torrent(&info_hash), log.lifetime());
+        let line = format!(
+            "Totals: {} bytes downloaded, {} bytes uploaded in this torrent, {} bytes downloaded, {} bytes uploaded in all",
+            torrent.downloaded, torrent.uploaded, lifetime.downloaded, lifetime.uploaded
+        );
+        eprintln!("{line}");
+        ctx.log(&line);
+    }
+    if let Some(cmd) = on_complete {
+        ctx.log(&format!("running on-complete command: {cmd}"));
+        let status = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .envs(env)
+            .stdout(if to_stdout {
+                Stdio::from(std::io::stderr())
+            } else {
+                Stdio::inherit()
+            })
+            .status()
+            .await
+            .context("failed to run on-complete command")?;
+        if !status.success() {
+            anyhow::bail!("on-complete command failed: {status}");
+        }
+    }
+    Ok(())
+}
+
+/// Environment variables describing a completed download for the on-complete command.
+fn hook_env(
+    name: &str,
+    file_path: &str,
+    info_hash: &[u8; 20],
+    length: usize,
+    stats: &Stats,
+) -> Vec<(&'static str, String)> {
+    let ratio = if stats.downloaded == 0 {
+        0.0
+    } else {
+        stats.uploaded as f64 / stats.downloaded as f64
+    };
+    vec![
+        ("BT_NAME", name.to_string()),
+        ("BT_PATH", file_path.to_string()),
+        ("BT_INFO_HASH", hex::encode(info_hash)),
+        ("BT_SIZE", length.to_string()),
+        ("BT_RATIO", format!("{ratio:.3}")),
+    ]
+}
+
+/// Connect peers and estimate downloading `torrent` from them, printing the plan.
+async fn dry_run(torrent: &Torrent, peers: &Peers, sample: usize) -> anyhow::Result<()> {
+    let mut sample = sample_swarm(torrent, peers, sample).await?;
+    let health = &sample.health;
+    let piece_count = torrent.info.piece_hashes.len();
+    println!("Size: {} bytes in {piece_count} pieces", torrent.length());
+    println!("Peers: {}/{} usable", health.connected, health.sampled);
+    println!("Distributed copies: {:.3}", health.distributed_copies());
+    if !health.is_completable() {
+        println!(
+            "Missing: {} pieces not available in sampled peers",
+            health.histogram()[0]
+        );
+    }
+    let Some(index) = health.most_available() else {
+        println!("Estimated time: unknown, no piece available");
+        return Ok(());
+    };
+    // Blocks are spread over all sampled peers as in a download, their throughput scales to the
+    // whole torrent.
+    match sample.time_piece(torrent, index).await {
+        Ok(v) => println!(
+            "Estimated time: {:.0}s ({v:.0} bytes/s sampled on piece {index})",
+            torrent.length() as f64 / v
+        ),
+        Err(e) => println!("Estimated time: unknown, failed to sample piece {index}: {e}"),
+    }
+    Ok(())
+}
+
+/// Peers given by `--peer`, `None` if not given.
+fn peers_from_args(peers: Vec<(String, u16)>) -> Option<Peers> {
+    if peers.is_empty() {
+        return None;
+    }
+    Some(
+        peers
+            .into_iter()
+            .map(|(ip, port)| Peer {
+                ip,
+                port,
+                source: PeerSource::Manual,
+            })
+            .collect(),
+    )
+}
+
+/// Parse octal mode bits like `644` or `0o755`.
+fn parse_mode(s: &str) -> Result<u32, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    match u32::from_str_radix(digits, 8) {
+        Ok(v) if v <= 0o7777 => Ok(v),
+        _ => Err(format!("invalid mode {s}, expected octal like 644")),
+    }
 }
 
 fn validate_ip_port(s: &str) -> Result<(String, u16), &'static str> {
-    match s.split_once(':') {
+    match s.rsplit_once(':') {
         Some((ip, port)) => {
-            let ip_re = Regex::new(r#"^((25[0-5]|(2[0-4]|1\d|[1-9]|)\d)\.?\b){4}$"#).unwrap();
-            if !ip_re.is_match(ip) {
-                return Err("invalid ip");
+            // Host names like onion ones are resolved when dialed, by the proxy if set.
+            let ip = match ip.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+                Some(v) => v.parse::<Ipv6Addr>().map(|x| x.to_string()),
+                None if is_host_name(ip) => Ok(ip.to_string()),
+                None => ip.parse::<Ipv4Addr>().map(|x| x.to_string()),
             }
+            .map_err(|_| "invalid ip")?;
             let port = if let Ok(p) = port.parse::<u16>() {
                 p
             } else {
                 return Err("invalid port");
             };
 
-            Ok((ip.to_string(), port))
+            Ok((ip, port))
         }
-        None => Err("invalid ip port format, expected to be <ip>:<port>, e.g. 192.168.0.1:54321"),
+        None => Err("invalid ip port format, expected to be <ip>:<port>, [<ipv6>]:<port> or <host>:<port>, e.g. 192.168.0.1:54321"),
     }
 }
 
 #[tokio::main]
-async fn main() -> BtResult<()> {
+async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    let ctx = AppContext::new(&cli)?;
+    let result = run(cli.command, &ctx).await;
+    if let Err(e) = &result {
+        ctx.log(&format!("error: {e:#}"));
+    }
+    result
+}
 
-    match cli.command {
+async fn run(command: Command, ctx: &AppContext) -> anyhow::Result<()> {
+    match command {
         Command::Decode(decode_args) => {
             let mut ctx = DecodeContext::from(decode_args.text.as_str());
             let decoded_value = decode_bencoded_value(&mut ctx)?;
-            println!("{}", decoded_value.to_string());
+            println!("{}", display_value(&decoded_value));
         }
         Command::Info(info_args) => {
             let torrent = Torrent::parse_from_file(info_args.file_path.as_str())?;
             torrent.print_info();
+            if let Some(path) = info_args.raw_info {
+                let path = ctx.output_path(path.to_string_lossy().into_owned());
+                std::fs::write(&path, torrent.raw_info_bytes())
+                    .with_context(|| format!("failed to save info dict to {path}"))?;
+            }
         }
         Command::Peers(peer_args) => {
             let torrent = Torrent::parse_from_file(peer_args.file_path.as_str())?;
-            let peer_info = discover_peer(
-                torrent.tracker_url(),
-                torrent.info_hash(),
-                0,
-                0,
-                torrent.length(),
-            )
-            .await
-            .context("failed to discover peer")?;
-            for peer in peer_info.peers.iter() {
-                println!("{}:{}", peer.ip, peer.port);
+            let peer_info = TrackerTiers::from_torrent(&torrent)
+                .announce_all(torrent.info_hash(), 0, 0, torrent.length())
+                .await
+                .context("failed to discover peer")?;
+            for (url, e) in peer_info.errors.iter() {
+                eprintln!("Tracker {url} failed: {e}");
+            }
+            #[cfg(feature = "geoip")]
+            let geoip = codecrafters_bittorrent::geoip::GeoIp::open(&peer_args.geoip_db)
+                .context("failed to open geoip database")?;
+            if peer_args.json {
+                let peers = peer_info
+                    .peers
+                    .iter()
+                    .map(|TrackedPeer { peer, trackers }| {
+                        let value =
+                            json!({ "ip": peer.ip, "port": peer.port, "trackers": trackers });
+                        #[cfg(feature = "geoip")]
+                        let value = match (peer_args.geoip_db.is_empty(), peer.ip.parse()) {
+                            (false, Ok(ip)) => {
+                                let mut value = value;
+                                value["location"] = json!(geoip.lookup(ip));
+                                value
+                            }
+                            _ => value,
+                        };
+                        value
+                    })
+                    .collect::<Vec<_>>();
+                let value = json!({
+                    "peers": peers,
+                    "webrtc_peers": peer_info.webrtc_peers,
+                    "seeders": peer_info.complete,
+                    "leechers": peer_info.incomplete,
+                });
+                println!("{value}");
+                return Ok(());
+            }
+            for TrackedPeer { peer, .. } in peer_info.peers.iter() {
+                #[cfg(feature = "geoip")]
+                if let (false, Ok(ip)) = (peer_args.geoip_db.is_empty(), peer.ip.parse()) {
+                    let location = geoip.lookup(ip);
+                    println!(
+                        "{} {} {}",
+                        peer,
+                        location.country.as_deref().unwrap_or("-"),
+                        location.as_org.as_deref().unwrap_or("-"),
+                    );
+                    continue;
+                }
+                println!("{peer}");
+            }
+            for peer_id in peer_info.webrtc_peers.iter() {
+                println!("{peer_id} (WebRTC, unreachable)");
+            }
+            // Stdout only lists peers.
+            if let Some(v) = peer_info.complete {
+                eprintln!("Seeders: {v}");
+            }
+            if let Some(v) = peer_info.incomplete {
+                eprintln!("Leechers: {v}");
             }
         }
         Command::Handshake(handshake_args) => {
             let torrent = Torrent::parse_from_file(handshake_args.file_path.as_str())?;
-            let message = HandshakeMessage::new(
-                torrent.info_hash().clone(),
-                PEER_ID.as_bytes().try_into().unwrap(),
-            );
+            let message =
+                HandshakeMessage::new(*torrent.info_hash(), PEER_ID.as_bytes().try_into().unwrap());
             let resp = handshake(
                 handshake_args.ip_port.0.as_str(),
                 handshake_args.ip_port.1,
@@ -232,48 +1369,64 @@ async fn main() -> BtResult<()> {
         }
         Command::DownloadPiece(download_piece_args) => {
             let torrent = Torrent::parse_from_file(download_piece_args.file_path.as_str())?;
-            let peer_info = discover_peer(
-                torrent.tracker_url(),
-                torrent.info_hash(),
-                0,
-                0,
-                torrent.length(),
-            )
-            .await
-            .context("failed to discover peer")?;
-            if peer_info.peers.is_empty() {
-                eprintln!("no peers found");
-                return Ok(());
-            }
-            download_piece(
-                &torrent,
-                &peer_info.peers,
-                download_piece_args.output,
+            Engine::download_piece(
+                torrent,
                 download_piece_args.index,
+                ctx.output_path(download_piece_args.output),
+                peers_from_args(download_piece_args.peers),
             )
             .await?;
         }
         Command::Download(download_args) => {
             let torrent = Torrent::parse_from_file(download_args.file_path.as_str())?;
-            let peer_info = discover_peer(
-                torrent.tracker_url(),
-                torrent.info_hash(),
-                0,
-                0,
-                torrent.length(),
-            )
-            .await
-            .context("failed to discover peer")?;
-            if peer_info.peers.is_empty() {
-                eprintln!("no peers found");
-                return Ok(());
+            if download_args.dry_run {
+                let peers = match peers_from_args(download_args.peers) {
+                    Some(v) => v,
+                    None => {
+                        TrackerTiers::from_torrent(&torrent)
+                            .announce(torrent.info_hash(), 0, 0, torrent.length())
+                            .await
+                            .context("failed to discover peer")?
+                            .peers
+                    }
+                };
+                return dry_run(&torrent, &peers, download_args.sample).await;
             }
-            download_file(&torrent, &peer_info.peers, download_args.output).await?;
+            // Required by clap unless dry run.
+            let output = download_args.output.unwrap_or_default();
+            let session_dir = download_args.session_dir.clone();
+            let options = DownloadOptions {
+                session_dir: download_args.session_dir,
+                peers: peers_from_args(download_args.peers),
+                disk_backend: download_args.disk_backend,
+                verify_md5: download_args.verify_md5,
+                prioritize_head_tail: download_args.prioritize_head_tail.then_some(true),
+                write_cache: ctx.write_cache.clone(),
+                incomplete_dir: ctx.incomplete_dir.clone(),
+                repair: false,
+            };
+            let handle = Engine::download(torrent, ctx.output_path(output), options).await?;
+            follow_download(
+                ctx,
+                handle,
+                download_args.debug_availability,
+                &download_args.progress,
+                ctx.on_complete(download_args.on_complete.as_deref()),
+                session_dir.as_deref(),
+            )
+            .await?;
         }
         Command::MagnetParse(magnet_parse_args) => {
             let manget =
                 Magnet::new(&magnet_parse_args.magnet_str).context("invalid magset string")?;
-            manget.print_info();
+            if magnet_parse_args.normalize {
+                for warning in manget.validate() {
+                    eprintln!("warning: {warning}");
+                }
+                println!("{}", manget.normalize());
+            } else {
+                manget.print_info();
+            }
         }
         Command::MagnetHandshake(magnet_handshake_args) => {
             let magnet =
@@ -285,50 +1438,209 @@ async fn main() -> BtResult<()> {
         Command::MagnetInfo(magnet_info_args) => {
             let magnet =
                 Magnet::new(&magnet_info_args.magnet_str).context("invalid magset string")?;
-            let resp = magnet_handshake(&magnet, true).await?;
-            let torrent = Torrent::new(magnet.tracker_url.unwrap(), resp.torrent_info.unwrap())
-                .context("failed to build torrent")?;
+            let torrent = Engine::resolve(magnet).await?;
             torrent.print_info();
         }
-        Command::MagnetDownloadPiece(args) => {
+        Command::FetchMetadata(args) => {
             let magnet = Magnet::new(&args.magnet_str).context("invalid magset string")?;
-            let resp = magnet_handshake(&magnet, true).await?;
-            let torrent = Torrent::new(magnet.tracker_url.unwrap(), resp.torrent_info.unwrap())
-                .context("failed to build torrent")?;
-            let peer_info = discover_peer(
-                torrent.tracker_url(),
-                torrent.info_hash(),
-                0,
-                0,
-                torrent.length(),
-            )
-            .await
-            .context("failed to discover peer")?;
-            if peer_info.peers.is_empty() {
-                eprintln!("no peers found");
-                return Ok(());
+            let torrent = Engine::resolve(magnet).await?;
+            let data = torrent.to_bytes().context("failed to encode torrent")?;
+            match ctx.output_path(args.output) {
+                path if path == "-" => std::io::stdout()
+                    .write_all(&data)
+                    .context("failed to write torrent to stdout")?,
+                path => std::fs::write(&path, data)
+                    .with_context(|| format!("failed to save torrent to {path}"))?,
             }
-            download_piece(&torrent, &peer_info.peers, args.output, args.index).await?;
+        }
+        Command::MagnetDownloadPiece(args) => {
+            let magnet = Magnet::new(&args.magnet_str).context("invalid magset string")?;
+            Engine::download_piece(magnet, args.index, ctx.output_path(args.output), None).await?;
         }
         Command::MagnetDownload(args) => {
             let magnet = Magnet::new(&args.magnet_str).context("invalid magset string")?;
-            let resp = magnet_handshake(&magnet, true).await?;
-            let torrent = Torrent::new(magnet.tracker_url.unwrap(), resp.torrent_info.unwrap())
-                .context("failed to build torrent")?;
-            let peer_info = discover_peer(
-                torrent.tracker_url(),
-                torrent.info_hash(),
-                0,
-                0,
-                torrent.length(),
+            let handle = Engine::download(
+                magnet,
+                ctx.output_path(args.output),
+                DownloadOptions {
+                    prioritize_head_tail: args.prioritize_head_tail.then_some(true),
+                    write_cache: ctx.write_cache.clone(),
+                    incomplete_dir: ctx.incomplete_dir.clone(),
+                    ..Default::default()
+                },
             )
-            .await
-            .context("failed to discover peer")?;
-            if peer_info.peers.is_empty() {
-                eprintln!("no peers found");
-                return Ok(());
+            .await?;
+            follow_download(
+                ctx,
+                handle,
+                false,
+                &args.progress,
+                ctx.on_complete(args.on_complete.as_deref()),
+                None,
+            )
+            .await?;
+        }
+        Command::Bench(args) => {
+            let results = bench::run_all(&args.dir, args.size * 1024 * 1024)
+                .await
+                .context("failed to run benchmarks")?;
+            for result in results {
+                println!(
+                    "{:<18}{:>10.1} MiB/s",
+                    format!("{}:", result.name),
+                    result.rate() / 1024.0 / 1024.0
+                );
+            }
+        }
+        Command::Lint(args) => {
+            let data = std::fs::read(&args.file_path)
+                .with_context(|| format!("failed to read {}", args.file_path.display()))?;
+            let warnings = lint::lint(&data);
+            for warning in warnings.iter() {
+                println!("{warning}");
+            }
+            if !warnings.is_empty() {
+                anyhow::bail!("{} problems found", warnings.len());
+            }
+        }
+        Command::Repair(args) => {
+            let torrent = Torrent::parse_from_file(args.file_path.as_str())?;
+            let options = DownloadOptions {
+                peers: peers_from_args(args.peers),
+                write_cache: ctx.write_cache.clone(),
+                repair: true,
+                ..Default::default()
+            };
+            let handle = Engine::download(torrent, ctx.output_path(args.output), options).await?;
+            follow_download(ctx, handle, false, &args.progress, None, None).await?;
+        }
+        Command::CrossSeed(args) => {
+            let torrent = Torrent::parse_from_file(args.file_path.as_str())?;
+            let found = find_content(&torrent, &args.dir)
+                .with_context(|| format!("failed to search {}", args.dir.display()))?;
+            let Some(found) = found else {
+                anyhow::bail!(
+                    "no file in {} matches {}",
+                    args.dir.display(),
+                    torrent.name()
+                );
+            };
+            println!("Matched: {}", found.display());
+            let output = PathBuf::from(
+                ctx.output_path(args.output.unwrap_or_else(|| torrent.name().into())),
+            );
+            if output != found {
+                link_file(&found, &output)
+                    .with_context(|| format!("failed to link to {}", output.display()))?;
+                println!("Linked: {}", output.display());
+            }
+        }
+        Command::Diff(args) => {
+            let torrent = Torrent::parse_from_file(args.file_path.as_str())?;
+            let states = check_file(&torrent, &args.path)
+                .with_context(|| format!("failed to check {}", args.path.display()))?;
+            let runs = bad_piece_runs(&states);
+            let bad = runs.iter().map(|(_, x)| x.len()).sum::<usize>();
+            // Single file torrents have one file to report.
+            println!(
+                "{} ({}): {bad}/{} pieces differ",
+                torrent.name(),
+                args.path.display(),
+                states.len()
+            );
+            let offset = |index: usize| index * torrent.nominal_piece_length();
+            for (state, range) in runs {
+                let state = match state {
+                    PieceState::Corrupt => "corrupt",
+                    _ => "missing",
+                };
+                let end = offset(range.end).min(torrent.length());
+                if range.len() == 1 {
+                    print!("  piece {}", range.start);
+                } else {
+                    print!("  pieces {}-{}", range.start, range.end - 1);
+                }
+                println!(" {state}, bytes {}..{end}", offset(range.start));
+            }
+            if bad > 0 {
+                anyhow::bail!("{bad} pieces differ");
+            }
+        }
+        Command::Swarm(args) => {
+            let torrent = Torrent::parse_from_file(args.file_path.as_str())?;
+            // Trackers in all tiers know different parts of the swarm.
+            let peer_info = TrackerTiers::from_torrent(&torrent)
+                .announce_all(torrent.info_hash(), 0, 0, torrent.length())
+                .await
+                .context("failed to discover peer")?;
+            for (url, e) in peer_info.errors.iter() {
+                eprintln!("Tracker {url} failed: {e}");
+            }
+            let peers = peer_info
+                .peers
+                .into_iter()
+                .map(|x| x.peer)
+                .collect::<Peers>();
+            let health = swarm_health(&torrent, &peers, args.sample).await?;
+            println!("Peers: {}/{} connected", health.connected, health.sampled);
+            let histogram = health.histogram();
+            let max = histogram.iter().max().copied().unwrap_or_default().max(1);
+            for (copies, count) in histogram.iter().enumerate() {
+                println!(
+                    "{copies:>4} copies: {count:>6} pieces {}",
+                    "#".repeat(count * 40 / max)
+                );
+            }
+            println!("Distributed copies: {:.3}", health.distributed_copies());
+            if !health.is_completable() {
+                println!(
+                    "Missing: {} pieces not available in sampled peers",
+                    histogram[0]
+                );
+            }
+        }
+        Command::Replay(args) => {
+            let data = std::fs::read(&args.file_path)
+                .with_context(|| format!("failed to read {}", args.file_path.display()))?;
+            for entry in replay(&data) {
+                match entry.message {
+                    Ok(v) => println!("{:>10} {:>8}: {v}", entry.offset, entry.length),
+                    Err(e) => println!(
+                        "{:>10} {:>8}: error: {:#}",
+                        entry.offset,
+                        entry.length,
+                        anyhow::Error::from(e)
+                    ),
+                }
+            }
+        }
+        Command::Completions(args) => {
+            let mut cmd = Cli::command();
+            let name = cmd.get_name().to_string();
+            clap_complete::generate(args.shell, &mut cmd, name, &mut std::io::stdout());
+        }
+        Command::Mangen => {
+            clap_mangen::Man::new(Cli::command())
+                .render(&mut std::io::stdout())
+                .context("failed to render man page")?;
+        }
+        Command::Scrape(args) => {
+            let source = Source::parse(&args.target)?;
+            let info_hash = *source.info_hash();
+            let tracker_url = source.tracker_url().map(|x| x.to_string());
+            let info = match tracker_url {
+                Some(url) => scrape_tracker(&url, &info_hash)
+                    .await
+                    .context("failed to scrape tracker")?,
+                None => dht::scrape(&info_hash)
+                    .await
+                    .context("failed to scrape from dht")?,
+            };
+            println!("Seeders: {}", info.complete);
+            println!("Leechers: {}", info.incomplete);
+            if let Some(downloaded) = info.downloaded {
+                println!("Downloaded: {downloaded}");
             }
-            download_file(&torrent, &peer_info.peers, args.output).await?;
         }
     }
     Ok(())
@@ -339,7 +1651,7 @@ mod test {
     use serde::{Deserialize, Serialize};
     use serde_bytes::ByteBuf;
 
-    use crate::{
+    use codecrafters_bittorrent::{
         encode::{encode_dictionary, EncodeContext},
         utils::decode_bytes_from_string,
     };
@@ -424,7 +1736,7 @@ mod test {
         let bad_pieces = decode_bytes_from_string(bad_pieces);
         assert_eq!(good_pieces, bad_pieces);
         let mut ctx2 = EncodeContext::new();
-        encode_dictionary(&mut ctx2, decoded_value.as_object().unwrap());
+        encode_dictionary(&mut ctx2, decoded_value.as_object().unwrap()).unwrap();
         assert_eq!(&ctx.data(), &ctx2.data());
         assert_eq!(
             String::from_utf8_lossy(&ctx.data()[170..200]),
@@ -438,4 +1750,151 @@ mod test {
         // }
         // panic!("{}", hash_str);
     }
+
+    #[test]
+    fn test_config() {
+        let config: Config = toml::from_str(
+            "output-dir = \"/tmp/bt\"\ntracker-insecure = true\nseed = 7\nmax-dials = 8\nfsync = \"each-flush\"\nfile-mode = \"640\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.output_dir, Some(PathBuf::from("/tmp/bt")));
+        assert!(config.tracker_insecure);
+        assert_eq!(config.seed, Some(7));
+        assert_eq!(config.max_dials, Some(8));
+        assert_eq!(config.fsync, Some(FsyncPolicy::EachFlush));
+        assert_eq!(config.file_mode.as_deref().map(parse_mode), Some(Ok(0o640)));
+        assert_eq!(parse_mode("0o755"), Ok(0o755));
+        assert!(parse_mode("8").is_err() && parse_mode("17777").is_err());
+        assert_eq!(config.log_file, None);
+        assert!(toml::from_str::<Config>("seed = \"7\"").is_err());
+
+        let ctx = AppContext {
+            output_dir: config.output_dir,
+            incomplete_dir: None,
+            log: None,
+            write_cache: None,
+            on_complete: None,
+        };
+        assert_eq!(ctx.output_path("a.iso".to_string()), "/tmp/bt/a.iso");
+        assert_eq!(ctx.output_path("/a.iso".to_string()), "/a.iso");
+        assert_eq!(ctx.output_path("-".to_string()), "-");
+    }
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_validate_ip_port() {
+        assert_eq!(
+            validate_ip_port("192.168.0.1:54321"),
+            Ok(("192.168.0.1".to_string(), 54321))
+        );
+        assert_eq!(
+            validate_ip_port("[2001:db8::1]:6881"),
+            Ok(("2001:db8::1".to_string(), 6881))
+        );
+        assert_eq!(validate_ip_port("2001:db8::1:6881"), Err("invalid ip"));
+        assert_eq!(validate_ip_port("256.0.0.1:1"), Err("invalid ip"));
+        assert_eq!(validate_ip_port("1.2.3.4:x"), Err("invalid port"));
+        assert_eq!(
+            validate_ip_port("abcdef.onion:6881"),
+            Ok(("abcdef.onion".to_string(), 6881))
+        );
+        assert_eq!(validate_ip_port("a b:1"), Err("invalid ip"));
+        assert!(validate_ip_port("1.2.3.4").is_err());
+    }
+
+    #[test]
+    fn test_hook_env() {
+        let mut stats = Stats::default();
+        stats.downloaded = 2048;
+        let env = hook_env("a.iso", "/tmp/a.iso", &[0xab; 20], 4096, &stats);
+        assert_eq!(env[0], ("BT_NAME", "a.iso".to_string()));
+        assert_eq!(env[2], ("BT_INFO_HASH", "ab".repeat(20)));
+        assert_eq!(env[3], ("BT_SIZE", "4096".to_string()));
+        assert_eq!(env[4], ("BT_RATIO", "0.000".to_string()));
+    }
+
+    #[test]
+    fn test_bad_piece_runs() {
+        use PieceState::*;
+        let runs = bad_piece_runs(&[Good, Corrupt, Corrupt, Missing, Good, Corrupt, Missing]);
+        assert_eq!(
+            runs,
+            [
+                (Corrupt, 1..3),
+                (Missing, 3..4),
+                (Corrupt, 5..6),
+                (Missing, 6..7)
+            ]
+        );
+        assert!(bad_piece_runs(&[Good, Good]).is_empty());
+    }
+
+    #[test]
+    fn test_progress() {
+        let mut progress = Progress::default();
+        let start = Instant::now();
+        progress.update(&DownloadEvent::Started {
+            length: 4096,
+            piece_count: 2,
+        });
+        progress.update(&DownloadEvent::PeerConnected {
+            addr: "127.0.0.1:6881".parse().unwrap(),
+            source: PeerSource::Manual,
+        });
+        progress.sample(start);
+        progress.update(&DownloadEvent::PieceVerified {
+            index: 0,
+            length: 2048,
+        });
+        progress.sample(start + Duration::from_secs(2));
+        assert_eq!(progress.status(), " 50.0%      1.0 KiB/s 1 peers");
+        assert!(progress
+            .bar()
+            .starts_with(&format!("[{}{}]", "#".repeat(15), " ".repeat(15))));
+    }
+
+    #[test]
+    fn test_json_progress_lines() {
+        let mut progress = Progress::default();
+        let mut out = vec![];
+        let start = Instant::now();
+        for (secs, event) in [
+            DownloadEvent::Started {
+                length: 4096,
+                piece_count: 2,
+            },
+            DownloadEvent::PieceUnavailable { index: 1 },
+            DownloadEvent::PieceVerified {
+                index: 0,
+                length: 2048,
+            },
+            DownloadEvent::PieceVerified {
+                index: 1,
+                length: 2048,
+            },
+            DownloadEvent::Completed,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            progress.update(&event);
+            if let Some(v) = Progress::json_event(&event) {
+                Progress::write_json(&mut out, &v).unwrap();
+            }
+            progress.sample(start + Duration::from_secs(secs as u64));
+            Progress::write_json(&mut out, &progress.json_sample()).unwrap();
+        }
+        let lines = String::from_utf8(out).unwrap();
+        let events = lines
+            .lines()
+            .map(|x| serde_json::from_str::<serde_json::Value>(x).unwrap()["event"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 8);
+        assert_eq!(events.last().unwrap(), "speed_sample");
+        assert!(events.contains(&json!("completed")));
+    }
 }
@@ -1,11 +1,11 @@
-use anyhow::Context;
+use md5::{Digest as _, Md5};
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 
 use crate::{
     decode::{decode_bencoded_value, DecodeContext},
     encode::{encode_dictionary, EncodeContext},
-    utils::BtResult,
+    utils::{raw_bytes_to_string, BtError, EncodeError, ParseError, PeerError},
 };
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -13,11 +13,23 @@ pub struct Torrent {
     #[serde(rename = "announce")]
     tracker_url: String,
 
+    /// Tiers of trackers in BEP 12, used instead of `tracker_url` if present.
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    announce_list: Option<Vec<Vec<String>>>,
+
     pub info: TorrentInfo,
 
     /// Byte arraym not hexed.
     #[serde(skip_serializing, skip_deserializing)]
     info_hash: [u8; 20],
+
+    /// Bencoded info dict, `info_hash` is calculated from it.
+    #[serde(skip_serializing, skip_deserializing)]
+    raw_info: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -31,18 +43,30 @@ pub struct TorrentInfo {
 
     pieces: String,
 
+    /// Optional hex encoded MD5 of the whole file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    md5sum: Option<String>,
+
+    /// File attributes in BEP 47, like `x` for executable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attr: Option<String>,
+
+    /// PEM certificate of the authority signing peers of an SSL torrent, peers only accept TLS
+    /// connections then.
+    #[serde(default, rename = "ssl-cert", skip_serializing_if = "Option::is_none")]
+    ssl_cert: Option<String>,
+
     #[serde(skip_serializing, skip_deserializing)]
     pub piece_hashes: Vec<Vec<u8>>,
 }
 
 impl Torrent {
-    pub fn new(tracker_url: String, mut info: TorrentInfo) -> BtResult<Torrent> {
+    pub fn new(tracker_url: String, mut info: TorrentInfo) -> Result<Torrent, ParseError> {
         let info_value = serde_json::to_value(&info).unwrap();
         let mut ctx = EncodeContext::new();
-        encode_dictionary(&mut ctx, info_value.as_object().unwrap());
-        let mut hasher = Sha1::new();
-        hasher.update(&ctx.data());
-        let info_hash = hasher.finalize().try_into().unwrap();
+        encode_dictionary(&mut ctx, info_value.as_object().unwrap()).map_err(ParseError::Encode)?;
+        let raw_info = ctx.consume();
+        let info_hash = Sha1::digest(&raw_info).into();
 
         let mut piece_hashes = vec![];
         for p in info.pieces.as_bytes().chunks_exact(40) {
@@ -57,29 +81,62 @@ impl Torrent {
             .collect();
 
         let torrent = Self {
-            tracker_url,
+            // Strings hold one char per byte, like the decoder gives.
+            tracker_url: raw_bytes_to_string(tracker_url.as_bytes()),
+            announce_list: None,
             info,
             info_hash,
+            raw_info,
         };
 
         Ok(torrent)
     }
 
-    pub fn parse_from_file(file_path: &str) -> BtResult<Torrent> {
-        let content =
-            std::fs::read(file_path).with_context(|| format!("failed to read file from"))?;
-        let mut ctx = DecodeContext::new(content);
-        let torrent: Torrent = decode_bencoded_value(&mut ctx)
-            .context("bencode decode failed")
-            .and_then(serde_json::Value::try_into)?;
+    pub fn parse_from_file(file_path: &str) -> Result<Torrent, BtError> {
+        let content = std::fs::read(file_path)?;
+        let torrent = Self::parse_from_bytes(&content)?;
         Ok(torrent)
     }
 
+    /// Parse torrent from the bencoded content of a torrent file.
+    ///
+    /// Info hash is calculated over the original bytes of info dict.
+    pub fn parse_from_bytes(data: &[u8]) -> Result<Torrent, ParseError> {
+        let mut ctx = DecodeContext::new(data.to_vec());
+        let value = decode_bencoded_value(&mut ctx).map_err(ParseError::Bencode)?;
+        let span = ctx.value_span("info");
+        let mut torrent = Self::try_from(value)?;
+        if let Some(span) = span {
+            torrent.raw_info = data[span].to_vec();
+            torrent.info_hash = Sha1::digest(&torrent.raw_info).into();
+        }
+        Ok(torrent)
+    }
+
+    /// Build torrent announcing to `tracker_url` from info dict `raw_info` fetched from peers.
+    pub fn from_metadata(tracker_url: String, raw_info: &[u8]) -> Result<Torrent, ParseError> {
+        // The encoder takes strings of one char per byte, like the decoder gives, not text.
+        let head = serde_json::json!({ "announce": raw_bytes_to_string(tracker_url.as_bytes()) });
+        Self::parse_from_bytes(&encode_file(&head, raw_info).map_err(ParseError::Encode)?)
+    }
+
+    /// Bencoded content of torrent file, the info dict kept as parsed so the info hash is the same.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut head = serde_json::json!({ "announce": self.tracker_url });
+        if let Some(list) = &self.announce_list {
+            head["announce-list"] = serde_json::json!(list);
+        }
+        encode_file(&head, &self.raw_info)
+    }
+
     pub fn print_info(&self) {
         println!("Tracker URL: {}", self.tracker_url);
         println!("Length: {}", self.info.length);
         println!("Info Hash: {}", hex::encode(self.info_hash));
         println!("Piece Length: {}", self.info.piece_length);
+        if let Some(md5sum) = &self.info.md5sum {
+            println!("MD5: {md5sum}");
+        }
         println!("Piece Hashs:");
         for ph in self.info.piece_hashes.iter() {
             let pstr = ph.iter().map(|x| x.to_owned() as char).collect::<String>();
@@ -87,18 +144,78 @@ impl Torrent {
         }
     }
 
+    /// Suggested name of the file.
+    pub fn name(&self) -> &str {
+        &self.info.name
+    }
+
     pub fn tracker_url(&self) -> &str {
         &self.tracker_url
     }
 
+    /// Trackers grouped in tiers, the announce url is the only tier if no announce list.
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        match &self.announce_list {
+            Some(v) if v.iter().any(|tier| !tier.is_empty()) => v.clone(),
+            _ => vec![vec![self.tracker_url.clone()]],
+        }
+    }
+
+    /// Any tracker is given, torrents from magnet links without `tr` have none.
+    pub fn has_trackers(&self) -> bool {
+        self.tracker_tiers().iter().flatten().any(|x| !x.is_empty())
+    }
+
     pub fn info_hash(&self) -> &[u8; 20] {
         &self.info_hash
     }
 
+    /// Bencoded info dict the info hash is calculated from.
+    ///
+    /// Same as in the torrent file if parsed from one, otherwise encoded from [`Torrent::info`].
+    pub fn raw_info_bytes(&self) -> &[u8] {
+        &self.raw_info
+    }
+
+    /// Hex encoded MD5 of the whole file, if given in torrent.
+    pub fn md5sum(&self) -> Option<&str> {
+        self.info.md5sum.as_deref()
+    }
+
+    /// The file is marked executable by the `x` attribute.
+    pub fn is_executable(&self) -> bool {
+        self.info.attr.as_deref().is_some_and(|x| x.contains('x'))
+    }
+
+    /// Certificate authority of peers if this is an SSL torrent.
+    pub fn ssl_cert(&self) -> Option<&str> {
+        self.info.ssl_cert.as_deref()
+    }
+
+    /// Check MD5 of downloaded file `data`, passes if the torrent does not have one.
+    pub fn verify_md5(&self, data: &[u8]) -> Result<(), PeerError> {
+        let Some(expected) = self.md5sum() else {
+            return Ok(());
+        };
+        let actually = hex::encode(Md5::digest(data));
+        if !expected.eq_ignore_ascii_case(&actually) {
+            return Err(PeerError::CheksumMismatch {
+                expected: expected.to_string(),
+                actually,
+            });
+        }
+        Ok(())
+    }
+
     pub fn length(&self) -> usize {
         self.info.length
     }
 
+    /// Length of all pieces but the last one.
+    pub fn nominal_piece_length(&self) -> usize {
+        self.info.piece_length
+    }
+
     /// Get the length of piece specified by `piece_index`.
     ///
     /// Usually `piece_length` but the last may be less than that.
@@ -120,20 +237,24 @@ impl Torrent {
 }
 
 impl TryFrom<serde_json::Value> for Torrent {
-    type Error = anyhow::Error;
+    type Error = ParseError;
 
     fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
         let info_map = value
             .get("info")
             .and_then(|x| x.as_object())
-            .context("info map not found")?;
+            .ok_or(ParseError::MissingField("info"))?;
+        // Merkle torrents in BEP 30 have the root of piece hash tree instead of all piece hashes.
+        if info_map.contains_key("root hash") && !info_map.contains_key("pieces") {
+            return Err(ParseError::UnsupportedTorrent("merkle torrent (BEP 30)"));
+        }
         let mut ctx = EncodeContext::new();
-        encode_dictionary(&mut ctx, info_map);
+        encode_dictionary(&mut ctx, info_map).map_err(ParseError::Encode)?;
 
-        let mut torrent = serde_json::from_value::<Self>(value)?;
-        let mut hasher = Sha1::new();
-        hasher.update(&ctx.data());
-        torrent.info_hash = hasher.finalize().try_into().unwrap();
+        let mut torrent =
+            serde_json::from_value::<Self>(value).map_err(ParseError::InvalidTorrent)?;
+        torrent.raw_info = ctx.consume();
+        torrent.info_hash = Sha1::digest(&torrent.raw_info).into();
 
         let mut piece_hashes = vec![];
         for p in torrent.info.pieces.as_bytes().chunks_exact(40) {
@@ -151,3 +272,80 @@ impl TryFrom<serde_json::Value> for Torrent {
         Ok(torrent)
     }
 }
+
+/// Torrent file of dict `head` with key `info` of bencoded `raw_info`, `head` has keys sorting
+/// before `info` only.
+fn encode_file(head: &serde_json::Value, raw_info: &[u8]) -> Result<Vec<u8>, EncodeError> {
+    let mut data = crate::encode::to_bytes(head)?;
+    // Reopen the dict to append the info dict as is.
+    data.pop();
+    data.extend_from_slice(b"4:info");
+    data.extend_from_slice(raw_info);
+    data.push(b'e');
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_from_bytes() {
+        let torrent = Torrent::parse_from_bytes(include_bytes!("../sample.torrent")).unwrap();
+        assert_eq!(
+            hex::encode(torrent.info_hash()),
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+        );
+
+        assert!(matches!(
+            Torrent::parse_from_bytes(b"d8:announce3:fooe"),
+            Err(ParseError::MissingField("info"))
+        ));
+        // Keys not sorted, re-encoding the info dict gives different bytes.
+        let data = b"d8:announce3:foo4:infod6:lengthi3e4:name1:a6:pieces0:12:piece lengthi1eee";
+        let torrent = Torrent::parse_from_bytes(data).unwrap();
+        assert_eq!(torrent.raw_info_bytes(), &data[22..data.len() - 1]);
+        assert_eq!(
+            torrent.info_hash(),
+            &<[u8; 20]>::from(Sha1::digest(&data[22..data.len() - 1]))
+        );
+
+        let data = b"d8:announce3:foo4:infod6:lengthi3e6:md5sum32:900150983CD24FB0D6963F7D28E17F724:name1:a6:pieces0:12:piece lengthi1eee";
+        let torrent = Torrent::parse_from_bytes(data).unwrap();
+        assert_eq!(torrent.md5sum(), Some("900150983CD24FB0D6963F7D28E17F72"));
+        assert!(torrent.verify_md5(b"abc").is_ok());
+        assert!(torrent.verify_md5(b"abd").is_err());
+        assert_eq!(torrent.ssl_cert(), None);
+
+        let data = b"d8:announce3:foo4:infod6:lengthi3e4:name1:a6:pieces0:12:piece lengthi1e8:ssl-cert4:x509ee";
+        let torrent = Torrent::parse_from_bytes(data).unwrap();
+        assert_eq!(torrent.ssl_cert(), Some("x509"));
+
+        let data = b"d8:announce3:foo4:infod6:lengthi3e4:name1:a12:piece lengthi1e9:root hash20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(matches!(
+            Torrent::parse_from_bytes(data),
+            Err(ParseError::UnsupportedTorrent(_))
+        ));
+
+        let data = b"d8:announce3:foo13:announce-listll3:fooel3:baree4:infod6:lengthi3e4:name1:a6:pieces0:12:piece lengthi1e1:xi0eee";
+        let torrent = Torrent::parse_from_bytes(data).unwrap();
+        assert_eq!(torrent.to_bytes().unwrap(), data);
+        let from_metadata =
+            Torrent::from_metadata("foo".to_string(), torrent.raw_info_bytes()).unwrap();
+        assert_eq!(from_metadata.info_hash(), torrent.info_hash());
+        assert!(from_metadata.has_trackers());
+        let trackerless = Torrent::from_metadata(String::new(), torrent.raw_info_bytes()).unwrap();
+        // Non ascii text is written as utf8, not one byte per char.
+        let text = Torrent::from_metadata("udp://é".to_string(), torrent.raw_info_bytes()).unwrap();
+        assert!(text
+            .to_bytes()
+            .unwrap()
+            .starts_with("d8:announce8:udp://é".as_bytes()));
+        assert!(!trackerless.has_trackers());
+
+        assert!(matches!(
+            Torrent::parse_from_bytes(b"x"),
+            Err(ParseError::Bencode(_))
+        ));
+    }
+}
@@ -1,12 +1,109 @@
-use std::future::Future;
+use std::{sync::Mutex, time::SystemTime};
 
-use futures::StreamExt;
 use thiserror::Error;
 
-pub type BtResult<T> = anyhow::Result<T, anyhow::Error>;
+/// Result used inside the library, errors carry context.
+///
+/// Public functions convert it to [`BtError`].
+pub(crate) type BtResult<T> = anyhow::Result<T, anyhow::Error>;
 
+/// Source error without a typed cause.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Errors returned from the library.
 #[derive(Debug, Error)]
 pub enum BtError {
+    #[error("bencode decode failed")]
+    Decode(#[from] DecodeError),
+
+    #[error("invalid torrent or magnet link")]
+    Parse(#[from] ParseError),
+
+    #[error("failed to query peers")]
+    Tracker(#[from] TrackerError),
+
+    #[error("failed to communicate with peer")]
+    Peer(#[from] PeerError),
+
+    #[error("failed to access file")]
+    Disk(#[from] std::io::Error),
+
+    #[error("peer violated the protocol")]
+    Protocol(#[from] ProtocolError),
+
+    #[error("download cancelled")]
+    Cancelled,
+}
+
+impl BtError {
+    /// Convert an internal error.
+    ///
+    /// Typed errors anywhere in the context chain are kept, others are wrapped by `fallback`.
+    pub(crate) fn classify(err: anyhow::Error, fallback: fn(BoxError) -> BtError) -> BtError {
+        let err = match err.downcast::<BtError>() {
+            Ok(v) => return v,
+            Err(e) => e,
+        };
+        let err = match err.downcast::<DecodeError>() {
+            Ok(v) => return v.into(),
+            Err(e) => e,
+        };
+        let err = match err.downcast::<ParseError>() {
+            Ok(v) => return v.into(),
+            Err(e) => e,
+        };
+        let err = match err.downcast::<TrackerError>() {
+            Ok(v) => return v.into(),
+            Err(e) => e,
+        };
+        let err = match err.downcast::<PeerError>() {
+            Ok(v) => return v.into(),
+            Err(e) => e,
+        };
+        let err = match err.downcast::<ProtocolError>() {
+            Ok(v) => return v.into(),
+            Err(e) => e,
+        };
+        fallback(err.into())
+    }
+
+    /// Wrap errors without a typed cause as tracker errors.
+    pub(crate) fn tracker(err: anyhow::Error) -> BtError {
+        Self::classify(err, |e| TrackerError::Request(e).into())
+    }
+
+    /// Wrap errors without a typed cause as peer errors.
+    pub(crate) fn peer(err: anyhow::Error) -> BtError {
+        Self::classify(err, |e| PeerError::Connection(e).into())
+    }
+
+    /// Whether trying again, later or with other peers, may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            BtError::Decode(_) | BtError::Parse(_) | BtError::Protocol(_) | BtError::Cancelled => {
+                false
+            }
+            BtError::Tracker(e) => match e {
+                TrackerError::Status(code) => *code == 429 || *code >= 500,
+                TrackerError::Failure(_)
+                | TrackerError::WebRtcOnlyPeers(_)
+                | TrackerError::Unproxied(_) => false,
+                TrackerError::Request(_) | TrackerError::Dht(_) => true,
+            },
+            BtError::Peer(_) => true,
+            BtError::Disk(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::WouldBlock
+            ),
+        }
+    }
+}
+
+/// Invalid bencode data.
+#[derive(Debug, Error)]
+pub enum DecodeError {
     #[error("data already consumed")]
     Ended,
 
@@ -25,67 +122,184 @@ pub enum BtError {
     #[error("invalid key of map {1} at {0}")]
     InvalidMapKey(usize, serde_json::Value),
 
-    #[error("invalid json value")]
-    SerializationError(#[from] serde_json::Error),
-
     #[error("char {ch} not found from pos {pos}")]
     CharNotFound { pos: usize, ch: u8 },
 
+    #[error("nesting too deep at {0}")]
+    TooDeep(usize),
+
+    #[error("malformed bencode")]
+    Malformed(#[source] BoxError),
+}
+
+/// Values bencode can not represent.
+#[derive(Debug, Error)]
+pub enum EncodeError {
+    #[error("unsupported value {0}")]
+    Unsupported(serde_json::Value),
+
+    #[error("char {0:?} out of byte range")]
+    InvalidChar(char),
+
+    #[error("value of {0} is not a hex string")]
+    InvalidHex(String),
+}
+
+/// Failures when announcing to trackers or looking up DHT.
+#[derive(Debug, Error)]
+pub enum TrackerError {
     #[error("http request failed with status code {0}")]
-    NetworkError(u16),
+    Status(u16),
+
+    #[error("tracker responded failure: {0}")]
+    Failure(String),
+
+    #[error("found {0} WebRTC peers only, connecting to WebRTC peers is not supported")]
+    WebRtcOnlyPeers(usize),
+
+    #[error("tracker request failed")]
+    Request(#[source] BoxError),
 
+    #[error("dht lookup failed")]
+    Dht(#[source] BoxError),
+
+    #[error("tracker {0} is not used through a proxy, only http trackers are")]
+    Unproxied(String),
+}
+
+/// Failures when downloading from peers.
+#[derive(Debug, Error)]
+pub enum PeerError {
     #[error("checksum mismatch: expected {expected}, actually {actually}")]
-    CheksumMismatchError { expected: String, actually: String },
+    CheksumMismatch { expected: String, actually: String },
+
+    #[error("piece {0} is not available in connected peers")]
+    PieceUnavailable(usize),
+
+    #[error("no peers found")]
+    NoPeers,
+
+    #[error("peer connection failed")]
+    Connection(#[source] BoxError),
 }
 
-pub fn u8_is_digit(n: &u8) -> bool {
-    n >= &b'0' && n <= &b'9'
+/// Peer sent something not following the protocol.
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("unknown message id {0}")]
+    UnknownMessage(u8),
+
+    #[error("unexpected message id {0}")]
+    UnexpectedMessage(u8),
+
+    #[error("invalid handshake message length {0}")]
+    InvalidHandshake(usize),
+
+    #[error("message length {length} exceeds the max {max}")]
+    MessageTooLong { length: usize, max: usize },
+
+    #[error("invalid bitfield length {length}, expected {expected}")]
+    InvalidBitfieldLength { length: usize, expected: usize },
+
+    #[error("invalid protocol {0:?} in handshake")]
+    InvalidProtocol(String),
+
+    #[error("info hash mismatch in handshake: expected {expected}, actually {actually}")]
+    InfoHashMismatch { expected: String, actually: String },
+
+    #[error("peer id in handshake is ours, connected to ourselves")]
+    OwnPeerId,
+
+    #[error("metadata hash mismatch: expected {expected}, actually {actually}")]
+    MetadataHashMismatch { expected: String, actually: String },
 }
 
-pub fn char_slice_to_usize(data: &[u8]) -> Option<usize> {
-    let mut ret = 0;
+/// Failures when parsing torrent files and magnet links.
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("failed to decode bencode")]
+    Bencode(#[source] DecodeError),
 
-    for (idx, d) in data.iter().rev().enumerate() {
-        if u8_is_digit(d) {
-            ret += (d.to_owned() as usize - 48) * 10_usize.pow(idx as u32);
-        } else {
-            return None;
-        }
-    }
+    #[error("field {0} not found")]
+    MissingField(&'static str),
+
+    #[error("failed to encode bencode")]
+    Encode(#[source] EncodeError),
+
+    #[error("invalid torrent content")]
+    InvalidTorrent(#[source] serde_json::Error),
 
-    Some(ret)
+    #[error("unsupported torrent type: {0}")]
+    UnsupportedTorrent(&'static str),
+
+    #[error("not a magnet link with btih info hash")]
+    InvalidMagnetPrefix,
+
+    #[error("invalid info hash {0}")]
+    InvalidInfoHash(String),
+
+    #[error("invalid magnet parameters")]
+    InvalidMagnetParams(#[source] serde_urlencoded::de::Error),
 }
 
-pub fn char_slice_to_isize(data: &[u8]) -> Option<isize> {
-    let mut ret = 0;
-    let neg = if let Some(b'-') = data.iter().next() {
-        true
-    } else {
-        false
-    };
+/// `s` is a DNS host name like `example.com` or an onion address, not an ip literal.
+pub fn is_host_name(s: &str) -> bool {
+    s.len() <= 253
+        && s.bytes().any(|x| x.is_ascii_alphabetic())
+        && s.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|x| x.is_ascii_alphanumeric() || x == b'-')
+        })
+}
 
-    let it = if neg {
-        data.iter().skip(1)
-    } else {
-        data.iter().skip(0)
-    };
+pub fn u8_is_digit(n: &u8) -> bool {
+    n.is_ascii_digit()
+}
 
-    let mut p = if neg { data.len() - 1 } else { data.len() } as u32;
+/// Parse ascii digits into usize, `None` if any byte is not a digit, `data` is empty or the
+/// number overflows.
+pub fn char_slice_to_usize(data: &[u8]) -> Option<usize> {
+    if data.is_empty() {
+        return None;
+    }
 
-    for i in it {
-        p -= 1;
-        if u8_is_digit(i) {
-            ret += (i.to_owned() as isize - 48) * 10_isize.pow(p);
-        } else {
+    data.iter().try_fold(0usize, |acc, d| {
+        if !u8_is_digit(d) {
             return None;
         }
-    }
+        acc.checked_mul(10)?.checked_add((d - b'0') as usize)
+    })
+}
 
-    if neg {
-        ret *= -1;
+/// Parse ascii digits with an optional leading '-' into isize, `None` if any other byte is not a
+/// digit, there is no digit or the number overflows.
+pub fn char_slice_to_isize(data: &[u8]) -> Option<isize> {
+    let (neg, digits) = match data.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        _ => (false, data),
+    };
+    if digits.is_empty() {
+        return None;
     }
 
-    Some(ret)
+    // Accumulate towards the sign so that isize::MIN does not overflow.
+    digits.iter().try_fold(0isize, |acc, d| {
+        if !u8_is_digit(d) {
+            return None;
+        }
+        let acc = acc.checked_mul(10)?;
+        let d = (d - b'0') as isize;
+        if neg {
+            acc.checked_sub(d)
+        } else {
+            acc.checked_add(d)
+        }
+    })
 }
 
 pub fn decode_bytes_from_string(s: &str) -> Vec<u8> {
@@ -96,21 +310,104 @@ pub fn encode_bytes_to_string(d: &Vec<u8>) -> String {
     hex::encode(d)
 }
 
-pub async fn parallel_future<T, U, W, V>(
-    task_source: T,
-    buffer_size: usize,
-    closure: U,
-) -> anyhow::Result<Vec<V>>
-where
-    T: Iterator,
-    U: FnMut(<T as Iterator>::Item) -> W,
-    W: Future<Output = anyhow::Result<V>> + Sized,
-{
-    let ret = futures::stream::iter(task_source.map(closure))
-        .buffered(buffer_size)
-        .collect::<Vec<anyhow::Result<V>>>()
-        .await
-        .into_iter()
-        .collect::<anyhow::Result<Vec<V>>>()?;
-    Ok(ret)
+/// Convert raw bytes into string in the same way `decode_string` does: each byte becomes a char.
+pub fn raw_bytes_to_string(d: &[u8]) -> String {
+    d.iter().map(|x| x.to_owned() as char).collect()
+}
+
+/// The reverse of [`raw_bytes_to_string`].
+///
+/// Strings holding chars out of byte range are not produced by the decoder, they are
+/// returned as utf8 bytes.
+pub fn string_to_raw_bytes(s: &str) -> Vec<u8> {
+    if s.chars().all(|x| (x as u32) <= 0xff) {
+        s.chars().map(|x| x as u8).collect()
+    } else {
+        s.as_bytes().to_vec()
+    }
+}
+
+/// State of the random number generator shared by the whole process, `None` before seeded.
+static RANDOM_STATE: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Seed all randomized behaviors, like DHT node ids and tracker transaction ids, so that a run
+/// can be reproduced.
+///
+/// Without calling this, a seed is taken from current time and process id on first use.
+pub fn set_random_seed(seed: u64) {
+    *RANDOM_STATE.lock().unwrap() = Some(seed);
+}
+
+/// Next random number from the shared generator (splitmix64).
+pub(crate) fn random_u64() -> u64 {
+    let mut state = RANDOM_STATE.lock().unwrap();
+    let s = state.get_or_insert_with(|| {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        (now.as_nanos() as u64) ^ (std::process::id() as u64).rotate_left(32)
+    });
+    *s = s.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *s;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Fill `buf` with random bytes from the shared generator.
+pub(crate) fn random_bytes(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let n = random_u64().to_be_bytes();
+        chunk.copy_from_slice(&n[..chunk.len()]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::{anyhow, Context};
+
+    use super::*;
+
+    #[test]
+    fn test_classify_error() {
+        let err = Err::<(), _>(ProtocolError::UnknownMessage(42))
+            .context("failed to read message")
+            .context("failed to download piece")
+            .unwrap_err();
+        assert!(matches!(
+            BtError::peer(err),
+            BtError::Protocol(ProtocolError::UnknownMessage(42))
+        ));
+
+        let err = BtError::tracker(anyhow!("connection refused"));
+        assert!(matches!(err, BtError::Tracker(TrackerError::Request(_))));
+        assert!(err.is_retryable());
+
+        assert!(BtError::Tracker(TrackerError::Status(503)).is_retryable());
+        assert!(!BtError::Tracker(TrackerError::Status(404)).is_retryable());
+        assert!(!BtError::Decode(DecodeError::Ended).is_retryable());
+    }
+
+    #[test]
+    fn test_parse_digits() {
+        assert_eq!(char_slice_to_usize(b"0"), Some(0));
+        assert_eq!(char_slice_to_usize(b"16384"), Some(16384));
+        assert_eq!(char_slice_to_usize(b""), None);
+        assert_eq!(char_slice_to_usize(b"12a"), None);
+        assert_eq!(
+            char_slice_to_usize(usize::MAX.to_string().as_bytes()),
+            Some(usize::MAX)
+        );
+        assert_eq!(char_slice_to_usize(b"99999999999999999999999"), None);
+
+        assert_eq!(char_slice_to_isize(b"-52"), Some(-52));
+        assert_eq!(char_slice_to_isize(b"52"), Some(52));
+        assert_eq!(char_slice_to_isize(b"-"), None);
+        assert_eq!(char_slice_to_isize(b"5-2"), None);
+        assert_eq!(
+            char_slice_to_isize(isize::MIN.to_string().as_bytes()),
+            Some(isize::MIN)
+        );
+        assert_eq!(char_slice_to_isize(b"9223372036854775808"), None);
+    }
 }